@@ -16,7 +16,7 @@ pub async fn list_accounts() -> Result<Vec<Account>, String> {
 pub async fn add_account(app: tauri::AppHandle, _email: String, refresh_token: String) -> Result<Account, String> {
     // 1. 使用 refresh_token 获取 access_token
     // 注意：这里我们忽略传入的 _email，而是直接去 Google 获取真实的邮箱
-    let token_res = modules::oauth::refresh_access_token(&refresh_token).await?;
+    let token_res = modules::oauth::refresh_access_token(&refresh_token, &crate::proxy::common::fingerprint::AccountIdentity::default()).await?;
 
     // 2. 获取用户信息
     let user_info = modules::oauth::get_user_info(&token_res.access_token).await?;
@@ -45,7 +45,11 @@ pub async fn add_account(app: tauri::AppHandle, _email: String, refresh_token: S
 
 /// 删除账号
 #[tauri::command]
-pub async fn delete_account(app: tauri::AppHandle, account_id: String) -> Result<(), String> {
+pub async fn delete_account(
+    app: tauri::AppHandle,
+    proxy_state: tauri::State<'_, crate::commands::proxy::ProxyServiceState>,
+    account_id: String,
+) -> Result<(), String> {
     modules::logger::log_info(&format!("收到删除账号请求: {}", account_id));
     modules::delete_account(&account_id).map_err(|e| {
         modules::logger::log_error(&format!("删除账号失败: {}", e));
@@ -53,6 +57,9 @@ pub async fn delete_account(app: tauri::AppHandle, account_id: String) -> Result
     })?;
     modules::logger::log_info(&format!("账号删除成功: {}", account_id));
     
+    // 立即从反代账号池移除
+    crate::commands::proxy::remove_proxy_accounts(&proxy_state, std::slice::from_ref(&account_id)).await;
+    
     // 强制同步托盘
     crate::modules::tray::update_tray_menus(&app);
     Ok(())
@@ -60,13 +67,20 @@ pub async fn delete_account(app: tauri::AppHandle, account_id: String) -> Result
 
 /// 批量删除账号
 #[tauri::command]
-pub async fn delete_accounts(app: tauri::AppHandle, account_ids: Vec<String>) -> Result<(), String> {
+pub async fn delete_accounts(
+    app: tauri::AppHandle,
+    proxy_state: tauri::State<'_, crate::commands::proxy::ProxyServiceState>,
+    account_ids: Vec<String>,
+) -> Result<(), String> {
     modules::logger::log_info(&format!("收到批量删除请求，共 {} 个账号", account_ids.len()));
     modules::account::delete_accounts(&account_ids).map_err(|e| {
         modules::logger::log_error(&format!("批量删除失败: {}", e));
         e
     })?;
     
+    // 立即从反代账号池移除
+    crate::commands::proxy::remove_proxy_accounts(&proxy_state, &account_ids).await;
+    
     // 强制同步托盘
     crate::modules::tray::update_tray_menus(&app);
     Ok(())
@@ -82,6 +96,63 @@ pub async fn switch_account(app: tauri::AppHandle, account_id: String) -> Result
     res
 }
 
+/// 设置账号专属出口代理 (为空时清除)
+/// 反代请求、OAuth 刷新与配额查询都会经过该代理
+#[tauri::command]
+pub async fn set_account_egress_proxy(
+    proxy_state: tauri::State<'_, crate::commands::proxy::ProxyServiceState>,
+    account_id: String,
+    egress_proxy: Option<String>,
+) -> Result<Account, String> {
+    let egress_proxy = egress_proxy
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty());
+    if let Some(url) = &egress_proxy {
+        crate::utils::http::build_egress_proxy(url)?;
+    }
+
+    let mut account = modules::load_account(&account_id)?;
+    account.egress_proxy = egress_proxy;
+    modules::save_account(&account)?;
+
+    // 立即同步到运行中的反代账号池
+    if let Some(instance) = proxy_state.instance.read().await.as_ref() {
+        if let Err(e) = instance.token_manager.load_accounts().await {
+            tracing::warn!("同步账号出口代理失败: {}", e);
+        }
+    }
+    Ok(account)
+}
+
+/// 设置账号使用的客户端指纹 (为空时使用默认指纹)
+#[tauri::command]
+pub async fn set_account_client_profile(
+    proxy_state: tauri::State<'_, crate::commands::proxy::ProxyServiceState>,
+    account_id: String,
+    client_profile: Option<String>,
+) -> Result<Account, String> {
+    let client_profile = client_profile
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    if let Some(name) = &client_profile {
+        let config = modules::config::load_app_config()?;
+        if !config.proxy.client_profiles.iter().any(|p| &p.name == name) {
+            return Err(format!("客户端指纹不存在: {}", name));
+        }
+    }
+
+    let mut account = modules::load_account(&account_id)?;
+    account.client_profile = client_profile;
+    modules::save_account(&account)?;
+
+    if let Some(instance) = proxy_state.instance.read().await.as_ref() {
+        if let Err(e) = instance.token_manager.load_accounts().await {
+            tracing::warn!("同步账号客户端指纹失败: {}", e);
+        }
+    }
+    Ok(account)
+}
+
 /// 获取当前账号
 #[tauri::command]
 pub async fn get_current_account() -> Result<Option<Account>, String> {
@@ -208,11 +279,17 @@ pub async fn save_config(
     proxy_state: tauri::State<'_, crate::commands::proxy::ProxyServiceState>,
     config: AppConfig
 ) -> Result<(), String> {
+    crate::proxy::middleware::auth::ApiKeyRegistry::validate(&config.proxy)?;
     modules::save_app_config(&config)?;
     
     // 通知托盘配置已更新
     let _ = app.emit("config://updated", ());
 
+    // 抓取配置与上游端点不依赖服务是否运行
+    proxy_state.capture.update_config(&config.proxy.capture);
+    crate::proxy::upstream::endpoint::configure(&config.proxy.upstream_endpoints);
+    crate::proxy::common::fingerprint::configure(&config.proxy);
+
     // 热更新正在运行的服务
     let instance_lock = proxy_state.instance.read().await;
     if let Some(instance) = instance_lock.as_ref() {
@@ -220,6 +297,22 @@ pub async fn save_config(
         instance.axum_server.update_mapping(&config.proxy).await;
         // 更新上游代理
         instance.axum_server.update_proxy(config.proxy.upstream_proxy.clone()).await;
+        // 更新 API Key 鉴权配置
+        instance.axum_server.update_security(&config.proxy).await;
+        // 更新上游超时配置
+        instance.axum_server.update_timeouts(&config.proxy).await;
+        // 更新重试策略
+        instance.axum_server.update_retry(&config.proxy).await;
+        // 更新远程媒体抓取配置
+        instance.axum_server.update_media_fetch(&config.proxy).await;
+        // 更新转发头信任配置
+        instance.axum_server.update_forwarding(&config.proxy).await;
+        // 更新上游录制/回放模式
+        if let Err(e) = instance.axum_server.update_fixtures(&config.proxy).await {
+            tracing::warn!("更新上游录制/回放配置失败: {}", e);
+        }
+        // 更新账号调度策略
+        instance.token_manager.update_strategy(&config.proxy);
         tracing::info!("已同步热更新反代服务配置");
     }
     
@@ -252,7 +345,7 @@ pub async fn start_oauth_login(app_handle: tauri::AppHandle) -> Result<Account,
     modules::logger::log_info(&format!("获取用户信息成功: {}", user_info.email));
     
     // 4. 尝试获取项目ID
-    let project_id = crate::proxy::project_resolver::fetch_project_id(&token_res.access_token)
+    let project_id = crate::proxy::project_resolver::fetch_project_id(&token_res.access_token, &crate::proxy::common::fingerprint::AccountIdentity::default())
         .await
         .ok();
     
@@ -307,7 +400,7 @@ pub async fn complete_oauth_login(app_handle: tauri::AppHandle) -> Result<Accoun
     modules::logger::log_info(&format!("获取用户信息成功: {}", user_info.email));
 
     // 4. 尝试获取项目ID
-    let project_id = crate::proxy::project_resolver::fetch_project_id(&token_res.access_token)
+    let project_id = crate::proxy::project_resolver::fetch_project_id(&token_res.access_token, &crate::proxy::common::fingerprint::AccountIdentity::default())
         .await
         .ok();
 
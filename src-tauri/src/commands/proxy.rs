@@ -3,6 +3,10 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 use serde::{Serialize, Deserialize};
 use crate::proxy::{ProxyConfig, TokenManager};
+use crate::proxy::capture::{CaptureStore, CaptureSummary};
+use crate::proxy::mock_upstream::{MockCall, MockUpstream, MockUpstreamScript};
+use crate::proxy::request_log::{RequestLog, RequestLogPage, RequestLogQuery};
+use crate::proxy::stats::{ProxyStats, StatsCollector};
 
 /// 反代服务状态
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,17 +17,13 @@ pub struct ProxyStatus {
     pub active_accounts: usize,
 }
 
-/// 反代服务统计
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct ProxyStats {
-    pub total_requests: u64,
-    pub success_count: u64,
-    pub error_count: u64,
-}
-
 /// 反代服务全局状态
 pub struct ProxyServiceState {
     pub instance: Arc<RwLock<Option<ProxyServiceInstance>>>,
+    pub stats: Arc<StatsCollector>,  // 请求统计，跨服务重启保留
+    pub request_log: Option<Arc<RequestLog>>,  // 结构化请求日志
+    pub capture: Arc<CaptureStore>,  // 请求/响应抓取
+    pub mock_upstream: Arc<RwLock<Option<MockUpstream>>>,  // 本地模拟 v1internal 服务
 }
 
 /// 反代服务实例
@@ -36,8 +36,23 @@ pub struct ProxyServiceInstance {
 
 impl ProxyServiceState {
     pub fn new() -> Self {
+        let data_dir = crate::modules::account::get_data_dir().ok();
+        let stats_path = data_dir.as_ref().map(|dir| dir.join("proxy_stats.json"));
+        let request_log = data_dir.as_ref().and_then(|dir| {
+            RequestLog::open(&dir.join("proxy_requests.db"))
+                .map_err(|e| tracing::warn!("{}", e))
+                .ok()
+        });
+        let capture_config = crate::modules::config::load_app_config()
+            .map(|c| c.proxy.capture)
+            .unwrap_or_default();
+        let capture_dir = data_dir.as_ref().map(|dir| dir.join("captures"));
         Self {
             instance: Arc::new(RwLock::new(None)),
+            stats: Arc::new(StatsCollector::new(stats_path)),
+            request_log: request_log.map(Arc::new),
+            capture: Arc::new(CaptureStore::new(capture_dir, capture_config)),
+            mock_upstream: Arc::new(RwLock::new(None)),
         }
     }
 }
@@ -55,12 +70,14 @@ pub async fn start_proxy_service(
     if instance_lock.is_some() {
         return Err("服务已在运行中".to_string());
     }
+    crate::proxy::middleware::auth::ApiKeyRegistry::validate(&config)?;
     
     // 2. 初始化 Token 管理器
     let app_data_dir = crate::modules::account::get_data_dir()?;
     let accounts_dir = app_data_dir.clone();
     
     let token_manager = Arc::new(TokenManager::new(accounts_dir));
+    token_manager.update_strategy(&config);
     
     // 3. 加载账号
     let active_accounts = token_manager.load_accounts().await
@@ -70,16 +87,27 @@ pub async fn start_proxy_service(
         return Err("没有可用账号，请先添加账号".to_string());
     }
     
+    // 后台提前刷新即将过期的 token
+    token_manager.start_auto_refresh();
+    // 监听账号目录，自动同步账号增删改
+    token_manager.start_account_sync();
+    
+    // 同步抓取配置、上游端点与客户端指纹
+    state.capture.update_config(&config.capture);
+    crate::proxy::upstream::endpoint::configure(&config.upstream_endpoints);
+    crate::proxy::common::fingerprint::configure(&config);
+    
+    // 定期保存请求统计
+    state.stats.start_auto_persist();
+    
     // 启动 Axum 服务器
     let (axum_server, server_handle) = 
         match crate::proxy::AxumServer::start(
-            config.port,
+            &config,
             token_manager.clone(),
-            config.anthropic_mapping.clone(),
-            config.openai_mapping.clone(),
-            config.custom_mapping.clone(),
-            config.request_timeout,
-            config.upstream_proxy.clone(),
+            state.stats.clone(),
+            state.request_log.clone(),
+            state.capture.clone(),
         ).await {
             Ok((server, handle)) => (server, handle),
             Err(e) => return Err(format!("启动 Axum 服务器失败: {}", e)),
@@ -127,6 +155,10 @@ pub async fn stop_proxy_service(
         instance.server_handle.await.ok();
     }
     
+    if let Err(e) = state.stats.persist() {
+        tracing::warn!("{}", e);
+    }
+    
     Ok(())
 }
 
@@ -154,12 +186,110 @@ pub async fn get_proxy_status(
 }
 
 /// 获取反代服务统计
+/// `since_ms` / `until_ms` 为 Unix 毫秒时间戳，省略表示不限 (统计按小时分桶)
 #[tauri::command]
 pub async fn get_proxy_stats(
-    _state: State<'_, ProxyServiceState>,
+    state: State<'_, ProxyServiceState>,
+    since_ms: Option<i64>,
+    until_ms: Option<i64>,
 ) -> Result<ProxyStats, String> {
-    // TODO: 实现统计收集
-    Ok(ProxyStats::default())
+    Ok(state.stats.query(since_ms, until_ms))
+}
+
+/// 分页查询反代请求日志
+#[tauri::command]
+pub async fn query_proxy_request_log(
+    state: State<'_, ProxyServiceState>,
+    query: RequestLogQuery,
+) -> Result<RequestLogPage, String> {
+    let log = state.request_log.clone().ok_or("请求日志不可用")?;
+    tokio::task::spawn_blocking(move || log.query(&query))
+        .await
+        .map_err(|e| format!("查询请求日志失败: {}", e))?
+}
+
+/// 列出已保存的请求抓取
+#[tauri::command]
+pub async fn list_proxy_captures(
+    state: State<'_, ProxyServiceState>,
+) -> Result<Vec<CaptureSummary>, String> {
+    state.capture.list()
+}
+
+/// 导出一条请求抓取 (JSON bundle) 到指定路径
+#[tauri::command]
+pub async fn export_proxy_capture(
+    state: State<'_, ProxyServiceState>,
+    request_id: String,
+    path: String,
+) -> Result<(), String> {
+    state.capture.export(&request_id, std::path::Path::new(&path))
+}
+
+/// 启动本地模拟 v1internal 服务，返回可填入 upstream_endpoints 的地址
+/// 已在运行时只替换脚本
+#[tauri::command]
+pub async fn start_mock_upstream(
+    state: State<'_, ProxyServiceState>,
+    port: Option<u16>,
+    script: Option<MockUpstreamScript>,
+) -> Result<String, String> {
+    let mut mock = state.mock_upstream.write().await;
+    let script = script.unwrap_or_default();
+    if let Some(running) = mock.as_ref() {
+        running.set_script(script);
+        return Ok(running.base_url());
+    }
+    let started = MockUpstream::start(port.unwrap_or(0), script).await?;
+    let base_url = started.base_url();
+    *mock = Some(started);
+    Ok(base_url)
+}
+
+/// 停止本地模拟 v1internal 服务
+#[tauri::command]
+pub async fn stop_mock_upstream(
+    state: State<'_, ProxyServiceState>,
+) -> Result<(), String> {
+    state.mock_upstream.write().await.take();
+    Ok(())
+}
+
+/// 本地模拟 v1internal 服务收到的调用
+#[tauri::command]
+pub async fn get_mock_upstream_calls(
+    state: State<'_, ProxyServiceState>,
+) -> Result<Vec<MockCall>, String> {
+    Ok(state
+        .mock_upstream
+        .read()
+        .await
+        .as_ref()
+        .map(|mock| mock.calls())
+        .unwrap_or_default())
+}
+
+/// 获取反代账号池的冷却/熔断状态
+#[tauri::command]
+pub async fn get_proxy_account_health(
+    state: State<'_, ProxyServiceState>,
+) -> Result<Vec<crate::proxy::account_health::AccountHealthStatus>, String> {
+    let instance_lock = state.instance.read().await;
+    
+    match instance_lock.as_ref() {
+        Some(instance) => Ok(instance.token_manager.health_snapshot()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// 从运行中的反代账号池移除账号 (删除账号后立即生效)
+pub async fn remove_proxy_accounts(state: &ProxyServiceState, account_ids: &[String]) {
+    let instance_lock = state.instance.read().await;
+    if let Some(instance) = instance_lock.as_ref() {
+        for account_id in account_ids {
+            instance.token_manager.remove_account(account_id);
+        }
+    }
 }
 
 /// 生成 API Key
@@ -168,7 +298,7 @@ pub fn generate_api_key() -> String {
     format!("sk-{}", uuid::Uuid::new_v4().simple())
 }
 
-/// 重新加载账号（当主应用添加/删除账号时调用）
+/// 重新加载账号（账号池会自动同步账号目录，此命令用于立即同步）
 #[tauri::command]
 pub async fn reload_proxy_accounts(
     state: State<'_, ProxyServiceState>,
@@ -205,6 +335,7 @@ pub async fn update_model_mapping(
     app_config.proxy.anthropic_mapping = config.anthropic_mapping;
     app_config.proxy.openai_mapping = config.openai_mapping;
     app_config.proxy.custom_mapping = config.custom_mapping;
+    app_config.proxy.model_fallbacks = config.model_fallbacks;
     crate::modules::config::save_app_config(&app_config).map_err(|e| e)?;
     
     Ok(())
@@ -82,6 +82,8 @@ pub fn run() {
             commands::delete_accounts,
             commands::switch_account,
             commands::get_current_account,
+            commands::set_account_egress_proxy,
+            commands::set_account_client_profile,
             // 配额命令
             commands::fetch_account_quota,
             commands::refresh_all_quotas,
@@ -108,6 +110,13 @@ pub fn run() {
             commands::proxy::stop_proxy_service,
             commands::proxy::get_proxy_status,
             commands::proxy::get_proxy_stats,
+            commands::proxy::query_proxy_request_log,
+            commands::proxy::list_proxy_captures,
+            commands::proxy::export_proxy_capture,
+            commands::proxy::start_mock_upstream,
+            commands::proxy::stop_mock_upstream,
+            commands::proxy::get_mock_upstream_calls,
+            commands::proxy::get_proxy_account_health,
             commands::proxy::generate_api_key,
             commands::proxy::reload_proxy_accounts,
             commands::proxy::update_model_mapping,
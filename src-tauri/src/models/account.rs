@@ -11,6 +11,12 @@ pub struct Account {
     pub quota: Option<QuotaData>,
     pub created_at: i64,
     pub last_used: i64,
+    /// 账号专属出口代理 (反代请求与 OAuth 刷新均经此代理，保证账号出口 IP 固定)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_proxy: Option<String>,
+    /// 客户端指纹名称 (对应 `client_profiles`，未设置时使用默认指纹)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_profile: Option<String>,
 }
 
 impl Account {
@@ -24,9 +30,16 @@ impl Account {
             quota: None,
             created_at: now,
             last_used: now,
+            egress_proxy: None,
+            client_profile: None,
         }
     }
 
+    /// 访问 Google 时使用的出口代理与客户端指纹
+    pub fn identity(&self) -> crate::proxy::common::fingerprint::AccountIdentity {
+        crate::proxy::common::fingerprint::AccountIdentity::new(self.egress_proxy.as_deref(), self.client_profile.as_deref())
+    }
+
     pub fn update_last_used(&mut self) {
         self.last_used = chrono::Utc::now().timestamp();
     }
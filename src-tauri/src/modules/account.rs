@@ -1,16 +1,23 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use serde_json;
 use uuid::Uuid;
 
 use crate::models::{Account, AccountIndex, AccountSummary, TokenData, QuotaData};
 use crate::modules;
+use dashmap::DashMap;
 use once_cell::sync::Lazy;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 /// 全局账号写入锁，防止并发操作导致索引文件损坏
 static ACCOUNT_INDEX_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
 
+/// 每个账号的 token 锁 (GUI 与反代共用)，同一账号同一时间只有一次刷新或账号文件读改写
+static ACCOUNT_TOKEN_LOCKS: Lazy<DashMap<String, Arc<tokio::sync::Mutex<()>>>> = Lazy::new(DashMap::new);
+
+/// token 剩余有效期低于该值时刷新
+const REFRESH_AHEAD_SECS: i64 = 300;
+
 // ... existing constants ...
 const DATA_DIR: &str = ".antigravity_tools";
 const ACCOUNTS_INDEX: &str = "accounts.json";
@@ -83,16 +90,25 @@ pub fn save_account_index(index: &AccountIndex) -> Result<(), String> {
         .map_err(|e| format!("替换索引文件失败: {}", e))
 }
 
+/// 获取账号文件路径
+pub fn get_account_path(account_id: &str) -> Result<PathBuf, String> {
+    Ok(get_accounts_dir()?.join(format!("{}.json", account_id)))
+}
+
 /// 加载账号数据
 pub fn load_account(account_id: &str) -> Result<Account, String> {
-    let accounts_dir = get_accounts_dir()?;
-    let account_path = accounts_dir.join(format!("{}.json", account_id));
+    let account_path = get_account_path(account_id)?;
     
     if !account_path.exists() {
         return Err(format!("账号不存在: {}", account_id));
     }
     
-    let content = fs::read_to_string(&account_path)
+    load_account_file(&account_path)
+}
+
+/// 从指定路径加载账号数据
+pub fn load_account_file(path: &Path) -> Result<Account, String> {
+    let content = fs::read_to_string(path)
         .map_err(|e| format!("读取账号数据失败: {}", e))?;
     
     serde_json::from_str(&content)
@@ -101,16 +117,99 @@ pub fn load_account(account_id: &str) -> Result<Account, String> {
 
 /// 保存账号数据
 pub fn save_account(account: &Account) -> Result<(), String> {
-    let accounts_dir = get_accounts_dir()?;
-    let account_path = accounts_dir.join(format!("{}.json", account.id));
-    
+    save_account_file(&get_account_path(&account.id)?, account)
+}
+
+/// 保存账号数据到指定路径 (原子化写入，反代同步账号目录时不会读到写了一半的文件)
+pub fn save_account_file(path: &Path, account: &Account) -> Result<(), String> {
     let content = serde_json::to_string_pretty(account)
         .map_err(|e| format!("序列化账号数据失败: {}", e))?;
     
-    fs::write(&account_path, content)
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, content)
+        .map_err(|e| format!("保存账号数据失败: {}", e))?;
+    fs::rename(&temp_path, path)
         .map_err(|e| format!("保存账号数据失败: {}", e))
 }
 
+/// 账号的 token 锁
+fn account_token_lock(account_id: &str) -> Arc<tokio::sync::Mutex<()>> {
+    ACCOUNT_TOKEN_LOCKS.entry(account_id.to_string()).or_default().clone()
+}
+
+/// 在账号的 token 锁内读取最新的账号文件、修改并写回
+/// 只改动 `update` 涉及的字段，不会用调用方手中的旧 token 覆盖其他方刚刷新的 token
+pub async fn update_account_file(account_id: &str, path: &Path, update: impl FnOnce(&mut Account)) -> Result<Account, String> {
+    let lock = account_token_lock(account_id);
+    let _guard = lock.lock().await;
+
+    let mut account = load_account_file(path)?;
+    update(&mut account);
+    save_account_file(path, &account)?;
+    Ok(account)
+}
+
+/// 刷新账号文件中的 token 并写回，返回最新的账号数据 (GUI 与反代共用)
+/// 同一账号的并发刷新在 token 锁上排队：拿到锁后先读取账号文件，
+/// 若文件中的 token 仍有效且已不是调用方手中的 `stale_access_token` (已被其他方刷新)，直接采用
+/// `force` 为 false 时文件中的 token 仍在有效期内即直接采用
+pub async fn refresh_account_token_file(account_id: &str, path: &Path, stale_access_token: &str, force: bool) -> Result<Account, String> {
+    let lock = account_token_lock(account_id);
+    let _guard = lock.lock().await;
+
+    let mut account = load_account_file(path)?;
+    let now = chrono::Utc::now().timestamp();
+    let valid = now < account.token.expiry_timestamp - REFRESH_AHEAD_SECS;
+    if valid && (!force || account.token.access_token != stale_access_token) {
+        return Ok(account);
+    }
+
+    let response = modules::oauth::refresh_access_token(&account.token.refresh_token, &account.identity()).await?;
+    account.token.access_token = response.access_token;
+    account.token.expires_in = response.expires_in;
+    account.token.expiry_timestamp = now + response.expires_in;
+    // 刷新时不一定会返回新的 refresh_token
+    if let Some(refresh_token) = response.refresh_token {
+        account.token.refresh_token = refresh_token;
+    }
+    save_account_file(path, &account)?;
+    Ok(account)
+}
+
+/// 确保账号 token 有效 (`force` 为 true 时无论是否过期都刷新)，内存中的账号随之更新
+/// 返回 token 是否发生变化
+pub async fn refresh_account_token(account: &mut Account, force: bool) -> Result<bool, String> {
+    let now = chrono::Utc::now().timestamp();
+    if !force && now < account.token.expiry_timestamp - REFRESH_AHEAD_SECS {
+        return Ok(false);
+    }
+
+    let path = get_account_path(&account.id)?;
+    let latest = refresh_account_token_file(&account.id, &path, &account.token.access_token, force).await?;
+    let changed = latest.token.access_token != account.token.access_token;
+    account.token = latest.token;
+    Ok(changed)
+}
+
+/// 更新账号用户名 (账号文件与索引)，不改动 token
+async fn save_account_name(account_id: &str, name: Option<String>) -> Result<(), String> {
+    update_account_file(account_id, &get_account_path(account_id)?, |account| account.name = name.clone()).await?;
+
+    let _lock = ACCOUNT_INDEX_LOCK.lock().map_err(|e| format!("获取锁失败: {}", e))?;
+    let mut index = load_account_index()?;
+    if let Some(summary) = index.accounts.iter_mut().find(|s| s.id == account_id) {
+        summary.name = name;
+        save_account_index(&index)?;
+    }
+    Ok(())
+}
+
+/// 保存配额查询时获取到的 project_id，不改动 token 的其他字段
+async fn save_project_id(account_id: &str, project_id: Option<String>) -> Result<(), String> {
+    update_account_file(account_id, &get_account_path(account_id)?, |account| account.token.project_id = project_id).await?;
+    Ok(())
+}
+
 /// 列出所有账号
 /// 列出所有账号
 pub fn list_accounts() -> Result<Vec<Account>, String> {
@@ -313,7 +412,7 @@ pub fn delete_accounts(account_ids: &[String]) -> Result<(), String> {
 
 /// 切换当前账号
 pub async fn switch_account(account_id: &str) -> Result<(), String> {
-    use crate::modules::{oauth, process, db};
+    use crate::modules::{process, db};
     
     let index = {
         let _lock = ACCOUNT_INDEX_LOCK.lock().map_err(|e| format!("获取锁失败: {}", e))?;
@@ -328,15 +427,9 @@ pub async fn switch_account(account_id: &str) -> Result<(), String> {
     let mut account = load_account(account_id)?;
     crate::modules::logger::log_info(&format!("正在切换到账号: {} (ID: {})", account.email, account.id));
     
-    // 2. 确保 Token 有效（自动刷新）
-    let fresh_token = oauth::ensure_fresh_token(&account.token).await
+    // 2. 确保 Token 有效（自动刷新，刷新结果已写回账号文件）
+    refresh_account_token(&mut account, false).await
         .map_err(|e| format!("Token 刷新失败: {}", e))?;
-        
-    // 如果 Token 更新了，保存回账号文件
-    if fresh_token.access_token != account.token.access_token {
-        account.token = fresh_token.clone();
-        save_account(&account)?;
-    }
     
     // 3. 关闭 Antigravity (增加超时时间到 20 秒)
     if process::is_antigravity_running() {
@@ -424,43 +517,27 @@ pub fn export_accounts() -> Result<Vec<(String, String)>, String> {
 }
 
 /// 带有重试机制的配额查询 (从 commands 移动到 modules 以便共享)
+/// token 刷新与账号文件写回都经过账号的 token 锁，与反代的刷新互不覆盖
 pub async fn fetch_quota_with_retry(account: &mut Account) -> crate::error::AppResult<QuotaData> {
     use crate::modules::oauth;
     use crate::error::AppError;
     use reqwest::StatusCode;
     
     // 1. 基于时间的检查 (Time-based check) - 先确保 Token 有效
-    let token = oauth::ensure_fresh_token(&account.token).await.map_err(AppError::OAuth)?;
-    
-    if token.access_token != account.token.access_token {
+    if refresh_account_token(account, false).await.map_err(AppError::OAuth)? {
         modules::logger::log_info(&format!("基于时间的 Token 刷新: {}", account.email));
-        account.token = token.clone();
-        
-        // 重新获取用户名 (Token 刷新后顺便获取)
-        let name = if account.name.is_none() || account.name.as_ref().map_or(false, |n| n.trim().is_empty()) {
-            match oauth::get_user_info(&token.access_token).await {
-                Ok(user_info) => user_info.get_display_name(),
-                Err(_) => None
-            }
-        } else {
-            account.name.clone()
-        };
-        
-        account.name = name.clone();
-        upsert_account(account.email.clone(), name, token.clone()).map_err(AppError::Account)?;
     }
 
-    // 0. 补充用户名 (如果 Token 没过期但也没用户名，或者上面没获取到)
+    // 0. 补充用户名 (如果 Token 没过期但也没用户名)
     if account.name.is_none() || account.name.as_ref().map_or(false, |n| n.trim().is_empty()) {
         modules::logger::log_info(&format!("账号 {} 缺少用户名，尝试获取...", account.email));
-        // 使用更新后的 token
         match oauth::get_user_info(&account.token.access_token).await {
             Ok(user_info) => {
                 let display_name = user_info.get_display_name();
                 modules::logger::log_info(&format!("成功获取用户名: {:?}", display_name));
                 account.name = display_name.clone();
                 // 立即保存
-                if let Err(e) = upsert_account(account.email.clone(), display_name, account.token.clone()) {
+                if let Err(e) = save_account_name(&account.id, display_name).await {
                      modules::logger::log_warn(&format!("保存用户名失败: {}", e));
                 }
             },
@@ -471,14 +548,14 @@ pub async fn fetch_quota_with_retry(account: &mut Account) -> crate::error::AppR
     }
 
     // 2. 尝试查询
-    let result = modules::fetch_quota(&account.token.access_token).await;
+    let result = modules::fetch_quota(&account.token.access_token, &account.identity()).await;
     
     // 捕获可能更新的 project_id 并保存
     if let Ok((ref _q, ref project_id)) = result {
         if project_id.is_some() && *project_id != account.token.project_id {
             modules::logger::log_info(&format!("检测到 project_id 更新 ({}), 正在保存...", account.email));
             account.token.project_id = project_id.clone();
-            if let Err(e) = upsert_account(account.email.clone(), account.name.clone(), account.token.clone()) {
+            if let Err(e) = save_project_id(&account.id, project_id.clone()).await {
                 modules::logger::log_warn(&format!("同步保存 project_id 失败: {}", e));
             }
         }
@@ -490,43 +567,18 @@ pub async fn fetch_quota_with_retry(account: &mut Account) -> crate::error::AppR
             if status == StatusCode::UNAUTHORIZED {
                 modules::logger::log_warn(&format!("401 Unauthorized for {}, forcing refresh...", account.email));
                 
-                // 强制刷新
-                let token_res = oauth::refresh_access_token(&account.token.refresh_token)
-                    .await
-                    .map_err(AppError::OAuth)?;
-                
-                let new_token = TokenData::new(
-                    token_res.access_token.clone(),
-                    account.token.refresh_token.clone(),
-                    token_res.expires_in,
-                    account.token.email.clone(),
-                    account.token.project_id.clone(), // 保留原有 project_id
-                    None, // 添加 None 作为 session_id
-                );
-                
-                // 重新获取用户名
-                let name = if account.name.is_none() || account.name.as_ref().map_or(false, |n| n.trim().is_empty()) {
-                    match oauth::get_user_info(&token_res.access_token).await {
-                        Ok(user_info) => user_info.get_display_name(),
-                        Err(_) => None
-                    }
-                } else {
-                    account.name.clone()
-                };
-                
-                account.token = new_token.clone();
-                account.name = name.clone();
-                upsert_account(account.email.clone(), name, new_token.clone()).map_err(AppError::Account)?;
+                // 强制刷新 (反代已刷新过时直接采用其结果)
+                refresh_account_token(account, true).await.map_err(AppError::OAuth)?;
                 
                 // 重试查询
-                let retry_result = modules::fetch_quota(&new_token.access_token).await;
+                let retry_result = modules::fetch_quota(&account.token.access_token, &account.identity()).await;
                 
                 // 同样处理重试时的 project_id 保存
                 if let Ok((ref _q, ref project_id)) = retry_result {
                     if project_id.is_some() && *project_id != account.token.project_id {
                         modules::logger::log_info(&format!("检测到重试后 project_id 更新 ({}), 正在保存...", account.email));
                         account.token.project_id = project_id.clone();
-                        let _ = upsert_account(account.email.clone(), account.name.clone(), account.token.clone());
+                        let _ = save_project_id(&account.id, project_id.clone()).await;
                     }
                 }
 
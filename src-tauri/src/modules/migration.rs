@@ -143,7 +143,7 @@ pub async fn import_from_v1() -> Result<Vec<Account>, String> {
                     if let Some(refresh_token) = refresh_token_opt {
                          crate::modules::logger::log_info(&format!("正在导入账号: {}", email_placeholder));
                          
-                         let (email, access_token, expires_in) = match oauth::refresh_access_token(&refresh_token).await {
+                         let (email, access_token, expires_in) = match oauth::refresh_access_token(&refresh_token, &crate::proxy::common::fingerprint::AccountIdentity::default()).await {
                             Ok(token_resp) => {
                                 match oauth::get_user_info(&token_resp.access_token).await {
                                     Ok(user_info) => (user_info.email, token_resp.access_token, token_resp.expires_in),
@@ -202,7 +202,7 @@ pub async fn import_from_custom_db_path(path_str: String) -> Result<Account, Str
         
     // 3. 使用 Refresh Token 获取最新的 Access Token 和用户信息
     crate::modules::logger::log_info("正在使用 Refresh Token 获取用户信息...");
-    let token_resp = oauth::refresh_access_token(&refresh_token).await?;
+    let token_resp = oauth::refresh_access_token(&refresh_token, &crate::proxy::common::fingerprint::AccountIdentity::default()).await?;
     let user_info = oauth::get_user_info(&token_resp.access_token).await?;
     
     let email = user_info.email;
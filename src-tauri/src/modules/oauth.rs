@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::proxy::common::fingerprint::AccountIdentity;
 
 // Google OAuth 配置
 const CLIENT_ID: &str = "1071006060591-tmhssin2h21lcre235vtolojh4g403ep.apps.googleusercontent.com";
@@ -121,8 +122,9 @@ pub async fn exchange_code(code: &str, redirect_uri: &str) -> Result<TokenRespon
 }
 
 /// 使用 refresh_token 刷新 access_token
-pub async fn refresh_access_token(refresh_token: &str) -> Result<TokenResponse, String> {
-    let client = crate::utils::http::create_client(15);
+/// `identity` 为账号的出口代理与客户端指纹 (新导入的账号传默认值)
+pub async fn refresh_access_token(refresh_token: &str, identity: &AccountIdentity) -> Result<TokenResponse, String> {
+    let client = identity.http_client(15)?;
     
     let params = [
         ("client_id", CLIENT_ID),
@@ -135,6 +137,7 @@ pub async fn refresh_access_token(refresh_token: &str) -> Result<TokenResponse,
     
     let response = client
         .post(TOKEN_URL)
+        .header("User-Agent", identity.user_agent())
         .form(&params)
         .send()
         .await
@@ -174,30 +177,3 @@ pub async fn get_user_info(access_token: &str) -> Result<UserInfo, String> {
         Err(format!("获取用户信息失败: {}", error_text))
     }
 }
-
-/// 检查并在需要时刷新 Token
-/// 返回最新的 access_token
-pub async fn ensure_fresh_token(
-    current_token: &crate::models::TokenData,
-) -> Result<crate::models::TokenData, String> {
-    let now = chrono::Local::now().timestamp();
-    
-    // 如果没有过期时间，或者还有超过 5 分钟有效期，直接返回
-    if current_token.expiry_timestamp > now + 300 {
-        return Ok(current_token.clone());
-    }
-    
-    // 需要刷新
-    crate::modules::logger::log_info("Token 即将过期，正在刷新...");
-    let response = refresh_access_token(&current_token.refresh_token).await?;
-    
-    // 构造新 TokenData
-    Ok(crate::models::TokenData::new(
-        response.access_token,
-        current_token.refresh_token.clone(), // 刷新时不一定会返回新的 refresh_token
-        response.expires_in,
-        current_token.email.clone(),
-        current_token.project_id.clone(), // 保留原有 project_id
-        None,  // session_id 会在 token_manager 中生成
-    ))
-}
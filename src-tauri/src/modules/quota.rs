@@ -2,10 +2,7 @@ use reqwest;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use crate::models::QuotaData;
-
-const QUOTA_API_URL: &str = "https://cloudcode-pa.googleapis.com/v1internal:fetchAvailableModels";
-const LOAD_PROJECT_API_URL: &str = "https://cloudcode-pa.googleapis.com/v1internal:loadCodeAssist";
-const USER_AGENT: &str = "antigravity/1.11.3 Darwin/arm64";
+use crate::proxy::common::fingerprint::AccountIdentity;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct QuotaResponse {
@@ -32,29 +29,31 @@ struct LoadProjectResponse {
     project_id: Option<String>,
 }
 
-/// 创建配置好的 HTTP Client
-fn create_client() -> reqwest::Client {
-    crate::utils::http::create_client(15)
-}
-
 /// 获取 Project ID
-async fn fetch_project_id(access_token: &str) -> Option<String> {
-    let client = create_client();
-    let body = json!({
-        "metadata": {
-            "ideType": "ANTIGRAVITY"
+async fn fetch_project_id(access_token: &str, identity: &AccountIdentity) -> Option<String> {
+    let client = match identity.http_client(15) {
+        Ok(client) => client,
+        Err(e) => {
+            crate::modules::logger::log_error(&e);
+            return None;
         }
-    });
+    };
+    let endpoints = crate::proxy::upstream::endpoint::current();
+    let body = identity.load_code_assist_body();
+    let user_agent = identity.user_agent();
 
     // 简单的重试
     for _ in 0..2 {
-        match client
-            .post(LOAD_PROJECT_API_URL)
-            .bearer_auth(access_token)
-            .header("User-Agent", USER_AGENT)
-            .json(&body)
-            .send()
-            .await 
+        match endpoints
+            .post("loadCodeAssist", None, |url| {
+                client
+                    .post(url)
+                    .bearer_auth(access_token)
+                    .header("User-Agent", &user_agent)
+                    .json(&body)
+                    .send()
+            })
+            .await
         {
             Ok(res) => {
                 if res.status().is_success() {
@@ -78,13 +77,15 @@ async fn fetch_project_id(access_token: &str) -> Option<String> {
 }
 
 /// 查询账号配额
-pub async fn fetch_quota(access_token: &str) -> crate::error::AppResult<(QuotaData, Option<String>)> {
+/// `identity` 为账号的出口代理与客户端指纹
+pub async fn fetch_quota(access_token: &str, identity: &AccountIdentity) -> crate::error::AppResult<(QuotaData, Option<String>)> {
     use crate::error::AppError;
     crate::modules::logger::log_info("开始外部查询配额...");
-    let client = create_client();
+    let client = identity.http_client(15).map_err(AppError::Config)?;
+    let user_agent = identity.user_agent();
     
     // 1. 获取 Project ID
-    let project_id = fetch_project_id(access_token).await;
+    let project_id = fetch_project_id(access_token, identity).await;
     crate::modules::logger::log_info(&format!("Project ID 获取结果: {:?}", project_id));
     
     // 2. 构建请求体
@@ -93,19 +94,22 @@ pub async fn fetch_quota(access_token: &str) -> crate::error::AppResult<(QuotaDa
         payload.insert("project".to_string(), json!(pid));
     }
     
-    let url = QUOTA_API_URL;
+    let endpoints = crate::proxy::upstream::endpoint::current();
     let max_retries = 3;
     let mut last_error: Option<AppError> = None;
 
-    crate::modules::logger::log_info(&format!("发送配额请求至 {}", url));
+    crate::modules::logger::log_info("发送配额请求 (fetchAvailableModels)");
 
     for attempt in 1..=max_retries {
-        match client
-            .post(url)
-            .bearer_auth(access_token)
-            .header("User-Agent", USER_AGENT)
-            .json(&json!(payload))
-            .send()
+        match endpoints
+            .post("fetchAvailableModels", None, |url| {
+                client
+                    .post(url)
+                    .bearer_auth(access_token)
+                    .header("User-Agent", &user_agent)
+                    .json(&json!(payload))
+                    .send()
+            })
             .await
         {
             Ok(response) => {
@@ -175,16 +179,3 @@ pub async fn fetch_quota(access_token: &str) -> crate::error::AppResult<(QuotaDa
     
     Err(last_error.unwrap_or_else(|| AppError::Unknown("配额查询失败".to_string())))
 }
-
-/// 批量查询所有账号配额 (备用功能)
-#[allow(dead_code)]
-pub async fn fetch_all_quotas(accounts: Vec<(String, String)>) -> Vec<(String, crate::error::AppResult<QuotaData>)> {
-    let mut results = Vec::new();
-    
-    for (account_id, access_token) in accounts {
-        let result = fetch_quota(&access_token).await.map(|(q, _)| q);
-        results.push((account_id, result));
-    }
-    
-    results
-}
@@ -0,0 +1,162 @@
+// 账号健康状态
+// 记录上游 429/403 触发的冷却期，以及连续 401 触发的熔断
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// 对所有配额组生效的冷却 (如 403)
+const ALL_GROUPS: &str = "*";
+
+/// 连续 401 达到该次数后熔断
+const AUTH_FAILURE_THRESHOLD: u32 = 2;
+
+#[derive(Debug, Clone, Default)]
+struct HealthEntry {
+    cooldowns: HashMap<String, i64>, // quota_group -> 冷却结束时间 (Unix 毫秒)
+    auth_failures: u32,
+    circuit_open: bool,
+}
+
+/// 单个配额组的冷却状态
+#[derive(Debug, Clone, Serialize)]
+pub struct CooldownStatus {
+    pub quota_group: String,
+    pub until_ms: i64,
+}
+
+/// 账号健康状态快照 (供前端展示)
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountHealthStatus {
+    pub account_id: String,
+    pub email: String,
+    pub cooldowns: Vec<CooldownStatus>,
+    pub auth_failures: u32,
+    pub circuit_open: bool,
+}
+
+/// 按账号记录冷却与熔断状态
+#[derive(Default)]
+pub struct AccountHealthTracker {
+    entries: DashMap<String, HealthEntry>,
+}
+
+impl AccountHealthTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 账号在指定配额组下是否可用 (未熔断且不在冷却期)
+    pub fn is_available(&self, account_id: &str, quota_group: &str, now_ms: i64) -> bool {
+        let Some(entry) = self.entries.get(account_id) else {
+            return true;
+        };
+        if entry.circuit_open {
+            return false;
+        }
+        [quota_group, ALL_GROUPS]
+            .iter()
+            .all(|g| entry.cooldowns.get(*g).is_none_or(|until| *until <= now_ms))
+    }
+
+    /// 记录配额组冷却，已有更长的冷却时保留较长者
+    pub fn start_cooldown(&self, account_id: &str, quota_group: &str, delay: Duration, now_ms: i64) {
+        let until = now_ms + delay.as_millis() as i64;
+        let mut entry = self.entries.entry(account_id.to_string()).or_default();
+        let slot = entry.cooldowns.entry(quota_group.to_string()).or_insert(until);
+        *slot = (*slot).max(until);
+    }
+
+    /// 记录对所有配额组生效的冷却
+    pub fn start_global_cooldown(&self, account_id: &str, delay: Duration, now_ms: i64) {
+        self.start_cooldown(account_id, ALL_GROUPS, delay, now_ms);
+    }
+
+    /// 记录一次 401，返回本次是否触发熔断
+    pub fn record_auth_failure(&self, account_id: &str) -> bool {
+        let mut entry = self.entries.entry(account_id.to_string()).or_default();
+        entry.auth_failures += 1;
+        if !entry.circuit_open && entry.auth_failures >= AUTH_FAILURE_THRESHOLD {
+            entry.circuit_open = true;
+            return true;
+        }
+        false
+    }
+
+    /// 请求成功，清零连续 401 计数
+    pub fn record_success(&self, account_id: &str) {
+        if let Some(mut entry) = self.entries.get_mut(account_id) {
+            entry.auth_failures = 0;
+        }
+    }
+
+    /// Token 刷新成功后关闭熔断
+    pub fn close_circuit(&self, account_id: &str) {
+        if let Some(mut entry) = self.entries.get_mut(account_id) {
+            entry.auth_failures = 0;
+            entry.circuit_open = false;
+        }
+    }
+
+    /// 生成状态快照，过期的冷却不会出现在结果中
+    pub fn snapshot(&self, account_id: &str, email: &str, now_ms: i64) -> AccountHealthStatus {
+        let entry = self.entries.get(account_id).map(|e| e.clone()).unwrap_or_default();
+        let mut cooldowns: Vec<CooldownStatus> = entry
+            .cooldowns
+            .iter()
+            .filter(|(_, until)| **until > now_ms)
+            .map(|(group, until)| CooldownStatus {
+                quota_group: group.clone(),
+                until_ms: *until,
+            })
+            .collect();
+        cooldowns.sort_by(|a, b| a.quota_group.cmp(&b.quota_group));
+
+        AccountHealthStatus {
+            account_id: account_id.to_string(),
+            email: email.to_string(),
+            cooldowns,
+            auth_failures: entry.auth_failures,
+            circuit_open: entry.circuit_open,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cooldown_per_group() {
+        let tracker = AccountHealthTracker::new();
+        tracker.start_cooldown("a", "claude", Duration::from_secs(30), 0);
+
+        assert!(!tracker.is_available("a", "claude", 29_999));
+        assert!(tracker.is_available("a", "gemini", 0));
+        assert!(tracker.is_available("a", "claude", 30_000));
+
+        // 较短的冷却不会覆盖较长的冷却
+        tracker.start_cooldown("a", "claude", Duration::from_secs(1), 0);
+        assert!(!tracker.is_available("a", "claude", 10_000));
+
+        tracker.start_global_cooldown("b", Duration::from_secs(60), 0);
+        assert!(!tracker.is_available("b", "gemini", 0));
+        assert_eq!(tracker.snapshot("b", "b@example.com", 0).cooldowns[0].quota_group, "*");
+        assert!(tracker.snapshot("b", "b@example.com", 60_000).cooldowns.is_empty());
+    }
+
+    #[test]
+    fn test_auth_circuit_breaker() {
+        let tracker = AccountHealthTracker::new();
+        assert!(!tracker.record_auth_failure("a"));
+        tracker.record_success("a");
+        assert!(!tracker.record_auth_failure("a"));
+        assert!(tracker.record_auth_failure("a"));
+        assert!(!tracker.record_auth_failure("a"));
+        assert!(!tracker.is_available("a", "claude", 0));
+
+        tracker.close_circuit("a");
+        assert!(tracker.is_available("a", "claude", 0));
+        assert_eq!(tracker.snapshot("a", "a@example.com", 0).auth_failures, 0);
+    }
+}
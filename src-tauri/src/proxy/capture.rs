@@ -0,0 +1,441 @@
+// 请求/响应抓取 (调试协议转换问题)
+// 保存客户端原始请求、转换后的 v1internal 请求、上游原始响应 (SSE) 与返回给客户端的内容
+// 写盘前脱敏：access token、API Key 及内联 base64 图片
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::config::CaptureConfig;
+
+/// 单个响应部分的抓取上限，超出部分丢弃并标记 truncated
+const MAX_PART_BYTES: usize = 8 * 1024 * 1024;
+/// 键名包含这些片段的字符串字段视为敏感信息
+const SECRET_KEY_HINTS: [&str; 6] = ["token", "api_key", "apikey", "authorization", "secret", "password"];
+/// 超过该长度且全部由 base64 字符组成的字符串视为内联二进制数据
+const BASE64_MIN_LEN: usize = 256;
+
+/// 抓取的响应部分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapturePart {
+    /// 上游 v1internal 原始响应
+    UpstreamResponse,
+    /// 转换后返回给客户端的内容
+    ClientResponse,
+}
+
+/// 一次完整的抓取记录 (即导出的 bundle 格式)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub request_id: String,
+    pub timestamp_ms: i64,
+    pub protocol: String,
+    pub api_key: Option<String>,
+    pub client_model: String,
+    pub client_request: Value,
+    pub upstream_request: Value,
+    pub upstream_response: String,
+    pub client_response: String,
+    pub truncated: bool,
+}
+
+/// 抓取列表项
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureSummary {
+    pub request_id: String,
+    pub timestamp_ms: i64,
+    pub protocol: String,
+    pub api_key: Option<String>,
+    pub client_model: String,
+    pub size_bytes: u64,
+}
+
+/// 待写盘的抓取记录及当时的保留上限
+type PendingCapture = (CaptureRecord, usize);
+
+/// 抓取存储 (独立于反代实例，服务停止后仍可导出)
+pub struct CaptureStore {
+    dir: Option<PathBuf>,
+    config: RwLock<CaptureConfig>,
+    writer: Option<mpsc::Sender<PendingCapture>>,
+}
+
+impl CaptureStore {
+    pub fn new(dir: Option<PathBuf>, config: CaptureConfig) -> Self {
+        // 写盘与保留清理放在单个后台线程，按会话结束顺序依次执行
+        let writer = dir.clone().map(|dir| {
+            let (tx, rx) = mpsc::channel::<PendingCapture>();
+            std::thread::spawn(move || {
+                while let Ok((record, max_entries)) = rx.recv() {
+                    if let Err(e) = write_record(&dir, &record, max_entries) {
+                        tracing::warn!("{}", e);
+                    }
+                }
+            });
+            tx
+        });
+        Self {
+            dir,
+            config: RwLock::new(config),
+            writer,
+        }
+    }
+
+    /// 更新抓取配置 (热更新)
+    pub fn update_config(&self, config: &CaptureConfig) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config.clone();
+    }
+
+    /// 判断请求是否需要抓取，需要时创建抓取会话
+    /// `client_request` 仅在需要抓取时才会被调用
+    pub fn begin(
+        &self,
+        request_id: &str,
+        protocol: ClientProtocol,
+        api_key: Option<&str>,
+        model: &str,
+        client_request: impl FnOnce() -> Value,
+    ) -> Option<Arc<CaptureSession>> {
+        let writer = self.writer.clone()?;
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner()).clone();
+        if !config.enabled || !should_capture(&config, api_key, model) {
+            return None;
+        }
+
+        Some(Arc::new(CaptureSession {
+            writer,
+            max_entries: config.max_entries,
+            request_id: request_id.to_string(),
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            protocol,
+            api_key: api_key.map(|s| s.to_string()),
+            client_model: model.to_string(),
+            data: Mutex::new(CaptureData {
+                client_request: client_request(),
+                ..Default::default()
+            }),
+        }))
+    }
+
+    /// 列出已保存的抓取 (按时间倒序)
+    pub fn list(&self) -> Result<Vec<CaptureSummary>, String> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let mut summaries = Vec::new();
+        for path in capture_files(dir)?.into_iter().rev() {
+            let size_bytes = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            match read_record(&path) {
+                Ok(record) => summaries.push(CaptureSummary {
+                    request_id: record.request_id,
+                    timestamp_ms: record.timestamp_ms,
+                    protocol: record.protocol,
+                    api_key: record.api_key,
+                    client_model: record.client_model,
+                    size_bytes,
+                }),
+                Err(e) => tracing::warn!("{}", e),
+            }
+        }
+        Ok(summaries)
+    }
+
+    /// 导出一条抓取记录到指定文件
+    pub fn export(&self, request_id: &str, dest: &Path) -> Result<(), String> {
+        let dir = self.dir.as_ref().ok_or("抓取目录不可用")?;
+        let source = capture_files(dir)?
+            .into_iter()
+            .find(|p| file_request_id(p) == Some(request_id))
+            .ok_or_else(|| format!("未找到请求 {} 的抓取记录", request_id))?;
+        std::fs::copy(&source, dest).map_err(|e| format!("导出抓取记录失败: {}", e))?;
+        Ok(())
+    }
+}
+
+fn should_capture(config: &CaptureConfig, api_key: Option<&str>, model: &str) -> bool {
+    if config.api_keys.is_empty() && config.models.is_empty() {
+        return true;
+    }
+    let key_matched = api_key.is_some_and(|name| config.api_keys.iter().any(|k| k == name));
+    key_matched || crate::proxy::middleware::policy::model_allowed(&config.models, &[model])
+}
+
+/// 抓取文件名: `<timestamp_ms>_<request_id>.json`，按文件名排序即时间顺序
+fn capture_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("读取抓取目录失败: {}", e)),
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("json") && file_request_id(p).is_some())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn file_request_id(path: &Path) -> Option<&str> {
+    path.file_stem()?.to_str()?.split_once('_').map(|(_, id)| id)
+}
+
+fn read_record(path: &Path) -> Result<CaptureRecord, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("读取抓取记录失败: {}", e))?;
+    serde_json::from_str(&content).map_err(|e| format!("解析抓取记录 {:?} 失败: {}", path, e))
+}
+
+#[derive(Default)]
+struct CaptureData {
+    client_request: Value,
+    upstream_request: Value,
+    upstream_response: Vec<u8>,
+    client_response: Vec<u8>,
+    secrets: Vec<String>,
+    truncated: bool,
+}
+
+/// 单个请求的抓取会话，所有引用释放后脱敏并交给后台线程写盘
+pub struct CaptureSession {
+    writer: mpsc::Sender<PendingCapture>,
+    max_entries: usize,
+    request_id: String,
+    timestamp_ms: i64,
+    protocol: ClientProtocol,
+    api_key: Option<String>,
+    client_model: String,
+    data: Mutex<CaptureData>,
+}
+
+impl CaptureSession {
+    fn with_data(&self, f: impl FnOnce(&mut CaptureData)) {
+        f(&mut self.data.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// 记录一次上游尝试的请求体，重试时覆盖上一次的请求与响应
+    pub fn set_upstream_request(&self, body: &Value, access_token: &str) {
+        self.with_data(|d| {
+            d.upstream_request = body.clone();
+            d.upstream_response.clear();
+            if !d.secrets.iter().any(|s| s == access_token) {
+                d.secrets.push(access_token.to_string());
+            }
+        });
+    }
+
+    /// 追加响应内容
+    pub fn append(&self, part: CapturePart, bytes: &[u8]) {
+        self.with_data(|d| {
+            let buffer = match part {
+                CapturePart::UpstreamResponse => &mut d.upstream_response,
+                CapturePart::ClientResponse => &mut d.client_response,
+            };
+            let room = MAX_PART_BYTES.saturating_sub(buffer.len());
+            if bytes.len() > room {
+                d.truncated = true;
+            }
+            buffer.extend_from_slice(&bytes[..bytes.len().min(room)]);
+        });
+    }
+
+    /// 追加 JSON 响应内容
+    pub fn append_json<T: Serialize>(&self, part: CapturePart, value: &T) {
+        if let Ok(bytes) = serde_json::to_vec(value) {
+            self.append(part, &bytes);
+        }
+    }
+
+    fn take_record(&self) -> CaptureRecord {
+        let data = std::mem::take(&mut *self.data.lock().unwrap_or_else(|e| e.into_inner()));
+        let scrub = |text: String| {
+            data.secrets
+                .iter()
+                .filter(|s| !s.is_empty())
+                .fold(text, |text, secret| text.replace(secret.as_str(), "[REDACTED]"))
+        };
+
+        let mut client_request = data.client_request;
+        let mut upstream_request = data.upstream_request;
+        redact_value(&mut client_request);
+        redact_value(&mut upstream_request);
+
+        CaptureRecord {
+            request_id: self.request_id.clone(),
+            timestamp_ms: self.timestamp_ms,
+            protocol: self.protocol.as_str().to_string(),
+            api_key: self.api_key.clone(),
+            client_model: self.client_model.clone(),
+            client_request,
+            upstream_request,
+            upstream_response: scrub(redact_text(&String::from_utf8_lossy(&data.upstream_response))),
+            client_response: scrub(redact_text(&String::from_utf8_lossy(&data.client_response))),
+            truncated: data.truncated,
+        }
+    }
+}
+
+impl Drop for CaptureSession {
+    fn drop(&mut self) {
+        let record = self.take_record();
+        let _ = self.writer.send((record, self.max_entries));
+    }
+}
+
+fn write_record(dir: &Path, record: &CaptureRecord, max_entries: usize) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("创建抓取目录失败: {}", e))?;
+    let path = dir.join(format!("{}_{}.json", record.timestamp_ms, record.request_id));
+    let content = serde_json::to_string_pretty(record).map_err(|e| format!("序列化抓取记录失败: {}", e))?;
+    std::fs::write(&path, content).map_err(|e| format!("保存抓取记录失败: {}", e))?;
+
+    let files = capture_files(dir)?;
+    let excess = files.len().saturating_sub(max_entries.max(1));
+    for old in &files[..excess] {
+        let _ = std::fs::remove_file(old);
+    }
+    Ok(())
+}
+
+/// 脱敏 JSON：敏感字段置为 [REDACTED]，内联 base64 / data URL 替换为长度说明
+pub fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if child.is_string() && SECRET_KEY_HINTS.iter().any(|hint| key.contains(hint)) {
+                    *child = Value::String("[REDACTED]".to_string());
+                } else {
+                    redact_value(child);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        Value::String(text) => {
+            if let Some(replacement) = redact_binary(text) {
+                *text = replacement;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_binary(text: &str) -> Option<String> {
+    if text.starts_with("data:") && text.contains(";base64,") {
+        return Some(format!("[data url redacted: {} chars]", text.len()));
+    }
+    let looks_base64 = text.len() >= BASE64_MIN_LEN
+        && text.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=' | b'-' | b'_'));
+    looks_base64.then(|| format!("[base64 redacted: {} chars]", text.len()))
+}
+
+/// 脱敏文本：整体为 JSON 时按 JSON 处理，否则逐行处理 SSE 的 data 行
+fn redact_text(text: &str) -> String {
+    if let Ok(mut json) = serde_json::from_str::<Value>(text) {
+        redact_value(&mut json);
+        return serde_json::to_string(&json).unwrap_or_default();
+    }
+
+    text.split_inclusive('\n')
+        .map(|line| {
+            let trimmed = line.trim_end_matches(['\r', '\n']);
+            let Some(data) = trimmed.strip_prefix("data:") else {
+                return line.to_string();
+            };
+            match serde_json::from_str::<Value>(data.trim()) {
+                Ok(mut json) => {
+                    redact_value(&mut json);
+                    format!("data: {}{}", json, &line[trimmed.len()..])
+                }
+                Err(_) => line.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// 将流经的数据块写入抓取会话 (未启用抓取时原样返回)
+pub fn tap_stream<E: Send + 'static>(
+    stream: Pin<Box<dyn Stream<Item = Result<Bytes, E>> + Send>>,
+    capture: &Option<Arc<CaptureSession>>,
+    part: CapturePart,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, E>> + Send>> {
+    let Some(capture) = capture.clone() else {
+        return stream;
+    };
+    Box::pin(stream.map(move |item| {
+        if let Ok(bytes) = &item {
+            capture.append(part, bytes);
+        }
+        item
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_value() {
+        let image = "A".repeat(BASE64_MIN_LEN);
+        let mut body = json!({
+            "api_key": "sk-secret",
+            "max_tokens": 1024,
+            "messages": [{
+                "content": [
+                    {"type": "image", "source": {"type": "base64", "data": image}},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+                    {"type": "text", "text": "hello"}
+                ]
+            }]
+        });
+        redact_value(&mut body);
+
+        assert_eq!(body["api_key"], "[REDACTED]");
+        assert_eq!(body["max_tokens"], 1024);
+        let content = &body["messages"][0]["content"];
+        assert_eq!(content[0]["source"]["data"], format!("[base64 redacted: {} chars]", BASE64_MIN_LEN));
+        assert!(content[1]["image_url"]["url"].as_str().unwrap().starts_with("[data url redacted"));
+        assert_eq!(content[2]["text"], "hello");
+    }
+
+    #[test]
+    fn test_capture_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("captures_{}", uuid::Uuid::new_v4().simple()));
+        let store = CaptureStore::new(
+            Some(dir.clone()),
+            CaptureConfig { enabled: true, models: vec!["gemini-*".to_string()], ..Default::default() },
+        );
+        assert!(store.begin("r0", ClientProtocol::Claude, None, "claude-sonnet-4-5", || json!({})).is_none());
+
+        let session = store
+            .begin("r1", ClientProtocol::Gemini, Some("alice"), "gemini-2.5-pro", || json!({"contents": []}))
+            .unwrap();
+        session.set_upstream_request(&json!({"project": "p"}), "ya29.token");
+        session.append(CapturePart::UpstreamResponse, b"data: {\"inlineData\":{\"token\":\"x\"}}\n\n");
+        session.append(CapturePart::ClientResponse, b"echo ya29.token");
+        drop(session);
+
+        let mut listed = Vec::new();
+        for _ in 0..100 {
+            listed = store.list().unwrap();
+            if !listed.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].request_id, "r1");
+
+        let bundle = dir.join("bundle.out");
+        store.export("r1", &bundle).unwrap();
+        let record: CaptureRecord = serde_json::from_str(&std::fs::read_to_string(&bundle).unwrap()).unwrap();
+        assert_eq!(record.upstream_response, "data: {\"inlineData\":{\"token\":\"[REDACTED]\"}}\n\n");
+        assert_eq!(record.client_response, "echo [REDACTED]");
+        assert_eq!(record.api_key.as_deref(), Some("alice"));
+        assert!(store.export("missing", &bundle).is_err());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
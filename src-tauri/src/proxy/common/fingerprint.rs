@@ -0,0 +1,145 @@
+// 客户端指纹
+// 反代、配额查询、project_id 获取与 OAuth 刷新统一使用的 User-Agent 与请求元数据，可按账号选择
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::sync::{Arc, RwLock};
+
+use crate::proxy::config::{ClientProfile, ProxyConfig};
+
+/// 已配置的指纹
+struct ProfileRegistry {
+    default: Arc<ClientProfile>,
+    named: Vec<Arc<ClientProfile>>,
+}
+
+impl ProfileRegistry {
+    fn from_config(config: &ProxyConfig) -> Self {
+        Self {
+            default: Arc::new(config.client_profile.clone()),
+            named: config.client_profiles.iter().cloned().map(Arc::new).collect(),
+        }
+    }
+}
+
+static PROFILES: Lazy<RwLock<ProfileRegistry>> = Lazy::new(|| {
+    let config = crate::modules::config::load_app_config()
+        .map(|c| c.proxy)
+        .unwrap_or_default();
+    RwLock::new(ProfileRegistry::from_config(&config))
+});
+
+/// 更新指纹配置 (服务启动、保存配置时调用)
+pub fn configure(config: &ProxyConfig) {
+    *PROFILES.write().unwrap_or_else(|e| e.into_inner()) = ProfileRegistry::from_config(config);
+}
+
+/// 按名称选择指纹，未指定或找不到时使用默认指纹
+pub fn resolve(name: Option<&str>) -> Arc<ClientProfile> {
+    let registry = PROFILES.read().unwrap_or_else(|e| e.into_inner());
+    name.filter(|n| !n.is_empty())
+        .and_then(|n| {
+            let found = registry.named.iter().find(|p| p.name == n).cloned();
+            if found.is_none() {
+                tracing::warn!("客户端指纹 {} 不存在，使用默认指纹", n);
+            }
+            found
+        })
+        .unwrap_or_else(|| registry.default.clone())
+}
+
+/// 构建 v1internal 请求体外层
+/// 使用内置默认指纹，发送前由 `AccountIdentity::stamp_body` 替换为账号指纹
+pub fn envelope(project_id: &str, request: Value, model: &str, request_type: &str) -> Value {
+    let profile = ClientProfile::default();
+    json!({
+        "project": project_id,
+        "requestId": format!("{}{}", profile.request_id_prefix, uuid::Uuid::new_v4()),
+        "request": request,
+        "model": model,
+        "userAgent": profile.body_user_agent,
+        "requestType": request_type,
+    })
+}
+
+/// 账号访问 Google 时的身份：出口代理与客户端指纹
+#[derive(Debug, Clone)]
+pub struct AccountIdentity {
+    pub egress_proxy: Option<String>,
+    pub profile: Arc<ClientProfile>,
+}
+
+impl Default for AccountIdentity {
+    /// 未关联账号的请求 (如新账号导入)：不使用出口代理，使用默认指纹
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl AccountIdentity {
+    pub fn new(egress_proxy: Option<&str>, profile: Option<&str>) -> Self {
+        Self {
+            egress_proxy: egress_proxy.map(str::to_string),
+            profile: resolve(profile),
+        }
+    }
+
+    /// 按账号出口代理创建 HTTP 客户端 (出口代理无效时返回错误)
+    pub fn http_client(&self, timeout_secs: u64) -> Result<reqwest::Client, String> {
+        crate::utils::http::create_client_for_account(timeout_secs, self.egress_proxy.as_deref())
+    }
+
+    pub fn user_agent(&self) -> String {
+        self.profile.user_agent()
+    }
+
+    /// 替换 v1internal 请求体中的 requestId 前缀与 userAgent 字段
+    pub fn stamp_body(&self, body: &mut Value) {
+        let Some(obj) = body.as_object_mut() else {
+            return;
+        };
+        if obj.contains_key("requestId") {
+            obj.insert(
+                "requestId".to_string(),
+                json!(format!("{}{}", self.profile.request_id_prefix, uuid::Uuid::new_v4())),
+            );
+        }
+        if obj.contains_key("userAgent") {
+            obj.insert("userAgent".to_string(), json!(self.profile.body_user_agent));
+        }
+    }
+
+    /// loadCodeAssist 请求体
+    pub fn load_code_assist_body(&self) -> Value {
+        json!({ "metadata": self.profile.metadata })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_applied_to_body() {
+        let profile = ClientProfile {
+            name: "mac".to_string(),
+            ide_version: "1.12.0".to_string(),
+            platform: "darwin/arm64".to_string(),
+            body_user_agent: "antigravity-mac".to_string(),
+            request_id_prefix: "mac-".to_string(),
+            ..Default::default()
+        };
+        let identity = AccountIdentity { egress_proxy: None, profile: Arc::new(profile) };
+        assert_eq!(identity.user_agent(), "antigravity/1.12.0 darwin/arm64");
+        assert_eq!(identity.load_code_assist_body()["metadata"]["ideType"], "ANTIGRAVITY");
+
+        let mut body = envelope("p", json!({}), "gemini-2.5-pro", "agent");
+        assert!(body["requestId"].as_str().unwrap().starts_with("agent-"));
+        identity.stamp_body(&mut body);
+        assert!(body["requestId"].as_str().unwrap().starts_with("mac-"));
+        assert_eq!(body["userAgent"], "antigravity-mac");
+
+        let mut other = json!({ "metadata": {} });
+        identity.stamp_body(&mut other);
+        assert!(other.get("requestId").is_none());
+    }
+}
@@ -0,0 +1,132 @@
+// 媒体内容处理
+// data URI 解析、MIME 推断，以及按白名单抓取远程图片
+use base64::{engine::general_purpose, Engine as _};
+use futures::StreamExt;
+
+use crate::proxy::config::MediaFetchConfig;
+
+/// 解码后的内联数据
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineData {
+    pub mime_type: String,
+    /// base64 编码的数据
+    pub data: String,
+}
+
+/// 解析 `data:<mime>;base64,<data>` 形式的 data URI
+pub fn parse_data_uri(uri: &str) -> Result<InlineData, String> {
+    let rest = uri.strip_prefix("data:").ok_or("Not a data URI")?;
+    let (meta, data) = rest.split_once(',').ok_or("Malformed data URI")?;
+    let mime_type = meta
+        .strip_suffix(";base64")
+        .ok_or("Only base64-encoded data URIs are supported")?;
+    let mime_type = if mime_type.is_empty() { "application/octet-stream" } else { mime_type };
+    Ok(InlineData { mime_type: mime_type.to_string(), data: data.to_string() })
+}
+
+/// 按文件名后缀推断 MIME 类型
+pub fn mime_from_filename(filename: &str) -> Option<&'static str> {
+    let ext = filename.rsplit_once('.')?.1.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "heic" => "image/heic",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        _ => return None,
+    })
+}
+
+/// 主机白名单匹配，`*.example.com` 匹配 example.com 的任意子域名
+pub fn host_allowed(patterns: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == pattern,
+        }
+    })
+}
+
+/// 下载远程文件并转为内联数据 (不跟随重定向，避免绕过白名单)
+pub async fn fetch_remote(config: &MediaFetchConfig, url: &str) -> Result<InlineData, String> {
+    let parsed = url::Url::parse(url).map_err(|e| format!("Invalid URL '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("Unsupported URL scheme '{}'", parsed.scheme()));
+    }
+    let host = parsed.host_str().unwrap_or_default();
+    if !host_allowed(&config.allowed_hosts, host) {
+        return Err(format!("Fetching media from '{}' is not allowed", host));
+    }
+
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(config.timeout_secs))
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+    let response = client
+        .get(parsed.clone())
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch '{}': {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch '{}': HTTP {}", url, response.status()));
+    }
+    if response.content_length().is_some_and(|len| len as usize > config.max_bytes) {
+        return Err(format!("'{}' exceeds the {} byte limit", url, config.max_bytes));
+    }
+
+    let header_mime = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or(v).trim().to_string())
+        .filter(|v| !v.is_empty() && v != "application/octet-stream");
+
+    let mut body = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| format!("Failed to fetch '{}': {}", url, e))?;
+        if body.len() + chunk.len() > config.max_bytes {
+            return Err(format!("'{}' exceeds the {} byte limit", url, config.max_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let mime_type = header_mime
+        .or_else(|| mime_from_filename(parsed.path()).map(str::to_string))
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    Ok(InlineData { mime_type, data: general_purpose::STANDARD.encode(body) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_data_uri_and_allowlist() {
+        let inline = parse_data_uri("data:image/png;base64,iVBORw0KGgo=").unwrap();
+        assert_eq!(inline, InlineData { mime_type: "image/png".to_string(), data: "iVBORw0KGgo=".to_string() });
+        assert!(parse_data_uri("data:text/plain,hello").is_err());
+        assert!(parse_data_uri("https://example.com/a.png").is_err());
+
+        let patterns = vec!["*.githubusercontent.com".to_string(), "example.com".to_string()];
+        assert!(host_allowed(&patterns, "raw.githubusercontent.com"));
+        assert!(host_allowed(&patterns, "Example.com"));
+        assert!(!host_allowed(&patterns, "githubusercontent.com.evil.io"));
+        assert!(!host_allowed(&patterns, "sub.example.com"));
+        assert!(!host_allowed(&[], "example.com"));
+    }
+}
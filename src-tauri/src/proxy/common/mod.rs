@@ -1,7 +1,12 @@
 // Common 模块 - 公共工具
 
 // pub mod error;
-// pub mod rate_limiter;
+pub mod rate_limiter;
 pub mod model_mapping;
 pub mod utils;
 pub mod json_schema;
+pub mod protocol;
+pub mod usage;
+pub mod fingerprint;
+pub mod tokens;
+pub mod media;
@@ -50,6 +50,17 @@ static CLAUDE_TO_GEMINI: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|
     m.insert("gemini-3-flash", "gemini-3-flash");
     m.insert("gemini-3-pro-image", "gemini-3-pro-image");
 
+    // 图像生成模型
+    m.insert("dall-e-2", "gemini-3-pro-image");
+    m.insert("dall-e-3", "gemini-3-pro-image");
+    m.insert("gpt-image-1", "gemini-3-pro-image");
+
+    // Embeddings 模型
+    m.insert("text-embedding-3-small", "gemini-embedding-001");
+    m.insert("text-embedding-3-large", "gemini-embedding-001");
+    m.insert("text-embedding-ada-002", "gemini-embedding-001");
+    m.insert("text-embedding-004", "text-embedding-004");
+
     m
 });
 
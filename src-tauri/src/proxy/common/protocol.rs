@@ -0,0 +1,157 @@
+// 客户端协议识别与协议化错误响应
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// 客户端所使用的协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientProtocol {
+    Claude,
+    OpenAI,
+    Gemini,
+}
+
+impl ClientProtocol {
+    /// 根据请求路径推断客户端协议
+    pub fn from_path(path: &str) -> Self {
+        if path.starts_with("/v1/messages") {
+            ClientProtocol::Claude
+        } else if path.starts_with("/v1beta") {
+            ClientProtocol::Gemini
+        } else {
+            ClientProtocol::OpenAI
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientProtocol::Claude => "claude",
+            ClientProtocol::OpenAI => "openai",
+            ClientProtocol::Gemini => "gemini",
+        }
+    }
+
+    /// 构造符合该协议格式的错误体
+    /// - Claude: `{"type":"error","error":{"type":"authentication_error","message":...}}`
+    /// - OpenAI: `{"error":{"message":...,"type":...,"param":null,"code":...}}`
+    /// - Gemini: `{"error":{"code":401,"message":...,"status":"UNAUTHENTICATED"}}`
+    pub fn error_body(&self, status: StatusCode, message: &str) -> Value {
+        match self {
+            ClientProtocol::Claude => {
+                let error_type = match status.as_u16() {
+                    400 => "invalid_request_error",
+                    401 => "authentication_error",
+                    403 => "permission_error",
+                    404 => "not_found_error",
+                    413 => "request_too_large",
+                    429 => "rate_limit_error",
+                    503 | 529 => "overloaded_error",
+                    _ => "api_error",
+                };
+                json!({
+                    "type": "error",
+                    "error": {
+                        "type": error_type,
+                        "message": message
+                    }
+                })
+            }
+            ClientProtocol::OpenAI => {
+                let (error_type, code) = match status.as_u16() {
+                    401 => ("invalid_request_error", Some("invalid_api_key")),
+                    403 => ("invalid_request_error", Some("permission_denied")),
+                    429 => ("rate_limit_error", Some("rate_limit_exceeded")),
+                    504 => ("server_error", Some("timeout")),
+                    400..=499 => ("invalid_request_error", None),
+                    _ => ("server_error", None),
+                };
+                json!({
+                    "error": {
+                        "message": message,
+                        "type": error_type,
+                        "param": null,
+                        "code": code
+                    }
+                })
+            }
+            ClientProtocol::Gemini => {
+                let grpc_status = match status.as_u16() {
+                    400 => "INVALID_ARGUMENT",
+                    401 => "UNAUTHENTICATED",
+                    403 => "PERMISSION_DENIED",
+                    404 => "NOT_FOUND",
+                    429 => "RESOURCE_EXHAUSTED",
+                    503 => "UNAVAILABLE",
+                    504 => "DEADLINE_EXCEEDED",
+                    _ => "INTERNAL",
+                };
+                json!({
+                    "error": {
+                        "code": status.as_u16(),
+                        "message": message,
+                        "status": grpc_status
+                    }
+                })
+            }
+        }
+    }
+
+    /// 构造流式响应中途出错时发送的 SSE 错误事件
+    /// Claude 使用 `event: error`，OpenAI/Gemini 直接以 data 行下发错误体
+    pub fn sse_error_event(&self, status: StatusCode, message: &str) -> Bytes {
+        let body = self.error_body(status, message);
+        match self {
+            ClientProtocol::Claude => Bytes::from(format!("event: error\ndata: {}\n\n", body)),
+            ClientProtocol::OpenAI | ClientProtocol::Gemini => Bytes::from(format!("data: {}\n\n", body)),
+        }
+    }
+
+    /// 构造符合该协议格式的错误响应
+    pub fn error_response(&self, status: StatusCode, message: &str) -> Response {
+        (status, Json(self.error_body(status, message))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(ClientProtocol::from_path("/v1/messages"), ClientProtocol::Claude);
+        assert_eq!(ClientProtocol::from_path("/v1/messages/count_tokens"), ClientProtocol::Claude);
+        assert_eq!(ClientProtocol::from_path("/v1/chat/completions"), ClientProtocol::OpenAI);
+        assert_eq!(ClientProtocol::from_path("/v1beta/models/gemini-2.5-flash:generateContent"), ClientProtocol::Gemini);
+    }
+
+    #[test]
+    fn test_error_body_shapes() {
+        let claude = ClientProtocol::Claude.error_body(StatusCode::UNAUTHORIZED, "bad key");
+        assert_eq!(claude["type"], "error");
+        assert_eq!(claude["error"]["type"], "authentication_error");
+
+        let openai = ClientProtocol::OpenAI.error_body(StatusCode::UNAUTHORIZED, "bad key");
+        assert_eq!(openai["error"]["code"], "invalid_api_key");
+        assert_eq!(openai["error"]["message"], "bad key");
+
+        let gemini = ClientProtocol::Gemini.error_body(StatusCode::UNAUTHORIZED, "bad key");
+        assert_eq!(gemini["error"]["code"], 401);
+        assert_eq!(gemini["error"]["status"], "UNAUTHENTICATED");
+    }
+
+    #[test]
+    fn test_sse_error_event() {
+        let claude = ClientProtocol::Claude.sse_error_event(StatusCode::GATEWAY_TIMEOUT, "stalled");
+        assert!(claude.starts_with(b"event: error\ndata: {"));
+
+        let gemini = ClientProtocol::Gemini.sse_error_event(StatusCode::GATEWAY_TIMEOUT, "stalled");
+        let text = std::str::from_utf8(&gemini).unwrap();
+        assert!(text.starts_with("data: ") && text.contains("DEADLINE_EXCEEDED"));
+    }
+}
@@ -1,51 +1,95 @@
 // Rate Limiter
-// 确保 API 调用间隔 ≥ 500ms
+// 按 key 区分的令牌桶限流器
 
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration, Instant};
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
 
-pub struct RateLimiter {
-    min_interval: Duration,
-    last_call: Arc<Mutex<Option<Instant>>>,
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
 }
 
-impl RateLimiter {
-    pub fn new(min_interval_ms: u64) -> Self {
+/// 按 key 区分的令牌桶限流器
+/// 每个 key 拥有独立的桶，容量与补充速率在每次调用时传入，以便配置热更新后立即生效
+pub struct KeyedRateLimiter {
+    buckets: DashMap<String, TokenBucket>,
+}
+
+impl KeyedRateLimiter {
+    pub fn new() -> Self {
         Self {
-            min_interval: Duration::from_millis(min_interval_ms),
-            last_call: Arc::new(Mutex::new(None)),
+            buckets: DashMap::new(),
         }
     }
 
-    pub async fn wait(&self) {
-        let mut last = self.last_call.lock().await;
-        if let Some(last_time) = *last {
-            let elapsed = last_time.elapsed();
-            if elapsed < self.min_interval {
-                sleep(self.min_interval - elapsed).await;
-            }
+    /// 尝试消耗一个令牌
+    /// `capacity` 为桶容量，`period` 内补满整个桶
+    /// 成功返回 Ok，否则返回需要等待的时长 (用于 retry-after)
+    pub fn try_acquire(&self, key: &str, capacity: u32, period: Duration) -> Result<(), Duration> {
+        self.try_acquire_at(key, capacity, period, Instant::now())
+    }
+
+    fn try_acquire_at(&self, key: &str, capacity: u32, period: Duration, now: Instant) -> Result<(), Duration> {
+        if capacity == 0 {
+            return Err(period);
         }
-        *last = Some(Instant::now());
+
+        let capacity = capacity as f64;
+        let refill_per_sec = capacity / period.as_secs_f64().max(0.001);
+
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert_with(|| TokenBucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / refill_per_sec))
+        }
+    }
+}
+
+impl Default for KeyedRateLimiter {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::time::Instant;
 
-    #[tokio::test]
-    async fn test_rate_limiter() {
-        let limiter = RateLimiter::new(500);
+    #[test]
+    fn test_token_bucket_per_key() {
+        let limiter = KeyedRateLimiter::new();
         let start = Instant::now();
+        let minute = Duration::from_secs(60);
 
-        limiter.wait().await; // 第一次调用，立即返回
-        let elapsed1 = start.elapsed().as_millis();
-        assert!(elapsed1 < 50);
+        // 容量 2，前两次立即通过，第三次被拒绝
+        assert!(limiter.try_acquire_at("a", 2, minute, start).is_ok());
+        assert!(limiter.try_acquire_at("a", 2, minute, start).is_ok());
+        let retry_after = limiter.try_acquire_at("a", 2, minute, start).unwrap_err();
+        assert_eq!(retry_after.as_secs(), 30);
+
+        // 其他 key 不受影响
+        assert!(limiter.try_acquire_at("b", 2, minute, start).is_ok());
+
+        // 30 秒后补充一个令牌
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.try_acquire_at("a", 2, minute, later).is_ok());
+        assert!(limiter.try_acquire_at("a", 2, minute, later).is_err());
+    }
 
-        limiter.wait().await; // 第二次调用，等待 500ms
-        let elapsed2 = start.elapsed().as_millis();
-        assert!(elapsed2 >= 500 && elapsed2 < 600);
+    #[test]
+    fn test_zero_capacity_always_rejects() {
+        let limiter = KeyedRateLimiter::new();
+        assert!(limiter.try_acquire("a", 0, Duration::from_secs(60)).is_err());
     }
 }
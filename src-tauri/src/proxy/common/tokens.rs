@@ -0,0 +1,161 @@
+// 输入 Token 统计
+// 优先调用上游 countTokens；上游不可用时按本地分词规则估算。system 与 tools 一并计入
+use serde_json::{json, Value};
+
+use crate::proxy::server::AppState;
+
+/// 每条消息的固定开销 (角色与分隔符)
+const MESSAGE_OVERHEAD: u64 = 4;
+/// 单张图片/文件按固定 Token 计 (与 Gemini 图片计费一致)
+const INLINE_DATA_TOKENS: u64 = 258;
+
+/// 统计 Gemini 格式请求 (contents / systemInstruction / tools) 的输入 Token 数
+pub async fn count_tokens(state: &AppState, model: &str, request: &Value) -> u64 {
+    match count_upstream(state, model, request).await {
+        Ok(total) => total,
+        Err(e) => {
+            let estimate = estimate_request(request);
+            tracing::warn!("countTokens 上游不可用 ({})，使用本地估算: {}", e, estimate);
+            estimate
+        }
+    }
+}
+
+async fn count_upstream(state: &AppState, model: &str, request: &Value) -> Result<u64, String> {
+    let quota_group = crate::proxy::common::utils::infer_quota_group(model);
+    let (access_token, _, email) = state.token_manager.get_token(&quota_group, None, &Default::default()).await.map_err(|e| e.to_string())?;
+    let identity = state.token_manager.identity(&email);
+    state
+        .upstream
+        .count_tokens(&access_token, count_tokens_body(model, request), &identity)
+        .await
+}
+
+/// 构建 countTokens 请求体
+/// 上游只统计 contents，因此将 system 与 tools 声明作为前置内容一并提交
+pub fn count_tokens_body(model: &str, request: &Value) -> Value {
+    let mut contents = Vec::new();
+    if let Some(parts) = request.pointer("/systemInstruction/parts").and_then(|p| p.as_array()) {
+        if !parts.is_empty() {
+            contents.push(json!({ "role": "user", "parts": parts }));
+        }
+    }
+    if let Some(tools) = request.get("tools").filter(|t| t.as_array().is_some_and(|a| !a.is_empty())) {
+        contents.push(json!({ "role": "user", "parts": [{ "text": tools.to_string() }] }));
+    }
+    if let Some(messages) = request.get("contents").and_then(|c| c.as_array()) {
+        contents.extend(messages.iter().cloned());
+    }
+
+    json!({
+        "request": {
+            "model": format!("models/{}", model),
+            "contents": contents,
+        }
+    })
+}
+
+/// 本地估算请求的输入 Token 数
+pub fn estimate_request(request: &Value) -> u64 {
+    let mut total = 0;
+    if let Some(system) = request.get("systemInstruction") {
+        total += MESSAGE_OVERHEAD + estimate_content(system);
+    }
+    if let Some(contents) = request.get("contents").and_then(|c| c.as_array()) {
+        for content in contents {
+            total += MESSAGE_OVERHEAD + estimate_content(content);
+        }
+    }
+    if let Some(tools) = request.get("tools").filter(|t| !t.is_null()) {
+        total += estimate_text(&tools.to_string());
+    }
+    total
+}
+
+/// 本地估算单条 Content (parts) 的 Token 数
+pub fn estimate_content(content: &Value) -> u64 {
+    content
+        .get("parts")
+        .and_then(|p| p.as_array())
+        .map_or(0, |parts| parts.iter().map(estimate_part).sum())
+}
+
+fn estimate_part(part: &Value) -> u64 {
+    if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+        return estimate_text(text);
+    }
+    if part.get("inlineData").is_some() || part.get("fileData").is_some() {
+        return INLINE_DATA_TOKENS;
+    }
+    // functionCall / functionResponse 等结构化内容按 JSON 文本估算 (不含 thoughtSignature)
+    let mut part = part.clone();
+    if let Some(obj) = part.as_object_mut() {
+        obj.remove("thoughtSignature");
+    }
+    estimate_text(&part.to_string())
+}
+
+/// 估算文本 Token 数：CJK 字符与标点各计 1 个，字母数字按每 4 个字符 1 个计
+pub fn estimate_text(text: &str) -> u64 {
+    let mut tokens = 0u64;
+    let mut word_len = 0u64;
+    for ch in text.chars() {
+        if ch.is_alphanumeric() && !is_cjk(ch) {
+            word_len += 1;
+            continue;
+        }
+        tokens += word_len.div_ceil(4);
+        word_len = 0;
+        if !ch.is_whitespace() {
+            tokens += 1;
+        }
+    }
+    tokens + word_len.div_ceil(4)
+}
+
+fn is_cjk(ch: char) -> bool {
+    matches!(ch as u32,
+        0x3040..=0x30FF       // 平假名/片假名
+        | 0x3400..=0x4DBF     // CJK 扩展 A
+        | 0x4E00..=0x9FFF     // CJK 统一汉字
+        | 0xAC00..=0xD7AF     // 韩文音节
+        | 0xF900..=0xFAFF     // CJK 兼容汉字
+        | 0x20000..=0x2FFFF)  // CJK 扩展 B 及以后
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_text() {
+        assert_eq!(estimate_text(""), 0);
+        assert_eq!(estimate_text("Hello, world!"), 6);
+        assert_eq!(estimate_text("你好世界"), 4);
+        assert!(estimate_text(&"lorem ipsum ".repeat(100)) >= 200);
+    }
+
+    #[test]
+    fn test_system_and_tools_are_counted() {
+        let request = json!({
+            "systemInstruction": { "parts": [{ "text": "You are a helpful assistant." }] },
+            "contents": [
+                { "role": "user", "parts": [{ "text": "Hi" }, { "inlineData": { "mimeType": "image/png", "data": "AAAA" } }] },
+                { "role": "model", "parts": [{ "functionCall": { "name": "read", "args": { "path": "a.rs" } }, "thoughtSignature": "x".repeat(4000) }] }
+            ],
+            "tools": [{ "functionDeclarations": [{ "name": "read", "description": "Read a file" }] }]
+        });
+        let messages_only = json!({ "contents": request["contents"] });
+        let full = estimate_request(&request);
+        let partial = estimate_request(&messages_only);
+        assert!(partial > INLINE_DATA_TOKENS && partial < INLINE_DATA_TOKENS + 50);
+        assert!(full > partial + 10);
+
+        let body = count_tokens_body("gemini-2.5-pro", &request);
+        assert_eq!(body["request"]["model"], "models/gemini-2.5-pro");
+        let contents = body["request"]["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 4);
+        assert_eq!(contents[0]["parts"][0]["text"], "You are a helpful assistant.");
+        assert!(contents[1]["parts"][0]["text"].as_str().unwrap().contains("functionDeclarations"));
+    }
+}
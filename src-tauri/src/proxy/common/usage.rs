@@ -0,0 +1,135 @@
+// Token 用量提取
+// 从上游 v1internal 响应 (JSON 或 SSE) 中解析 usageMetadata
+
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::pin::Pin;
+
+/// 单次请求的 Token 用量
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub thinking_tokens: u64,
+}
+
+impl TokenUsage {
+    /// 从 Gemini usageMetadata 解析 (兼容 v1internal 的 response 包装)
+    pub fn from_response(value: &Value) -> Option<Self> {
+        let raw = value.get("response").unwrap_or(value);
+        let meta = raw.get("usageMetadata")?;
+        let field = |name: &str| meta.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+
+        Some(Self {
+            input_tokens: field("promptTokenCount"),
+            output_tokens: field("candidatesTokenCount"),
+            thinking_tokens: field("thoughtsTokenCount"),
+        })
+    }
+
+    pub fn total(&self) -> u64 {
+        self.input_tokens + self.output_tokens + self.thinking_tokens
+    }
+}
+
+/// 流结束 (或被客户端中断丢弃) 时触发回调
+struct UsageTap<F: FnOnce(Option<TokenUsage>)> {
+    usage: Option<TokenUsage>,
+    on_complete: Option<F>,
+}
+
+impl<F: FnOnce(Option<TokenUsage>)> Drop for UsageTap<F> {
+    fn drop(&mut self) {
+        if let Some(callback) = self.on_complete.take() {
+            callback(self.usage);
+        }
+    }
+}
+
+/// 包装上游 SSE 字节流，透传数据的同时记录最后一次出现的 usageMetadata
+/// Gemini 在流中会多次下发累计的 usageMetadata，取最后一次即为最终用量
+pub fn tap_usage_stream<F>(
+    mut upstream: Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send>>,
+    on_complete: F,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send>>
+where
+    F: FnOnce(Option<TokenUsage>) + Send + 'static,
+{
+    Box::pin(async_stream::stream! {
+        let mut tap = UsageTap { usage: None, on_complete: Some(on_complete) };
+        let mut buffer = BytesMut::new();
+
+        while let Some(item) = upstream.next().await {
+            if let Ok(bytes) = &item {
+                buffer.extend_from_slice(bytes);
+                while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                    let line = buffer.split_to(pos + 1);
+                    if let Some(usage) = parse_sse_usage(&line) {
+                        tap.usage = Some(usage);
+                    }
+                }
+            }
+            yield item;
+        }
+    })
+}
+
+fn parse_sse_usage(line: &[u8]) -> Option<TokenUsage> {
+    let line = std::str::from_utf8(line).ok()?.trim();
+    let data = line.strip_prefix("data:")?.trim();
+    if !data.contains("usageMetadata") {
+        return None;
+    }
+    let json: Value = serde_json::from_str(data).ok()?;
+    TokenUsage::from_response(&json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_from_response() {
+        let resp = json!({
+            "response": {
+                "usageMetadata": {
+                    "promptTokenCount": 10,
+                    "candidatesTokenCount": 20,
+                    "thoughtsTokenCount": 5,
+                    "totalTokenCount": 35
+                }
+            }
+        });
+        let usage = TokenUsage::from_response(&resp).unwrap();
+        assert_eq!(usage.input_tokens, 10);
+        assert_eq!(usage.output_tokens, 20);
+        assert_eq!(usage.thinking_tokens, 5);
+        assert_eq!(usage.total(), 35);
+        assert!(TokenUsage::from_response(&json!({})).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tap_usage_stream_keeps_last_usage() {
+        let chunks: Vec<Result<Bytes, reqwest::Error>> = vec![
+            Ok(Bytes::from("data: {\"response\":{\"usageMetadata\":{\"promptTokenCount\":3}}}\n\n")),
+            Ok(Bytes::from("data: {\"response\":{\"usageMetadata\":{\"promptTokenCount\":3,\"candidatesTokenCount\":")),
+            Ok(Bytes::from("7}}}\n\n")),
+        ];
+        let recorded = Arc::new(Mutex::new(None));
+        let sink = recorded.clone();
+
+        let stream = tap_usage_stream(Box::pin(futures::stream::iter(chunks)), move |usage| {
+            *sink.lock().unwrap() = usage;
+        });
+        let passed: Vec<_> = stream.collect().await;
+
+        assert_eq!(passed.len(), 3);
+        let usage = recorded.lock().unwrap().unwrap();
+        assert_eq!(usage.input_tokens, 3);
+        assert_eq!(usage.output_tokens, 7);
+    }
+}
@@ -9,6 +9,18 @@ pub fn generate_random_id() -> String {
         .collect()
 }
 
+/// 提取会话亲和键，优先使用 `x-session-id` 请求头，其次使用请求体中的会话标识
+/// (Claude `metadata.user_id` / OpenAI `user`)
+pub fn extract_session_key(headers: &axum::http::HeaderMap, body_hint: Option<&str>) -> Option<String> {
+    headers
+        .get("x-session-id")
+        .and_then(|v| v.to_str().ok())
+        .or(body_hint)
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+}
+
 /// 根据模型名称推断 quota group ("claude" 或 "gemini")
 pub fn infer_quota_group(model: &str) -> String {
     if model.to_lowercase().starts_with("claude") {
@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+
+use crate::proxy::common::protocol::ClientProtocol;
 // use std::path::PathBuf;
 
 /// 反代服务配置
@@ -10,9 +12,16 @@ pub struct ProxyConfig {
     /// 监听端口
     pub port: u16,
     
-    /// API 密钥
+    /// API 密钥 (主密钥，留空则关闭鉴权)
     pub api_key: String,
-    
+
+    /// 额外的具名 API 密钥 (供多人共享反代时分别发放)
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+
+    /// /metrics 端点的访问密钥 (与客户端 API Key 独立，留空则要求有效的客户端 API Key)
+    #[serde(default)]
+    pub metrics_key: String,
 
     /// 是否自动启动
     pub auto_start: bool,
@@ -29,13 +38,293 @@ pub struct ProxyConfig {
     #[serde(default)]
     pub custom_mapping: std::collections::HashMap<String, String>,
 
-    /// API 请求超时时间(秒)
+    /// 模型降级链 (key: 路由后的目标模型, value: 按顺序尝试的备用模型)
+    /// 目标模型配额耗尽、所有账号均不可用或上游返回 404 时依次改用备用模型
+    #[serde(default)]
+    pub model_fallbacks: std::collections::HashMap<String, Vec<String>>,
+
+    /// API 请求超时时间(秒)，作用于非流式请求
     #[serde(default = "default_request_timeout")]
     pub request_timeout: u64,
 
+    /// 流式请求首个数据块超时(秒)
+    #[serde(default = "default_stream_first_byte_timeout")]
+    pub stream_first_byte_timeout: u64,
+
+    /// 流式请求数据块间空闲超时(秒)
+    #[serde(default = "default_stream_idle_timeout")]
+    pub stream_idle_timeout: u64,
+
     /// 上游代理配置
     #[serde(default)]
     pub upstream_proxy: UpstreamProxyConfig,
+
+    /// Cloud Code (v1internal) 端点列表，按顺序故障转移；为空时使用官方端点
+    #[serde(default)]
+    pub upstream_endpoints: Vec<String>,
+
+    /// 账号调度策略
+    #[serde(default)]
+    pub scheduling_strategy: SchedulingStrategy,
+
+    /// 严格优先级策略下的账号顺序 (email 或账号 ID)
+    #[serde(default)]
+    pub account_priority: Vec<String>,
+
+    /// 请求/响应抓取 (调试协议转换问题)
+    #[serde(default)]
+    pub capture: CaptureConfig,
+
+    /// 上游录制/回放 (离线复现)
+    #[serde(default)]
+    pub fixtures: UpstreamFixtureConfig,
+
+    /// 默认客户端指纹 (所有 Google 请求的 User-Agent 与请求元数据)
+    #[serde(default)]
+    pub client_profile: ClientProfile,
+
+    /// 额外的具名客户端指纹，账号可按名称选择
+    #[serde(default)]
+    pub client_profiles: Vec<ClientProfile>,
+
+    /// 各协议的重试与账号轮换策略
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// 远程媒体抓取 (OpenAI 消息中 http(s) 图片链接)
+    #[serde(default)]
+    pub media_fetch: MediaFetchConfig,
+
+    /// 生成文件链接时信任 X-Forwarded-Host / X-Forwarded-Proto (仅在反向代理之后开启)
+    /// 关闭时链接使用代理自身的监听地址
+    #[serde(default)]
+    pub trust_forwarded_headers: bool,
+}
+
+/// 账号调度策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulingStrategy {
+    /// 轮询
+    RoundRobin,
+    /// 最久未使用优先
+    LeastRecentlyUsed,
+    /// 进行中请求最少优先
+    LeastInFlight,
+    /// 按剩余配额加权轮换
+    #[default]
+    WeightedQuota,
+    /// 严格按 account_priority 顺序
+    StrictPriority,
+}
+
+/// 具名 API 密钥配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    /// 密钥名称 (用于日志、统计与策略计数，须唯一且不能为主密钥名称 `default`)
+    pub name: String,
+    /// 密钥内容
+    pub key: String,
+    /// 是否启用
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// 过期时间 (Unix 秒)，为空表示永不过期
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+    /// 允许调用的模型 (精确条目匹配客户端模型名或路由后的目标模型，`*` 后缀通配只匹配目标模型)，为空表示不限制
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+    /// 每分钟请求数上限
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    /// 同时进行的流式请求上限
+    #[serde(default)]
+    pub max_concurrent_streams: Option<u32>,
+    /// 每日 Token 预算 (按 UTC 自然日，统计 usageMetadata 的输入/输出/思考 Token)
+    #[serde(default)]
+    pub daily_token_budget: Option<u64>,
+}
+
+impl ApiKeyConfig {
+    /// 是否配置了任何访问策略
+    pub fn has_policy(&self) -> bool {
+        !self.allowed_models.is_empty()
+            || self.requests_per_minute.is_some()
+            || self.max_concurrent_streams.is_some()
+            || self.daily_token_budget.is_some()
+    }
+}
+
+/// 请求/响应抓取配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureConfig {
+    /// 是否启用
+    #[serde(default)]
+    pub enabled: bool,
+    /// 只抓取这些 API Key (名称) 的请求
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// 只抓取这些模型的请求 (支持 `*` 后缀通配)
+    /// api_keys 与 models 均为空时抓取全部请求，否则命中任意一项即抓取
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// 最多保留的抓取条数，超出后删除最旧的
+    #[serde(default = "default_capture_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            api_keys: Vec::new(),
+            models: Vec::new(),
+            max_entries: default_capture_max_entries(),
+        }
+    }
+}
+
+/// 远程媒体抓取配置
+/// 上游不能直接读取图片链接，须由代理下载后内联；仅允许白名单内的主机以防止访问内网
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaFetchConfig {
+    /// 允许抓取的主机 (支持 `*.example.com` 通配)，为空时拒绝所有远程链接
+    pub allowed_hosts: Vec<String>,
+    /// 单个文件大小上限 (字节)
+    pub max_bytes: usize,
+    /// 抓取超时 (秒)
+    pub timeout_secs: u64,
+}
+
+impl Default for MediaFetchConfig {
+    fn default() -> Self {
+        Self {
+            allowed_hosts: Vec::new(),
+            max_bytes: 20 * 1024 * 1024,
+            timeout_secs: 30,
+        }
+    }
+}
+
+/// 重试与账号轮换策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// 最大尝试次数 (不超过账号池大小)
+    pub max_attempts: usize,
+    /// 退避基准时长，第 n 次重试等待 `base * 2^(n-1)` 并加随机抖动
+    pub backoff_base_ms: u64,
+    /// 退避上限
+    pub backoff_max_ms: u64,
+    /// 429 响应携带 retryDelay 时按该时长等待后再重试
+    pub honor_retry_delay: bool,
+    /// retryDelay 超过该值时不等待，直接轮换账号
+    pub max_retry_delay_ms: u64,
+    /// 上游 5xx 时是否换账号重试
+    pub retry_server_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff_base_ms: 200,
+            backoff_max_ms: 2_000,
+            honor_retry_delay: true,
+            max_retry_delay_ms: 10_000,
+            retry_server_errors: false,
+        }
+    }
+}
+
+/// 按客户端协议区分的重试策略
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub claude: RetryPolicy,
+    pub openai: RetryPolicy,
+    pub gemini: RetryPolicy,
+}
+
+impl RetryConfig {
+    pub fn policy(&self, protocol: ClientProtocol) -> RetryPolicy {
+        match protocol {
+            ClientProtocol::Claude => self.claude,
+            ClientProtocol::OpenAI => self.openai,
+            ClientProtocol::Gemini => self.gemini,
+        }
+    }
+}
+
+/// 客户端指纹
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientProfile {
+    /// 名称 (账号通过名称选择)
+    pub name: String,
+    /// IDE 版本
+    pub ide_version: String,
+    /// 平台，如 `windows/amd64`、`darwin/arm64`
+    pub platform: String,
+    /// 完整 User-Agent，为空时生成 `antigravity/<ide_version> <platform>`
+    pub user_agent: String,
+    /// loadCodeAssist 等请求的 metadata 字段
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// 请求体中的 userAgent 字段
+    pub body_user_agent: String,
+    /// 请求体中 requestId 的前缀
+    pub request_id_prefix: String,
+}
+
+impl Default for ClientProfile {
+    fn default() -> Self {
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("ideType".to_string(), serde_json::Value::from("ANTIGRAVITY"));
+        Self {
+            name: "default".to_string(),
+            ide_version: "1.11.9".to_string(),
+            platform: "windows/amd64".to_string(),
+            user_agent: String::new(),
+            metadata,
+            body_user_agent: "antigravity".to_string(),
+            request_id_prefix: "agent-".to_string(),
+        }
+    }
+}
+
+impl ClientProfile {
+    /// HTTP User-Agent
+    pub fn user_agent(&self) -> String {
+        if self.user_agent.is_empty() {
+            format!("antigravity/{} {}", self.ide_version, self.platform)
+        } else {
+            self.user_agent.clone()
+        }
+    }
+}
+
+/// 上游录制/回放模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamFixtureMode {
+    /// 正常访问上游
+    #[default]
+    Off,
+    /// 访问上游，同时将请求/响应写入夹具目录
+    Record,
+    /// 不访问网络，按顺序返回夹具目录中的响应
+    Replay,
+}
+
+/// 上游录制/回放配置
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct UpstreamFixtureConfig {
+    #[serde(default)]
+    pub mode: UpstreamFixtureMode,
+    /// 夹具目录，为空时使用数据目录下的 fixtures
+    #[serde(default)]
+    pub dir: String,
 }
 
 /// 上游代理配置
@@ -45,6 +334,14 @@ pub struct UpstreamProxyConfig {
     pub enabled: bool,
     /// 代理地址 (http://, https://, socks5://)
     pub url: String,
+    /// 代理认证用户名 (也可直接写在地址中)
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// 不经过代理的主机 (与 NO_PROXY 环境变量格式相同，如 `localhost`、`.internal`、`10.0.0.0/8`)
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
 }
 
 impl Default for ProxyConfig {
@@ -53,12 +350,27 @@ impl Default for ProxyConfig {
             enabled: false,
             port: 8045,
             api_key: format!("sk-{}", uuid::Uuid::new_v4().simple()),
+            api_keys: Vec::new(),
+            metrics_key: String::new(),
             auto_start: false,
             anthropic_mapping: std::collections::HashMap::new(),
             openai_mapping: std::collections::HashMap::new(),
             custom_mapping: std::collections::HashMap::new(),
+            model_fallbacks: std::collections::HashMap::new(),
             request_timeout: default_request_timeout(),
+            stream_first_byte_timeout: default_stream_first_byte_timeout(),
+            stream_idle_timeout: default_stream_idle_timeout(),
             upstream_proxy: UpstreamProxyConfig::default(),
+            upstream_endpoints: Vec::new(),
+            scheduling_strategy: SchedulingStrategy::default(),
+            account_priority: Vec::new(),
+            capture: CaptureConfig::default(),
+            fixtures: UpstreamFixtureConfig::default(),
+            client_profile: ClientProfile::default(),
+            client_profiles: Vec::new(),
+            retry: RetryConfig::default(),
+            media_fetch: MediaFetchConfig::default(),
+            trust_forwarded_headers: false,
         }
     }
 }
@@ -66,3 +378,19 @@ impl Default for ProxyConfig {
 fn default_request_timeout() -> u64 {
     120  // 默认 120 秒,原来 60 秒太短
 }
+
+fn default_stream_first_byte_timeout() -> u64 {
+    90
+}
+
+fn default_stream_idle_timeout() -> u64 {
+    60
+}
+
+fn default_capture_max_entries() -> usize {
+    200
+}
+
+fn default_true() -> bool {
+    true
+}
@@ -0,0 +1,91 @@
+// 生成文件存储
+// 保存图像生成结果，供 `response_format=url` 通过 /files/{id} 访问
+// 仅保存在内存中 (重启后丢失)，超出容量时淘汰最早的文件
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// 默认容量 256 MB
+const MAX_TOTAL_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    pub mime_type: String,
+    pub data: Bytes,
+}
+
+#[derive(Default)]
+struct StoreInner {
+    files: HashMap<String, StoredFile>,
+    /// 写入顺序，队首最早
+    order: VecDeque<String>,
+    total_bytes: usize,
+}
+
+pub struct FileStore {
+    inner: Mutex<StoreInner>,
+    capacity: usize,
+}
+
+impl Default for FileStore {
+    fn default() -> Self {
+        Self::new(MAX_TOTAL_BYTES)
+    }
+}
+
+impl FileStore {
+    pub fn new(capacity: usize) -> Self {
+        Self { inner: Mutex::new(StoreInner::default()), capacity }
+    }
+
+    /// 保存文件并返回 id (随机 UUID 加扩展名，不可猜测)
+    pub fn insert(&self, mime_type: &str, data: Bytes) -> String {
+        let id = format!("{}.{}", uuid::Uuid::new_v4().simple(), extension(mime_type));
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        while inner.total_bytes + data.len() > self.capacity {
+            let Some(oldest) = inner.order.pop_front() else { break };
+            if let Some(file) = inner.files.remove(&oldest) {
+                inner.total_bytes -= file.data.len();
+            }
+        }
+        inner.total_bytes += data.len();
+        inner.order.push_back(id.clone());
+        inner.files.insert(id.clone(), StoredFile { mime_type: mime_type.to_string(), data });
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<StoredFile> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.files.get(id).cloned()
+    }
+}
+
+fn extension(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        "image/png" => "png",
+        _ => "bin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_oldest_over_capacity() {
+        let store = FileStore::new(10);
+        let first = store.insert("image/png", Bytes::from_static(b"123456"));
+        assert!(first.ends_with(".png"));
+        assert_eq!(store.get(&first).unwrap().data, Bytes::from_static(b"123456"));
+
+        let second = store.insert("image/jpeg", Bytes::from_static(b"7890"));
+        assert!(store.get(&first).is_some());
+        let third = store.insert("image/jpeg", Bytes::from_static(b"abc"));
+        assert!(store.get(&first).is_none());
+        assert!(store.get(&second).is_some() && store.get(&third).is_some());
+        assert!(store.get("missing.png").is_none());
+    }
+}
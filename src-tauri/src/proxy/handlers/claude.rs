@@ -1,31 +1,54 @@
 // Claude 协议处理器
 
 use axum::{
-    body::Body,
-    extract::{Json, State},
-    http::{header, StatusCode},
+    extract::{Extension, Json, State},
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
-use bytes::Bytes;
 use futures::StreamExt;
+use serde::Deserialize;
 use serde_json::{json, Value};
-use tokio::time::{sleep, Duration};
-use tracing::{debug, error};
+use std::sync::Arc;
 
 use crate::proxy::mappers::claude::{
     transform_claude_request_in, transform_response, create_claude_sse_stream, ClaudeRequest,
 };
+use crate::proxy::capture::CapturePart;
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::tokens::count_tokens;
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::middleware::stats::RequestTrace;
 use crate::proxy::server::AppState;
-
-const MAX_RETRY_ATTEMPTS: usize = 3;
+use crate::proxy::upstream::prefetch::ByteStream;
+use crate::proxy::upstream::retry::RetryEngine;
 
 /// 处理 Claude messages 请求
 /// 
 /// 处理 Chat 消息请求流程
 pub async fn handle_messages(
     State(state): State<AppState>,
-    Json(request): Json<ClaudeRequest>,
+    headers: HeaderMap,
+    identity: Option<Extension<ApiKeyIdentity>>,
+    trace: Option<Extension<Arc<RequestTrace>>>,
+    Json(body): Json<Value>,
 ) -> Response {
+    let identity = identity.map(|Extension(i)| i);
+    let trace = trace.map(|Extension(t)| t).unwrap_or_else(RequestTrace::detached);
+    let request = match ClaudeRequest::deserialize(&body) {
+        Ok(r) => r,
+        Err(e) => return ClientProtocol::Claude.error_response(StatusCode::BAD_REQUEST, &format!("Invalid request: {}", e)),
+    };
+    trace.set_client_model(&request.model);
+    // 抓取原始请求体 (保留未知字段，便于排查转换问题)
+    let capture = state.capture.begin(
+        trace.request_id(),
+        ClientProtocol::Claude,
+        identity.as_ref().map(|i| i.name.as_str()),
+        &request.model,
+        || body.clone(),
+    );
+
     // 获取最新一条“有意义”的消息内容（用于日志记录和后台任务检测）
     // 策略：反向遍历，首先筛选出所有角色为 "user" 的消息，然后从中找到第一条非 "Warmup" 且非空的文本消息
     // 获取最新一条“有意义”的消息内容（用于日志记录和后台任务检测）
@@ -74,267 +97,190 @@ pub async fn handle_messages(
     
     crate::modules::logger::log_info(&format!("Received Claude request for model: {}, content_preview: {:.100}...", request.model, latest_msg));
 
-    // 1. 获取 会话 ID (已废弃基于内容的哈希，改用 TokenManager 内部的时间窗口锁定)
-    let session_id: Option<&str> = None;
+    // 1. 获取会话亲和键 (x-session-id 或 metadata.user_id)，同一会话固定使用同一账号
+    let session_key = crate::proxy::common::utils::extract_session_key(
+        &headers,
+        request.metadata.as_ref().and_then(|m| m.user_id.as_deref()),
+    );
+    let session_id = session_key.as_deref();
+
+    // 2. 模型路由解析
+    // --- 核心优化：智能识别并拦截后台自动请求 ---
+    // 关键词识别：标题生成、摘要提取、下一步提示建议等
+    // [Optimization] 使用更长的预览窗口 (500 chars) 以捕获更具体的意图
+    let preview_msg = latest_msg.chars().take(500).collect::<String>();
+    let is_background_task = preview_msg.contains("write a 5-10 word title") 
+        || preview_msg.contains("Respond with the title")
+        || preview_msg.contains("Concise summary")
+        || preview_msg.contains("prompt suggestion generator");
 
-    // 2. 获取 UpstreamClient
-    let upstream = state.upstream.clone();
-    
-    // 3. 准备闭包
     let mut request_for_body = request.clone();
-    let token_manager = state.token_manager;
-    
-    let pool_size = token_manager.len();
-    let max_attempts = MAX_RETRY_ATTEMPTS.min(pool_size).max(1);
-
-    let mut last_error = String::new();
-    let mut retried_without_thinking = false;
-    
-    for attempt in 0..max_attempts {
-        // 4. 获取 Token (使用内置的时间窗口锁定机制)
-        let model_group = crate::proxy::common::utils::infer_quota_group(&request_for_body.model);
-        let (access_token, project_id, email) = match token_manager.get_token(&model_group, session_id).await {
-            Ok(t) => t,
-            Err(e) => {
-                 return (
-                    StatusCode::SERVICE_UNAVAILABLE,
-                    Json(json!({
-                        "type": "error",
-                        "error": {
-                            "type": "overloaded_error",
-                            "message": format!("No available accounts: {}", e)
-                        }
-                    }))
-                ).into_response();
-            }
-        };
-
-        tracing::info!("Using account: {} for request", email);
-        
-        // 5. 构建请求体
-        let mut mapped_model = crate::proxy::common::model_mapping::resolve_model_route(
-            &request_for_body.model,
-            &*state.custom_mapping.read().await,
-            &*state.openai_mapping.read().await,
-            &*state.anthropic_mapping.read().await,
+    let mapped_model = if is_background_task {
+        let mapped_model = "gemini-2.5-flash".to_string();
+        trace.set_background();
+        tracing::info!("[AUTO] 检测到后台自动任务 ({}...)，已智能重定向到廉价节点: {}", 
+           preview_msg,
+           mapped_model
         );
+        // [Optimization] **后台任务净化**: 
+        // 此类任务纯粹为文本处理，绝不需要执行工具。
+        // 强制清空 tools 字段，彻底根除 "Multiple tools" (400) 冲突风险。
+        request_for_body.tools = None;
+        mapped_model
+    } else {
+        let mapped_model = state.resolve_model(&request.model).await;
+        // [USER] 标记真实用户请求
+        // [Optimization] 使用 WARN 级别高亮显示用户消息，防止被后台任务日志淹没
+        tracing::warn!("[USER] 检测到用户交互请求 ({}...)，保持原模型: {}", 
+           preview_msg,
+           mapped_model
+        );
+        mapped_model
+    };
+    trace.set_mapped_model(&mapped_model);
 
-        // --- 核心优化：智能识别并拦截后台自动请求 ---
-        // --- 核心优化：智能识别与拦截后台自动请求 ---
-        // 关键词识别：标题生成、摘要提取、下一步提示建议等
-        // [Optimization] 使用更长的预览窗口 (500 chars) 以捕获更具体的意图
-        let preview_msg = latest_msg.chars().take(500).collect::<String>();
-        let is_background_task = preview_msg.contains("write a 5-10 word title") 
-            || preview_msg.contains("Respond with the title")
-            || preview_msg.contains("Concise summary")
-            || preview_msg.contains("prompt suggestion generator");
-
-        // 传递映射后的模型名
-        let mut request_with_mapped = request_for_body.clone();
-
-        if is_background_task {
-             mapped_model = "gemini-2.5-flash".to_string();
-             tracing::info!("[AUTO] 检测到后台自动任务 ({}...)，已智能重定向到廉价节点: {}", 
-                preview_msg,
-                mapped_model
-             );
-             // [Optimization] **后台任务净化**: 
-             // 此类任务纯粹为文本处理，绝不需要执行工具。
-             // 强制清空 tools 字段，彻底根除 "Multiple tools" (400) 冲突风险。
-             request_with_mapped.tools = None;
-        } else {
-             // [USER] 标记真实用户请求
-             // [Optimization] 使用 WARN 级别高亮显示用户消息，防止被后台任务日志淹没
-             tracing::warn!("[USER] 检测到用户交互请求 ({}...)，保持原模型: {}", 
-                preview_msg,
-                mapped_model
-             );
-        }
-        
-        request_with_mapped.model = mapped_model;
+    // 3. 按路由后的模型确定配额组 (去除 thinking 重试不会改变配额组)
+    let model_group = crate::proxy::common::utils::infer_quota_group(&mapped_model);
+    let mut retry = RetryEngine::new(&state, ClientProtocol::Claude, trace.clone(), identity.as_ref(), &mapped_model, &model_group, session_id).await;
 
-        // 生成 Trace ID (简单用时间戳后缀)
-        // let _trace_id = format!("req_{}", chrono::Utc::now().timestamp_subsec_millis());
+    let mut retried_without_thinking = false;
 
-        let gemini_body = match transform_claude_request_in(&request_with_mapped, &project_id) {
-            Ok(b) => b,
-            Err(e) => {
-                 return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({
-                        "type": "error",
-                        "error": {
-                            "type": "api_error",
-                            "message": format!("Transform error: {}", e)
-                        }
-                    }))
-                ).into_response();
-            }
-        };
-        
-    // 4. 上游调用
     let is_stream = request.stream;
     let method = if is_stream { "streamGenerateContent" } else { "generateContent" };
     let query = if is_stream { Some("alt=sse") } else { None };
 
-    let response = match upstream.call_v1_internal(
-        method,
-        &access_token,
-        gemini_body,
-        query
-    ).await {
-            Ok(r) => r,
+    loop {
+        // 4. 获取账号
+        let account = match retry.next_account().await {
+            Ok(Some(account)) => account,
+            Ok(None) => return retry.exhausted().into_response(),
+            Err(failure) => return failure.into_response(),
+        };
+        
+        // 5. 构建请求体，传递当前模型名 (可能已降级)
+        let mut request_with_mapped = request_for_body.clone();
+        request_with_mapped.model = retry.model().to_string();
+
+        let gemini_body = match transform_claude_request_in(&request_with_mapped, &account.project_id) {
+            Ok(b) => b,
             Err(e) => {
-                last_error = e.clone();
-                tracing::warn!("Request failed on attempt {}/{}: {}", attempt + 1, max_attempts, e);
-                continue;
+                return ClientProtocol::Claude.error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("Transform error: {}", e));
             }
         };
-        
-        let status = response.status();
-        
-        // 成功
-        if status.is_success() {
-            // 处理流式响应
-            if request.stream {
-                let stream = response.bytes_stream();
-                let gemini_stream = Box::pin(stream);
-                let claude_stream = create_claude_sse_stream(gemini_stream);
 
-                // 转换为 Bytes stream
-                let sse_stream = claude_stream.map(|result| -> Result<Bytes, std::io::Error> {
-                    match result {
-                        Ok(bytes) => Ok(bytes),
-                        Err(e) => Ok(Bytes::from(format!("data: {{\"error\":\"{}\"}}\n\n", e))),
-                    }
-                });
+        if let Some(capture) = &capture {
+            capture.set_upstream_request(&gemini_body, &account.access_token);
+        }
 
-                return Response::builder()
-                    .status(StatusCode::OK)
-                    .header(header::CONTENT_TYPE, "text/event-stream")
-                    .header(header::CACHE_CONTROL, "no-cache")
-                    .header(header::CONNECTION, "keep-alive")
-                    .body(Body::from_stream(sse_stream))
-                    .unwrap();
-            } else {
-                // 处理非流式响应
-                let bytes = match response.bytes().await {
-                    Ok(b) => b,
-                    Err(e) => return (StatusCode::BAD_GATEWAY, format!("Failed to read body: {}", e)).into_response(),
-                };
-                
-                // Debug print
-                if let Ok(text) = String::from_utf8(bytes.to_vec()) {
-                    debug!("Upstream Response for Claude request: {}", text);
+        // 6. 上游调用
+        let response = match retry.send(&account, method, query, gemini_body).await {
+            Ok(r) => r,
+            Err(error) => {
+                // 思维签名失效 (常见于 /resume 之后) 时去除 thinking 后立即重试一次
+                if error.status == Some(400)
+                    && !retried_without_thinking
+                    && (error.body.contains("Invalid `signature`")
+                        || error.body.contains("thinking.signature: Field required")
+                        || error.body.contains("thinking.signature"))
+                {
+                    retried_without_thinking = true;
+                    tracing::warn!("Upstream rejected thinking signature; retrying once with thinking stripped");
+                    strip_thinking(&mut request_for_body);
+                    // 仍使用目标模型时，按去除 thinking 后的模型重新路由
+                    if !is_background_task && !retry.is_fallback() {
+                        let mapped_model = state.resolve_model(&request_for_body.model).await;
+                        trace.set_mapped_model(&mapped_model);
+                        retry.replace_model(&mapped_model);
+                    }
+                    retry.retry_repaired(&account, error);
+                    continue;
                 }
+                match retry.handle_error(error) {
+                    Ok(()) => continue,
+                    Err(failure) => return failure.into_response(),
+                }
+            }
+        };
 
-                let gemini_resp: Value = match serde_json::from_slice(&bytes) {
-                    Ok(v) => v,
-                    Err(e) => return (StatusCode::BAD_GATEWAY, format!("Parse error: {}", e)).into_response(),
-                };
-
-                // 解包 response 字段（v1internal 格式）
-                let raw = gemini_resp.get("response").unwrap_or(&gemini_resp);
-
-                // 转换为 Gemini Response 结构
-                let gemini_response: crate::proxy::mappers::claude::models::GeminiResponse = match serde_json::from_value(raw.clone()) {
-                    Ok(r) => r,
-                    Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Convert error: {}", e)).into_response(),
-                };
-                
-                // 转换
-                let claude_response = match transform_response(&gemini_response) {
-                    Ok(r) => r,
-                    Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Transform error: {}", e)).into_response(),
-                };
-
-                return Json(claude_response).into_response();
+        // 7. 处理响应 (流式 / 非流式)
+        if is_stream {
+            // 首个有效内容到达前失败时换账号重试
+            let translate = |gemini_stream| -> ByteStream<String> {
+                // 转换错误以 SSE 事件告知客户端
+                Box::pin(create_claude_sse_stream(gemini_stream).map(|result| match result {
+                    Ok(bytes) => Ok(bytes),
+                    Err(e) => Ok(ClientProtocol::Claude.sse_error_event(StatusCode::BAD_GATEWAY, &e)),
+                }))
+            };
+            match retry.stream_response(account, response, &capture, translate).await {
+                Ok(response) => return response,
+                Err(error) => match retry.handle_error(error) {
+                    Ok(()) => continue,
+                    Err(failure) => return failure.into_response(),
+                },
             }
-        }
-        
-        // 处理错误
-        let error_text = response.text().await.unwrap_or_else(|_| format!("HTTP {}", status));
-        last_error = format!("HTTP {}: {}", status, error_text);
-        
-        let status_code = status.as_u16();
-        
-        // Handle transient 429s using upstream-provided retry delay (avoid surfacing errors to clients).
-        if status_code == 429 {
-            if let Some(delay_ms) = crate::proxy::upstream::retry::parse_retry_delay(&error_text) {
-                let actual_delay = delay_ms.saturating_add(200).min(10_000);
-                tracing::warn!(
-                    "Claude Upstream 429 on attempt {}/{}, waiting {}ms then retrying",
-                    attempt + 1,
-                    max_attempts,
-                    actual_delay
-                );
-                sleep(Duration::from_millis(actual_delay)).await;
-                continue;
+        } else {
+            // 处理非流式响应
+            let bytes = match response.bytes().await {
+                Ok(b) => b,
+                Err(e) => return ClientProtocol::Claude.error_response(StatusCode::BAD_GATEWAY, &format!("Failed to read body: {}", e)),
+            };
+            if let Some(capture) = &capture {
+                capture.append(CapturePart::UpstreamResponse, &bytes);
             }
-        }
 
-        // Special-case 400 errors caused by invalid/foreign thinking signatures (common after /resume).
-        // Retry once by stripping thinking blocks & thinking config from the request, and by disabling
-        // the "-thinking" model variant if present.
-        if status_code == 400
-            && !retried_without_thinking
-            && (error_text.contains("Invalid `signature`")
-                || error_text.contains("thinking.signature: Field required")
-                || error_text.contains("thinking.signature"))
-        {
-            retried_without_thinking = true;
-            tracing::warn!("Upstream rejected thinking signature; retrying once with thinking stripped");
+            let gemini_resp: Value = match serde_json::from_slice(&bytes) {
+                Ok(v) => v,
+                Err(e) => return ClientProtocol::Claude.error_response(StatusCode::BAD_GATEWAY, &format!("Parse error: {}", e)),
+            };
 
-            // 1) Remove thinking config
-            request_for_body.thinking = None;
+            let usage = TokenUsage::from_response(&gemini_resp);
+            trace.record_usage(usage);
+            state.key_policies.record(identity.as_ref(), usage);
 
-            // 2) Remove thinking blocks from message history
-            for msg in request_for_body.messages.iter_mut() {
-                if let crate::proxy::mappers::claude::models::MessageContent::Array(blocks) = &mut msg.content {
-                    blocks.retain(|b| !matches!(b, crate::proxy::mappers::claude::models::ContentBlock::Thinking { .. }));
-                }
-            }
+            // 解包 response 字段（v1internal 格式）
+            let raw = gemini_resp.get("response").unwrap_or(&gemini_resp);
 
-            // 3) Prefer non-thinking Claude model variant on retry (best-effort)
-            if request_for_body.model.contains("claude-") {
-                let mut m = request_for_body.model.clone();
-                m = m.replace("-thinking", "");
-                // If it's a dated alias, fall back to a stable non-thinking id
-                if m.contains("claude-sonnet-4-5-") {
-                    m = "claude-sonnet-4-5".to_string();
-                } else if m.contains("claude-opus-4-5-") || m.contains("claude-opus-4-") {
-                    m = "claude-opus-4-5".to_string();
-                }
-                request_for_body.model = m;
-            }
+            // 转换为 Gemini Response 结构
+            let gemini_response: crate::proxy::mappers::claude::models::GeminiResponse = match serde_json::from_value(raw.clone()) {
+                Ok(r) => r,
+                Err(e) => return ClientProtocol::Claude.error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("Convert error: {}", e)),
+            };
+            
+            // 转换
+            let claude_response = match transform_response(&gemini_response) {
+                Ok(r) => r,
+                Err(e) => return ClientProtocol::Claude.error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("Transform error: {}", e)),
+            };
 
-            continue;
+            if let Some(capture) = &capture {
+                capture.append_json(CapturePart::ClientResponse, &claude_response);
+            }
+            return Json(claude_response).into_response();
         }
+    }
+}
 
-        // 只有 429 (限流), 403 (权限/地区限制) 和 401 (认证失效) 触发账号轮换
-        if status_code == 429 || status_code == 403 || status_code == 401 {
-            // 如果是 429 且标记为配额耗尽（明确），直接报错，避免穿透整个账号池
-            if status_code == 429 && error_text.contains("QUOTA_EXHAUSTED") {
-                error!("Claude Quota exhausted (429) on attempt {}/{}, stopping to protect pool.", attempt + 1, max_attempts);
-                return (status, error_text).into_response();
-            }
+/// 去除 thinking 配置与历史中的 thinking 块，并尽量改用非 thinking 模型
+fn strip_thinking(request: &mut ClaudeRequest) {
+    request.thinking = None;
 
-            tracing::warn!("Claude Upstream {} on attempt {}/{}, rotating account", status, attempt + 1, max_attempts);
-            continue;
+    for msg in request.messages.iter_mut() {
+        if let crate::proxy::mappers::claude::models::MessageContent::Array(blocks) = &mut msg.content {
+            blocks.retain(|b| !matches!(b, crate::proxy::mappers::claude::models::ContentBlock::Thinking { .. }));
         }
-        
-        // 404 等由于模型配置或路径错误的 HTTP 异常，直接报错，不进行无效轮换
-        error!("Claude Upstream non-retryable error {}: {}", status_code, error_text);
-        return (status, error_text).into_response();
     }
-    
-    (StatusCode::TOO_MANY_REQUESTS, Json(json!({
-        "type": "error",
-        "error": {
-            "type": "overloaded_error",
-            "message": format!("All {} attempts failed. Last error: {}", max_attempts, last_error)
+
+    if request.model.contains("claude-") {
+        let mut m = request.model.replace("-thinking", "");
+        // 带日期的别名回退到稳定的非 thinking 模型
+        if m.contains("claude-sonnet-4-5-") {
+            m = "claude-sonnet-4-5".to_string();
+        } else if m.contains("claude-opus-4-5-") || m.contains("claude-opus-4-") {
+            m = "claude-opus-4-5".to_string();
         }
-    }))).into_response()
+        request.model = m;
+    }
 }
 
 /// 列出可用模型
@@ -364,12 +310,23 @@ pub async fn handle_list_models() -> impl IntoResponse {
     }))
 }
 
-/// 计算 tokens (占位符)
-pub async fn handle_count_tokens(Json(_body): Json<Value>) -> impl IntoResponse {
-    Json(json!({
-        "input_tokens": 0,
-        "output_tokens": 0
-    }))
+/// 统计输入 Token 数
+/// 按实际发送给上游的请求统计 (含 system 与 tools)，优先调用上游 countTokens，不可用时使用本地估算
+pub async fn handle_count_tokens(
+    State(state): State<AppState>,
+    Json(mut request): Json<ClaudeRequest>,
+) -> Response {
+    request.model = state.resolve_model(&request.model).await;
+    let body = match transform_claude_request_in(&request, "") {
+        Ok(b) => b,
+        Err(e) => {
+            return ClientProtocol::Claude.error_response(StatusCode::BAD_REQUEST, &format!("Transform error: {}", e));
+        }
+    };
+    let model = body.get("model").and_then(|m| m.as_str()).unwrap_or(&request.model);
+    let input_tokens = count_tokens(&state, model, &body["request"]).await;
+
+    Json(json!({ "input_tokens": input_tokens })).into_response()
 }
 
 #[cfg(test)]
@@ -0,0 +1,166 @@
+// Embeddings Handler
+// OpenAI /v1/embeddings 与 Gemini 原生 embedContent / batchEmbedContents，上游统一使用 batchEmbedContents
+use axum::{extract::State, extract::{Extension, Json}, http::StatusCode, response::{IntoResponse, Response}};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::proxy::common::fingerprint;
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::tokens::{estimate_content, estimate_text};
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::mappers::openai::embeddings::{
+    build_embed_requests, extract_embeddings, transform_embedding_response, EmbeddingRequest, MAX_BATCH_SIZE,
+};
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::middleware::stats::RequestTrace;
+use crate::proxy::server::AppState;
+use crate::proxy::upstream::retry::RetryEngine;
+
+/// 处理 OpenAI embeddings 请求
+pub async fn handle_embeddings(
+    State(state): State<AppState>,
+    identity: Option<Extension<ApiKeyIdentity>>,
+    trace: Option<Extension<Arc<RequestTrace>>>,
+    Json(body): Json<Value>,
+) -> Response {
+    let identity = identity.map(|Extension(i)| i);
+    let trace = trace.map(|Extension(t)| t).unwrap_or_else(RequestTrace::detached);
+    let request: EmbeddingRequest = match serde_json::from_value(body) {
+        Ok(r) => r,
+        Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &format!("Invalid request: {}", e)),
+    };
+    let inputs = match request.inputs() {
+        Ok(inputs) => inputs,
+        Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    trace.set_client_model(&request.model);
+
+    let mapped_model = state.resolve_model(&request.model).await;
+    trace.set_mapped_model(&mapped_model);
+
+    let requests = build_embed_requests(&inputs, &mapped_model, request.dimensions);
+    let embeddings = match embed(&state, ClientProtocol::OpenAI, &trace, identity.as_ref(), &mapped_model, requests).await {
+        Ok(e) => e,
+        Err(response) => return response,
+    };
+
+    let prompt_tokens = inputs.iter().map(|input| estimate_text(input)).sum();
+    record_usage(&trace, prompt_tokens);
+    Json(transform_embedding_response(&embeddings, &request.model, request.wants_base64(), prompt_tokens)).into_response()
+}
+
+/// 处理 Gemini 原生 embedContent / batchEmbedContents (由 `gemini::handle_generate` 按方法分派)
+pub async fn handle_gemini_embed(
+    state: &AppState,
+    identity: Option<&ApiKeyIdentity>,
+    trace: &Arc<RequestTrace>,
+    model_name: &str,
+    method: &str,
+    body: Value,
+) -> Response {
+    let batch = method == "batchEmbedContents";
+    let requests = if batch {
+        body.get("requests").and_then(|r| r.as_array()).cloned().unwrap_or_default()
+    } else {
+        vec![body]
+    };
+    if requests.is_empty() || requests.iter().any(|r| !r.is_object()) {
+        return ClientProtocol::Gemini.error_response(StatusCode::BAD_REQUEST, "Invalid embedding request");
+    }
+
+    let mapped_model = state.resolve_model(model_name).await;
+    trace.set_mapped_model(&mapped_model);
+
+    let prompt_tokens = requests.iter().filter_map(|r| r.get("content")).map(estimate_content).sum();
+    let embeddings = match embed(state, ClientProtocol::Gemini, trace, identity, &mapped_model, requests).await {
+        Ok(e) => e,
+        Err(response) => return response,
+    };
+
+    record_usage(trace, prompt_tokens);
+    if batch {
+        Json(json!({ "embeddings": embeddings })).into_response()
+    } else {
+        Json(json!({ "embedding": embeddings.into_iter().next() })).into_response()
+    }
+}
+
+/// 上游 batchEmbedContents 不返回 usageMetadata，本地估算的用量只写入响应与请求统计，
+/// 不计入 API Key 每日 Token 预算 (预算按上游实际用量计费)
+fn record_usage(trace: &RequestTrace, prompt_tokens: u64) {
+    trace.record_usage(Some(TokenUsage { input_tokens: prompt_tokens, ..Default::default() }));
+}
+
+/// 按上游批量上限分批获取向量，结果与输入顺序一致
+/// 只有首批允许降级，后续批次固定使用首批实际应答的模型 (不同模型的向量不可混用)，失败则整个请求失败
+async fn embed(
+    state: &AppState,
+    protocol: ClientProtocol,
+    trace: &Arc<RequestTrace>,
+    identity: Option<&ApiKeyIdentity>,
+    model: &str,
+    mut requests: Vec<Value>,
+) -> Result<Vec<Value>, Response> {
+    let mut embeddings = Vec::with_capacity(requests.len());
+    let mut model = model.to_string();
+    for (index, chunk) in requests.chunks_mut(MAX_BATCH_SIZE).enumerate() {
+        let (batch, served_model) = embed_batch(state, protocol, trace, identity, &model, index == 0, chunk).await?;
+        model = served_model;
+        if batch.len() != chunk.len() {
+            return Err(protocol.error_response(
+                StatusCode::BAD_GATEWAY,
+                &format!("Upstream returned {} embeddings for {} inputs", batch.len(), chunk.len()),
+            ));
+        }
+        embeddings.extend(batch);
+    }
+    Ok(embeddings)
+}
+
+async fn embed_batch(
+    state: &AppState,
+    protocol: ClientProtocol,
+    trace: &Arc<RequestTrace>,
+    identity: Option<&ApiKeyIdentity>,
+    model: &str,
+    allow_fallback: bool,
+    requests: &mut [Value],
+) -> Result<(Vec<Value>, String), Response> {
+    let quota_group = crate::proxy::common::utils::infer_quota_group(model);
+    let mut retry = RetryEngine::new(state, protocol, trace.clone(), identity, model, &quota_group, None).await;
+    if !allow_fallback {
+        retry.disable_fallback();
+    }
+
+    loop {
+        let account = match retry.next_account().await {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(retry.exhausted().into_response()),
+            Err(failure) => return Err(failure.into_response()),
+        };
+
+        // 每条请求的 model 须与当前模型一致 (可能已降级)
+        let model_name = format!("models/{}", retry.model());
+        for request in requests.iter_mut() {
+            if let Some(obj) = request.as_object_mut() {
+                obj.insert("model".to_string(), json!(model_name));
+            }
+        }
+        let body = fingerprint::envelope(&account.project_id, json!({ "requests": requests }), retry.model(), "agent");
+
+        let response = match retry.send(&account, "batchEmbedContents", None, body).await {
+            Ok(r) => r,
+            Err(error) => match retry.handle_error(error) {
+                Ok(()) => continue,
+                Err(failure) => return Err(failure.into_response()),
+            },
+        };
+
+        let json: Value = response
+            .json()
+            .await
+            .map_err(|e| protocol.error_response(StatusCode::BAD_GATEWAY, &format!("Parse error: {}", e)))?;
+        let embeddings = extract_embeddings(&json).map_err(|e| protocol.error_response(StatusCode::BAD_GATEWAY, &e))?;
+        return Ok((embeddings, retry.model().to_string()));
+    }
+}
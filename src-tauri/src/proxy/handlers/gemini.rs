@@ -1,20 +1,34 @@
 // Gemini Handler
-use axum::{extract::State, extract::{Json, Path}, http::StatusCode, response::IntoResponse};
+use axum::{extract::State, extract::{Extension, Json, Path}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
 use serde_json::{json, Value};
+use std::sync::Arc;
 use tracing::{debug, error};
 
 use crate::proxy::mappers::gemini::{wrap_request, unwrap_response};
+use crate::proxy::capture::CapturePart;
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::tokens::count_tokens;
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::middleware::stats::RequestTrace;
 use crate::proxy::server::AppState;
- 
-const MAX_RETRY_ATTEMPTS: usize = 3;
- 
+use crate::proxy::upstream::prefetch::ByteStream;
+use crate::proxy::upstream::retry::RetryEngine;
+
 /// 处理 generateContent 和 streamGenerateContent
 /// 路径参数: model_name, method (e.g. "gemini-pro", "generateContent")
 pub async fn handle_generate(
     State(state): State<AppState>,
     Path(model_action): Path<String>,
+    headers: HeaderMap,
+    identity: Option<Extension<ApiKeyIdentity>>,
+    trace: Option<Extension<Arc<RequestTrace>>>,
     Json(body): Json<Value>
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+) -> Response {
+    let identity = identity.map(|Extension(i)| i);
+    let trace = trace.map(|Extension(t)| t).unwrap_or_else(RequestTrace::detached);
     // 解析 model:method
     let (model_name, method) = if let Some((m, action)) = model_action.rsplit_once(':') {
         (m.to_string(), action.to_string())
@@ -24,175 +38,160 @@ pub async fn handle_generate(
 
     crate::modules::logger::log_info(&format!("Received Gemini request: {}/{}", model_name, method));
 
+    // Embeddings 请求单独处理
+    if method == "embedContent" || method == "batchEmbedContents" {
+        trace.set_client_model(&model_name);
+        return super::embeddings::handle_gemini_embed(&state, identity.as_ref(), &trace, &model_name, &method, body).await;
+    }
+
     // 1. 验证方法
     if method != "generateContent" && method != "streamGenerateContent" {
-        return Err((StatusCode::BAD_REQUEST, format!("Unsupported method: {}", method)));
+        return ClientProtocol::Gemini.error_response(StatusCode::BAD_REQUEST, &format!("Unsupported method: {}", method));
     }
     let is_stream = method == "streamGenerateContent";
+    trace.set_client_model(&model_name);
+    let capture = state.capture.begin(
+        trace.request_id(),
+        ClientProtocol::Gemini,
+        identity.as_ref().map(|i| i.name.as_str()),
+        &model_name,
+        || body.clone(),
+    );
+
+    // 会话亲和键 (仅支持 x-session-id 请求头)
+    let session_key = crate::proxy::common::utils::extract_session_key(&headers, None);
+
+    // 2. 模型路由解析
+    let mapped_model = state.resolve_model(&model_name).await;
+    trace.set_mapped_model(&mapped_model);
+
+    let model_group = crate::proxy::common::utils::infer_quota_group(&mapped_model);
+    let mut retry = RetryEngine::new(&state, ClientProtocol::Gemini, trace.clone(), identity.as_ref(), &mapped_model, &model_group, session_key.as_deref()).await;
+
+    let query_string = if is_stream { Some("alt=sse") } else { None };
+    let upstream_method = if is_stream { "streamGenerateContent" } else { "generateContent" };
+
+    loop {
+        // 3. 获取账号
+        let account = match retry.next_account().await {
+            Ok(Some(account)) => account,
+            Ok(None) => return retry.exhausted().into_response(),
+            Err(failure) => return failure.into_response(),
+        };
 
-    // 2. 获取 UpstreamClient 和 TokenManager
-    let upstream = state.upstream.clone();
-    let token_manager = state.token_manager;
-    let pool_size = token_manager.len();
-    let max_attempts = MAX_RETRY_ATTEMPTS.min(pool_size).max(1);
-    
-    let mut last_error = String::new();
-
-    for attempt in 0..max_attempts {
-        // 3. 模型路由解析
-        let mapped_model = crate::proxy::common::model_mapping::resolve_model_route(
-            &model_name,
-            &*state.custom_mapping.read().await,
-            &*state.openai_mapping.read().await,
-            &*state.anthropic_mapping.read().await,
-        );
-
-        // 4. 获取 Token
-        let model_group = crate::proxy::common::utils::infer_quota_group(&mapped_model);
-        let (access_token, project_id, email) = match token_manager.get_token(&model_group, None).await {
-            Ok(t) => t,
-            Err(e) => {
-                return Err((StatusCode::SERVICE_UNAVAILABLE, format!("Token error: {}", e)));
-            }
+        // 4. 包装请求 (project injection)，模型可能已降级
+        let wrapped_body = wrap_request(&body, &account.project_id, retry.model());
+        if let Some(capture) = &capture {
+            capture.set_upstream_request(&wrapped_body, &account.access_token);
+        }
+
+        // 5. 上游调用
+        let response = match retry.send(&account, upstream_method, query_string, wrapped_body).await {
+            Ok(r) => r,
+            Err(error) => match retry.handle_error(error) {
+                Ok(()) => continue,
+                Err(failure) => return failure.into_response(),
+            },
         };
 
-        tracing::info!("Using account: {} for request", email);
+        // 6. 响应处理
+        if is_stream {
+            // 首个有效内容到达前失败时换账号重试
+            match retry.stream_response(account, response, &capture, unwrap_sse_stream).await {
+                Ok(response) => return response,
+                Err(error) => match retry.handle_error(error) {
+                    Ok(()) => continue,
+                    Err(failure) => return failure.into_response(),
+                },
+            }
+        }
 
-        // 5. 包装请求 (project injection)
-        let wrapped_body = wrap_request(&body, &project_id, &mapped_model);
+        let gemini_resp: Value = match response.json().await {
+            Ok(v) => v,
+            Err(e) => return ClientProtocol::Gemini.error_response(StatusCode::BAD_GATEWAY, &format!("Parse error: {}", e)),
+        };
 
-        // 5. 上游调用
-        let query_string = if is_stream { Some("alt=sse") } else { None };
-        let upstream_method = if is_stream { "streamGenerateContent" } else { "generateContent" };
+        let usage = TokenUsage::from_response(&gemini_resp);
+        trace.record_usage(usage);
+        state.key_policies.record(identity.as_ref(), usage);
+        let unwrapped = unwrap_response(&gemini_resp);
+        if let Some(capture) = &capture {
+            capture.append_json(CapturePart::UpstreamResponse, &gemini_resp);
+            capture.append_json(CapturePart::ClientResponse, &unwrapped);
+        }
+        return Json(unwrapped).into_response();
+    }
+}
 
-        let response = match upstream
-            .call_v1_internal(upstream_method, &access_token, wrapped_body, query_string)
-            .await {
-                Ok(r) => r,
-                Err(e) => {
-                    last_error = e.clone();
-                    tracing::warn!("Gemini Request failed on attempt {}/{}: {}", attempt + 1, max_attempts, e);
-                    continue;
-                }
-            };
-
-        let status = response.status();
-        if status.is_success() {
-            // 6. 响应处理
-            if is_stream {
-                use axum::body::Body;
-                use axum::response::Response;
-                use bytes::{Bytes, BytesMut};
-                use futures::StreamExt;
-                
-                let mut response_stream = response.bytes_stream();
-                let mut buffer = BytesMut::new();
-
-                let stream = async_stream::stream! {
-                    while let Some(item) = response_stream.next().await {
-                        match item {
-                            Ok(bytes) => {
-                                debug!("[Gemini-SSE] Received chunk: {} bytes", bytes.len());
-                                buffer.extend_from_slice(&bytes);
-                                while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
-                                    let line_raw = buffer.split_to(pos + 1);
-                                    if let Ok(line_str) = std::str::from_utf8(&line_raw) {
-                                        let line = line_str.trim();
-                                        if line.is_empty() { continue; }
-                                        
-                                        if line.starts_with("data: ") {
-                                            let json_part = line.trim_start_matches("data: ").trim();
-                                            if json_part == "[DONE]" {
-                                                yield Ok::<Bytes, String>(Bytes::from("data: [DONE]\n\n"));
-                                                continue;
-                                            }
-                                            
-                                            match serde_json::from_str::<Value>(json_part) {
-                                                Ok(mut json) => {
-                                                    // Unwrap v1internal response wrapper
-                                                    if let Some(inner) = json.get_mut("response").map(|v| v.take()) {
-                                                        let new_line = format!("data: {}\n\n", serde_json::to_string(&inner).unwrap_or_default());
-                                                        yield Ok::<Bytes, String>(Bytes::from(new_line));
-                                                    } else {
-                                                        yield Ok::<Bytes, String>(Bytes::from(format!("data: {}\n\n", serde_json::to_string(&json).unwrap_or_default())));
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    debug!("[Gemini-SSE] JSON parse error: {}, passing raw line", e);
-                                                    yield Ok::<Bytes, String>(Bytes::from(format!("{}\n\n", line)));
-                                                }
-                                            }
+/// 去除 v1internal 的 response 包装，按行转发 Gemini SSE
+fn unwrap_sse_stream(mut response_stream: ByteStream<reqwest::Error>) -> ByteStream<String> {
+    let mut buffer = BytesMut::new();
+
+    let stream = async_stream::stream! {
+        while let Some(item) = response_stream.next().await {
+            match item {
+                Ok(bytes) => {
+                    debug!("[Gemini-SSE] Received chunk: {} bytes", bytes.len());
+                    buffer.extend_from_slice(&bytes);
+                    while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                        let line_raw = buffer.split_to(pos + 1);
+                        if let Ok(line_str) = std::str::from_utf8(&line_raw) {
+                            let line = line_str.trim();
+                            if line.is_empty() { continue; }
+                            
+                            if line.starts_with("data: ") {
+                                let json_part = line.trim_start_matches("data: ").trim();
+                                if json_part == "[DONE]" {
+                                    yield Ok::<Bytes, String>(Bytes::from("data: [DONE]\n\n"));
+                                    continue;
+                                }
+                                
+                                match serde_json::from_str::<Value>(json_part) {
+                                    Ok(mut json) => {
+                                        // Unwrap v1internal response wrapper
+                                        if let Some(inner) = json.get_mut("response").map(|v| v.take()) {
+                                            let new_line = format!("data: {}\n\n", serde_json::to_string(&inner).unwrap_or_default());
+                                            yield Ok::<Bytes, String>(Bytes::from(new_line));
                                         } else {
-                                            // Non-data lines (comments, etc.)
-                                            yield Ok::<Bytes, String>(Bytes::from(format!("{}\n\n", line)));
+                                            yield Ok::<Bytes, String>(Bytes::from(format!("data: {}\n\n", serde_json::to_string(&json).unwrap_or_default())));
                                         }
-                                    } else {
-                                        // Non-UTF8 data? Just pass it through or skip
-                                        debug!("[Gemini-SSE] Non-UTF8 line encountered");
-                                        yield Ok::<Bytes, String>(line_raw.freeze());
+                                    }
+                                    Err(e) => {
+                                        debug!("[Gemini-SSE] JSON parse error: {}, passing raw line", e);
+                                        yield Ok::<Bytes, String>(Bytes::from(format!("{}\n\n", line)));
                                     }
                                 }
+                            } else {
+                                // Non-data lines (comments, etc.)
+                                yield Ok::<Bytes, String>(Bytes::from(format!("{}\n\n", line)));
                             }
-                            Err(e) => {
-                                error!("[Gemini-SSE] Connection error: {}", e);
-                                yield Err(format!("Stream error: {}", e));
-                            }
+                        } else {
+                            // Non-UTF8 data? Just pass it through or skip
+                            debug!("[Gemini-SSE] Non-UTF8 line encountered");
+                            yield Ok::<Bytes, String>(line_raw.freeze());
                         }
                     }
-                };
-                
-                let body = Body::from_stream(stream);
-                return Ok(Response::builder()
-                    .header("Content-Type", "text/event-stream")
-                    .header("Cache-Control", "no-cache")
-                    .header("Connection", "keep-alive")
-                    .body(body)
-                    .unwrap()
-                    .into_response());
-            }
-
-            let gemini_resp: Value = response
-                .json()
-                .await
-                .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Parse error: {}", e)))?;
-
-            let unwrapped = unwrap_response(&gemini_resp);
-            return Ok(Json(unwrapped).into_response());
-        }
-
-        // 处理错误并重试
-        let status_code = status.as_u16();
-        let error_text = response.text().await.unwrap_or_default();
-        last_error = format!("HTTP {}: {}", status_code, error_text);
- 
-        // 只有 429 (限流), 403 (权限/地区限制) 和 401 (认证失效) 触发账号轮换
-        if status_code == 429 || status_code == 403 || status_code == 401 {
-            // 如果是 429 且标记为配额耗尽，直接报错，避免穿透整个账号池
-            if status_code == 429 && (error_text.contains("QUOTA_EXHAUSTED") || error_text.contains("quota")) {
-                error!("Gemini Quota exhausted (429) on attempt {}/{}, stopping to protect pool.", attempt + 1, max_attempts);
-                return Err((status, error_text));
+                }
+                Err(e) => {
+                    error!("[Gemini-SSE] Connection error: {}", e);
+                    yield Err(format!("Stream error: {}", e));
+                }
             }
-
-            tracing::warn!("Gemini Upstream {} on attempt {}/{}, rotating account", status_code, attempt + 1, max_attempts);
-            continue;
         }
- 
-        // 404 等由于模型配置或路径错误的 HTTP 异常，直接报错，不进行无效轮换
-        error!("Gemini Upstream non-retryable error {}: {}", status_code, error_text);
-        return Err((status, error_text));
-    }
+    };
 
-    Ok((StatusCode::TOO_MANY_REQUESTS, format!("All accounts exhausted. Last error: {}", last_error)).into_response())
+    Box::pin(stream)
 }
 
-pub async fn handle_list_models(State(state): State<AppState>) -> Result<impl IntoResponse, (StatusCode, String)> {
+pub async fn handle_list_models(State(state): State<AppState>) -> Result<impl IntoResponse, Response> {
     let model_group = "gemini";
-    let (access_token, _, _) = state.token_manager.get_token(model_group, None).await
-        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, format!("Token error: {}", e)))?;
+    let (access_token, _, email) = state.token_manager.get_token(model_group, None, &Default::default()).await
+        .map_err(|e| ClientProtocol::Gemini.error_response(StatusCode::SERVICE_UNAVAILABLE, &format!("Token error: {}", e)))?;
+    let account_identity = state.token_manager.identity(&email);
 
     // Fetch from upstream
-    let upstream_models = state.upstream.fetch_available_models(&access_token).await
-        .map_err(|e| (StatusCode::BAD_GATEWAY, e))?;
+    let upstream_models = state.upstream.fetch_available_models(&access_token, &account_identity).await
+        .map_err(|e| ClientProtocol::Gemini.error_response(StatusCode::BAD_GATEWAY, &e))?;
 
     // Transform map to Gemini list format
     let mut models = Vec::new();
@@ -236,10 +235,13 @@ pub async fn handle_get_model(Path(model_name): Path<String>) -> impl IntoRespon
     }))
 }
 
-pub async fn handle_count_tokens(State(state): State<AppState>, Path(_model_name): Path<String>, Json(_body): Json<Value>) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let model_group = "gemini";
-    let (_access_token, _project_id, _) = state.token_manager.get_token(model_group, None).await
-        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, format!("Token error: {}", e)))?;
-    
-    Ok(Json(json!({"totalTokens": 0})))
+/// 统计输入 Token 数
+/// 请求体可为 `{contents}` 或 `{generateContentRequest}`，后者的 systemInstruction 与 tools 一并计入
+/// 优先调用上游 countTokens，不可用时使用本地估算
+pub async fn handle_count_tokens(State(state): State<AppState>, Path(model_name): Path<String>, Json(body): Json<Value>) -> impl IntoResponse {
+    let mapped_model = state.resolve_model(&model_name).await;
+    let request = body.get("generateContentRequest").unwrap_or(&body);
+    let total_tokens = count_tokens(&state, &mapped_model, request).await;
+
+    Json(json!({ "totalTokens": total_tokens }))
 }
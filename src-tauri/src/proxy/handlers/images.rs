@@ -0,0 +1,301 @@
+// Images Handler
+// OpenAI /v1/images/generations 与 /v1/images/edits，上游使用 gemini-3-pro-image
+use axum::{
+    extract::{Extension, Json, Multipart, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::proxy::common::fingerprint;
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::mappers::openai::images::{build_image_request, extract_images, ImageRequest, InputImage, IMAGE_MODEL};
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::middleware::stats::RequestTrace;
+use crate::proxy::server::AppState;
+use crate::proxy::upstream::retry::RetryEngine;
+
+/// 处理图像生成请求
+pub async fn handle_generations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    identity: Option<Extension<ApiKeyIdentity>>,
+    trace: Option<Extension<Arc<RequestTrace>>>,
+    Json(body): Json<Value>,
+) -> Response {
+    let identity = identity.map(|Extension(i)| i);
+    let trace = trace.map(|Extension(t)| t).unwrap_or_else(RequestTrace::detached);
+    let request: ImageRequest = match serde_json::from_value(body) {
+        Ok(r) => r,
+        Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &format!("Invalid request: {}", e)),
+    };
+    generate(&state, &headers, identity.as_ref(), &trace, request, Vec::new(), None).await
+}
+
+/// 处理图像编辑请求 (multipart/form-data: image / image[] / mask / prompt 等)
+pub async fn handle_edits(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    identity: Option<Extension<ApiKeyIdentity>>,
+    trace: Option<Extension<Arc<RequestTrace>>>,
+    multipart: Multipart,
+) -> Response {
+    let identity = identity.map(|Extension(i)| i);
+    let trace = trace.map(|Extension(t)| t).unwrap_or_else(RequestTrace::detached);
+    let (request, images, mask) = match parse_edit_form(multipart).await {
+        Ok(form) => form,
+        Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    if images.is_empty() {
+        return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, "image is required");
+    }
+    generate(&state, &headers, identity.as_ref(), &trace, request, images, mask).await
+}
+
+/// 读取生成的文件 (无需 API Key，id 为随机 UUID)
+pub async fn handle_get_file(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.files.get(&id) {
+        Some(file) => (
+            [(header::CONTENT_TYPE, file.mime_type), (header::CACHE_CONTROL, "private, max-age=86400".to_string())],
+            file.data,
+        )
+            .into_response(),
+        None => ClientProtocol::OpenAI.error_response(StatusCode::NOT_FOUND, &format!("File '{}' not found", id)),
+    }
+}
+
+async fn parse_edit_form(mut multipart: Multipart) -> Result<(ImageRequest, Vec<InputImage>, Option<InputImage>), String> {
+    let mut request = ImageRequest::default();
+    let mut prompt = None;
+    let mut images = Vec::new();
+    let mut mask = None;
+
+    while let Some(field) = multipart.next_field().await.map_err(|e| format!("Invalid multipart body: {}", e))? {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "image" | "image[]" | "mask" => {
+                let content_type = field.content_type().map(str::to_string);
+                let data = field.bytes().await.map_err(|e| format!("Failed to read {}: {}", name, e))?;
+                let image = input_image(content_type, &data).ok_or_else(|| format!("{} must be a PNG, JPEG or WebP image", name))?;
+                if name == "mask" {
+                    mask = Some(image);
+                } else {
+                    images.push(image);
+                }
+            }
+            _ => {
+                let value = field.text().await.map_err(|e| format!("Failed to read {}: {}", name, e))?;
+                match name.as_str() {
+                    "prompt" => prompt = Some(value),
+                    "model" => request.model = Some(value),
+                    "n" => request.n = Some(value.trim().parse().map_err(|_| format!("Invalid n '{}'", value))?),
+                    "size" => request.size = Some(value),
+                    "quality" => request.quality = Some(value),
+                    "response_format" => request.response_format = Some(value),
+                    "user" => request.user = Some(value),
+                    _ => tracing::debug!("[Images] 忽略表单字段: {}", name),
+                }
+            }
+        }
+    }
+
+    request.prompt = prompt.ok_or("prompt is required")?;
+    Ok((request, images, mask))
+}
+
+/// 按声明的类型或文件头识别图片格式
+fn input_image(content_type: Option<String>, data: &[u8]) -> Option<InputImage> {
+    let mime_type = match content_type.as_deref() {
+        Some(mime @ ("image/png" | "image/jpeg" | "image/webp")) => mime,
+        _ if data.starts_with(b"\x89PNG") => "image/png",
+        _ if data.starts_with(b"\xFF\xD8\xFF") => "image/jpeg",
+        _ if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" => "image/webp",
+        _ => return None,
+    };
+    Some(InputImage { mime_type: mime_type.to_string(), data: general_purpose::STANDARD.encode(data) })
+}
+
+async fn generate(
+    state: &AppState,
+    headers: &HeaderMap,
+    identity: Option<&ApiKeyIdentity>,
+    trace: &Arc<RequestTrace>,
+    request: ImageRequest,
+    images: Vec<InputImage>,
+    mask: Option<InputImage>,
+) -> Response {
+    let (count, wants_url, image_config) = match (request.count(), request.wants_url(), request.image_config()) {
+        (Ok(count), Ok(wants_url), Ok(config)) => (count, wants_url, config),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &e)
+        }
+    };
+    if request.prompt.trim().is_empty() {
+        return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, "prompt must not be empty");
+    }
+    trace.set_client_model(request.model());
+
+    // 只有图像模型支持 imageConfig，其他映射结果统一改用默认图像模型
+    let mut mapped_model = state.resolve_model(request.model()).await;
+    if !mapped_model.starts_with(IMAGE_MODEL) {
+        mapped_model = IMAGE_MODEL.to_string();
+    }
+    trace.set_mapped_model(&mapped_model);
+
+    let session_key = crate::proxy::common::utils::extract_session_key(headers, request.user.as_deref());
+    let inner_request = build_image_request(&request.prompt, &images, mask.as_ref(), image_config);
+
+    // 上游每次请求返回一张图片，n 张并发生成
+    let results = futures::future::try_join_all(
+        (0..count).map(|_| generate_one(state, trace, identity, &mapped_model, &inner_request, session_key.as_deref())),
+    )
+    .await;
+    let responses = match results {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+
+    let base_url = if wants_url {
+        file_base_url(state.listen_addr, headers, *state.trust_forwarded_headers.read().await)
+    } else {
+        String::new()
+    };
+
+    let mut usage = TokenUsage::default();
+    let mut data = Vec::new();
+    let mut refusal = String::new();
+    for response in &responses {
+        if let Some(u) = TokenUsage::from_response(response) {
+            usage.input_tokens += u.input_tokens;
+            usage.output_tokens += u.output_tokens;
+            usage.thinking_tokens += u.thinking_tokens;
+        }
+        let (generated, text) = extract_images(response);
+        if generated.is_empty() {
+            // 保留第一条非空的拒绝说明，后续无说明的结果不覆盖
+            if refusal.is_empty() {
+                refusal = text;
+            }
+            continue;
+        }
+        for image in generated {
+            let mut item = if wants_url {
+                let bytes = match general_purpose::STANDARD.decode(&image.data) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        return ClientProtocol::OpenAI
+                            .error_response(StatusCode::BAD_GATEWAY, &format!("Invalid image data from upstream: {}", e))
+                    }
+                };
+                let id = state.files.insert(&image.mime_type, Bytes::from(bytes));
+                json!({ "url": format!("{}/files/{}", base_url, id) })
+            } else {
+                json!({ "b64_json": image.data })
+            };
+            if !text.is_empty() {
+                item["revised_prompt"] = json!(text);
+            }
+            data.push(item);
+        }
+    }
+
+    trace.record_usage(Some(usage));
+    state.key_policies.record(identity, Some(usage));
+
+    if data.is_empty() {
+        let message = if refusal.is_empty() {
+            "Upstream returned no image".to_string()
+        } else {
+            format!("Upstream returned no image: {}", refusal)
+        };
+        return ClientProtocol::OpenAI.error_response(StatusCode::BAD_GATEWAY, &message);
+    }
+
+    Json(json!({
+        "created": chrono::Utc::now().timestamp(),
+        "data": data,
+        "usage": {
+            "input_tokens": usage.input_tokens,
+            "output_tokens": usage.output_tokens + usage.thinking_tokens,
+            "total_tokens": usage.total(),
+        }
+    }))
+    .into_response()
+}
+
+async fn generate_one(
+    state: &AppState,
+    trace: &Arc<RequestTrace>,
+    identity: Option<&ApiKeyIdentity>,
+    model: &str,
+    inner_request: &Value,
+    session_key: Option<&str>,
+) -> Result<Value, Response> {
+    let quota_group = crate::proxy::common::utils::infer_quota_group(model);
+    let mut retry = RetryEngine::new(state, ClientProtocol::OpenAI, trace.clone(), identity, model, &quota_group, session_key).await;
+
+    loop {
+        let account = match retry.next_account().await {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(retry.exhausted().into_response()),
+            Err(failure) => return Err(failure.into_response()),
+        };
+
+        let body = fingerprint::envelope(&account.project_id, inner_request.clone(), retry.model(), "image_gen");
+        let response = match retry.send(&account, "generateContent", None, body).await {
+            Ok(r) => r,
+            Err(error) => match retry.handle_error(error) {
+                Ok(()) => continue,
+                Err(failure) => return Err(failure.into_response()),
+            },
+        };
+
+        return response
+            .json()
+            .await
+            .map_err(|e| ClientProtocol::OpenAI.error_response(StatusCode::BAD_GATEWAY, &format!("Parse error: {}", e)));
+    }
+}
+
+/// 文件链接的地址前缀：默认使用代理自身的监听地址
+/// 仅在配置信任转发头时采用反向代理传入的 X-Forwarded-Host / X-Forwarded-Proto
+fn file_base_url(listen_addr: SocketAddr, headers: &HeaderMap, trust_forwarded: bool) -> String {
+    let forwarded = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    };
+    if trust_forwarded {
+        if let Some(host) = forwarded("x-forwarded-host") {
+            let scheme = forwarded("x-forwarded-proto").filter(|p| matches!(*p, "http" | "https")).unwrap_or("http");
+            return format!("{}://{}", scheme, host);
+        }
+    }
+    format!("http://{}", listen_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_base_url_ignores_client_headers() {
+        let addr: SocketAddr = "127.0.0.1:8045".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "evil.example".parse().unwrap());
+        headers.insert("x-forwarded-host", "proxy.example.com".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+
+        assert_eq!(file_base_url(addr, &headers, false), "http://127.0.0.1:8045");
+        assert_eq!(file_base_url(addr, &headers, true), "https://proxy.example.com");
+        assert_eq!(file_base_url(addr, &HeaderMap::new(), true), "http://127.0.0.1:8045");
+    }
+}
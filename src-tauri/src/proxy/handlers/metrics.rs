@@ -0,0 +1,43 @@
+// Prometheus 指标端点
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::proxy::middleware::auth::constant_time_eq;
+use crate::proxy::server::AppState;
+
+/// GET /metrics
+/// 配置了 metrics_key 时需携带 `Authorization: Bearer <metrics_key>`，否则需携带有效的客户端 API Key
+/// 两者都未配置时不提供指标 (指标包含账号邮箱与配额)
+pub async fn handle_metrics(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .map(|s| s.trim())
+        .unwrap_or("");
+
+    let expected = state.metrics_key.read().await.clone();
+    if !expected.is_empty() {
+        if !constant_time_eq(presented.as_bytes(), expected.as_bytes()) {
+            return (StatusCode::UNAUTHORIZED, "Invalid metrics key").into_response();
+        }
+    } else {
+        let registry = state.api_keys.read().await;
+        if !registry.is_enforced() {
+            return (StatusCode::FORBIDDEN, "Metrics are disabled until metrics_key or an API key is configured").into_response();
+        }
+        if let Err(e) = registry.verify(Some(presented), chrono::Utc::now().timestamp()) {
+            return (e.status(), e.message()).into_response();
+        }
+    }
+
+    let body = crate::proxy::metrics::render(&state.stats, &state.token_manager);
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
@@ -4,3 +4,7 @@
 pub mod claude;
 pub mod openai;
 pub mod gemini;
+pub mod embeddings;
+pub mod images;
+pub mod responses;
+pub mod metrics;
@@ -1,123 +1,120 @@
 // OpenAI Handler
-use axum::{extract::State, extract::Json, http::StatusCode, response::IntoResponse};
+use axum::{extract::State, extract::{Extension, Json}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use serde::Deserialize;
 use serde_json::{json, Value};
-use tracing::{debug, error};
+use std::sync::Arc;
+use tracing::debug;
 
-use crate::proxy::mappers::openai::{transform_openai_request, transform_openai_response, OpenAIRequest};
+use crate::proxy::mappers::openai::{resolve_remote_media, transform_openai_request, transform_openai_response, OpenAIRequest};
 // use crate::proxy::upstream::client::UpstreamClient; // 通过 state 获取
+use crate::proxy::capture::CapturePart;
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::middleware::stats::RequestTrace;
 use crate::proxy::server::AppState;
- 
-const MAX_RETRY_ATTEMPTS: usize = 3;
- 
+use crate::proxy::upstream::retry::RetryEngine;
+
 pub async fn handle_chat_completions(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    identity: Option<Extension<ApiKeyIdentity>>,
+    trace: Option<Extension<Arc<RequestTrace>>>,
     Json(body): Json<Value>
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let openai_req: OpenAIRequest = serde_json::from_value(body)
-        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid request: {}", e)))?;
+) -> Response {
+    let identity = identity.map(|Extension(i)| i);
+    let trace = trace.map(|Extension(t)| t).unwrap_or_else(RequestTrace::detached);
+    let mut openai_req = match OpenAIRequest::deserialize(&body) {
+        Ok(r) => r,
+        Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &format!("Invalid request: {}", e)),
+    };
+    trace.set_client_model(&openai_req.model);
+    let capture = state.capture.begin(
+        trace.request_id(),
+        ClientProtocol::OpenAI,
+        identity.as_ref().map(|i| i.name.as_str()),
+        &openai_req.model,
+        || body.clone(),
+    );
 
     debug!("Received OpenAI request for model: {}", openai_req.model);
 
-    // 1. 获取 UpstreamClient (Clone handle)
-    let upstream = state.upstream.clone();
-    let token_manager = state.token_manager;
-    let pool_size = token_manager.len();
-    let max_attempts = MAX_RETRY_ATTEMPTS.min(pool_size).max(1);
-    
-    let mut last_error = String::new();
- 
-    for attempt in 0..max_attempts {
-        // 2. 获取 Token
-        let model_group = crate::proxy::common::utils::infer_quota_group(&openai_req.model);
-        let (access_token, project_id, email) = match token_manager.get_token(&model_group, None).await {
-            Ok(t) => t,
-            Err(e) => {
-                return Err((StatusCode::SERVICE_UNAVAILABLE, format!("Token error: {}", e)));
-            }
-        };
+    // 远程图片须先下载并内联 (上游无法直接读取链接)
+    let media_fetch = state.media_fetch.read().await.clone();
+    if let Err(e) = resolve_remote_media(&mut openai_req, &media_fetch).await {
+        return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &e);
+    }
 
-        tracing::info!("Using account: {} for request", email);
+    // 会话亲和键 (x-session-id 或 user 字段)
+    let session_key = crate::proxy::common::utils::extract_session_key(&headers, openai_req.user.as_deref());
 
-        // 3. 转换请求
-        let mapped_model = crate::proxy::common::model_mapping::resolve_model_route(
-            &openai_req.model,
-            &*state.custom_mapping.read().await,
-            &*state.openai_mapping.read().await,
-            &*state.anthropic_mapping.read().await,
-        );
-        let gemini_body = transform_openai_request(&openai_req, &project_id, &mapped_model);
+    // 1. 模型路由解析
+    let mapped_model = state.resolve_model(&openai_req.model).await;
+    trace.set_mapped_model(&mapped_model);
 
-        // 4. 发送请求
-        let list_response = openai_req.stream;
-        let method = if list_response { "streamGenerateContent" } else { "generateContent" };
-        let query_string = if list_response { Some("alt=sse") } else { None };
-
-        let response = match upstream
-            .call_v1_internal(method, &access_token, gemini_body, query_string)
-            .await {
-                Ok(r) => r,
-                Err(e) => {
-                    last_error = e.clone();
-                    tracing::warn!("OpenAI Request failed on attempt {}/{}: {}", attempt + 1, max_attempts, e);
-                    continue;
-                }
-            };
-
-        let status = response.status();
-        if status.is_success() {
-            // 5. 处理流式 vs 非流式
-            if list_response {
-                use crate::proxy::mappers::openai::streaming::create_openai_sse_stream;
-                use axum::response::Response;
-                use axum::body::Body;
-// Removed redundant StreamExt
-
-                let gemini_stream = response.bytes_stream();
-                let openai_stream = create_openai_sse_stream(Box::pin(gemini_stream), openai_req.model.clone());
-                let body = Body::from_stream(openai_stream);
-
-                return Ok(Response::builder()
-                    .header("Content-Type", "text/event-stream")
-                    .header("Cache-Control", "no-cache")
-                    .header("Connection", "keep-alive")
-                    .body(body)
-                    .unwrap()
-                    .into_response());
-            }
+    let model_group = crate::proxy::common::utils::infer_quota_group(&mapped_model);
+    let mut retry = RetryEngine::new(&state, ClientProtocol::OpenAI, trace.clone(), identity.as_ref(), &mapped_model, &model_group, session_key.as_deref()).await;
 
-            let gemini_resp: Value = response
-                .json()
-                .await
-                .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Parse error: {}", e)))?;
+    let list_response = openai_req.stream;
+    let method = if list_response { "streamGenerateContent" } else { "generateContent" };
+    let query_string = if list_response { Some("alt=sse") } else { None };
+
+    loop {
+        // 2. 获取账号
+        let account = match retry.next_account().await {
+            Ok(Some(account)) => account,
+            Ok(None) => return retry.exhausted().into_response(),
+            Err(failure) => return failure.into_response(),
+        };
 
-            let openai_response = transform_openai_response(&gemini_resp);
-            return Ok(Json(openai_response).into_response());
+        // 3. 转换请求 (按当前模型，可能已降级)
+        let gemini_body = match transform_openai_request(&openai_req, &account.project_id, retry.model()) {
+            Ok(b) => b,
+            Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &e),
+        };
+        if let Some(capture) = &capture {
+            capture.set_upstream_request(&gemini_body, &account.access_token);
         }
 
-        // 处理特定错误并重试
-        let status_code = status.as_u16();
-        let error_text = response.text().await.unwrap_or_default();
-        last_error = format!("HTTP {}: {}", status_code, error_text);
- 
-        // 只有 429 (限流), 403 (权限/地区限制) 和 401 (认证失效) 触发账号轮换
-        if status_code == 429 || status_code == 403 || status_code == 401 {
-            // 如果是 429 且标记为配额耗尽，直接报错，避免穿透整个账号池
-            if status_code == 429 && (error_text.contains("QUOTA_EXHAUSTED") || error_text.contains("quota")) {
-                error!("OpenAI Quota exhausted (429) on attempt {}/{}, stopping to protect pool.", attempt + 1, max_attempts);
-                return Err((status, error_text));
+        // 4. 发送请求
+        let response = match retry.send(&account, method, query_string, gemini_body).await {
+            Ok(r) => r,
+            Err(error) => match retry.handle_error(error) {
+                Ok(()) => continue,
+                Err(failure) => return failure.into_response(),
+            },
+        };
+
+        // 5. 处理流式 vs 非流式
+        if list_response {
+            use crate::proxy::mappers::openai::streaming::create_openai_sse_stream;
+
+            // 首个有效内容到达前失败时换账号重试
+            let model = openai_req.model.clone();
+            match retry.stream_response(account, response, &capture, |gemini_stream| create_openai_sse_stream(gemini_stream, model)).await {
+                Ok(response) => return response,
+                Err(error) => match retry.handle_error(error) {
+                    Ok(()) => continue,
+                    Err(failure) => return failure.into_response(),
+                },
             }
+        }
+
+        let gemini_resp: Value = match response.json().await {
+            Ok(v) => v,
+            Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_GATEWAY, &format!("Parse error: {}", e)),
+        };
 
-            tracing::warn!("OpenAI Upstream {} on attempt {}/{}, rotating account", status_code, attempt + 1, max_attempts);
-            continue;
+        let usage = TokenUsage::from_response(&gemini_resp);
+        trace.record_usage(usage);
+        state.key_policies.record(identity.as_ref(), usage);
+        let openai_response = transform_openai_response(&gemini_resp);
+        if let Some(capture) = &capture {
+            capture.append_json(CapturePart::UpstreamResponse, &gemini_resp);
+            capture.append_json(CapturePart::ClientResponse, &openai_response);
         }
- 
-        // 404 等由于模型配置或路径错误的 HTTP 异常，直接报错，不进行无效轮换
-        error!("OpenAI Upstream non-retryable error {}: {}", status_code, error_text);
-        return Err((status, error_text));
+        return Json(openai_response).into_response();
     }
-
-    // 所有尝试均失败
-    Err((StatusCode::TOO_MANY_REQUESTS, format!("All accounts exhausted. Last error: {}", last_error)))
 }
 
 pub async fn handle_list_models() -> impl IntoResponse {
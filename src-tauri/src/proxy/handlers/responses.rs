@@ -0,0 +1,162 @@
+// Responses Handler
+// OpenAI /v1/responses，对话状态保存在本地 (previous_response_id 由代理接续，不依赖上游)
+use axum::{extract::State, extract::{Extension, Json}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::proxy::capture::CapturePart;
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::mappers::responses::{
+    convert_input, create_responses_sse_stream, transform_responses_request, ResponseBuilder, ResponsesInput, ResponsesRequest,
+};
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::middleware::stats::RequestTrace;
+use crate::proxy::response_store::ResponseStore;
+use crate::proxy::server::AppState;
+use crate::proxy::upstream::retry::RetryEngine;
+
+pub async fn handle_responses(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    identity: Option<Extension<ApiKeyIdentity>>,
+    trace: Option<Extension<Arc<RequestTrace>>>,
+    Json(body): Json<Value>,
+) -> Response {
+    let identity = identity.map(|Extension(i)| i);
+    let trace = trace.map(|Extension(t)| t).unwrap_or_else(RequestTrace::detached);
+    let request = match ResponsesRequest::deserialize(&body) {
+        Ok(r) => r,
+        Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &format!("Invalid request: {}", e)),
+    };
+    trace.set_client_model(&request.model);
+    let capture = state.capture.begin(
+        trace.request_id(),
+        ClientProtocol::OpenAI,
+        identity.as_ref().map(|i| i.name.as_str()),
+        &request.model,
+        || body.clone(),
+    );
+
+    debug!("Received Responses request for model: {}", request.model);
+
+    // 1. 接续此前保存的对话
+    let history = match &request.previous_response_id {
+        // 只能接续同一 API Key 创建的对话，其他 Key 视为不存在
+        Some(id) => match state.responses.conversation(id, identity.as_ref().map(|i| i.name.as_str())) {
+            Some(history) => history,
+            None => {
+                return ClientProtocol::OpenAI
+                    .error_response(StatusCode::NOT_FOUND, &format!("Previous response with id '{}' not found.", id))
+            }
+        },
+        None => Vec::new(),
+    };
+    let input = match convert_input(&request, history) {
+        Ok(input) => input,
+        Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let session_key = crate::proxy::common::utils::extract_session_key(&headers, request.user.as_deref());
+
+    // 2. 模型路由解析
+    let mapped_model = state.resolve_model(&request.model).await;
+    trace.set_mapped_model(&mapped_model);
+
+    let model_group = crate::proxy::common::utils::infer_quota_group(&mapped_model);
+    let mut retry = RetryEngine::new(&state, ClientProtocol::OpenAI, trace.clone(), identity.as_ref(), &mapped_model, &model_group, session_key.as_deref()).await;
+
+    let method = if request.stream { "streamGenerateContent" } else { "generateContent" };
+    let query_string = if request.stream { Some("alt=sse") } else { None };
+
+    loop {
+        let account = match retry.next_account().await {
+            Ok(Some(account)) => account,
+            Ok(None) => return retry.exhausted().into_response(),
+            Err(failure) => return failure.into_response(),
+        };
+
+        // 3. 转换请求 (按当前模型，可能已降级)
+        let gemini_body = transform_responses_request(&request, &input, &account.project_id, retry.model());
+        if let Some(capture) = &capture {
+            capture.set_upstream_request(&gemini_body, &account.access_token);
+        }
+
+        let response = match retry.send(&account, method, query_string, gemini_body).await {
+            Ok(r) => r,
+            Err(error) => match retry.handle_error(error) {
+                Ok(()) => continue,
+                Err(failure) => return failure.into_response(),
+            },
+        };
+
+        let mut builder = ResponseBuilder::new(&request.model, request.previous_response_id.as_deref());
+
+        // 4. 处理流式 vs 非流式
+        if request.stream {
+            let store = request.should_store().then(|| state.responses.clone());
+            let owner = identity.as_ref().map(|i| i.name.clone());
+            let previous_response_id = request.previous_response_id.clone();
+            let input = input.clone();
+            let translate = move |gemini_stream| {
+                create_responses_sse_stream(gemini_stream, builder, move |builder| {
+                    if let Some(store) = store {
+                        save_turn(&store, builder, owner.as_deref(), previous_response_id.as_deref(), &input);
+                    }
+                })
+            };
+            match retry.stream_response(account, response, &capture, translate).await {
+                Ok(response) => return response,
+                Err(error) => match retry.handle_error(error) {
+                    Ok(()) => continue,
+                    Err(failure) => return failure.into_response(),
+                },
+            }
+        }
+
+        let gemini_resp: Value = match response.json().await {
+            Ok(v) => v,
+            Err(e) => return ClientProtocol::OpenAI.error_response(StatusCode::BAD_GATEWAY, &format!("Parse error: {}", e)),
+        };
+
+        let usage = TokenUsage::from_response(&gemini_resp);
+        trace.record_usage(usage);
+        state.key_policies.record(identity.as_ref(), usage);
+
+        builder.push_chunk(&gemini_resp);
+        if request.should_store() {
+            save_turn(
+                &state.responses,
+                &builder,
+                identity.as_ref().map(|i| i.name.as_str()),
+                request.previous_response_id.as_deref(),
+                &input,
+            );
+        }
+        let response_json = builder.response();
+        if let Some(capture) = &capture {
+            capture.append_json(CapturePart::UpstreamResponse, &gemini_resp);
+            capture.append_json(CapturePart::ClientResponse, &response_json);
+        }
+        return Json(response_json).into_response();
+    }
+}
+
+/// 保存本轮输入与模型输出，供后续请求通过 previous_response_id 接续
+fn save_turn(
+    store: &ResponseStore,
+    builder: &ResponseBuilder,
+    owner: Option<&str>,
+    previous_response_id: Option<&str>,
+    input: &ResponsesInput,
+) {
+    let mut turn = input.turn().to_vec();
+    let content = builder.model_content();
+    // 空输出 (如被安全策略拦截) 不写入对话，否则后续请求会因空 parts 被上游拒绝
+    if content["parts"].as_array().is_some_and(|p| !p.is_empty()) {
+        turn.push(content);
+    }
+    store.insert(builder.id(), owner, previous_response_id, turn);
+}
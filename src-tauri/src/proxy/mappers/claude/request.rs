@@ -106,18 +106,8 @@ pub fn transform_claude_request_in(
          }
     }
 
-    // 生成 requestId
-    let request_id = format!("agent-{}", uuid::Uuid::new_v4());
-
-    // 构建最终请求体
-    let mut body = json!({
-        "project": project_id,
-        "requestId": request_id,
-        "request": inner_request,
-        "model": config.final_model,
-        "userAgent": "antigravity",
-        "requestType": config.request_type,
-    });
+    // 构建最终请求体 (requestId / userAgent 发送时按账号指纹替换)
+    let mut body = crate::proxy::common::fingerprint::envelope(project_id, inner_request, &config.final_model, &config.request_type);
 
     // 如果提供了 metadata.user_id，则复用为 sessionId
     if let Some(metadata) = &claude_req.metadata {
@@ -72,16 +72,7 @@ pub fn wrap_request(body: &Value, project_id: &str, mapped_model: &str) -> Value
          }
     }
 
-    let final_request = json!({
-        "project": project_id,
-        "requestId": format!("agent-{}", uuid::Uuid::new_v4()), // 修正为 agent- 前缀
-        "request": inner_request,
-        "model": config.final_model,
-        "userAgent": "antigravity",
-        "requestType": config.request_type
-    });
-
-    final_request
+    crate::proxy::common::fingerprint::envelope(project_id, inner_request, &config.final_model, &config.request_type)
 }
 
 /// 解包响应（提取 response 字段）
@@ -3,5 +3,6 @@
 
 pub mod claude;
 pub mod openai;
+pub mod responses;
 pub mod gemini;
 pub mod common_utils;
@@ -0,0 +1,144 @@
+// OpenAI Embeddings ↔ Gemini batchEmbedContents 转换
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Gemini batchEmbedContents 单次最多包含的输入数
+pub const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    /// 字符串或字符串数组 (不支持 Token ID 数组)
+    pub input: Value,
+    /// `float` (默认) 或 `base64`
+    #[serde(default)]
+    pub encoding_format: Option<String>,
+    #[serde(default)]
+    pub dimensions: Option<u32>,
+}
+
+impl EmbeddingRequest {
+    /// 展开为输入文本列表
+    pub fn inputs(&self) -> Result<Vec<String>, String> {
+        let inputs = match &self.input {
+            Value::String(s) => vec![s.clone()],
+            Value::Array(items) => items
+                .iter()
+                .map(|item| item.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| "input must be a string or an array of strings (token arrays are not supported)".to_string())?,
+            _ => return Err("input must be a string or an array of strings".to_string()),
+        };
+        if inputs.is_empty() {
+            return Err("input must not be empty".to_string());
+        }
+        Ok(inputs)
+    }
+
+    pub fn wants_base64(&self) -> bool {
+        self.encoding_format.as_deref() == Some("base64")
+    }
+}
+
+/// 构建 Gemini embedContent 请求列表
+pub fn build_embed_requests(inputs: &[String], model: &str, dimensions: Option<u32>) -> Vec<Value> {
+    inputs
+        .iter()
+        .map(|text| {
+            let mut request = json!({
+                "model": format!("models/{}", model),
+                "content": { "parts": [{ "text": text }] },
+            });
+            if let Some(dimensions) = dimensions {
+                request["outputDimensionality"] = json!(dimensions);
+            }
+            request
+        })
+        .collect()
+}
+
+/// 从 batchEmbedContents 响应中提取向量 (兼容 v1internal 的 response 包装与单条 embedding)
+pub fn extract_embeddings(response: &Value) -> Result<Vec<Value>, String> {
+    let raw = response.get("response").unwrap_or(response);
+    if let Some(embeddings) = raw.get("embeddings").and_then(|e| e.as_array()) {
+        return Ok(embeddings.clone());
+    }
+    if let Some(embedding) = raw.get("embedding") {
+        return Ok(vec![embedding.clone()]);
+    }
+    Err("Upstream response has no embeddings".to_string())
+}
+
+/// 转换为 OpenAI embeddings 响应
+pub fn transform_embedding_response(embeddings: &[Value], model: &str, base64: bool, prompt_tokens: u64) -> Value {
+    let data: Vec<Value> = embeddings
+        .iter()
+        .enumerate()
+        .map(|(index, embedding)| {
+            let values: Vec<f32> = embedding
+                .get("values")
+                .and_then(|v| v.as_array())
+                .map(|v| v.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect())
+                .unwrap_or_default();
+            let encoded = if base64 {
+                // 与 OpenAI 一致：小端 float32 数组的 base64
+                let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                json!(general_purpose::STANDARD.encode(bytes))
+            } else {
+                json!(values)
+            };
+            json!({
+                "object": "embedding",
+                "index": index,
+                "embedding": encoded,
+            })
+        })
+        .collect();
+
+    json!({
+        "object": "list",
+        "data": data,
+        "model": model,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "total_tokens": prompt_tokens,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_round_trip() {
+        let request: EmbeddingRequest = serde_json::from_value(json!({
+            "model": "text-embedding-3-small",
+            "input": ["hello", "world"],
+            "dimensions": 256,
+            "encoding_format": "base64"
+        }))
+        .unwrap();
+        let inputs = request.inputs().unwrap();
+        let upstream = build_embed_requests(&inputs, "gemini-embedding-001", request.dimensions);
+        assert_eq!(upstream.len(), 2);
+        assert_eq!(upstream[0]["model"], "models/gemini-embedding-001");
+        assert_eq!(upstream[1]["content"]["parts"][0]["text"], "world");
+        assert_eq!(upstream[1]["outputDimensionality"], 256);
+
+        let response = json!({ "response": { "embeddings": [{ "values": [1.0, -0.5] }, { "values": [0.25] }] } });
+        let embeddings = extract_embeddings(&response).unwrap();
+        let body = transform_embedding_response(&embeddings, "text-embedding-3-small", false, 2);
+        assert_eq!(body["data"][0]["embedding"], json!([1.0, -0.5]));
+        assert_eq!(body["data"][1]["index"], 1);
+        assert_eq!(body["usage"]["total_tokens"], 2);
+
+        let encoded = transform_embedding_response(&embeddings, "m", true, 2);
+        let bytes = general_purpose::STANDARD.decode(encoded["data"][0]["embedding"].as_str().unwrap()).unwrap();
+        assert_eq!(bytes, [1.0f32.to_le_bytes(), (-0.5f32).to_le_bytes()].concat());
+
+        let tokens: EmbeddingRequest = serde_json::from_value(json!({ "model": "m", "input": [[1, 2, 3]] })).unwrap();
+        assert!(tokens.inputs().is_err());
+    }
+}
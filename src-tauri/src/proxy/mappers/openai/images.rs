@@ -0,0 +1,233 @@
+// OpenAI Images ↔ Gemini 图像生成转换
+// size / quality 映射到 imageConfig，n 张图片对应 n 次上游请求
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// 图像生成使用的上游模型
+pub const IMAGE_MODEL: &str = "gemini-3-pro-image";
+/// 单次请求最多生成的图片数 (与 OpenAI 一致)
+pub const MAX_IMAGES: u32 = 10;
+
+/// 上游支持的宽高比
+const ASPECT_RATIOS: &[(&str, f64)] = &[
+    ("1:1", 1.0),
+    ("2:3", 2.0 / 3.0),
+    ("3:2", 3.0 / 2.0),
+    ("3:4", 3.0 / 4.0),
+    ("4:3", 4.0 / 3.0),
+    ("4:5", 4.0 / 5.0),
+    ("5:4", 5.0 / 4.0),
+    ("9:16", 9.0 / 16.0),
+    ("16:9", 16.0 / 9.0),
+    ("21:9", 21.0 / 9.0),
+];
+
+/// `/v1/images/generations` 请求 (`/v1/images/edits` 的表单字段相同)
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImageRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub prompt: String,
+    #[serde(default)]
+    pub n: Option<u32>,
+    /// `WIDTHxHEIGHT` 或 `auto`
+    #[serde(default)]
+    pub size: Option<String>,
+    /// `standard` / `hd` (DALL·E) 或 `low` / `medium` / `high` / `auto` (gpt-image)
+    #[serde(default)]
+    pub quality: Option<String>,
+    /// `b64_json` 或 `url`
+    #[serde(default)]
+    pub response_format: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+/// 编辑请求附带的输入图片
+#[derive(Debug, Clone)]
+pub struct InputImage {
+    pub mime_type: String,
+    /// base64 编码的图片数据
+    pub data: String,
+}
+
+/// 上游返回的图片
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedImage {
+    pub mime_type: String,
+    /// base64 编码的图片数据
+    pub data: String,
+}
+
+impl ImageRequest {
+    pub fn model(&self) -> &str {
+        self.model.as_deref().filter(|m| !m.is_empty()).unwrap_or(IMAGE_MODEL)
+    }
+
+    pub fn count(&self) -> Result<u32, String> {
+        match self.n.unwrap_or(1) {
+            n @ 1..=MAX_IMAGES => Ok(n),
+            n => Err(format!("n must be between 1 and {}, got {}", MAX_IMAGES, n)),
+        }
+    }
+
+    /// 未指定 response_format 时与 OpenAI 一致：DALL·E 模型返回 url，其余返回 b64_json
+    pub fn wants_url(&self) -> Result<bool, String> {
+        match self.response_format.as_deref() {
+            Some("url") => Ok(true),
+            Some("b64_json") => Ok(false),
+            Some(other) => Err(format!("Invalid response_format '{}', expected 'url' or 'b64_json'", other)),
+            None => Ok(self.model().starts_with("dall-e")),
+        }
+    }
+
+    /// 将 size / quality 映射为 Gemini imageConfig
+    pub fn image_config(&self) -> Result<Value, String> {
+        let mut aspect_ratio = "1:1";
+        // 0 = 1K (默认), 1 = 2K, 2 = 4K
+        let mut resolution = 0;
+
+        if let Some(size) = self.size.as_deref().filter(|s| *s != "auto") {
+            let (width, height) = size
+                .split_once('x')
+                .and_then(|(w, h)| Some((w.trim().parse::<u32>().ok()?, h.trim().parse::<u32>().ok()?)))
+                .filter(|(w, h)| *w > 0 && *h > 0)
+                .ok_or_else(|| format!("Invalid size '{}', expected WIDTHxHEIGHT", size))?;
+            aspect_ratio = closest_aspect_ratio(width as f64 / height as f64);
+            resolution = match width.max(height) {
+                0..=1024 => 0,
+                1025..=2048 => 1,
+                _ => 2,
+            };
+        }
+
+        resolution = resolution.max(match self.quality.as_deref() {
+            Some("hd") | Some("high") => 2,
+            Some("medium") => 1,
+            _ => 0,
+        });
+
+        let mut config = json!({ "aspectRatio": aspect_ratio });
+        match resolution {
+            1 => config["imageSize"] = json!("2K"),
+            2 => config["imageSize"] = json!("4K"),
+            _ => {}
+        }
+        Ok(config)
+    }
+}
+
+fn closest_aspect_ratio(ratio: f64) -> &'static str {
+    ASPECT_RATIOS
+        .iter()
+        .min_by(|a, b| {
+            let da = (a.1.ln() - ratio.ln()).abs();
+            let db = (b.1.ln() - ratio.ln()).abs();
+            da.total_cmp(&db)
+        })
+        .map(|(name, _)| *name)
+        .unwrap_or("1:1")
+}
+
+/// 构建单张图片的 Gemini 请求 (不含 v1internal 外层)
+/// 编辑请求的输入图片放在提示词之前；mask 作为额外图片附带说明
+pub fn build_image_request(prompt: &str, images: &[InputImage], mask: Option<&InputImage>, image_config: Value) -> Value {
+    let mut parts: Vec<Value> = images
+        .iter()
+        .map(|image| json!({ "inlineData": { "mimeType": image.mime_type, "data": image.data } }))
+        .collect();
+
+    let mut text = prompt.to_string();
+    if let Some(mask) = mask {
+        parts.push(json!({ "inlineData": { "mimeType": mask.mime_type, "data": mask.data } }));
+        text.push_str("\n\nThe last image is a mask: only edit the areas that are fully transparent in the mask and keep everything else unchanged.");
+    }
+    parts.push(json!({ "text": text }));
+
+    json!({
+        "contents": [{ "role": "user", "parts": parts }],
+        "generationConfig": { "imageConfig": image_config },
+        "safetySettings": [
+            { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_HATE_SPEECH", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_SEXUALLY_EXPLICIT", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_CIVIC_INTEGRITY", "threshold": "OFF" },
+        ]
+    })
+}
+
+/// 提取上游响应中的图片与附带文本 (兼容 v1internal 的 response 包装)
+pub fn extract_images(gemini_response: &Value) -> (Vec<GeneratedImage>, String) {
+    let raw = gemini_response.get("response").unwrap_or(gemini_response);
+    let mut images = Vec::new();
+    let mut text = String::new();
+
+    let parts = raw
+        .pointer("/candidates/0/content/parts")
+        .and_then(|p| p.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    for part in parts {
+        if part.get("thought").and_then(|t| t.as_bool()).unwrap_or(false) {
+            continue;
+        }
+        if let Some(data) = part.pointer("/inlineData/data").and_then(|d| d.as_str()) {
+            let mime_type = part.pointer("/inlineData/mimeType").and_then(|m| m.as_str()).unwrap_or("image/png");
+            images.push(GeneratedImage { mime_type: mime_type.to_string(), data: data.to_string() });
+        } else if let Some(t) = part.get("text").and_then(|t| t.as_str()) {
+            text.push_str(t);
+        }
+    }
+    (images, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(size: Option<&str>, quality: Option<&str>) -> ImageRequest {
+        ImageRequest {
+            prompt: "a cat".to_string(),
+            size: size.map(str::to_string),
+            quality: quality.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_image_config_mapping() {
+        assert_eq!(request(None, None).image_config().unwrap(), json!({ "aspectRatio": "1:1" }));
+        assert_eq!(request(Some("1792x1024"), None).image_config().unwrap(), json!({ "aspectRatio": "16:9", "imageSize": "2K" }));
+        assert_eq!(request(Some("1024x1536"), Some("high")).image_config().unwrap(), json!({ "aspectRatio": "2:3", "imageSize": "4K" }));
+        assert_eq!(request(Some("1024x1024"), Some("hd")).image_config().unwrap()["imageSize"], "4K");
+        assert!(request(Some("large"), None).image_config().is_err());
+
+        let mut dalle = request(None, None);
+        dalle.model = Some("dall-e-3".to_string());
+        assert!(dalle.wants_url().unwrap());
+        assert!(!request(None, None).wants_url().unwrap());
+        dalle.n = Some(11);
+        assert!(dalle.count().is_err());
+    }
+
+    #[test]
+    fn test_extract_images() {
+        let response = json!({
+            "response": {
+                "candidates": [{
+                    "content": {
+                        "parts": [
+                            { "text": "planning", "thought": true },
+                            { "text": "Here is your cat." },
+                            { "inlineData": { "mimeType": "image/jpeg", "data": "AAAA" } }
+                        ]
+                    }
+                }]
+            }
+        });
+        let (images, text) = extract_images(&response);
+        assert_eq!(images, vec![GeneratedImage { mime_type: "image/jpeg".to_string(), data: "AAAA".to_string() }]);
+        assert_eq!(text, "Here is your cat.");
+    }
+}
@@ -1,6 +1,8 @@
 // OpenAI mapper 模块
 // 负责 OpenAI ↔ Gemini 协议转换
 
+pub mod embeddings;
+pub mod images;
 pub mod models;
 pub mod request;
 pub mod response;
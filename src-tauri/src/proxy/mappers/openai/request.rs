@@ -1,8 +1,84 @@
 // OpenAI → Gemini 请求转换
 use super::models::*;
 use serde_json::{json, Value};
+use std::collections::HashMap;
 
-pub fn transform_openai_request(request: &OpenAIRequest, project_id: &str, mapped_model: &str) -> Value {
+use crate::proxy::common::media::{fetch_remote, mime_from_filename, parse_data_uri};
+use crate::proxy::config::MediaFetchConfig;
+
+/// 预处理消息中的媒体内容：下载远程图片并替换为 data URI，拒绝无法内联的文件引用
+/// 须在 `transform_openai_request` 之前调用 (转换本身不做网络请求)
+pub async fn resolve_remote_media(request: &mut OpenAIRequest, config: &MediaFetchConfig) -> Result<(), String> {
+    for msg in &mut request.messages {
+        let Some(OpenAIContent::Array(parts)) = &mut msg.content else { continue };
+        for part in parts {
+            match part {
+                OpenAIContentPart::ImageUrl { image_url } if !image_url.url.starts_with("data:") => {
+                    let inline = fetch_remote(config, &image_url.url).await?;
+                    if !inline.mime_type.starts_with("image/") {
+                        return Err(format!("'{}' is not an image ({})", image_url.url, inline.mime_type));
+                    }
+                    image_url.url = format!("data:{};base64,{}", inline.mime_type, inline.data);
+                }
+                OpenAIContentPart::ImageUrl { image_url } => {
+                    parse_data_uri(&image_url.url)?;
+                }
+                OpenAIContentPart::File { file } if file.file_data.is_none() => {
+                    return Err("file parts must include file_data (file_id is not supported)".to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 将消息内容转换为 Gemini parts
+/// 图片链接须已由 `resolve_remote_media` 替换为 data URI，不会原样转发给上游
+fn content_parts(content: &OpenAIContent) -> Result<Vec<Value>, String> {
+    let parts = match content {
+        OpenAIContent::String(text) => return Ok(vec![json!({ "text": text })]),
+        OpenAIContent::Array(parts) => parts,
+    };
+
+    let mut out = Vec::with_capacity(parts.len());
+    for part in parts {
+        match part {
+            OpenAIContentPart::Text { text } => out.push(json!({ "text": text })),
+            OpenAIContentPart::Refusal { refusal } => out.push(json!({ "text": refusal })),
+            OpenAIContentPart::ImageUrl { image_url } => {
+                let inline = parse_data_uri(&image_url.url)
+                    .map_err(|e| format!("image_url must be a data URI or an allowed http(s) URL: {}", e))?;
+                out.push(json!({ "inlineData": { "mimeType": inline.mime_type, "data": inline.data } }));
+            }
+            OpenAIContentPart::InputAudio { input_audio } => {
+                let mime_type = match input_audio.format.as_str() {
+                    "mp3" => "audio/mpeg".to_string(),
+                    format => format!("audio/{}", format),
+                };
+                out.push(json!({ "inlineData": { "mimeType": mime_type, "data": input_audio.data } }));
+            }
+            OpenAIContentPart::File { file } => {
+                let data = file
+                    .file_data
+                    .as_deref()
+                    .ok_or("file parts must include file_data (file_id is not supported)")?;
+                let inline = parse_data_uri(data).ok();
+                let mime_type = inline
+                    .as_ref()
+                    .map(|i| i.mime_type.as_str())
+                    .filter(|m| *m != "application/octet-stream")
+                    .or_else(|| file.filename.as_deref().and_then(mime_from_filename))
+                    .unwrap_or("application/pdf");
+                let data = inline.as_ref().map_or(data, |i| i.data.as_str());
+                out.push(json!({ "inlineData": { "mimeType": mime_type, "data": data } }));
+            }
+        }
+    }
+    Ok(out)
+}
+
+pub fn transform_openai_request(request: &OpenAIRequest, project_id: &str, mapped_model: &str) -> Result<Value, String> {
     // Resolve grounding config
     let config = crate::proxy::mappers::common_utils::resolve_request_config(&request.model, mapped_model);
 
@@ -11,16 +87,22 @@ pub fn transform_openai_request(request: &OpenAIRequest, project_id: &str, mappe
     
     // 1. 提取所有 System Message
     let system_instructions: Vec<String> = request.messages.iter()
-        .filter(|msg| msg.role == "system")
-        .filter_map(|msg| msg.content.clone())
+        .filter(|msg| msg.role == "system" || msg.role == "developer")
+        .filter_map(|msg| msg.content.as_ref().map(|c| c.text()))
+        .collect();
+
+    // tool 消息只带 tool_call_id，函数名从此前的 tool_calls 中查找
+    let call_names: HashMap<&str, &str> = request.messages.iter()
+        .flat_map(|msg| msg.tool_calls.iter().flatten())
+        .map(|tc| (tc.id.as_str(), tc.function.name.as_str()))
         .collect();
 
     // 2. 构建 Gemini contents (过滤掉 system)
     let contents: Vec<Value> = request
         .messages
         .iter()
-        .filter(|msg| msg.role != "system")
-        .map(|msg| {
+        .filter(|msg| msg.role != "system" && msg.role != "developer")
+        .map(|msg| -> Result<Value, String> {
             let role = match msg.role.as_str() {
                 "assistant" => "model",
                 "tool" => "user", // OpenAI 'tool' role maps to user side in Gemini function response
@@ -28,10 +110,20 @@ pub fn transform_openai_request(request: &OpenAIRequest, project_id: &str, mappe
             };
 
             let mut parts = Vec::new();
+
+            // Gemini 没有发言者字段，用户消息的 name 以前缀文本保留 (多人对话中区分发言者)
+            if let Some(name) = msg.name.as_deref().filter(|n| msg.role == "user" && !n.is_empty()) {
+                parts.push(json!({"text": format!("[{}]", name)}));
+            }
             
-            // Handle text content
-            if let Some(content) = &msg.content {
-                parts.push(json!({"text": content}));
+            // Handle content (tool 消息的内容作为函数结果)
+            if let Some(content) = msg.content.as_ref().filter(|_| msg.role != "tool") {
+                parts.extend(content_parts(content)?);
+            }
+
+            // 助手拒绝回答的说明保留为文本
+            if let Some(refusal) = &msg.refusal {
+                parts.push(json!({"text": refusal}));
             }
 
             // Handle tool calls (assistant message)
@@ -50,22 +142,25 @@ pub fn transform_openai_request(request: &OpenAIRequest, project_id: &str, mappe
             // Handle tool response
             if msg.role == "tool" {
                 if let (Some(id), Some(content)) = (&msg.tool_call_id, &msg.content) {
+                    let name = msg.name.as_deref()
+                        .or_else(|| call_names.get(id.as_str()).copied())
+                        .unwrap_or("unknown");
                     parts.push(json!({
                         "functionResponse": {
-                           "name": "unknown", // Need to find name if possible, or just use id
+                           "name": name,
                            "id": id,
-                           "response": { "result": content }
+                           "response": { "result": content.text() }
                         }
                     }));
                 }
             }
 
-            json!({
+            Ok(json!({
                 "role": role,
                 "parts": parts
-            })
+            }))
         })
-        .collect();
+        .collect::<Result<_, _>>()?;
 
     // 3. 构建请求体
     let mut gen_config = json!({
@@ -162,14 +257,7 @@ pub fn transform_openai_request(request: &OpenAIRequest, project_id: &str, mappe
          }
     }
 
-    json!({
-        "project": project_id,
-        "requestId": format!("openai-{}", uuid::Uuid::new_v4()),
-        "request": inner_request,
-        "model": config.final_model,
-        "userAgent": "antigravity", // Changed from "antigravity-openai" to match Claude
-        "requestType": config.request_type
-    })
+    Ok(crate::proxy::common::fingerprint::envelope(project_id, inner_request, &config.final_model, &config.request_type))
 }
 
 #[cfg(test)]
@@ -182,9 +270,11 @@ mod tests {
             model: "gpt-4".to_string(),
             messages: vec![OpenAIMessage {
                 role: "user".to_string(),
-                content: Some("Hello".to_string()),
+                content: Some(OpenAIContent::String("Hello".to_string())),
                 tool_calls: None,
                 tool_call_id: None,
+                name: None,
+                refusal: None,
             }],
             stream: false,
             max_tokens: None,
@@ -194,11 +284,13 @@ mod tests {
             response_format: None,
             tools: None,
             tool_choice: None,
+            user: None,
         };
 
-        let result = transform_openai_request(&req, "test-project", "gemini-1.5-pro-latest");
+        let result = transform_openai_request(&req, "test-project", "gemini-1.5-pro-latest").unwrap();
         assert_eq!(result["project"], "test-project");
-        assert!(result["requestId"].as_str().unwrap().starts_with("openai-"));
+        assert!(result["requestId"].as_str().unwrap().starts_with("agent-"));
+        assert_eq!(result["userAgent"], "antigravity");
         
         // Ensure contents are present
         let contents = result["request"]["contents"].as_array().unwrap();
@@ -213,21 +305,27 @@ mod tests {
             messages: vec![
                 OpenAIMessage {
                     role: "system".to_string(),
-                    content: Some("System Prompt 1".to_string()),
+                    content: Some(OpenAIContent::String("System Prompt 1".to_string())),
                     tool_calls: None,
                     tool_call_id: None,
+                    name: None,
+                    refusal: None,
                 },
                 OpenAIMessage {
                     role: "system".to_string(),
-                    content: Some("System Prompt 2".to_string()),
+                    content: Some(OpenAIContent::String("System Prompt 2".to_string())),
                     tool_calls: None,
                     tool_call_id: None,
+                    name: None,
+                    refusal: None,
                 },
                 OpenAIMessage {
                     role: "user".to_string(),
-                    content: Some("User Message".to_string()),
+                    content: Some(OpenAIContent::String("User Message".to_string())),
                     tool_calls: None,
                     tool_call_id: None,
+                    name: None,
+                    refusal: None,
                 }
             ],
             stream: false,
@@ -238,9 +336,10 @@ mod tests {
             response_format: None,
             tools: None,
             tool_choice: None,
+            user: None,
         };
 
-        let result = transform_openai_request(&req, "test-project", "gemini-1.5-pro-latest");
+        let result = transform_openai_request(&req, "test-project", "gemini-1.5-pro-latest").unwrap();
         let inner_request = &result["request"];
 
         // 1. Verify systemInstruction is present
@@ -258,4 +357,48 @@ mod tests {
         assert_eq!(contents[0]["role"], "user");
         assert_eq!(contents[0]["parts"][0]["text"], "User Message");
     }
+
+    #[test]
+    fn test_transform_openai_request_content_parts() {
+        let req: OpenAIRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [
+                { "role": "user", "name": "alice", "content": [
+                    { "type": "text", "text": "What is in these?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=", "detail": "high" } },
+                    { "type": "input_audio", "input_audio": { "data": "SUQz", "format": "mp3" } },
+                    { "type": "file", "file": { "filename": "report.pdf", "file_data": "JVBERi0=" } }
+                ]},
+                { "role": "assistant", "content": null, "refusal": "I can't help with that.",
+                  "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "lookup", "arguments": "{}" } }] },
+                { "role": "tool", "tool_call_id": "call_1", "content": [{ "type": "text", "text": "42" }] }
+            ]
+        }))
+        .unwrap();
+
+        let result = transform_openai_request(&req, "test-project", "gemini-2.5-pro").unwrap();
+        let contents = result["request"]["contents"].as_array().unwrap();
+        let user_parts = contents[0]["parts"].as_array().unwrap();
+        assert_eq!(user_parts.len(), 5);
+        assert_eq!(user_parts[0]["text"], "[alice]");
+        assert_eq!(user_parts[2]["inlineData"], json!({ "mimeType": "image/png", "data": "iVBORw0KGgo=" }));
+        assert_eq!(user_parts[3]["inlineData"]["mimeType"], "audio/mpeg");
+        assert_eq!(user_parts[4]["inlineData"], json!({ "mimeType": "application/pdf", "data": "JVBERi0=" }));
+
+        assert_eq!(contents[1]["parts"][0]["text"], "I can't help with that.");
+        let tool_parts = contents[2]["parts"].as_array().unwrap();
+        assert_eq!(tool_parts.len(), 1);
+        assert_eq!(tool_parts[0]["functionResponse"]["name"], "lookup");
+        assert_eq!(tool_parts[0]["functionResponse"]["response"]["result"], "42");
+
+        // 未经抓取的远程链接不会原样转发给上游
+        let remote: OpenAIRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [{ "role": "user", "content": [
+                { "type": "image_url", "image_url": { "url": "http://169.254.169.254/latest/meta-data" } }
+            ]}]
+        }))
+        .unwrap();
+        assert!(transform_openai_request(&remote, "test-project", "gemini-2.5-pro").is_err());
+    }
 }
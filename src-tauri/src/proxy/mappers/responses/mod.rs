@@ -0,0 +1,12 @@
+// Responses mapper 模块
+// 负责 OpenAI Responses API ↔ Gemini 协议转换
+
+pub mod models;
+pub mod request;
+pub mod response;
+pub mod streaming;
+
+pub use models::*;
+pub use request::{convert_input, transform_responses_request, ResponsesInput};
+pub use response::ResponseBuilder;
+pub use streaming::create_responses_sse_stream;
@@ -0,0 +1,331 @@
+// Responses → Gemini 请求转换
+use super::models::*;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// 转换后的对话：历史 contents 加本轮输入，以及输入项中的 system/developer 消息
+#[derive(Debug, Clone)]
+pub struct ResponsesInput {
+    pub contents: Vec<Value>,
+    pub system: Vec<String>,
+    /// 本轮输入在 contents 中的起始位置
+    turn_start: usize,
+}
+
+impl ResponsesInput {
+    /// 本轮新增的 contents (不含历史)
+    pub fn turn(&self) -> &[Value] {
+        &self.contents[self.turn_start..]
+    }
+}
+
+/// 将 `input` 转换为 Gemini contents，追加在 `history` (previous_response_id 对应的对话) 之后
+pub fn convert_input(request: &ResponsesRequest, history: Vec<Value>) -> Result<ResponsesInput, String> {
+    let mut call_names = collect_call_names(&history);
+    let mut contents = Vec::new();
+    let mut system = Vec::new();
+
+    let items = match &request.input {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::String(text)) => vec![json!({ "type": "message", "role": "user", "content": text })],
+        Some(Value::Array(items)) => items.clone(),
+        Some(_) => return Err("input must be a string or an array of items".to_string()),
+    };
+
+    for item in &items {
+        // 简写形式的消息没有 type 字段
+        match item.get("type").and_then(|t| t.as_str()).unwrap_or("message") {
+            "message" => {
+                let parts = message_parts(item.get("content"))?;
+                match item.get("role").and_then(|r| r.as_str()).unwrap_or("user") {
+                    "system" | "developer" => system.extend(
+                        parts.iter().filter_map(|p| p.get("text").and_then(|t| t.as_str()).map(str::to_string)),
+                    ),
+                    "assistant" => push_parts(&mut contents, "model", parts),
+                    _ => push_parts(&mut contents, "user", parts),
+                }
+            }
+            "function_call" => {
+                let call_id = str_field(item, "call_id")?;
+                let name = str_field(item, "name")?;
+                let arguments = item.get("arguments").and_then(|a| a.as_str()).unwrap_or("{}");
+                call_names.insert(call_id.to_string(), name.to_string());
+                push_parts(&mut contents, "model", vec![json!({
+                    "functionCall": {
+                        "name": name,
+                        "args": serde_json::from_str::<Value>(arguments).unwrap_or(json!({})),
+                        "id": call_id
+                    }
+                })]);
+            }
+            "function_call_output" => {
+                let call_id = str_field(item, "call_id")?;
+                let output = match item.get("output") {
+                    Some(Value::String(s)) => s.clone(),
+                    Some(v) => v.to_string(),
+                    None => String::new(),
+                };
+                let name = call_names.get(call_id).map(String::as_str).unwrap_or("unknown");
+                push_parts(&mut contents, "user", vec![json!({
+                    "functionResponse": {
+                        "name": name,
+                        "id": call_id,
+                        "response": { "result": output }
+                    }
+                })]);
+            }
+            "reasoning" => {
+                // 只有带签名 (encrypted_content) 的思考内容才能回传给上游
+                if let Some(signature) = item.get("encrypted_content").and_then(|s| s.as_str()) {
+                    let text = item
+                        .get("summary")
+                        .and_then(|s| s.as_array())
+                        .map(|s| s.iter().filter_map(|p| p.get("text").and_then(|t| t.as_str())).collect::<Vec<_>>().join("\n"))
+                        .unwrap_or_default();
+                    push_parts(&mut contents, "model", vec![json!({ "text": text, "thought": true, "thoughtSignature": signature })]);
+                }
+            }
+            other => tracing::warn!("[Responses] 忽略不支持的输入项类型: {}", other),
+        }
+    }
+
+    if contents.is_empty() && history.is_empty() {
+        return Err("input must not be empty".to_string());
+    }
+    // 本轮输入不与历史合并，保存时只需记录本轮新增部分
+    let turn_start = history.len();
+    Ok(ResponsesInput { contents: [history, contents].concat(), system, turn_start })
+}
+
+pub fn transform_responses_request(
+    request: &ResponsesRequest,
+    input: &ResponsesInput,
+    project_id: &str,
+    mapped_model: &str,
+) -> Value {
+    let config = crate::proxy::mappers::common_utils::resolve_request_config(&request.model, mapped_model);
+
+    // 1. 生成参数
+    let mut gen_config = json!({
+        "maxOutputTokens": request.max_output_tokens.unwrap_or(64000),
+        "temperature": request.temperature.unwrap_or(1.0),
+        "topP": request.top_p.unwrap_or(1.0),
+    });
+
+    let format_type = request.text.as_ref().and_then(|t| t.pointer("/format/type")).and_then(|t| t.as_str());
+    if matches!(format_type, Some("json_object") | Some("json_schema")) {
+        gen_config["responseMimeType"] = json!("application/json");
+    }
+
+    if let Some(effort) = request.reasoning.as_ref().and_then(|r| r.effort.as_deref()) {
+        let budget = match effort {
+            "minimal" => 512,
+            "low" => 1024,
+            "high" => 24576,
+            _ => 8192,
+        };
+        gen_config["thinkingConfig"] = json!({ "includeThoughts": true, "thinkingBudget": budget });
+    }
+
+    let mut inner_request = json!({
+        "contents": input.contents,
+        "generationConfig": gen_config,
+        "safetySettings": [
+            { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_HATE_SPEECH", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_SEXUALLY_EXPLICIT", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "threshold": "OFF" },
+            { "category": "HARM_CATEGORY_CIVIC_INTEGRITY", "threshold": "OFF" },
+        ]
+    });
+
+    // 2. 函数工具 (Responses 格式为扁平的 { type, name, description, parameters })
+    let function_declarations: Vec<Value> = request
+        .tools
+        .iter()
+        .flatten()
+        .filter(|tool| tool.get("type").and_then(|t| t.as_str()) == Some("function"))
+        .map(|tool| {
+            let mut declaration = json!({
+                "name": tool.get("name").cloned().unwrap_or(json!("unknown")),
+                "description": tool.get("description").cloned().unwrap_or(json!("")),
+            });
+            if let Some(mut params) = tool.get("parameters").cloned() {
+                crate::proxy::common::json_schema::clean_json_schema(&mut params);
+                declaration["parameters"] = params;
+            }
+            declaration
+        })
+        .collect();
+    if !function_declarations.is_empty() {
+        inner_request["tools"] = json!([{ "functionDeclarations": function_declarations }]);
+        if let Some(tool_config) = request.tool_choice.as_ref().and_then(tool_config) {
+            inner_request["toolConfig"] = tool_config;
+        }
+    }
+
+    // 3. instructions 与输入中的 system/developer 消息
+    let system: Vec<&str> = request
+        .instructions
+        .iter()
+        .chain(input.system.iter())
+        .map(String::as_str)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if !system.is_empty() {
+        inner_request["systemInstruction"] = json!({ "parts": [{ "text": system.join("\n\n") }] });
+    }
+
+    if config.inject_google_search {
+        crate::proxy::mappers::common_utils::inject_google_search_tool(&mut inner_request);
+    }
+
+    // requestId / userAgent 发送时按账号指纹替换
+    crate::proxy::common::fingerprint::envelope(project_id, inner_request, &config.final_model, &config.request_type)
+}
+
+/// tool_choice → functionCallingConfig
+fn tool_config(choice: &Value) -> Option<Value> {
+    let config = match choice {
+        Value::String(mode) => match mode.as_str() {
+            "none" => json!({ "mode": "NONE" }),
+            "required" => json!({ "mode": "ANY" }),
+            _ => json!({ "mode": "AUTO" }),
+        },
+        Value::Object(_) => {
+            let name = choice.get("name").and_then(|n| n.as_str())?;
+            json!({ "mode": "ANY", "allowedFunctionNames": [name] })
+        }
+        _ => return None,
+    };
+    Some(json!({ "functionCallingConfig": config }))
+}
+
+fn message_parts(content: Option<&Value>) -> Result<Vec<Value>, String> {
+    match content {
+        Some(Value::String(text)) => Ok(vec![json!({ "text": text })]),
+        Some(Value::Array(parts)) => parts.iter().filter_map(content_part).collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn content_part(part: &Value) -> Option<Result<Value, String>> {
+    let part_type = part.get("type").and_then(|t| t.as_str()).unwrap_or("");
+    match part_type {
+        "input_text" | "output_text" | "text" => {
+            part.get("text").and_then(|t| t.as_str()).map(|text| Ok(json!({ "text": text })))
+        }
+        "refusal" => part.get("refusal").and_then(|t| t.as_str()).map(|text| Ok(json!({ "text": text }))),
+        "input_image" | "input_file" => {
+            let url = part.get("image_url").or_else(|| part.get("file_data")).and_then(|u| u.as_str())?;
+            Some(
+                parse_data_url(url)
+                    .map(|(mime_type, data)| json!({ "inlineData": { "mimeType": mime_type, "data": data } }))
+                    .ok_or_else(|| format!("{} only supports base64 data URLs", part_type)),
+            )
+        }
+        other => {
+            tracing::warn!("[Responses] 忽略不支持的内容类型: {}", other);
+            None
+        }
+    }
+}
+
+/// 解析 `data:<mime>;base64,<data>`
+fn parse_data_url(url: &str) -> Option<(&str, &str)> {
+    let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+    let mime_type = header.strip_suffix(";base64")?;
+    Some((mime_type, data))
+}
+
+fn str_field<'a>(item: &'a Value, field: &str) -> Result<&'a str, String> {
+    item.get(field)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("input item is missing `{}`", field))
+}
+
+/// 追加 parts，与上一条同角色的 content 合并 (并行工具调用及其结果须在同一轮中)
+fn push_parts(contents: &mut Vec<Value>, role: &str, parts: Vec<Value>) {
+    if parts.is_empty() {
+        return;
+    }
+    if let Some(last) = contents.last_mut().filter(|c| c.get("role").and_then(|r| r.as_str()) == Some(role)) {
+        if let Some(existing) = last.get_mut("parts").and_then(|p| p.as_array_mut()) {
+            existing.extend(parts);
+            return;
+        }
+    }
+    contents.push(json!({ "role": role, "parts": parts }));
+}
+
+/// 历史中 functionCall 的 id → 函数名
+fn collect_call_names(history: &[Value]) -> HashMap<String, String> {
+    history
+        .iter()
+        .filter_map(|c| c.get("parts").and_then(|p| p.as_array()))
+        .flatten()
+        .filter_map(|p| p.get("functionCall"))
+        .filter_map(|fc| Some((fc.get("id")?.as_str()?.to_string(), fc.get("name")?.as_str()?.to_string())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(input: Value) -> ResponsesRequest {
+        serde_json::from_value(json!({ "model": "gpt-4o", "input": input })).unwrap()
+    }
+
+    #[test]
+    fn test_convert_input_items() {
+        let history = vec![
+            json!({ "role": "user", "parts": [{ "text": "List files" }] }),
+            json!({ "role": "model", "parts": [{ "functionCall": { "name": "ls", "args": {}, "id": "call_1" }, "thoughtSignature": "sig" }] }),
+        ];
+        let req = request(json!([
+            { "type": "function_call_output", "call_id": "call_1", "output": "a.rs" },
+            { "role": "developer", "content": "Be brief." },
+            { "type": "message", "role": "user", "content": [
+                { "type": "input_text", "text": "Now read it" },
+                { "type": "input_image", "image_url": "data:image/png;base64,AAAA" }
+            ]}
+        ]));
+
+        let input = convert_input(&req, history).unwrap();
+        assert_eq!(input.system, vec!["Be brief."]);
+        assert_eq!(input.turn().len(), 1);
+        // 工具结果与后续用户消息合并为同一轮
+        assert_eq!(input.contents.len(), 3);
+        let user = &input.contents[2]["parts"];
+        assert_eq!(user[0]["functionResponse"]["name"], "ls");
+        assert_eq!(user[1]["text"], "Now read it");
+        assert_eq!(user[2]["inlineData"]["mimeType"], "image/png");
+
+        let remote = request(json!([{ "role": "user", "content": [{ "type": "input_image", "image_url": "https://example.com/a.png" }] }]));
+        assert!(convert_input(&remote, Vec::new()).is_err());
+        assert!(convert_input(&request(json!([])), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_transform_responses_request() {
+        let req: ResponsesRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "instructions": "You are terse.",
+            "input": "Hi",
+            "reasoning": { "effort": "low" },
+            "tool_choice": { "type": "function", "name": "ls" },
+            "tools": [{ "type": "function", "name": "ls", "description": "List", "parameters": { "type": "object", "properties": {} } }]
+        }))
+        .unwrap();
+        let input = convert_input(&req, Vec::new()).unwrap();
+        let body = transform_responses_request(&req, &input, "p", "gemini-2.5-pro");
+        let inner = &body["request"];
+        assert_eq!(body["model"], "gemini-2.5-pro");
+        assert_eq!(inner["contents"][0]["parts"][0]["text"], "Hi");
+        assert_eq!(inner["systemInstruction"]["parts"][0]["text"], "You are terse.");
+        assert_eq!(inner["tools"][0]["functionDeclarations"][0]["name"], "ls");
+        assert_eq!(inner["toolConfig"]["functionCallingConfig"]["allowedFunctionNames"][0], "ls");
+        assert_eq!(inner["generationConfig"]["thinkingConfig"]["thinkingBudget"], 1024);
+    }
+}
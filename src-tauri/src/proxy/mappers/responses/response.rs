@@ -0,0 +1,421 @@
+// Gemini → Responses 响应转换
+// 流式与非流式共用 ResponseBuilder：逐块接收 Gemini 响应，生成输出项与语义事件，并保留原始 parts 供对话接续
+use bytes::Bytes;
+use serde_json::{json, Value};
+
+use crate::proxy::common::usage::TokenUsage;
+
+#[derive(Debug, Clone)]
+enum OutputItem {
+    Reasoning { id: String, text: String, signature: Option<String> },
+    Message { id: String, text: String },
+    FunctionCall { id: String, call_id: String, name: String, arguments: String },
+}
+
+impl OutputItem {
+    fn to_json(&self, status: &str) -> Value {
+        match self {
+            OutputItem::Reasoning { id, text, signature } => {
+                let mut item = json!({
+                    "id": id,
+                    "type": "reasoning",
+                    "summary": if text.is_empty() { json!([]) } else { json!([{ "type": "summary_text", "text": text }]) },
+                });
+                if let Some(signature) = signature {
+                    item["encrypted_content"] = json!(signature);
+                }
+                item
+            }
+            OutputItem::Message { id, text } => json!({
+                "id": id,
+                "type": "message",
+                "role": "assistant",
+                "status": status,
+                "content": if status == "in_progress" { json!([]) } else { json!([output_text(text)]) },
+            }),
+            OutputItem::FunctionCall { id, call_id, name, arguments } => json!({
+                "id": id,
+                "type": "function_call",
+                "call_id": call_id,
+                "name": name,
+                "arguments": if status == "in_progress" { "" } else { arguments.as_str() },
+                "status": status,
+            }),
+        }
+    }
+}
+
+fn output_text(text: &str) -> Value {
+    json!({ "type": "output_text", "text": text, "annotations": [] })
+}
+
+fn item_id(prefix: &str) -> String {
+    format!("{}_{}", prefix, uuid::Uuid::new_v4().simple())
+}
+
+/// 构建 Responses 响应对象与流式事件
+pub struct ResponseBuilder {
+    id: String,
+    model: String,
+    created_at: i64,
+    previous_response_id: Option<String>,
+    items: Vec<OutputItem>,
+    /// 最后一个输出项是否仍在接收增量
+    open: bool,
+    /// 模型输出的原始 parts (合并相邻文本)，用于保存对话
+    model_parts: Vec<Value>,
+    usage: Option<TokenUsage>,
+    finish_reason: Option<String>,
+    sequence_number: u64,
+}
+
+impl ResponseBuilder {
+    pub fn new(model: &str, previous_response_id: Option<&str>) -> Self {
+        Self {
+            id: item_id("resp"),
+            model: model.to_string(),
+            created_at: chrono::Utc::now().timestamp(),
+            previous_response_id: previous_response_id.map(str::to_string),
+            items: Vec::new(),
+            open: false,
+            model_parts: Vec::new(),
+            usage: None,
+            finish_reason: None,
+            sequence_number: 0,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 模型本轮输出 (Gemini content)，追加到对话后保存
+    pub fn model_content(&self) -> Value {
+        json!({ "role": "model", "parts": self.model_parts })
+    }
+
+    /// 流开始事件
+    pub fn start_events(&mut self) -> Vec<Bytes> {
+        let response = self.response_json("in_progress");
+        vec![
+            self.event("response.created", json!({ "response": response })),
+            self.event("response.in_progress", json!({ "response": response })),
+        ]
+    }
+
+    /// 处理一个 Gemini 响应 (或 SSE 数据块)，返回对应的流式事件
+    pub fn push_chunk(&mut self, chunk: &Value) -> Vec<Bytes> {
+        let raw = chunk.get("response").unwrap_or(chunk);
+        let mut events = Vec::new();
+
+        if let Some(usage) = TokenUsage::from_response(raw) {
+            self.usage = Some(usage);
+        }
+        let candidate = raw.get("candidates").and_then(|c| c.get(0));
+        if let Some(reason) = candidate.and_then(|c| c.get("finishReason")).and_then(|f| f.as_str()) {
+            self.finish_reason = Some(reason.to_string());
+        }
+
+        let parts = candidate
+            .and_then(|c| c.pointer("/content/parts"))
+            .and_then(|p| p.as_array())
+            .cloned()
+            .unwrap_or_default();
+        for mut part in parts {
+            // 缺少 id 的函数调用补充 call_id，保存的对话与输出项保持一致
+            if let Some(fc) = part.get_mut("functionCall").and_then(|fc| fc.as_object_mut()) {
+                fc.entry("id").or_insert_with(|| json!(item_id("call")));
+            }
+            self.store_part(&part);
+            let signature = part.get("thoughtSignature").and_then(|s| s.as_str());
+
+            if let Some(fc) = part.get("functionCall") {
+                events.extend(self.function_call(fc));
+            } else if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+                if part.get("thought").and_then(|t| t.as_bool()).unwrap_or(false) {
+                    events.extend(self.reasoning_delta(text, signature));
+                } else if !text.is_empty() {
+                    events.extend(self.text_delta(text));
+                }
+            } else if let Some(img) = part.get("inlineData") {
+                // 图片以 Markdown data URI 形式输出 (与 Chat Completions 一致)
+                let mime_type = img.get("mimeType").and_then(|v| v.as_str()).unwrap_or("image/png");
+                let data = img.get("data").and_then(|v| v.as_str()).unwrap_or("");
+                if !data.is_empty() {
+                    events.extend(self.text_delta(&format!("![image](data:{};base64,{})", mime_type, data)));
+                }
+            }
+        }
+        events
+    }
+
+    /// 流结束事件 (关闭未完成的输出项并发送 response.completed / response.incomplete)
+    pub fn finish_events(&mut self) -> Vec<Bytes> {
+        let mut events = self.close_item();
+        let response = self.response();
+        let event_type = if response["status"] == "incomplete" { "response.incomplete" } else { "response.completed" };
+        events.push(self.event(event_type, json!({ "response": response })));
+        events
+    }
+
+    /// 完整的 Responses 对象
+    pub fn response(&self) -> Value {
+        let status = if self.finish_reason.as_deref() == Some("MAX_TOKENS") { "incomplete" } else { "completed" };
+        self.response_json(status)
+    }
+
+    fn response_json(&self, status: &str) -> Value {
+        let output: Vec<Value> = if status == "in_progress" {
+            Vec::new()
+        } else {
+            self.items.iter().map(|item| item.to_json("completed")).collect()
+        };
+        let usage = self.usage.map(|u| {
+            json!({
+                "input_tokens": u.input_tokens,
+                "input_tokens_details": { "cached_tokens": 0 },
+                "output_tokens": u.output_tokens + u.thinking_tokens,
+                "output_tokens_details": { "reasoning_tokens": u.thinking_tokens },
+                "total_tokens": u.total(),
+            })
+        });
+
+        json!({
+            "id": self.id,
+            "object": "response",
+            "created_at": self.created_at,
+            "status": status,
+            "model": self.model,
+            "output": output,
+            "previous_response_id": self.previous_response_id,
+            "incomplete_details": if status == "incomplete" { json!({ "reason": "max_output_tokens" }) } else { Value::Null },
+            "parallel_tool_calls": true,
+            "usage": usage,
+            "error": null,
+        })
+    }
+
+    fn event(&mut self, event_type: &str, mut data: Value) -> Bytes {
+        data["type"] = json!(event_type);
+        data["sequence_number"] = json!(self.sequence_number);
+        self.sequence_number += 1;
+        Bytes::from(format!("event: {}\ndata: {}\n\n", event_type, serde_json::to_string(&data).unwrap_or_default()))
+    }
+
+    fn open_item(&mut self, item: OutputItem) -> Vec<Bytes> {
+        let mut events = self.close_item();
+        let output_index = self.items.len();
+        events.push(self.event("response.output_item.added", json!({ "output_index": output_index, "item": item.to_json("in_progress") })));
+        match &item {
+            OutputItem::Message { id, .. } => {
+                let id = id.clone();
+                events.push(self.event(
+                    "response.content_part.added",
+                    json!({ "item_id": id, "output_index": output_index, "content_index": 0, "part": output_text("") }),
+                ));
+            }
+            OutputItem::Reasoning { id, .. } => {
+                let id = id.clone();
+                events.push(self.event(
+                    "response.reasoning_summary_part.added",
+                    json!({ "item_id": id, "output_index": output_index, "summary_index": 0, "part": { "type": "summary_text", "text": "" } }),
+                ));
+            }
+            OutputItem::FunctionCall { .. } => {}
+        }
+        self.items.push(item);
+        self.open = true;
+        events
+    }
+
+    fn close_item(&mut self) -> Vec<Bytes> {
+        if !self.open {
+            return Vec::new();
+        }
+        self.open = false;
+        let output_index = self.items.len() - 1;
+        let item = self.items[output_index].clone();
+        let mut events = Vec::new();
+        match &item {
+            OutputItem::Message { id, text } => {
+                events.push(self.event(
+                    "response.output_text.done",
+                    json!({ "item_id": id, "output_index": output_index, "content_index": 0, "text": text }),
+                ));
+                events.push(self.event(
+                    "response.content_part.done",
+                    json!({ "item_id": id, "output_index": output_index, "content_index": 0, "part": output_text(text) }),
+                ));
+            }
+            OutputItem::Reasoning { id, text, .. } => {
+                events.push(self.event(
+                    "response.reasoning_summary_text.done",
+                    json!({ "item_id": id, "output_index": output_index, "summary_index": 0, "text": text }),
+                ));
+                events.push(self.event(
+                    "response.reasoning_summary_part.done",
+                    json!({ "item_id": id, "output_index": output_index, "summary_index": 0, "part": { "type": "summary_text", "text": text } }),
+                ));
+            }
+            OutputItem::FunctionCall { id, arguments, .. } => {
+                events.push(self.event(
+                    "response.function_call_arguments.done",
+                    json!({ "item_id": id, "output_index": output_index, "arguments": arguments }),
+                ));
+            }
+        }
+        events.push(self.event("response.output_item.done", json!({ "output_index": output_index, "item": item.to_json("completed") })));
+        events
+    }
+
+    fn text_delta(&mut self, delta: &str) -> Vec<Bytes> {
+        let mut events = Vec::new();
+        if !(self.open && matches!(self.items.last(), Some(OutputItem::Message { .. }))) {
+            events.extend(self.open_item(OutputItem::Message { id: item_id("msg"), text: String::new() }));
+        }
+        let output_index = self.items.len() - 1;
+        let Some(OutputItem::Message { id, text }) = self.items.last_mut() else {
+            return events;
+        };
+        text.push_str(delta);
+        let id = id.clone();
+        events.push(self.event(
+            "response.output_text.delta",
+            json!({ "item_id": id, "output_index": output_index, "content_index": 0, "delta": delta }),
+        ));
+        events
+    }
+
+    fn reasoning_delta(&mut self, delta: &str, new_signature: Option<&str>) -> Vec<Bytes> {
+        let mut events = Vec::new();
+        if !(self.open && matches!(self.items.last(), Some(OutputItem::Reasoning { .. }))) {
+            events.extend(self.open_item(OutputItem::Reasoning { id: item_id("rs"), text: String::new(), signature: None }));
+        }
+        let output_index = self.items.len() - 1;
+        let Some(OutputItem::Reasoning { id, text, signature }) = self.items.last_mut() else {
+            return events;
+        };
+        text.push_str(delta);
+        if let Some(new_signature) = new_signature {
+            *signature = Some(new_signature.to_string());
+        }
+        let id = id.clone();
+        if !delta.is_empty() {
+            events.push(self.event(
+                "response.reasoning_summary_text.delta",
+                json!({ "item_id": id, "output_index": output_index, "summary_index": 0, "delta": delta }),
+            ));
+        }
+        events
+    }
+
+    /// Gemini 一次给出完整的函数调用，依次发送新增、参数增量与完成事件
+    fn function_call(&mut self, fc: &Value) -> Vec<Bytes> {
+        let name = fc.get("name").and_then(|v| v.as_str()).unwrap_or("unknown").to_string();
+        let arguments = fc.get("args").map(|v| v.to_string()).unwrap_or_else(|| "{}".to_string());
+        let call_id = fc
+            .get("id")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| item_id("call"));
+        let id = item_id("fc");
+
+        let mut events = self.open_item(OutputItem::FunctionCall { id: id.clone(), call_id, name, arguments: arguments.clone() });
+        let output_index = self.items.len() - 1;
+        events.push(self.event(
+            "response.function_call_arguments.delta",
+            json!({ "item_id": id, "output_index": output_index, "delta": arguments }),
+        ));
+        events.extend(self.close_item());
+        events
+    }
+
+    /// 记录原始 part，相邻的同类文本合并
+    fn store_part(&mut self, part: &Value) {
+        let thought = part.get("thought").and_then(|t| t.as_bool()).unwrap_or(false);
+        if let (Some(text), Some(last)) = (part.get("text").and_then(|t| t.as_str()), self.model_parts.last_mut()) {
+            let last_thought = last.get("thought").and_then(|t| t.as_bool()).unwrap_or(false);
+            if last_thought == thought && last.get("text").and_then(|t| t.as_str()).is_some() {
+                let merged = format!("{}{}", last["text"].as_str().unwrap_or(""), text);
+                last["text"] = json!(merged);
+                if let Some(signature) = part.get("thoughtSignature") {
+                    last["thoughtSignature"] = signature.clone();
+                }
+                return;
+            }
+        }
+        self.model_parts.push(part.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events_text(events: &[Bytes]) -> String {
+        events.iter().map(|b| String::from_utf8_lossy(b).to_string()).collect()
+    }
+
+    #[test]
+    fn test_build_response() {
+        let mut builder = ResponseBuilder::new("gpt-4o", None);
+        builder.push_chunk(&json!({
+            "response": {
+                "candidates": [{
+                    "content": { "parts": [
+                        { "text": "Thinking", "thought": true, "thoughtSignature": "sig" },
+                        { "text": "Hello" },
+                        { "functionCall": { "name": "ls", "args": { "path": "." } } }
+                    ]},
+                    "finishReason": "STOP"
+                }],
+                "usageMetadata": { "promptTokenCount": 5, "candidatesTokenCount": 3, "thoughtsTokenCount": 2 }
+            }
+        }));
+
+        let response = builder.response();
+        assert_eq!(response["status"], "completed");
+        assert_eq!(response["output"][0]["type"], "reasoning");
+        assert_eq!(response["output"][0]["encrypted_content"], "sig");
+        assert_eq!(response["output"][1]["content"][0]["text"], "Hello");
+        assert_eq!(response["output"][2]["name"], "ls");
+        assert_eq!(response["output"][2]["arguments"], r#"{"path":"."}"#);
+        assert_eq!(response["usage"]["output_tokens"], 5);
+        assert_eq!(response["usage"]["output_tokens_details"]["reasoning_tokens"], 2);
+
+        // 保存的函数调用带有与输出项一致的 call_id
+        let stored = builder.model_content();
+        assert_eq!(stored["parts"][2]["functionCall"]["id"], response["output"][2]["call_id"]);
+    }
+
+    #[test]
+    fn test_stream_events() {
+        let mut builder = ResponseBuilder::new("gpt-4o", Some("resp_prev"));
+        let mut events = builder.start_events();
+        events.extend(builder.push_chunk(&json!({ "candidates": [{ "content": { "parts": [{ "text": "Hel" }] } }] })));
+        events.extend(builder.push_chunk(&json!({ "candidates": [{ "content": { "parts": [{ "text": "lo" }] } }] })));
+        events.extend(builder.push_chunk(&json!({ "candidates": [{ "content": { "parts": [{ "functionCall": { "name": "ls", "args": {}, "id": "call_9" } }] }, "finishReason": "MAX_TOKENS" }] })));
+        events.extend(builder.finish_events());
+
+        let text = events_text(&events);
+        let order = [
+            "response.created",
+            "response.output_item.added",
+            "response.output_text.delta",
+            "response.output_text.done",
+            "response.function_call_arguments.delta",
+            "response.function_call_arguments.done",
+            "response.incomplete",
+        ];
+        let mut position = 0;
+        for event in order {
+            position += text[position..].find(&format!("event: {}\n", event)).unwrap_or_else(|| panic!("missing {}", event));
+        }
+        assert!(text.contains(r#""text":"Hello""#));
+        assert!(text.contains(r#""previous_response_id":"resp_prev""#));
+
+        // 相邻文本块合并保存
+        assert_eq!(builder.model_content()["parts"][0]["text"], "Hello");
+        assert_eq!(builder.model_content()["parts"][1]["functionCall"]["id"], "call_9");
+    }
+}
@@ -0,0 +1,54 @@
+// Responses 流式转换
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::pin::Pin;
+
+use super::response::ResponseBuilder;
+
+/// 将 Gemini SSE 流转换为 Responses 语义事件流
+/// 流正常结束后以最终状态调用 `on_complete` (用于保存对话)
+pub fn create_responses_sse_stream<F>(
+    mut gemini_stream: Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send>>,
+    mut builder: ResponseBuilder,
+    on_complete: F,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, String>> + Send>>
+where
+    F: FnOnce(&ResponseBuilder) + Send + 'static,
+{
+    let stream = async_stream::stream! {
+        let mut buffer = BytesMut::new();
+        for event in builder.start_events() {
+            yield Ok::<Bytes, String>(event);
+        }
+
+        while let Some(item) = gemini_stream.next().await {
+            match item {
+                Ok(bytes) => {
+                    buffer.extend_from_slice(&bytes);
+                    while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                        let line_raw = buffer.split_to(pos + 1);
+                        let Ok(line) = std::str::from_utf8(&line_raw) else { continue };
+                        let Some(data) = line.trim().strip_prefix("data:") else { continue };
+                        let Ok(chunk) = serde_json::from_str::<Value>(data.trim()) else { continue };
+                        for event in builder.push_chunk(&chunk) {
+                            yield Ok(event);
+                        }
+                    }
+                }
+                Err(e) => {
+                    let error = json!({ "type": "error", "code": "upstream_error", "message": format!("Upstream error: {}", e), "param": null });
+                    yield Ok(Bytes::from(format!("event: error\ndata: {}\n\n", error)));
+                    return;
+                }
+            }
+        }
+
+        for event in builder.finish_events() {
+            yield Ok(event);
+        }
+        on_complete(&builder);
+    };
+
+    Box::pin(stream)
+}
@@ -0,0 +1,123 @@
+// Prometheus 指标导出
+// 文本格式 (text exposition format 0.0.4)，由 GET /metrics 提供
+use std::fmt::Write;
+
+use crate::proxy::stats::StatsCollector;
+use crate::proxy::TokenManager;
+
+/// 指标名前缀
+pub const METRIC_PREFIX: &str = "antigravity_proxy";
+
+/// Prometheus 文本格式构建器
+#[derive(Default)]
+pub struct PrometheusText {
+    out: String,
+}
+
+impl PrometheusText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 声明指标 (HELP / TYPE)，`name` 不含前缀
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {}_{} {}", METRIC_PREFIX, name, help);
+        let _ = writeln!(self.out, "# TYPE {}_{} {}", METRIC_PREFIX, name, kind);
+    }
+
+    /// 写入一个样本，`name` 不含前缀 (直方图需带 `_bucket` 等后缀)
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let _ = write!(self.out, "{}_{}", METRIC_PREFIX, name);
+        if !labels.is_empty() {
+            let rendered: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+                .collect();
+            let _ = write!(self.out, "{{{}}}", rendered.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// 渲染全部指标：请求统计 + 账号池状态
+pub fn render(stats: &StatsCollector, token_manager: &TokenManager) -> String {
+    let mut text = PrometheusText::new();
+    stats.write_metrics(&mut text);
+
+    text.family("accounts", "gauge", "Number of accounts in the proxy pool.");
+    text.sample("accounts", &[], token_manager.len() as f64);
+
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let health = token_manager.health_snapshot();
+
+    text.family(
+        "account_cooldown_seconds",
+        "gauge",
+        "Remaining cooldown per account and quota group (\"*\" applies to all groups).",
+    );
+    for account in &health {
+        for cooldown in &account.cooldowns {
+            let remaining = (cooldown.until_ms - now_ms).max(0) as f64 / 1000.0;
+            text.sample(
+                "account_cooldown_seconds",
+                &[("account", &account.email), ("quota_group", &cooldown.quota_group)],
+                remaining,
+            );
+        }
+    }
+
+    text.family("account_circuit_open", "gauge", "1 if the account is disabled by the 401 circuit breaker.");
+    for account in &health {
+        text.sample(
+            "account_circuit_open",
+            &[("account", &account.email)],
+            if account.circuit_open { 1.0 } else { 0.0 },
+        );
+    }
+
+    text.family(
+        "account_quota_remaining_percent",
+        "gauge",
+        "Last known remaining quota per account and model (0-100).",
+    );
+    for (email, quota) in token_manager.quota_snapshot() {
+        for model in &quota.models {
+            text.sample(
+                "account_quota_remaining_percent",
+                &[("account", &email), ("model", &model.name)],
+                model.percentage as f64,
+            );
+        }
+    }
+
+    text.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_text_format() {
+        let mut text = PrometheusText::new();
+        text.family("requests_total", "counter", "Total requests.");
+        text.sample("requests_total", &[("protocol", "claude"), ("model", "a\"b")], 3.0);
+        text.sample("accounts", &[], 1.5);
+
+        assert_eq!(
+            text.finish(),
+            "# HELP antigravity_proxy_requests_total Total requests.\n\
+             # TYPE antigravity_proxy_requests_total counter\n\
+             antigravity_proxy_requests_total{protocol=\"claude\",model=\"a\\\"b\"} 3\n\
+             antigravity_proxy_accounts 1.5\n"
+        );
+    }
+}
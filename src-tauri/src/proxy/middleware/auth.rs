@@ -1,45 +1,291 @@
 // API Key 认证中间件
 use axum::{
-    extract::Request,
+    extract::{Request, State},
     http::{header, StatusCode},
     middleware::Next,
     response::Response,
 };
 
-/// API Key 认证中间件
-pub async fn auth_middleware(request: Request, next: Next) -> Result<Response, StatusCode> {
-    // Log the request method and URI
-    tracing::info!("Request: {} {}", request.method(), request.uri());
-    
-    // 从 header 中提取 API key
-    let api_key = request
-        .headers()
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::config::{ApiKeyConfig, ProxyConfig};
+use crate::proxy::server::AppState;
+
+/// 主密钥 (`ProxyConfig::api_key`) 在日志与统计中使用的名称
+const PRIMARY_KEY_NAME: &str = "default";
+
+/// 通过认证的 API Key 身份，写入 request extensions 供后续 handler 使用
+#[derive(Debug, Clone)]
+pub struct ApiKeyIdentity {
+    pub name: String,
+}
+
+/// 鉴权失败原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Disabled(String),
+    Expired(String),
+}
+
+impl AuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::Missing | AuthError::Invalid => StatusCode::UNAUTHORIZED,
+            AuthError::Disabled(_) | AuthError::Expired(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            AuthError::Missing => "Missing API key".to_string(),
+            AuthError::Invalid => "Invalid API key".to_string(),
+            AuthError::Disabled(name) => format!("API key '{}' is disabled", name),
+            AuthError::Expired(name) => format!("API key '{}' has expired", name),
+        }
+    }
+}
+
+/// API Key 注册表 (由 ProxyConfig 构建，支持热更新)
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeyRegistry {
+    keys: Vec<ApiKeyConfig>,
+}
+
+impl ApiKeyRegistry {
+    /// 校验具名密钥：名称是用量、限流与预算的身份，不能为空、重复或与主密钥名称相同
+    pub fn validate(config: &ProxyConfig) -> Result<(), String> {
+        let mut names = std::collections::HashSet::new();
+        for entry in &config.api_keys {
+            let name = entry.name.trim();
+            if name.is_empty() {
+                return Err("API key name must not be empty".to_string());
+            }
+            if name == PRIMARY_KEY_NAME {
+                return Err(format!("API key name '{}' is reserved for the primary key", PRIMARY_KEY_NAME));
+            }
+            if !names.insert(name) {
+                return Err(format!("Duplicate API key name '{}'", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// 未通过 `validate` 的具名密钥 (空名、保留名、重名) 被忽略，避免与其他密钥共用策略与用量
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        let mut keys = Vec::new();
+
+        if !config.api_key.is_empty() {
+            keys.push(ApiKeyConfig {
+                name: PRIMARY_KEY_NAME.to_string(),
+                key: config.api_key.clone(),
+                enabled: true,
+                expires_at: None,
+                allowed_models: Vec::new(),
+                requests_per_minute: None,
+                max_concurrent_streams: None,
+                daily_token_budget: None,
+            });
+        }
+
+        let mut names = std::collections::HashSet::from([PRIMARY_KEY_NAME.to_string()]);
+        for entry in config.api_keys.iter().filter(|k| !k.key.is_empty()) {
+            let name = entry.name.trim();
+            if name.is_empty() || !names.insert(name.to_string()) {
+                tracing::warn!("忽略名称无效或重复的 API Key: '{}'", entry.name);
+                continue;
+            }
+            keys.push(ApiKeyConfig { name: name.to_string(), ..entry.clone() });
+        }
+
+        Self { keys }
+    }
+
+    /// 按名称查找密钥配置
+    pub fn get(&self, name: &str) -> Option<&ApiKeyConfig> {
+        self.keys.iter().find(|k| k.name == name)
+    }
+
+    /// 未配置任何密钥时不做鉴权
+    pub fn is_enforced(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// 校验客户端提交的密钥
+    /// 对所有密钥逐一做常量时间比较，不因提前命中而短路
+    pub fn verify(&self, presented: Option<&str>, now: i64) -> Result<&ApiKeyConfig, AuthError> {
+        let presented = presented.filter(|k| !k.is_empty()).ok_or(AuthError::Missing)?;
+
+        let mut matched = None;
+        for entry in &self.keys {
+            if constant_time_eq(presented.as_bytes(), entry.key.as_bytes()) && matched.is_none() {
+                matched = Some(entry);
+            }
+        }
+
+        let entry = matched.ok_or(AuthError::Invalid)?;
+        if !entry.enabled {
+            return Err(AuthError::Disabled(entry.name.clone()));
+        }
+        if entry.expires_at.is_some_and(|ts| now >= ts) {
+            return Err(AuthError::Expired(entry.name.clone()));
+        }
+
+        Ok(entry)
+    }
+}
+
+/// 常量时间比较，耗时只与较长输入的长度相关
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let len = a.len().max(b.len());
+    let mut diff = 0u8;
+    for i in 0..len {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= x ^ y;
+    }
+    diff == 0 && a.len() == b.len()
+}
+
+/// 从请求中提取 API key
+/// 支持 `Authorization: Bearer`、`x-api-key` (Anthropic)、`x-goog-api-key` 与 `?key=` (Gemini)
+fn extract_api_key(request: &Request) -> Option<String> {
+    let headers = request.headers();
+
+    headers
         .get(header::AUTHORIZATION)
         .and_then(|h| h.to_str().ok())
         .and_then(|s| s.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|h| h.to_str().ok()))
+        .or_else(|| headers.get("x-goog-api-key").and_then(|h| h.to_str().ok()))
+        .map(|s| s.trim().to_string())
         .or_else(|| {
-            request
-                .headers()
-                .get("x-api-key")
-                .and_then(|h| h.to_str().ok())
-        });
+            request.uri().query().and_then(|q| {
+                url::form_urlencoded::parse(q.as_bytes())
+                    .find(|(k, _)| k == "key")
+                    .map(|(_, v)| v.into_owned())
+            })
+        })
+}
 
-    // TODO: 实际验证 API key
-    // 目前暂时允许所有请求通过
-    if api_key.is_some() || true {
-        Ok(next.run(request).await)
-    } else {
-        Err(StatusCode::UNAUTHORIZED)
+/// API Key 认证中间件
+pub async fn auth_middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // Log the request method and URI
+    tracing::info!("Request: {} {}", request.method(), request.uri().path());
+
+    // 健康检查不需要鉴权，/metrics 在 handler 中按 metrics_key 或客户端 API Key 鉴权
+    // 生成的文件以随机 id 访问，便于直接在浏览器中打开
+    if matches!(request.uri().path(), "/healthz" | "/metrics") || request.uri().path().starts_with("/files/") {
+        return next.run(request).await;
     }
+
+    let identity = {
+        let registry = state.api_keys.read().await;
+        if !registry.is_enforced() {
+            None
+        } else {
+            let presented = extract_api_key(&request);
+            match registry.verify(presented.as_deref(), chrono::Utc::now().timestamp()) {
+                Ok(entry) => Some(ApiKeyIdentity { name: entry.name.clone() }),
+                Err(e) => {
+                    let protocol = ClientProtocol::from_path(request.uri().path());
+                    tracing::warn!(
+                        "[Auth] 拒绝 {} 请求 {}: {}",
+                        protocol.as_str(),
+                        request.uri().path(),
+                        e.message()
+                    );
+                    return protocol.error_response(e.status(), &e.message());
+                }
+            }
+        }
+    };
+
+    if let Some(identity) = identity {
+        request.extensions_mut().insert(identity);
+    }
+
+    next.run(request).await
 }
 
 #[cfg(test)]
 mod tests {
-    // 移除未使用的 use super::*;
+    use super::*;
+
+    fn key(name: &str, key: &str, enabled: bool, expires_at: Option<i64>) -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: name.to_string(),
+            key: key.to_string(),
+            enabled,
+            expires_at,
+            allowed_models: Vec::new(),
+            requests_per_minute: None,
+            max_concurrent_streams: None,
+            daily_token_budget: None,
+        }
+    }
+
+    fn registry() -> ApiKeyRegistry {
+        let config = ProxyConfig {
+            api_key: "sk-primary".to_string(),
+            api_keys: vec![
+                key("alice", "sk-alice", true, None),
+                key("bob", "sk-bob", false, None),
+                key("carol", "sk-carol", true, Some(1_000)),
+            ],
+            ..Default::default()
+        };
+        ApiKeyRegistry::from_config(&config)
+    }
+
+    #[test]
+    fn test_verify_keys() {
+        let registry = registry();
+        assert!(registry.is_enforced());
+        assert_eq!(registry.verify(Some("sk-primary"), 0).unwrap().name, "default");
+        assert_eq!(registry.verify(Some("sk-alice"), 0).unwrap().name, "alice");
+        assert_eq!(registry.verify(None, 0).unwrap_err(), AuthError::Missing);
+        assert_eq!(registry.verify(Some("sk-alic"), 0).unwrap_err(), AuthError::Invalid);
+        assert_eq!(registry.verify(Some("sk-bob"), 0).unwrap_err(), AuthError::Disabled("bob".to_string()));
+        assert_eq!(registry.verify(Some("sk-carol"), 999).unwrap().name, "carol");
+        assert_eq!(registry.verify(Some("sk-carol"), 1_000).unwrap_err(), AuthError::Expired("carol".to_string()));
+    }
+
+    #[test]
+    fn test_reject_duplicate_and_reserved_names() {
+        let config = |keys: Vec<ApiKeyConfig>| ProxyConfig { api_key: "sk-primary".to_string(), api_keys: keys, ..Default::default() };
+
+        assert!(ApiKeyRegistry::validate(&config(vec![key("alice", "sk-a", true, None), key("bob", "sk-b", true, None)])).is_ok());
+        assert!(ApiKeyRegistry::validate(&config(vec![key("alice", "sk-a", true, None), key(" alice", "sk-b", true, None)])).is_err());
+        assert!(ApiKeyRegistry::validate(&config(vec![key("default", "sk-a", true, None)])).is_err());
+        assert!(ApiKeyRegistry::validate(&config(vec![key(" ", "sk-a", true, None)])).is_err());
+
+        // 已存在的无效配置：只保留第一个同名密钥，保留名密钥被忽略
+        let registry = ApiKeyRegistry::from_config(&config(vec![
+            key("alice", "sk-a", true, None),
+            key("alice", "sk-b", true, None),
+            key("default", "sk-c", true, None),
+        ]));
+        assert_eq!(registry.verify(Some("sk-a"), 0).unwrap().name, "alice");
+        assert_eq!(registry.verify(Some("sk-b"), 0).unwrap_err(), AuthError::Invalid);
+        assert_eq!(registry.verify(Some("sk-c"), 0).unwrap_err(), AuthError::Invalid);
+    }
+
+    #[test]
+    fn test_empty_config_not_enforced() {
+        let config = ProxyConfig { api_key: String::new(), ..Default::default() };
+        assert!(!ApiKeyRegistry::from_config(&config).is_enforced());
+    }
 
     #[test]
-    fn test_auth_placeholder() {
-        // Placeholder test
-        assert!(true);
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(!constant_time_eq(b"", b"a"));
     }
 }
@@ -3,6 +3,10 @@
 pub mod auth;
 pub mod cors;
 pub mod logging;
+pub mod policy;
+pub mod stats;
 
 pub use auth::auth_middleware;
 pub use cors::cors_layer;
+pub use policy::policy_middleware;
+pub use stats::stats_middleware;
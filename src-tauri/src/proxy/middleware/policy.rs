@@ -0,0 +1,313 @@
+// API Key 策略中间件
+// 模型白名单 / 每分钟请求数 / 并发流数量 / 每日 Token 预算
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use dashmap::DashMap;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::rate_limiter::KeyedRateLimiter;
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::server::AppState;
+use crate::proxy::stats::StatsCollector;
+use crate::proxy::upstream::guard::GuardedStream;
+
+/// 请求体读取上限 (与路由的 DefaultBodyLimit 保持一致)
+const MAX_BODY_BYTES: usize = 100 * 1024 * 1024;
+
+/// 并发流占位，drop 时自动释放
+pub struct StreamGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// API Key 运行时策略状态 (限流桶、并发计数)
+/// 策略本身来自 ApiKeyRegistry；当日用量记在 StatsCollector 中随统计落盘，重启后预算不会重置
+pub struct KeyPolicyManager {
+    limiter: KeyedRateLimiter,
+    active_streams: DashMap<String, Arc<AtomicUsize>>,
+    stats: Arc<StatsCollector>,
+}
+
+impl KeyPolicyManager {
+    pub fn new(stats: Arc<StatsCollector>) -> Self {
+        Self {
+            limiter: KeyedRateLimiter::new(),
+            active_streams: DashMap::new(),
+            stats,
+        }
+    }
+
+    /// 记录一次请求消耗的 Token
+    pub fn record_usage(&self, key_name: &str, usage: TokenUsage) {
+        self.stats.record_key_tokens(key_name, usage.total());
+    }
+
+    /// 按请求身份记录用量 (未鉴权或上游未返回用量时忽略)
+    pub fn record(&self, identity: Option<&ApiKeyIdentity>, usage: Option<TokenUsage>) {
+        if let (Some(identity), Some(usage)) = (identity, usage) {
+            self.record_usage(&identity.name, usage);
+        }
+    }
+
+    /// 当日已用 Token
+    pub fn tokens_used_today(&self, key_name: &str) -> u64 {
+        self.stats.key_tokens_today(key_name)
+    }
+
+    /// 生成用量回调，供流式/非流式响应结束时调用
+    pub fn usage_recorder(
+        self: &Arc<Self>,
+        identity: Option<ApiKeyIdentity>,
+    ) -> impl FnOnce(Option<TokenUsage>) + Send + 'static {
+        let manager = self.clone();
+        move |usage| manager.record(identity.as_ref(), usage)
+    }
+
+    fn try_open_stream(&self, key_name: &str, max: u32) -> Option<StreamGuard> {
+        let counter = self
+            .active_streams
+            .entry(key_name.to_string())
+            .or_insert_with(|| Arc::new(AtomicUsize::new(0)))
+            .clone();
+
+        if counter.fetch_add(1, Ordering::SeqCst) >= max as usize {
+            counter.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(StreamGuard { counter })
+    }
+}
+
+/// 请求的目标模型与是否流式
+struct RequestTarget {
+    model: Option<String>,
+    stream: bool,
+}
+
+/// 解析请求目标，JSON 请求体会被读出后重新放回
+async fn inspect_request(request: Request) -> Result<(Request, RequestTarget), String> {
+    let path = request.uri().path().to_string();
+
+    // Gemini: /v1beta/models/{model}:{method}
+    if let Some(rest) = path.strip_prefix("/v1beta/models/") {
+        let model_action = rest.split('/').next().unwrap_or(rest);
+        let (model, method) = model_action.rsplit_once(':').unwrap_or((model_action, ""));
+        let target = RequestTarget {
+            model: Some(model.to_string()),
+            stream: method == "streamGenerateContent",
+        };
+        return Ok((request, target));
+    }
+
+    if request.method() != Method::POST {
+        return Ok((request, RequestTarget { model: None, stream: false }));
+    }
+
+    // Claude / OpenAI: 从 JSON 请求体读取 model 与 stream
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|e| format!("Failed to read request body: {}", e))?;
+
+    let json: Option<Value> = serde_json::from_slice(&bytes).ok();
+    let model = json.as_ref().and_then(|v| v.get("model")).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let target = RequestTarget {
+        // 图像接口可省略 model (编辑接口为表单)，按实际使用的图像模型校验
+        model: model.or_else(|| {
+            path.starts_with("/v1/images/")
+                .then(|| crate::proxy::mappers::openai::images::IMAGE_MODEL.to_string())
+        }),
+        stream: json
+            .as_ref()
+            .and_then(|v| v.get("stream"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    };
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), target))
+}
+
+/// 模型白名单匹配，支持 `prefix*` 通配
+pub(crate) fn model_allowed(patterns: &[String], candidates: &[&str]) -> bool {
+    patterns.iter().any(|pattern| {
+        candidates.iter().any(|model| match pattern.strip_suffix('*') {
+            Some(prefix) => model.to_lowercase().starts_with(&prefix.to_lowercase()),
+            None => model.eq_ignore_ascii_case(pattern),
+        })
+    })
+}
+
+/// 匹配白名单，`mapped` 为客户端模型 `model` 路由后的目标模型
+/// 通配条目只按目标模型匹配 (客户端模型名可经自定义映射指向任意模型)，
+/// 精确条目还可匹配客户端模型名，兼容按 gpt-4o / claude-sonnet-4-5 等客户端名称编写的白名单
+fn check_model_route(patterns: &[String], model: &str, mapped: &str) -> bool {
+    model_allowed(patterns, &[mapped])
+        || patterns.iter().any(|p| !p.ends_with('*') && p.eq_ignore_ascii_case(model))
+}
+
+/// 距下一个 UTC 零点的时长 (每日预算重置时间)
+fn until_next_utc_day() -> Duration {
+    let now = chrono::Utc::now();
+    let tomorrow = (now.date_naive() + chrono::Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .map(|t| t.and_utc())
+        .unwrap_or(now);
+    (tomorrow - now).to_std().unwrap_or(Duration::from_secs(1))
+}
+
+/// 带 retry-after 的 429 响应
+fn too_many_requests(protocol: ClientProtocol, message: &str, retry_after: Duration) -> Response {
+    let mut response = protocol.error_response(StatusCode::TOO_MANY_REQUESTS, message);
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    if let Ok(value) = HeaderValue::from_str(&secs.to_string()) {
+        response.headers_mut().insert(header::RETRY_AFTER, value);
+    }
+    response
+}
+
+/// API Key 策略中间件 (须位于 auth_middleware 之后)
+pub async fn policy_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(identity) = request.extensions().get::<ApiKeyIdentity>().cloned() else {
+        return next.run(request).await;
+    };
+
+    let policy = state.api_keys.read().await.get(&identity.name).cloned();
+    let Some(policy) = policy.filter(|p| p.has_policy()) else {
+        return next.run(request).await;
+    };
+
+    let protocol = ClientProtocol::from_path(request.uri().path());
+    let (request, target) = match inspect_request(request).await {
+        Ok(r) => r,
+        Err(e) => return protocol.error_response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    // 1. 模型白名单 (客户端模型名或路由后的目标模型)
+    if !policy.allowed_models.is_empty() {
+        if let Some(model) = &target.model {
+            let mapped = state.resolve_model(model).await;
+            if !check_model_route(&policy.allowed_models, model, &mapped) {
+                tracing::warn!("[Policy] API key '{}' 无权调用模型 {} (-> {})", identity.name, model, mapped);
+                return protocol.error_response(
+                    StatusCode::FORBIDDEN,
+                    &format!("API key '{}' is not allowed to use model '{}'", identity.name, model),
+                );
+            }
+        }
+    }
+
+    // 2. 每日 Token 预算
+    if let Some(budget) = policy.daily_token_budget {
+        let used = state.key_policies.tokens_used_today(&identity.name);
+        if used >= budget {
+            tracing::warn!("[Policy] API key '{}' 当日 Token 预算已用尽 ({}/{})", identity.name, used, budget);
+            return too_many_requests(
+                protocol,
+                &format!("Daily token budget exhausted for API key '{}' ({}/{})", identity.name, used, budget),
+                until_next_utc_day(),
+            );
+        }
+    }
+
+    // 3. 每分钟请求数
+    if let Some(rpm) = policy.requests_per_minute {
+        if let Err(wait) = state.key_policies.limiter.try_acquire(&identity.name, rpm, Duration::from_secs(60)) {
+            tracing::warn!("[Policy] API key '{}' 超出每分钟请求限制 ({} rpm)", identity.name, rpm);
+            return too_many_requests(
+                protocol,
+                &format!("Rate limit exceeded for API key '{}': {} requests per minute", identity.name, rpm),
+                wait,
+            );
+        }
+    }
+
+    // 4. 并发流数量
+    let stream_guard = match (target.stream, policy.max_concurrent_streams) {
+        (true, Some(max)) => match state.key_policies.try_open_stream(&identity.name, max) {
+            Some(guard) => Some(guard),
+            None => {
+                tracing::warn!("[Policy] API key '{}' 超出并发流限制 ({})", identity.name, max);
+                return too_many_requests(
+                    protocol,
+                    &format!("Too many concurrent streams for API key '{}' (max {})", identity.name, max),
+                    Duration::from_secs(1),
+                );
+            }
+        },
+        _ => None,
+    };
+
+    let response = next.run(request).await;
+
+    // 将并发占位绑定到响应体，流结束或客户端断开时释放
+    match stream_guard {
+        Some(guard) => {
+            let (parts, body) = response.into_parts();
+            let body = Body::from_stream(GuardedStream::new(body.into_data_stream(), guard));
+            Response::from_parts(parts, body)
+        }
+        None => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_allowed() {
+        let patterns = vec!["claude-sonnet-4-5".to_string(), "gemini-2.5-*".to_string()];
+        assert!(model_allowed(&patterns, &["claude-sonnet-4-5"]));
+        assert!(model_allowed(&patterns, &["gpt-4o-mini", "gemini-2.5-flash"]));
+        assert!(!model_allowed(&patterns, &["claude-opus-4-5-thinking"]));
+    }
+
+    #[test]
+    fn test_model_route_checked_after_mapping() {
+        let patterns = vec!["claude-*".to_string()];
+        assert!(check_model_route(&patterns, "claude-sonnet-4-5", "claude-sonnet-4-5"));
+
+        // 客户端模型名命中白名单，但经自定义映射指向白名单外的模型
+        assert!(!check_model_route(&patterns, "claude-foo", "gemini-3-pro-high"));
+
+        // 按客户端模型名编写的精确条目仍然生效
+        let patterns = vec!["gpt-4o".to_string(), "claude-sonnet-4-5".to_string()];
+        assert!(check_model_route(&patterns, "gpt-4o", "gemini-2.5-pro"));
+        assert!(check_model_route(&patterns, "claude-sonnet-4-5", "claude-sonnet-4-5"));
+        assert!(!check_model_route(&patterns, "gpt-4o-mini", "gemini-2.5-flash"));
+    }
+
+    #[test]
+    fn test_daily_usage_and_streams() {
+        let manager = KeyPolicyManager::new(Arc::new(StatsCollector::new(None)));
+        manager.record_usage("alice", TokenUsage { input_tokens: 10, output_tokens: 5, thinking_tokens: 1 });
+        manager.record_usage("alice", TokenUsage { input_tokens: 4, output_tokens: 0, thinking_tokens: 0 });
+        assert_eq!(manager.tokens_used_today("alice"), 20);
+        assert_eq!(manager.tokens_used_today("bob"), 0);
+
+        let first = manager.try_open_stream("alice", 1);
+        assert!(first.is_some());
+        assert!(manager.try_open_stream("alice", 1).is_none());
+        drop(first);
+        assert!(manager.try_open_stream("alice", 1).is_some());
+    }
+}
@@ -0,0 +1,247 @@
+// 请求统计中间件
+// 为每个请求创建 RequestTrace，handler 补充模型/账号/重试信息，响应体结束时写入统计与请求日志
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use futures::StreamExt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::request_log::RequestLog;
+use crate::proxy::server::AppState;
+use crate::proxy::stats::{RequestRecord, StatsCollector};
+
+/// 请求结束时写入的目标
+struct TraceSinks {
+    stats: Arc<StatsCollector>,
+    log: Option<Arc<RequestLog>>,
+}
+
+#[derive(Default)]
+struct TraceState {
+    client_model: Option<String>,
+    mapped_model: Option<String>,
+    served_model: Option<String>,
+    account: Option<String>,
+    background: bool,
+    attempts: u32,
+    rotations: u32,
+    status: u16,
+    ttft_ms: Option<u64>,
+    usage: Option<TokenUsage>,
+}
+
+/// 单个请求的统计上下文
+/// 所有引用 (handler、用量回调、响应体) 释放后写入统计
+pub struct RequestTrace {
+    sinks: Option<TraceSinks>,
+    request_id: String,
+    protocol: ClientProtocol,
+    started: Instant,
+    timestamp_ms: i64,
+    state: Mutex<TraceState>,
+}
+
+impl RequestTrace {
+    fn new(sinks: Option<TraceSinks>, protocol: ClientProtocol) -> Self {
+        Self {
+            sinks,
+            request_id: uuid::Uuid::new_v4().simple().to_string(),
+            protocol,
+            started: Instant::now(),
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            state: Mutex::new(TraceState::default()),
+        }
+    }
+
+    /// 不写入统计的空上下文 (未经过统计中间件时使用)
+    pub fn detached() -> Arc<Self> {
+        Arc::new(Self::new(None, ClientProtocol::OpenAI))
+    }
+
+    /// 请求 ID (同时通过 `x-request-id` 响应头返回给客户端)
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    fn with_state(&self, f: impl FnOnce(&mut TraceState)) {
+        f(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// 客户端请求的模型
+    pub fn set_client_model(&self, model: &str) {
+        self.with_state(|s| s.client_model = Some(model.to_string()));
+    }
+
+    /// 路由映射后的上游模型
+    pub fn set_mapped_model(&self, model: &str) {
+        self.with_state(|s| s.mapped_model = Some(model.to_string()));
+    }
+
+    /// 实际应答的模型 (通过 `x-served-model` 响应头返回给客户端)
+    pub fn set_served_model(&self, model: &str) {
+        self.with_state(|s| s.served_model = Some(model.to_string()));
+    }
+
+    fn served_model(&self) -> Option<String> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).served_model.clone()
+    }
+
+    /// 标记为后台自动任务
+    pub fn set_background(&self) {
+        self.with_state(|s| s.background = true);
+    }
+
+    /// 开始一次上游尝试，账号与上一次不同时计为一次轮换
+    pub fn begin_attempt(&self, account: &str) {
+        self.with_state(|s| {
+            if s.account.as_deref().is_some_and(|prev| prev != account) {
+                s.rotations += 1;
+            }
+            s.attempts += 1;
+            s.account = Some(account.to_string());
+        });
+    }
+
+    /// 记录上游返回的 Token 用量
+    pub fn record_usage(&self, usage: Option<TokenUsage>) {
+        if usage.is_some() {
+            self.with_state(|s| s.usage = usage);
+        }
+    }
+
+    /// 包装用量回调，同时写入本请求的统计
+    pub fn usage_recorder<F>(self: &Arc<Self>, inner: F) -> impl FnOnce(Option<TokenUsage>) + Send + 'static
+    where
+        F: FnOnce(Option<TokenUsage>) + Send + 'static,
+    {
+        let trace = self.clone();
+        move |usage| {
+            trace.record_usage(usage);
+            inner(usage);
+        }
+    }
+
+    /// 请求最终状态 (响应头发出时写入；流式响应中途失败时由看门狗改写)
+    pub fn set_status(&self, status: u16) {
+        self.with_state(|s| s.status = status);
+    }
+
+    fn mark_first_byte(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.with_state(|s| {
+            s.ttft_ms.get_or_insert(elapsed);
+        });
+    }
+}
+
+impl Drop for RequestTrace {
+    fn drop(&mut self) {
+        let Some(sinks) = &self.sinks else {
+            return;
+        };
+        let state = std::mem::take(&mut *self.state.lock().unwrap_or_else(|e| e.into_inner()));
+
+        let record = RequestRecord {
+            request_id: std::mem::take(&mut self.request_id),
+            timestamp_ms: self.timestamp_ms,
+            protocol: self.protocol.as_str().to_string(),
+            client_model: state.client_model,
+            mapped_model: state.mapped_model,
+            served_model: state.served_model,
+            account: state.account,
+            background: state.background,
+            status: state.status,
+            retries: state.attempts.saturating_sub(1),
+            rotations: state.rotations,
+            latency_ms: self.started.elapsed().as_millis() as u64,
+            ttft_ms: state.ttft_ms,
+            usage: state.usage,
+        };
+        sinks.stats.record(&record);
+        if let Some(log) = &sinks.log {
+            log.append(record);
+        }
+    }
+}
+
+/// 请求统计中间件 (须位于 auth_middleware 外层，鉴权失败的请求同样计入)
+/// 只统计 POST 请求 (模型列表、健康检查等不计入)
+pub async fn stats_middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+
+    let protocol = ClientProtocol::from_path(request.uri().path());
+    let sinks = TraceSinks {
+        stats: state.stats.clone(),
+        log: state.request_log.clone(),
+    };
+    let trace = Arc::new(RequestTrace::new(Some(sinks), protocol));
+    request.extensions_mut().insert(trace.clone());
+
+    let mut response = next.run(request).await;
+    trace.set_status(response.status().as_u16());
+    if let Ok(value) = HeaderValue::from_str(trace.request_id()) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    if let Some(value) = trace.served_model().and_then(|m| HeaderValue::from_str(&m).ok()) {
+        response.headers_mut().insert("x-served-model", value);
+    }
+
+    let streaming = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+
+    // 将统计上下文绑定到响应体，流式响应记录首个数据块时间
+    let (parts, body) = response.into_parts();
+    let body = Body::from_stream(body.into_data_stream().map(move |chunk| {
+        if streaming {
+            trace.mark_first_byte();
+        }
+        chunk
+    }));
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_records_on_drop() {
+        let collector = Arc::new(StatsCollector::new(None));
+        let sinks = TraceSinks { stats: collector.clone(), log: None };
+        let trace = Arc::new(RequestTrace::new(Some(sinks), ClientProtocol::Gemini));
+        trace.set_client_model("gemini-2.5-pro");
+        trace.begin_attempt("a@example.com");
+        trace.begin_attempt("a@example.com");
+        trace.begin_attempt("b@example.com");
+        trace.set_status(200);
+
+        let recorder = trace.usage_recorder(|_| {});
+        drop(trace);
+        assert_eq!(collector.query(None, None).total.total_requests, 0);
+
+        recorder(Some(TokenUsage { input_tokens: 1, output_tokens: 2, thinking_tokens: 0 }));
+        let stats = collector.query(None, None);
+        assert_eq!(stats.total.total_requests, 1);
+        assert_eq!(stats.total.retries, 2);
+        assert_eq!(stats.total.rotations, 1);
+        assert_eq!(stats.total.output_tokens, 2);
+        assert_eq!(stats.by_protocol["gemini"].success_count, 1);
+        assert_eq!(stats.by_account["b@example.com"].total_requests, 1);
+    }
+}
@@ -0,0 +1,386 @@
+// 本地模拟 v1internal 服务
+// 实现 generateContent / streamGenerateContent / loadCodeAssist / fetchAvailableModels，响应可通过脚本控制
+// 将 upstream_endpoints 指向 base_url 后，反代、配额查询与账号切换流程均可脱离 Google 端到端运行
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::time::Duration;
+
+use crate::proxy::common::protocol::ClientProtocol;
+
+/// 模拟服务脚本
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MockUpstreamScript {
+    /// 正常回复的文本
+    pub reply_text: String,
+    /// 流式回复拆分的块数
+    pub stream_chunks: usize,
+    /// 流式回复的块间隔
+    pub chunk_delay_ms: u64,
+    /// loadCodeAssist 返回的 project_id，为空时不返回 (模拟无资格账号)
+    pub project_id: String,
+    /// fetchAvailableModels 返回的模型配额
+    pub models: Vec<MockModelQuota>,
+    /// 按顺序消费的一次性响应，key 为方法名或 `*` (方法名优先)，用完后恢复正常回复
+    pub queue: HashMap<String, Vec<MockResponse>>,
+    /// 指定 access token 的固定响应 (例如模拟某个账号持续 429)，优先于 queue
+    pub accounts: HashMap<String, MockResponse>,
+}
+
+impl Default for MockUpstreamScript {
+    fn default() -> Self {
+        Self {
+            reply_text: "Hello from the mock upstream.".to_string(),
+            stream_chunks: 3,
+            chunk_delay_ms: 50,
+            project_id: "mock-project-00000".to_string(),
+            models: vec![
+                MockModelQuota::new("gemini-2.5-pro"),
+                MockModelQuota::new("gemini-2.5-flash"),
+                MockModelQuota::new("claude-sonnet-4-5"),
+            ],
+            queue: HashMap::new(),
+            accounts: HashMap::new(),
+        }
+    }
+}
+
+/// 模型配额
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockModelQuota {
+    pub name: String,
+    /// 剩余比例 (0-1)
+    #[serde(default = "default_remaining_fraction")]
+    pub remaining_fraction: f64,
+    #[serde(default)]
+    pub reset_time: String,
+}
+
+impl MockModelQuota {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            remaining_fraction: default_remaining_fraction(),
+            reset_time: String::new(),
+        }
+    }
+}
+
+fn default_remaining_fraction() -> f64 {
+    1.0
+}
+
+/// 脚本化响应
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MockResponse {
+    /// HTTP 状态码，0 视为 200
+    pub status: u16,
+    /// 响应体，为空时：200 返回正常回复，其它状态码生成 Google 风格错误
+    pub body: Option<Value>,
+    /// 错误原因 (ErrorInfo.reason)，例如 `RATE_LIMIT_EXCEEDED`、`QUOTA_EXHAUSTED`
+    pub reason: Option<String>,
+    /// 429 的重试延迟 (RetryInfo.retryDelay，秒)
+    pub retry_delay_secs: Option<f64>,
+    /// 返回前等待
+    pub delay_ms: u64,
+}
+
+/// 模拟服务收到的一次调用
+#[derive(Debug, Clone, Serialize)]
+pub struct MockCall {
+    pub method: String,
+    pub access_token: String,
+    pub body: Value,
+}
+
+struct MockState {
+    script: Mutex<MockUpstreamScript>,
+    calls: Mutex<Vec<MockCall>>,
+}
+
+impl MockState {
+    /// 取出本次调用的脚本化响应 (若有)，同时返回脚本快照
+    fn next_response(&self, method: &str, access_token: &str) -> (Option<MockResponse>, MockUpstreamScript) {
+        let mut script = self.script.lock().unwrap_or_else(|e| e.into_inner());
+        let scripted = script.accounts.get(access_token).cloned().or_else(|| {
+            [method, "*"].iter().find_map(|key| {
+                let queue = script.queue.get_mut(*key)?;
+                (!queue.is_empty()).then(|| queue.remove(0))
+            })
+        });
+        (scripted, script.clone())
+    }
+}
+
+/// 运行中的模拟服务
+pub struct MockUpstream {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+}
+
+impl MockUpstream {
+    /// 在 127.0.0.1 上启动，port 为 0 时随机分配
+    pub async fn start(port: u16, script: MockUpstreamScript) -> Result<Self, String> {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+            .await
+            .map_err(|e| format!("模拟上游端口 {} 绑定失败: {}", port, e))?;
+        let addr = listener.local_addr().map_err(|e| e.to_string())?;
+
+        let state = Arc::new(MockState {
+            script: Mutex::new(script),
+            calls: Mutex::new(Vec::new()),
+        });
+        // 路径形如 `/v1internal:generateContent`，统一由 fallback 解析
+        let app = Router::new().fallback(handle_call).with_state(state.clone());
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let result = axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_rx.await;
+                })
+                .await;
+            if let Err(e) = result {
+                tracing::warn!("模拟上游服务异常退出: {}", e);
+            }
+        });
+        tracing::info!("模拟上游服务启动在 http://{}/v1internal", addr);
+
+        Ok(Self { addr, state, shutdown_tx: Some(shutdown_tx) })
+    }
+
+    /// 可直接填入 upstream_endpoints 的地址
+    pub fn base_url(&self) -> String {
+        format!("http://{}/v1internal", self.addr)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// 替换脚本 (未消费的队列一并替换)
+    pub fn set_script(&self, script: MockUpstreamScript) {
+        *self.state.script.lock().unwrap_or_else(|e| e.into_inner()) = script;
+    }
+
+    /// 已收到的调用
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.calls.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+async fn handle_call(
+    State(state): State<Arc<MockState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some((_, method)) = uri.path().rsplit_once(':') else {
+        return error_response(StatusCode::NOT_FOUND, None, None, &format!("Unknown path {}", uri.path()));
+    };
+    let access_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .to_string();
+    let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    state.calls.lock().unwrap_or_else(|e| e.into_inner()).push(MockCall {
+        method: method.to_string(),
+        access_token: access_token.clone(),
+        body: request.clone(),
+    });
+
+    let (scripted, script) = state.next_response(method, &access_token);
+    let mut body_override = None;
+    if let Some(scripted) = scripted {
+        if scripted.delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(scripted.delay_ms)).await;
+        }
+        let status = StatusCode::from_u16(scripted.status).unwrap_or(StatusCode::OK);
+        if status != StatusCode::OK {
+            return match scripted.body {
+                Some(body) => (status, Json(body)).into_response(),
+                None => error_response(status, scripted.reason.as_deref(), scripted.retry_delay_secs, "Scripted error from the mock upstream."),
+            };
+        }
+        body_override = scripted.body;
+    }
+
+    match method {
+        "generateContent" => {
+            Json(body_override.unwrap_or_else(|| generate_response(&script, &request, &script.reply_text, true))).into_response()
+        }
+        "streamGenerateContent" => stream_response(&script, &request, body_override),
+        "loadCodeAssist" => Json(body_override.unwrap_or_else(|| {
+            if script.project_id.is_empty() {
+                json!({ "currentTier": { "id": "free-tier" } })
+            } else {
+                json!({ "cloudaicompanionProject": script.project_id, "currentTier": { "id": "free-tier" } })
+            }
+        }))
+        .into_response(),
+        "fetchAvailableModels" => Json(body_override.unwrap_or_else(|| {
+            let models: serde_json::Map<String, Value> = script
+                .models
+                .iter()
+                .map(|m| {
+                    (
+                        m.name.clone(),
+                        json!({ "quotaInfo": { "remainingFraction": m.remaining_fraction, "resetTime": m.reset_time } }),
+                    )
+                })
+                .collect();
+            json!({ "models": models })
+        }))
+        .into_response(),
+        _ => error_response(StatusCode::NOT_FOUND, None, None, &format!("Method {} is not implemented by the mock upstream.", method)),
+    }
+}
+
+/// v1internal 响应 (外层包裹 `response`)
+fn generate_response(script: &MockUpstreamScript, request: &Value, text: &str, last: bool) -> Value {
+    let mut candidate = json!({ "content": { "role": "model", "parts": [{ "text": text }] } });
+    let mut response = json!({
+        "candidates": [],
+        "modelVersion": request.get("model").cloned().unwrap_or(Value::Null),
+        "responseId": "mock-response",
+    });
+    if last {
+        candidate["finishReason"] = json!("STOP");
+        let prompt_tokens = (request.to_string().len() / 4).max(1);
+        let output_tokens = (script.reply_text.chars().count() / 4).max(1);
+        response["usageMetadata"] = json!({
+            "promptTokenCount": prompt_tokens,
+            "candidatesTokenCount": output_tokens,
+            "totalTokenCount": prompt_tokens + output_tokens,
+        });
+    }
+    response["candidates"] = json!([candidate]);
+    json!({ "response": response })
+}
+
+fn stream_response(script: &MockUpstreamScript, request: &Value, body_override: Option<Value>) -> Response {
+    let events: Vec<Value> = match body_override {
+        Some(body) => vec![body],
+        None => {
+            let chars: Vec<char> = script.reply_text.chars().collect();
+            let chunk_size = chars.len().div_ceil(script.stream_chunks.max(1)).max(1);
+            let pieces: Vec<String> = chars.chunks(chunk_size).map(|c| c.iter().collect()).collect();
+            let count = pieces.len().max(1);
+            (0..count)
+                .map(|i| {
+                    let text = pieces.get(i).map(String::as_str).unwrap_or_default();
+                    generate_response(script, request, text, i + 1 == count)
+                })
+                .collect()
+        }
+    };
+    let delay = Duration::from_millis(script.chunk_delay_ms);
+
+    let stream = async_stream::stream! {
+        for (i, event) in events.into_iter().enumerate() {
+            if i > 0 && !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            yield Ok::<Bytes, std::io::Error>(Bytes::from(format!("data: {}\n\n", event)));
+        }
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .body(Body::from_stream(stream))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+/// Google 风格错误 (含 ErrorInfo / RetryInfo)
+fn error_response(status: StatusCode, reason: Option<&str>, retry_delay_secs: Option<f64>, message: &str) -> Response {
+    let mut body = ClientProtocol::Gemini.error_body(status, message);
+    let mut details = Vec::new();
+    let reason = reason.or((status == StatusCode::TOO_MANY_REQUESTS).then_some("RATE_LIMIT_EXCEEDED"));
+    if let Some(reason) = reason {
+        details.push(json!({
+            "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+            "reason": reason,
+            "domain": "cloudcode-pa.googleapis.com",
+        }));
+    }
+    if let Some(secs) = retry_delay_secs {
+        details.push(json!({
+            "@type": "type.googleapis.com/google.rpc.RetryInfo",
+            "retryDelay": format!("{}s", secs),
+        }));
+    }
+    if !details.is_empty() {
+        body["error"]["details"] = json!(details);
+    }
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::upstream::endpoint::UpstreamEndpoints;
+
+    #[tokio::test]
+    async fn test_mock_upstream_script() {
+        let mut script = MockUpstreamScript { chunk_delay_ms: 0, ..Default::default() };
+        script.queue.insert(
+            "generateContent".to_string(),
+            vec![MockResponse { status: 429, reason: Some("QUOTA_EXHAUSTED".to_string()), retry_delay_secs: Some(1.5), ..Default::default() }],
+        );
+        script.accounts.insert("banned".to_string(), MockResponse { status: 403, ..Default::default() });
+        let mock = MockUpstream::start(0, script).await.unwrap();
+        let client = reqwest::Client::new();
+        let call = |method: &str, token: &str| {
+            client
+                .post(UpstreamEndpoints::method_url(&mock.base_url(), method, None))
+                .bearer_auth(token)
+                .json(&json!({ "model": "gemini-2.5-pro", "request": {} }))
+                .send()
+        };
+
+        let limited = call("generateContent", "t1").await.unwrap();
+        assert_eq!(limited.status(), 429);
+        let error_text = limited.text().await.unwrap();
+        assert!(error_text.contains("QUOTA_EXHAUSTED"));
+        assert_eq!(crate::proxy::upstream::retry::parse_retry_delay(&error_text), Some(1500));
+
+        let ok: Value = call("generateContent", "t1").await.unwrap().json().await.unwrap();
+        assert_eq!(ok["response"]["candidates"][0]["content"]["parts"][0]["text"], "Hello from the mock upstream.");
+        assert_eq!(call("loadCodeAssist", "banned").await.unwrap().status(), 403);
+
+        let project: Value = call("loadCodeAssist", "t2").await.unwrap().json().await.unwrap();
+        assert_eq!(project["cloudaicompanionProject"], "mock-project-00000");
+        let models: Value = call("fetchAvailableModels", "t2").await.unwrap().json().await.unwrap();
+        assert_eq!(models["models"]["gemini-2.5-pro"]["quotaInfo"]["remainingFraction"], 1.0);
+
+        let stream = call("streamGenerateContent", "t2").await.unwrap().text().await.unwrap();
+        assert_eq!(stream.matches("data: ").count(), 3);
+        assert!(stream.contains("\"finishReason\":\"STOP\""));
+
+        assert_eq!(mock.calls().len(), 6);
+        assert_eq!(mock.calls()[0].access_token, "t1");
+    }
+}
@@ -3,6 +3,15 @@
 // 现有模块 (保留)
 pub mod config;
 pub mod token_manager;
+pub mod account_health;
+pub mod scheduler;
+pub mod stats;
+pub mod request_log;
+pub mod capture;
+pub mod response_store;
+pub mod file_store;
+pub mod metrics;
+pub mod mock_upstream;
 pub mod project_resolver;
 pub mod server;
 
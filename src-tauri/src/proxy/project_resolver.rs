@@ -1,25 +1,25 @@
 use serde_json::Value;
 
+use crate::proxy::common::fingerprint::AccountIdentity;
+
 /// 使用 Antigravity 的 loadCodeAssist API 获取 project_id
 /// 这是获取 cloudaicompanionProject 的正确方式
-pub async fn fetch_project_id(access_token: &str) -> Result<String, String> {
-    let url = "https://cloudcode-pa.googleapis.com/v1internal:loadCodeAssist";
-    
-    let request_body = serde_json::json!({
-        "metadata": {
-            "ideType": "ANTIGRAVITY"
-        }
-    });
-    
-    let client = crate::utils::http::create_client(30);
-    let response = client
-        .post(url)
-        .bearer_auth(access_token)
-        .header("Host", "cloudcode-pa.googleapis.com")
-        .header("User-Agent", "antigravity/1.11.9 windows/amd64")
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
+/// `identity` 为账号的出口代理与客户端指纹
+pub async fn fetch_project_id(access_token: &str, identity: &AccountIdentity) -> Result<String, String> {
+    let request_body = identity.load_code_assist_body();
+    let user_agent = identity.user_agent();
+
+    let client = identity.http_client(30)?;
+    let response = crate::proxy::upstream::endpoint::current()
+        .post("loadCodeAssist", None, |url| {
+            client
+                .post(url)
+                .bearer_auth(access_token)
+                .header("User-Agent", &user_agent)
+                .header("Content-Type", "application/json")
+                .json(&request_body)
+                .send()
+        })
         .await
         .map_err(|e| format!("loadCodeAssist 请求失败: {}", e))?;
     
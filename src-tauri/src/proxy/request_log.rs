@@ -0,0 +1,315 @@
+// 结构化请求日志
+// 每个反代请求一条记录，保存在 SQLite (proxy_requests.db)，由后台线程批量写入
+use rusqlite::{params, params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::stats::RequestRecord;
+
+/// 请求日志保留时长
+const RETENTION_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+/// 单页最大条数
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// 请求日志查询条件 (均为可选)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestLogQuery {
+    pub since_ms: Option<i64>,
+    pub until_ms: Option<i64>,
+    pub account: Option<String>,
+    /// 匹配客户端模型或映射后的模型
+    pub model: Option<String>,
+    pub status: Option<u16>,
+    /// 页码，从 0 开始
+    pub page: u32,
+    pub page_size: u32,
+}
+
+/// 一页查询结果 (按时间倒序)
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestLogPage {
+    pub total: u64,
+    pub page: u32,
+    pub page_size: u32,
+    pub entries: Vec<RequestRecord>,
+}
+
+/// SQLite 请求日志
+pub struct RequestLog {
+    conn: Arc<Mutex<Connection>>,
+    writer: mpsc::Sender<RequestRecord>,
+}
+
+impl RequestLog {
+    /// 打开 (或创建) 日志数据库，并清理超出保留期的记录
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| format!("打开请求日志数据库失败: {}", e))?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(|e| format!("设置请求日志数据库失败: {}", e))?;
+        Self::init(conn)
+    }
+
+    /// 内存数据库 (测试用)
+    #[cfg(test)]
+    fn open_in_memory() -> Result<Self, String> {
+        Self::init(Connection::open_in_memory().map_err(|e| e.to_string())?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, String> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS requests (
+                id TEXT PRIMARY KEY,
+                timestamp_ms INTEGER NOT NULL,
+                protocol TEXT NOT NULL,
+                client_model TEXT,
+                mapped_model TEXT,
+                account TEXT,
+                background INTEGER NOT NULL DEFAULT 0,
+                attempts INTEGER NOT NULL,
+                rotations INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                latency_ms INTEGER NOT NULL,
+                ttft_ms INTEGER,
+                input_tokens INTEGER,
+                output_tokens INTEGER,
+                thinking_tokens INTEGER,
+                served_model TEXT
+            );
+            CREATE INDEX IF NOT EXISTS idx_requests_time ON requests (timestamp_ms);
+            CREATE INDEX IF NOT EXISTS idx_requests_account ON requests (account, timestamp_ms);",
+        )
+        .map_err(|e| format!("初始化请求日志表失败: {}", e))?;
+
+        let cutoff = chrono::Utc::now().timestamp_millis() - RETENTION_MS;
+        conn.execute("DELETE FROM requests WHERE timestamp_ms < ?1", params![cutoff])
+            .map_err(|e| format!("清理过期请求日志失败: {}", e))?;
+
+        let conn = Arc::new(Mutex::new(conn));
+        let (tx, rx) = mpsc::channel::<RequestRecord>();
+        let writer_conn = conn.clone();
+
+        // 写入放在独立线程，避免在请求路径上阻塞
+        std::thread::spawn(move || {
+            while let Ok(first) = rx.recv() {
+                let mut batch = vec![first];
+                batch.extend(rx.try_iter());
+
+                let mut conn = writer_conn.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = insert_batch(&mut conn, &batch) {
+                    tracing::warn!("写入请求日志失败: {}", e);
+                }
+            }
+        });
+
+        Ok(Self { conn, writer: tx })
+    }
+
+    /// 追加一条记录 (异步写入)
+    pub fn append(&self, record: RequestRecord) {
+        let _ = self.writer.send(record);
+    }
+
+    /// 分页查询
+    pub fn query(&self, query: &RequestLogQuery) -> Result<RequestLogPage, String> {
+        let page_size = match query.page_size {
+            0 => 50,
+            n => n.min(MAX_PAGE_SIZE),
+        };
+
+        let mut clauses: Vec<&str> = Vec::new();
+        let mut args: Vec<rusqlite::types::Value> = Vec::new();
+        if let Some(since) = query.since_ms {
+            clauses.push("timestamp_ms >= ?");
+            args.push(since.into());
+        }
+        if let Some(until) = query.until_ms {
+            clauses.push("timestamp_ms <= ?");
+            args.push(until.into());
+        }
+        if let Some(account) = &query.account {
+            clauses.push("account = ?");
+            args.push(account.clone().into());
+        }
+        if let Some(model) = &query.model {
+            clauses.push("(client_model = ? OR mapped_model = ? OR served_model = ?)");
+            args.push(model.clone().into());
+            args.push(model.clone().into());
+            args.push(model.clone().into());
+        }
+        if let Some(status) = query.status {
+            clauses.push("status = ?");
+            args.push((status as i64).into());
+        }
+        let filter = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        };
+
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let total: i64 = conn
+            .query_row(&format!("SELECT COUNT(*) FROM requests {}", filter), params_from_iter(args.iter()), |row| row.get(0))
+            .map_err(|e| format!("查询请求日志失败: {}", e))?;
+
+        let mut page_args = args;
+        page_args.push((page_size as i64).into());
+        page_args.push((query.page as i64 * page_size as i64).into());
+
+        let sql = format!(
+            "SELECT id, timestamp_ms, protocol, client_model, mapped_model, account, background, attempts,
+                    rotations, status, latency_ms, ttft_ms, input_tokens, output_tokens, thinking_tokens, served_model
+             FROM requests {} ORDER BY timestamp_ms DESC LIMIT ? OFFSET ?",
+            filter
+        );
+        let mut stmt = conn.prepare(&sql).map_err(|e| format!("查询请求日志失败: {}", e))?;
+        let entries = stmt
+            .query_map(params_from_iter(page_args.iter()), |row| {
+                let attempts: u32 = row.get(7)?;
+                let input: Option<u64> = row.get(12)?;
+                let usage = input.map(|input_tokens| -> rusqlite::Result<TokenUsage> {
+                    Ok(TokenUsage {
+                        input_tokens,
+                        output_tokens: row.get::<_, Option<u64>>(13)?.unwrap_or(0),
+                        thinking_tokens: row.get::<_, Option<u64>>(14)?.unwrap_or(0),
+                    })
+                });
+                Ok(RequestRecord {
+                    request_id: row.get(0)?,
+                    timestamp_ms: row.get(1)?,
+                    protocol: row.get(2)?,
+                    client_model: row.get(3)?,
+                    mapped_model: row.get(4)?,
+                    served_model: row.get(15)?,
+                    account: row.get(5)?,
+                    background: row.get(6)?,
+                    retries: attempts.saturating_sub(1),
+                    rotations: row.get(8)?,
+                    status: row.get(9)?,
+                    latency_ms: row.get(10)?,
+                    ttft_ms: row.get(11)?,
+                    usage: usage.transpose()?,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("查询请求日志失败: {}", e))?;
+
+        Ok(RequestLogPage {
+            total: total as u64,
+            page: query.page,
+            page_size,
+            entries,
+        })
+    }
+}
+
+fn insert_batch(conn: &mut Connection, batch: &[RequestRecord]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT OR REPLACE INTO requests (id, timestamp_ms, protocol, client_model, mapped_model, account,
+                background, attempts, rotations, status, latency_ms, ttft_ms, input_tokens, output_tokens, thinking_tokens, served_model)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        )?;
+        for r in batch {
+            stmt.execute(params![
+                r.request_id,
+                r.timestamp_ms,
+                r.protocol,
+                r.client_model,
+                r.mapped_model,
+                r.account,
+                r.background,
+                r.retries + 1,
+                r.rotations,
+                r.status,
+                r.latency_ms as i64,
+                r.ttft_ms.map(|v| v as i64),
+                r.usage.map(|u| u.input_tokens as i64),
+                r.usage.map(|u| u.output_tokens as i64),
+                r.usage.map(|u| u.thinking_tokens as i64),
+                r.served_model,
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, timestamp_ms: i64, account: &str, status: u16) -> RequestRecord {
+        RequestRecord {
+            request_id: id.to_string(),
+            timestamp_ms,
+            protocol: "openai".to_string(),
+            client_model: Some("gpt-4o".to_string()),
+            mapped_model: Some("gemini-2.5-pro".to_string()),
+            account: Some(account.to_string()),
+            status,
+            retries: 1,
+            latency_ms: 120,
+            usage: Some(TokenUsage { input_tokens: 3, output_tokens: 4, thinking_tokens: 0 }),
+            ..Default::default()
+        }
+    }
+
+    fn wait_for(log: &RequestLog, expected: u64) {
+        for _ in 0..100 {
+            if log.query(&RequestLogQuery::default()).unwrap().total == expected {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("request log writer did not flush");
+    }
+
+    #[test]
+    fn test_append_and_filter() {
+        let log = RequestLog::open_in_memory().unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+        log.append(record("r1", now - 2_000, "a@example.com", 200));
+        log.append(record("r2", now - 1_000, "b@example.com", 429));
+        log.append(record("r3", now, "a@example.com", 200));
+        wait_for(&log, 3);
+
+        let page = log.query(&RequestLogQuery { account: Some("a@example.com".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.entries[0].request_id, "r3");
+        assert_eq!(page.entries[0].retries, 1);
+        assert_eq!(page.entries[0].usage.unwrap().output_tokens, 4);
+
+        let errors = log.query(&RequestLogQuery { status: Some(429), ..Default::default() }).unwrap();
+        assert_eq!(errors.entries.len(), 1);
+        assert_eq!(errors.entries[0].request_id, "r2");
+
+        let by_model = log.query(&RequestLogQuery { model: Some("gemini-2.5-pro".to_string()), since_ms: Some(now - 1_500), ..Default::default() }).unwrap();
+        assert_eq!(by_model.total, 2);
+
+        let second_page = log.query(&RequestLogQuery { page: 1, page_size: 2, ..Default::default() }).unwrap();
+        assert_eq!(second_page.entries.len(), 1);
+        assert_eq!(second_page.entries[0].request_id, "r1");
+    }
+
+    #[test]
+    fn test_served_model_filter() {
+        let log = RequestLog::open_in_memory().unwrap();
+
+        let now = chrono::Utc::now().timestamp_millis();
+        log.append(record("r1", now - 1_000, "a@example.com", 200));
+        log.append(RequestRecord {
+            served_model: Some("gemini-3-pro-high".to_string()),
+            ..record("r2", now, "a@example.com", 200)
+        });
+        wait_for(&log, 2);
+
+        let page = log.query(&RequestLogQuery { model: Some("gemini-3-pro-high".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.entries[0].mapped_model.as_deref(), Some("gemini-2.5-pro"));
+        assert_eq!(page.entries[0].served_model.as_deref(), Some("gemini-3-pro-high"));
+    }
+}
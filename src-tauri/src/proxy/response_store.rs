@@ -0,0 +1,129 @@
+// Responses API 对话存储
+// 每个 response 保存本轮新增的 contents 与上一轮 id，previous_response_id 接续时沿链拼接完整对话
+// 每轮记录创建它的 API Key，只有同一 Key 可以接续
+// 仅保存在内存中 (重启后丢失)，超出容量时淘汰最久未使用的
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// 最多保存的 response 数
+const MAX_RESPONSES: usize = 2000;
+
+struct StoredTurn {
+    /// 创建该轮的 API Key 名称 (未启用鉴权时为 None)
+    owner: Option<String>,
+    previous: Option<String>,
+    contents: Vec<Value>,
+}
+
+#[derive(Default)]
+struct StoreInner {
+    turns: HashMap<String, StoredTurn>,
+    /// 使用顺序，队首最久未使用
+    order: VecDeque<String>,
+}
+
+impl StoreInner {
+    fn touch(&mut self, id: &str) {
+        if let Some(pos) = self.order.iter().position(|x| x == id) {
+            if let Some(id) = self.order.remove(pos) {
+                self.order.push_back(id);
+            }
+        }
+    }
+}
+
+pub struct ResponseStore {
+    inner: Mutex<StoreInner>,
+    capacity: usize,
+}
+
+impl Default for ResponseStore {
+    fn default() -> Self {
+        Self::new(MAX_RESPONSES)
+    }
+}
+
+impl ResponseStore {
+    pub fn new(capacity: usize) -> Self {
+        Self { inner: Mutex::new(StoreInner::default()), capacity: capacity.max(1) }
+    }
+
+    /// 保存一轮对话 (本轮输入与模型输出)
+    pub fn insert(&self, id: &str, owner: Option<&str>, previous: Option<&str>, contents: Vec<Value>) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        while inner.turns.len() >= self.capacity {
+            let Some(oldest) = inner.order.pop_front() else { break };
+            inner.turns.remove(&oldest);
+        }
+        let turn = StoredTurn {
+            owner: owner.map(str::to_string),
+            previous: previous.map(str::to_string),
+            contents,
+        };
+        inner.turns.insert(id.to_string(), turn);
+        inner.order.push_back(id.to_string());
+    }
+
+    /// 拼接截至 `id` 的完整对话；链上任一轮已被淘汰或不属于 `owner` 时返回 None
+    pub fn conversation(&self, id: &str, owner: Option<&str>) -> Option<Vec<Value>> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut chain = Vec::new();
+        let mut current = Some(id.to_string());
+        while let Some(turn_id) = current {
+            let turn = inner.turns.get(&turn_id).filter(|t| t.owner.as_deref() == owner)?;
+            current = turn.previous.clone();
+            chain.push(turn_id);
+            if chain.len() > self.capacity {
+                return None;
+            }
+        }
+
+        let mut contents = Vec::new();
+        for turn_id in chain.iter().rev() {
+            inner.touch(turn_id);
+            contents.extend(inner.turns[turn_id].contents.iter().cloned());
+        }
+        Some(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_conversation_chain() {
+        let store = ResponseStore::new(3);
+        store.insert("r1", None, None, vec![json!(1), json!(2)]);
+        store.insert("r2", None, Some("r1"), vec![json!(3)]);
+        assert_eq!(store.conversation("r2", None).unwrap(), vec![json!(1), json!(2), json!(3)]);
+        assert!(store.conversation("missing", None).is_none());
+
+        // 访问过的链不会被优先淘汰
+        store.insert("x", None, None, vec![json!(0)]);
+        store.conversation("r2", None);
+        store.insert("r3", None, Some("r2"), vec![json!(4)]);
+        assert!(store.conversation("x", None).is_none());
+        assert_eq!(store.conversation("r3", None).unwrap().len(), 4);
+
+        // 祖先被淘汰后无法接续
+        store.insert("y", None, None, vec![]);
+        assert!(store.conversation("r3", None).is_none());
+    }
+
+    #[test]
+    fn test_conversation_scoped_to_owner() {
+        let store = ResponseStore::new(10);
+        store.insert("r1", Some("alice"), None, vec![json!(1)]);
+        store.insert("r2", Some("alice"), Some("r1"), vec![json!(2)]);
+        assert_eq!(store.conversation("r2", Some("alice")).unwrap().len(), 2);
+        assert!(store.conversation("r2", Some("bob")).is_none());
+        assert!(store.conversation("r2", None).is_none());
+
+        // 接续他人的对话链时，链上不属于自己的轮次同样不可见
+        store.insert("r3", Some("bob"), Some("r2"), vec![json!(3)]);
+        assert!(store.conversation("r3", Some("bob")).is_none());
+    }
+}
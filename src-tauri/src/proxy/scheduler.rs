@@ -0,0 +1,242 @@
+// 账号调度策略
+// TokenManager 先按配额/冷却/熔断筛选出候选账号，再交由策略决定使用哪一个
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::proxy::config::SchedulingStrategy;
+
+/// 账号运行时活动 (最近使用时间、进行中的请求数)
+#[derive(Debug, Default)]
+pub struct AccountActivity {
+    last_used_ms: AtomicI64,
+    in_flight: AtomicUsize,
+}
+
+impl AccountActivity {
+    pub fn touch(&self, now_ms: i64) {
+        self.last_used_ms.store(now_ms, Ordering::SeqCst);
+    }
+
+    pub fn last_used_ms(&self) -> i64 {
+        self.last_used_ms.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+/// 进行中的请求占位，drop 时自动减少计数
+pub struct InFlightGuard {
+    activity: Arc<AccountActivity>,
+}
+
+impl InFlightGuard {
+    pub fn new(activity: Arc<AccountActivity>) -> Self {
+        activity.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self { activity }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.activity.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 参与调度的候选账号
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub account_id: String,
+    pub email: String,
+    pub headroom: i32,
+    pub last_used_ms: i64,
+    pub in_flight: usize,
+}
+
+/// 账号选择策略
+pub trait SelectionStrategy: Send + Sync {
+    /// `candidates` 非空且已按 account_id 排序，返回选中项的下标
+    fn select(&self, candidates: &[Candidate]) -> usize;
+}
+
+/// 轮询
+struct RoundRobin {
+    cursor: AtomicUsize,
+}
+
+impl SelectionStrategy for RoundRobin {
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        self.cursor.fetch_add(1, Ordering::SeqCst) % candidates.len()
+    }
+}
+
+/// 最久未使用优先
+struct LeastRecentlyUsed;
+
+impl SelectionStrategy for LeastRecentlyUsed {
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        index_of_min(candidates, |c| (c.last_used_ms, 0))
+    }
+}
+
+/// 进行中请求最少优先，相同时最久未使用优先
+struct LeastInFlight;
+
+impl SelectionStrategy for LeastInFlight {
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        index_of_min(candidates, |c| (c.in_flight as i64, c.last_used_ms))
+    }
+}
+
+/// 按剩余配额加权的平滑轮询 (smooth weighted round-robin)
+/// 每次选择时各候选的当前权重增加其剩余配额，选中当前权重最高者并减去本轮总权重，
+/// 因此各账号被选中的次数与剩余配额成正比且交错分布，结果确定
+struct WeightedQuota {
+    current: Mutex<HashMap<String, i64>>,  // account_id -> 当前权重
+}
+
+impl SelectionStrategy for WeightedQuota {
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        let Ok(mut current) = self.current.lock() else {
+            return 0;
+        };
+        // 不再是候选的账号 (冷却/耗尽/移除) 重新开始累计
+        current.retain(|id, _| candidates.iter().any(|c| &c.account_id == id));
+
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for (i, candidate) in candidates.iter().enumerate() {
+            let weight = i64::from(candidate.headroom.max(1));
+            total += weight;
+            let value = current.entry(candidate.account_id.clone()).or_insert(0);
+            *value += weight;
+            match best {
+                Some((_, max)) if *value <= max => {}
+                _ => best = Some((i, *value)),
+            }
+        }
+
+        let (idx, _) = best.unwrap_or((0, 0));
+        if let Some(value) = current.get_mut(&candidates[idx].account_id) {
+            *value -= total;
+        }
+        idx
+    }
+}
+
+/// 严格按配置顺序 (email 或账号 ID)，未列出的账号排在最后
+struct StrictPriority {
+    order: Vec<String>,
+}
+
+impl SelectionStrategy for StrictPriority {
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        index_of_min(candidates, |c| {
+            let rank = self
+                .order
+                .iter()
+                .position(|p| p == &c.email || p == &c.account_id)
+                .unwrap_or(self.order.len());
+            (rank as i64, 0)
+        })
+    }
+}
+
+/// 取排序键最小的候选，键相同时取下标最小者 (保证结果确定)
+fn index_of_min(candidates: &[Candidate], key: impl Fn(&Candidate) -> (i64, i64)) -> usize {
+    (0..candidates.len())
+        .min_by_key(|&i| (key(&candidates[i]), i))
+        .unwrap_or(0)
+}
+
+/// 根据配置构建调度策略
+pub fn build_strategy(kind: SchedulingStrategy, priority: &[String]) -> Arc<dyn SelectionStrategy> {
+    match kind {
+        SchedulingStrategy::RoundRobin => Arc::new(RoundRobin { cursor: AtomicUsize::new(0) }),
+        SchedulingStrategy::LeastRecentlyUsed => Arc::new(LeastRecentlyUsed),
+        SchedulingStrategy::LeastInFlight => Arc::new(LeastInFlight),
+        SchedulingStrategy::WeightedQuota => Arc::new(WeightedQuota { current: Mutex::new(HashMap::new()) }),
+        SchedulingStrategy::StrictPriority => Arc::new(StrictPriority { order: priority.to_vec() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: &str, headroom: i32, last_used_ms: i64, in_flight: usize) -> Candidate {
+        Candidate {
+            account_id: id.to_string(),
+            email: format!("{}@example.com", id),
+            headroom,
+            last_used_ms,
+            in_flight,
+        }
+    }
+
+    fn picks(strategy: &dyn SelectionStrategy, candidates: &[Candidate], n: usize) -> Vec<String> {
+        (0..n)
+            .map(|_| candidates[strategy.select(candidates)].account_id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_strategies_are_deterministic() {
+        let candidates = vec![
+            candidate("a", 50, 300, 2),
+            candidate("b", 90, 100, 1),
+            candidate("c", 90, 200, 1),
+        ];
+
+        let rr = build_strategy(SchedulingStrategy::RoundRobin, &[]);
+        assert_eq!(picks(rr.as_ref(), &candidates, 4), ["a", "b", "c", "a"]);
+
+        let lru = build_strategy(SchedulingStrategy::LeastRecentlyUsed, &[]);
+        assert_eq!(picks(lru.as_ref(), &candidates, 2), ["b", "b"]);
+
+        let lif = build_strategy(SchedulingStrategy::LeastInFlight, &[]);
+        assert_eq!(picks(lif.as_ref(), &candidates, 1), ["b"]);
+
+        let quota = build_strategy(SchedulingStrategy::WeightedQuota, &[]);
+        assert_eq!(picks(quota.as_ref(), &candidates, 5), ["b", "c", "a", "b", "c"]);
+
+        let priority = build_strategy(
+            SchedulingStrategy::StrictPriority,
+            &["c@example.com".to_string(), "a".to_string()],
+        );
+        assert_eq!(picks(priority.as_ref(), &candidates, 2), ["c", "c"]);
+        assert_eq!(picks(priority.as_ref(), &candidates[..2], 1), ["a"]);
+        assert_eq!(picks(priority.as_ref(), &candidates[1..2], 1), ["b"]);
+    }
+
+    #[test]
+    fn test_weighted_quota_distribution() {
+        let candidates = vec![
+            candidate("a", 100, 0, 0),
+            candidate("b", 99, 0, 0),
+            candidate("c", 25, 0, 0),
+        ];
+        let quota = build_strategy(SchedulingStrategy::WeightedQuota, &[]);
+
+        // 一个完整周期 (总权重 224 次) 内选中次数与剩余配额完全成比例
+        let picks = picks(quota.as_ref(), &candidates, 224 * 3);
+        let count = |id: &str| picks.iter().filter(|p| p.as_str() == id).count();
+        assert_eq!((count("a"), count("b"), count("c")), (300, 297, 75));
+
+        // 不会连续把流量压在同一个账号上
+        assert!(picks.windows(3).all(|w| !(w[0] == w[1] && w[1] == w[2])));
+    }
+
+    #[test]
+    fn test_in_flight_guard() {
+        let activity = Arc::new(AccountActivity::default());
+        let first = InFlightGuard::new(activity.clone());
+        let second = InFlightGuard::new(activity.clone());
+        assert_eq!(activity.in_flight(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(activity.in_flight(), 0);
+    }
+}
@@ -9,6 +9,17 @@ use tower_http::trace::TraceLayer;
 use std::sync::Arc;
 use tokio::sync::oneshot;
 use crate::proxy::TokenManager;
+use crate::proxy::middleware::auth::ApiKeyRegistry;
+use crate::proxy::middleware::policy::KeyPolicyManager;
+use crate::proxy::upstream::client::UpstreamClient;
+use crate::proxy::capture::CaptureStore;
+use crate::proxy::config::{MediaFetchConfig, RetryConfig};
+use crate::proxy::request_log::RequestLog;
+use crate::proxy::response_store::ResponseStore;
+use crate::proxy::file_store::FileStore;
+use crate::proxy::stats::StatsCollector;
+use crate::proxy::upstream::fixtures::UpstreamFixtures;
+use crate::proxy::upstream::timeout::UpstreamTimeouts;
 
 
 /// Axum 应用状态
@@ -18,13 +29,36 @@ pub struct AppState {
     pub anthropic_mapping: Arc<tokio::sync::RwLock<std::collections::HashMap<String, String>>>,
     pub openai_mapping: Arc<tokio::sync::RwLock<std::collections::HashMap<String, String>>>,
     pub custom_mapping: Arc<tokio::sync::RwLock<std::collections::HashMap<String, String>>>,
-    #[allow(dead_code)]
-    pub request_timeout: u64,  // API 请求超时(秒)
+    pub model_fallbacks: Arc<tokio::sync::RwLock<std::collections::HashMap<String, Vec<String>>>>,  // 模型降级链
     #[allow(dead_code)]
     pub thought_signature_map: Arc<tokio::sync::Mutex<std::collections::HashMap<String, String>>>, // 思维链签名映射 (ID -> Signature)
     #[allow(dead_code)]
     pub upstream_proxy: Arc<tokio::sync::RwLock<crate::proxy::config::UpstreamProxyConfig>>,
-    pub upstream: Arc<crate::proxy::upstream::client::UpstreamClient>,
+    pub upstream: Arc<UpstreamClient>,
+    pub api_keys: Arc<tokio::sync::RwLock<ApiKeyRegistry>>,  // 客户端 API Key 注册表
+    pub key_policies: Arc<KeyPolicyManager>,  // API Key 限流/并发/预算计数
+    pub stats: Arc<StatsCollector>,  // 请求统计
+    pub request_log: Option<Arc<RequestLog>>,  // 结构化请求日志 (数据库打开失败时为空)
+    pub capture: Arc<CaptureStore>,  // 请求/响应抓取
+    pub metrics_key: Arc<tokio::sync::RwLock<String>>,  // /metrics 访问密钥
+    pub retry: Arc<tokio::sync::RwLock<RetryConfig>>,  // 各协议的重试策略
+    pub responses: Arc<ResponseStore>,  // Responses API 对话存储
+    pub files: Arc<FileStore>,  // 生成文件 (图片) 存储
+    pub media_fetch: Arc<tokio::sync::RwLock<MediaFetchConfig>>,  // 远程媒体抓取白名单
+    pub listen_addr: std::net::SocketAddr,  // 代理监听地址 (用于拼接文件链接)
+    pub trust_forwarded_headers: Arc<tokio::sync::RwLock<bool>>,  // 是否信任反向代理转发头
+}
+
+impl AppState {
+    /// 按当前映射表解析客户端模型对应的上游模型
+    pub async fn resolve_model(&self, model: &str) -> String {
+        crate::proxy::common::model_mapping::resolve_model_route(
+            model,
+            &*self.custom_mapping.read().await,
+            &*self.openai_mapping.read().await,
+            &*self.anthropic_mapping.read().await,
+        )
+    }
 }
 
 /// Axum 服务器实例
@@ -33,7 +67,14 @@ pub struct AxumServer {
     anthropic_mapping: Arc<tokio::sync::RwLock<std::collections::HashMap<String, String>>>,
     openai_mapping: Arc<tokio::sync::RwLock<std::collections::HashMap<String, String>>>,
     custom_mapping: Arc<tokio::sync::RwLock<std::collections::HashMap<String, String>>>,
+    model_fallbacks: Arc<tokio::sync::RwLock<std::collections::HashMap<String, Vec<String>>>>,
     proxy_state: Arc<tokio::sync::RwLock<crate::proxy::config::UpstreamProxyConfig>>,
+    api_keys: Arc<tokio::sync::RwLock<ApiKeyRegistry>>,
+    metrics_key: Arc<tokio::sync::RwLock<String>>,
+    retry: Arc<tokio::sync::RwLock<RetryConfig>>,
+    media_fetch: Arc<tokio::sync::RwLock<MediaFetchConfig>>,
+    trust_forwarded_headers: Arc<tokio::sync::RwLock<bool>>,
+    upstream: Arc<UpstreamClient>,
 }
 
 impl AxumServer {
@@ -50,39 +91,108 @@ impl AxumServer {
             let mut m = self.custom_mapping.write().await;
             *m = config.custom_mapping.clone();
         }
-        tracing::info!("模型映射 (Anthropic/OpenAI/Custom) 已全量热更新");
+        {
+            let mut m = self.model_fallbacks.write().await;
+            *m = config.model_fallbacks.clone();
+        }
+        tracing::info!("模型映射 (Anthropic/OpenAI/Custom) 与降级链已全量热更新");
     }
 
     /// 更新代理配置
     pub async fn update_proxy(&self, new_config: crate::proxy::config::UpstreamProxyConfig) {
         let mut proxy = self.proxy_state.write().await;
+        self.upstream.set_proxy(&new_config);
         *proxy = new_config;
         tracing::info!("上游代理配置已热更新");
     }
+
+    /// 更新客户端 API Key 与 /metrics 密钥配置
+    pub async fn update_security(&self, config: &crate::proxy::config::ProxyConfig) {
+        let mut keys = self.api_keys.write().await;
+        *keys = ApiKeyRegistry::from_config(config);
+        *self.metrics_key.write().await = config.metrics_key.clone();
+        tracing::info!("API Key 鉴权配置已热更新");
+    }
+
+    /// 更新上游超时配置
+    pub async fn update_timeouts(&self, config: &crate::proxy::config::ProxyConfig) {
+        self.upstream.set_timeouts(UpstreamTimeouts::from_config(config));
+        tracing::info!("上游超时配置已热更新");
+    }
+
+    /// 更新重试策略
+    pub async fn update_retry(&self, config: &crate::proxy::config::ProxyConfig) {
+        *self.retry.write().await = config.retry.clone();
+        tracing::info!("重试策略已热更新");
+    }
+
+    /// 更新远程媒体抓取配置
+    pub async fn update_media_fetch(&self, config: &crate::proxy::config::ProxyConfig) {
+        *self.media_fetch.write().await = config.media_fetch.clone();
+        tracing::info!("远程媒体抓取配置已热更新");
+    }
+
+    /// 更新是否信任反向代理转发头
+    pub async fn update_forwarding(&self, config: &crate::proxy::config::ProxyConfig) {
+        *self.trust_forwarded_headers.write().await = config.trust_forwarded_headers;
+        tracing::info!("转发头信任配置已热更新");
+    }
+
+    /// 更新上游录制/回放模式
+    pub async fn update_fixtures(&self, config: &crate::proxy::config::ProxyConfig) -> Result<(), String> {
+        self.upstream.set_fixtures(UpstreamFixtures::from_config(&config.fixtures)?);
+        tracing::info!("上游录制/回放配置已热更新");
+        Ok(())
+    }
+
     /// 启动 Axum 服务器
     pub async fn start(
-        port: u16,
+        config: &crate::proxy::config::ProxyConfig,
         token_manager: Arc<TokenManager>,
-        anthropic_mapping: std::collections::HashMap<String, String>,
-        openai_mapping: std::collections::HashMap<String, String>,
-        custom_mapping: std::collections::HashMap<String, String>,
-        _request_timeout: u64,
-        upstream_proxy: crate::proxy::config::UpstreamProxyConfig,
+        stats: Arc<StatsCollector>,
+        request_log: Option<Arc<RequestLog>>,
+        capture: Arc<CaptureStore>,
     ) -> Result<(Self, tokio::task::JoinHandle<()>), String> {
-        let mapping_state = Arc::new(tokio::sync::RwLock::new(anthropic_mapping));
-        let openai_mapping_state = Arc::new(tokio::sync::RwLock::new(openai_mapping));
-        let custom_mapping_state = Arc::new(tokio::sync::RwLock::new(custom_mapping));
+        let port = config.port;
+        let upstream_proxy = config.upstream_proxy.clone();
+        let mapping_state = Arc::new(tokio::sync::RwLock::new(config.anthropic_mapping.clone()));
+        let openai_mapping_state = Arc::new(tokio::sync::RwLock::new(config.openai_mapping.clone()));
+        let custom_mapping_state = Arc::new(tokio::sync::RwLock::new(config.custom_mapping.clone()));
+        let model_fallbacks_state = Arc::new(tokio::sync::RwLock::new(config.model_fallbacks.clone()));
         let proxy_state = Arc::new(tokio::sync::RwLock::new(upstream_proxy.clone()));
+        let api_keys_state = Arc::new(tokio::sync::RwLock::new(ApiKeyRegistry::from_config(config)));
+        let metrics_key_state = Arc::new(tokio::sync::RwLock::new(config.metrics_key.clone()));
+        let retry_state = Arc::new(tokio::sync::RwLock::new(config.retry.clone()));
+        let media_fetch_state = Arc::new(tokio::sync::RwLock::new(config.media_fetch.clone()));
+        let forwarding_state = Arc::new(tokio::sync::RwLock::new(config.trust_forwarded_headers));
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        let upstream = Arc::new(UpstreamClient::new(
+            Some(upstream_proxy.clone()),
+            UpstreamTimeouts::from_config(config),
+        ));
+        upstream.set_fixtures(UpstreamFixtures::from_config(&config.fixtures)?);
 
         let state = AppState {
             token_manager: token_manager.clone(),
             anthropic_mapping: mapping_state.clone(),
             openai_mapping: openai_mapping_state.clone(),
             custom_mapping: custom_mapping_state.clone(),
-            request_timeout: 300, // 5分钟超时
+            model_fallbacks: model_fallbacks_state.clone(),
             thought_signature_map: Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new())),
             upstream_proxy: proxy_state.clone(),
-            upstream: Arc::new(crate::proxy::upstream::client::UpstreamClient::new(Some(upstream_proxy.clone()))),
+            upstream: upstream.clone(),
+            api_keys: api_keys_state.clone(),
+            key_policies: Arc::new(KeyPolicyManager::new(stats.clone())),
+            stats,
+            request_log,
+            capture,
+            metrics_key: metrics_key_state.clone(),
+            retry: retry_state.clone(),
+            responses: Arc::new(ResponseStore::default()),
+            files: Arc::new(FileStore::default()),
+            media_fetch: media_fetch_state.clone(),
+            listen_addr: addr,
+            trust_forwarded_headers: forwarding_state.clone(),
         };
         
         // 构建路由 - 使用新架构的 handlers！
@@ -92,6 +202,11 @@ impl AxumServer {
             // OpenAI Protocol
             .route("/v1/models", get(handlers::openai::handle_list_models))
             .route("/v1/chat/completions", post(handlers::openai::handle_chat_completions))
+            .route("/v1/embeddings", post(handlers::embeddings::handle_embeddings))
+            .route("/v1/responses", post(handlers::responses::handle_responses))
+            .route("/v1/images/generations", post(handlers::images::handle_generations))
+            .route("/v1/images/edits", post(handlers::images::handle_edits))
+            .route("/files/:id", get(handlers::images::handle_get_file))
             
             // Claude Protocol
             .route("/v1/messages", post(handlers::claude::handle_messages))
@@ -104,15 +219,17 @@ impl AxumServer {
             .route("/v1beta/models/:model", get(handlers::gemini::handle_get_model).post(handlers::gemini::handle_generate))
             .route("/v1beta/models/:model/countTokens", post(handlers::gemini::handle_count_tokens)) // Specific route priority
             .route("/healthz", get(health_check_handler))
+            .route("/metrics", get(handlers::metrics::handle_metrics))
             .layer(DefaultBodyLimit::max(100 * 1024 * 1024))
             .layer(TraceLayer::new_for_http())
-            .layer(axum::middleware::from_fn(crate::proxy::middleware::auth_middleware))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), crate::proxy::middleware::policy_middleware))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), crate::proxy::middleware::auth_middleware))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), crate::proxy::middleware::stats_middleware))
             .layer(crate::proxy::middleware::cors_layer())
             .with_state(state);
         
         // 绑定地址
-        let addr = format!("127.0.0.1:{}", port);
-        let listener = tokio::net::TcpListener::bind(&addr)
+        let listener = tokio::net::TcpListener::bind(addr)
             .await
             .map_err(|e| format!("端口 {} 绑定失败: {}", port, e))?;
         
@@ -126,7 +243,14 @@ impl AxumServer {
             anthropic_mapping: mapping_state.clone(),
             openai_mapping: openai_mapping_state.clone(),
             custom_mapping: custom_mapping_state.clone(),
+            model_fallbacks: model_fallbacks_state,
             proxy_state,
+            api_keys: api_keys_state,
+            metrics_key: metrics_key_state,
+            retry: retry_state,
+            media_fetch: media_fetch_state,
+            trust_forwarded_headers: forwarding_state,
+            upstream,
         };
         
         // 在新任务中启动服务器
@@ -0,0 +1,641 @@
+// 反代请求统计
+// 按小时分桶聚合，按协议 / 客户端模型 / 映射后模型 / 账号分别计数；连同各 API Key 当日 Token 用量落盘到 proxy_stats.json
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::proxy::common::usage::TokenUsage;
+use crate::proxy::metrics::PrometheusText;
+
+/// 统计分桶粒度
+const BUCKET_MS: i64 = 60 * 60 * 1000;
+/// 统计保留时长
+const RETENTION_MS: i64 = 30 * 24 * BUCKET_MS;
+/// 定期落盘间隔
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+/// 延迟直方图的桶上界 (毫秒)，超出最后一个上界的计入溢出桶
+const LATENCY_BOUNDS_MS: [u64; 12] = [
+    100, 250, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 30_000, 60_000, 120_000, 300_000,
+];
+
+/// 单次请求的记录 (同时用于统计聚合与请求日志)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RequestRecord {
+    pub request_id: String,
+    pub timestamp_ms: i64,
+    pub protocol: String,
+    pub client_model: Option<String>,
+    pub mapped_model: Option<String>,
+    /// 实际应答的模型 (触发模型降级时与 mapped_model 不同)
+    pub served_model: Option<String>,
+    pub account: Option<String>,
+    /// 是否为被识别并重定向的后台任务 (标题生成、摘要等)
+    pub background: bool,
+    pub status: u16,
+    pub retries: u32,
+    pub rotations: u32,
+    pub latency_ms: u64,
+    pub ttft_ms: Option<u64>,
+    pub usage: Option<TokenUsage>,
+}
+
+/// 固定分桶的延迟直方图，可跨时间桶合并
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Histogram {
+    counts: Vec<u64>,
+    #[serde(default)]
+    sum_ms: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value_ms: u64) {
+        if self.counts.len() != LATENCY_BOUNDS_MS.len() + 1 {
+            self.counts.resize(LATENCY_BOUNDS_MS.len() + 1, 0);
+        }
+        let slot = LATENCY_BOUNDS_MS
+            .iter()
+            .position(|bound| value_ms <= *bound)
+            .unwrap_or(LATENCY_BOUNDS_MS.len());
+        self.counts[slot] += 1;
+        self.sum_ms += value_ms;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (slot, count) in other.counts.iter().enumerate() {
+            self.counts[slot] += count;
+        }
+        self.sum_ms += other.sum_ms;
+    }
+
+    /// 分位数 (返回所在桶的上界，溢出桶返回最大上界)
+    fn percentile(&self, p: f64) -> Option<u64> {
+        let total: u64 = self.counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = ((total as f64) * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (slot, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Some(LATENCY_BOUNDS_MS[slot.min(LATENCY_BOUNDS_MS.len() - 1)]);
+            }
+        }
+        LATENCY_BOUNDS_MS.last().copied()
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        Percentiles {
+            p50: self.percentile(0.50),
+            p90: self.percentile(0.90),
+            p99: self.percentile(0.99),
+        }
+    }
+
+    /// 以秒为单位导出为 Prometheus 直方图
+    fn write_metrics(&self, text: &mut PrometheusText, name: &str, labels: &[(&str, &str)]) {
+        let mut cumulative = 0;
+        for (slot, bound) in LATENCY_BOUNDS_MS.iter().enumerate() {
+            cumulative += self.counts.get(slot).copied().unwrap_or(0);
+            let le = (*bound as f64 / 1000.0).to_string();
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            text.sample(&format!("{}_bucket", name), &bucket_labels, cumulative as f64);
+        }
+        let total: u64 = self.counts.iter().sum();
+        let mut inf_labels = labels.to_vec();
+        inf_labels.push(("le", "+Inf"));
+        text.sample(&format!("{}_bucket", name), &inf_labels, total as f64);
+        text.sample(&format!("{}_sum", name), labels, self.sum_ms as f64 / 1000.0);
+        text.sample(&format!("{}_count", name), labels, total as f64);
+    }
+}
+
+/// 一组请求的累计计数
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Counters {
+    requests: u64,
+    success: u64,
+    errors: u64,
+    status: BTreeMap<u16, u64>,
+    retries: u64,
+    rotations: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    thinking_tokens: u64,
+    latency: Histogram,
+    ttft: Histogram,
+}
+
+impl Counters {
+    fn observe(&mut self, record: &RequestRecord) {
+        self.requests += 1;
+        if (200..300).contains(&record.status) {
+            self.success += 1;
+        } else {
+            self.errors += 1;
+        }
+        *self.status.entry(record.status).or_insert(0) += 1;
+        self.retries += record.retries as u64;
+        self.rotations += record.rotations as u64;
+        if let Some(usage) = record.usage {
+            self.input_tokens += usage.input_tokens;
+            self.output_tokens += usage.output_tokens;
+            self.thinking_tokens += usage.thinking_tokens;
+        }
+        self.latency.observe(record.latency_ms);
+        if let Some(ttft) = record.ttft_ms {
+            self.ttft.observe(ttft);
+        }
+    }
+
+    fn merge(&mut self, other: &Counters) {
+        self.requests += other.requests;
+        self.success += other.success;
+        self.errors += other.errors;
+        for (status, count) in &other.status {
+            *self.status.entry(*status).or_insert(0) += count;
+        }
+        self.retries += other.retries;
+        self.rotations += other.rotations;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.thinking_tokens += other.thinking_tokens;
+        self.latency.merge(&other.latency);
+        self.ttft.merge(&other.ttft);
+    }
+
+    fn summary(&self) -> StatsSummary {
+        StatsSummary {
+            total_requests: self.requests,
+            success_count: self.success,
+            error_count: self.errors,
+            status_counts: self.status.clone(),
+            retries: self.retries,
+            rotations: self.rotations,
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens,
+            thinking_tokens: self.thinking_tokens,
+            latency_ms: self.latency.percentiles(),
+            ttft_ms: self.ttft.percentiles(),
+        }
+    }
+}
+
+/// 一个小时内的统计
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StatsBucket {
+    total: Counters,
+    by_protocol: BTreeMap<String, Counters>,
+    by_model: BTreeMap<String, Counters>,
+    by_mapped_model: BTreeMap<String, Counters>,
+    by_account: BTreeMap<String, Counters>,
+}
+
+impl StatsBucket {
+    fn observe(&mut self, record: &RequestRecord) {
+        self.total.observe(record);
+        self.by_protocol.entry(record.protocol.clone()).or_default().observe(record);
+        if let Some(model) = &record.client_model {
+            self.by_model.entry(model.clone()).or_default().observe(record);
+        }
+        if let Some(model) = &record.mapped_model {
+            self.by_mapped_model.entry(model.clone()).or_default().observe(record);
+        }
+        if let Some(account) = &record.account {
+            self.by_account.entry(account.clone()).or_default().observe(record);
+        }
+    }
+
+    fn merge(&mut self, other: &StatsBucket) {
+        self.total.merge(&other.total);
+        for (mine, theirs) in [
+            (&mut self.by_protocol, &other.by_protocol),
+            (&mut self.by_model, &other.by_model),
+            (&mut self.by_mapped_model, &other.by_mapped_model),
+            (&mut self.by_account, &other.by_account),
+        ] {
+            for (key, counters) in theirs {
+                mine.entry(key.clone()).or_default().merge(counters);
+            }
+        }
+    }
+}
+
+/// 延迟分位数 (毫秒，取直方图桶上界，无数据时为空)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub p50: Option<u64>,
+    pub p90: Option<u64>,
+    pub p99: Option<u64>,
+}
+
+/// 一组请求的统计汇总
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatsSummary {
+    pub total_requests: u64,
+    pub success_count: u64,
+    pub error_count: u64,
+    pub status_counts: BTreeMap<u16, u64>,
+    pub retries: u64,
+    pub rotations: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub thinking_tokens: u64,
+    pub latency_ms: Percentiles,
+    pub ttft_ms: Percentiles,
+}
+
+/// 反代服务统计 (时间窗口内)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProxyStats {
+    #[serde(flatten)]
+    pub total: StatsSummary,
+    pub since_ms: Option<i64>,
+    pub until_ms: Option<i64>,
+    pub by_protocol: BTreeMap<String, StatsSummary>,
+    pub by_model: BTreeMap<String, StatsSummary>,
+    pub by_mapped_model: BTreeMap<String, StatsSummary>,
+    pub by_account: BTreeMap<String, StatsSummary>,
+}
+
+fn summarize(groups: &BTreeMap<String, Counters>) -> BTreeMap<String, StatsSummary> {
+    groups.iter().map(|(key, counters)| (key.clone(), counters.summary())).collect()
+}
+
+/// API Key 当日已用 Token (按 UTC 自然日，用于每日预算)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyDailyUsage {
+    /// UTC 日期 (YYYY-MM-DD)
+    day: String,
+    tokens: u64,
+}
+
+/// 统计文件内容
+#[derive(Default, Serialize, Deserialize)]
+struct StatsSnapshot {
+    buckets: BTreeMap<i64, StatsBucket>,
+    #[serde(default)]
+    key_usage: BTreeMap<String, KeyDailyUsage>,
+}
+
+fn utc_today() -> String {
+    chrono::Utc::now().date_naive().to_string()
+}
+
+/// 进程启动以来的累计计数，供 /metrics 导出
+/// 只增不减 (不按保留期清理，也不落盘)，满足 Prometheus counter 语义
+#[derive(Default)]
+struct LifetimeCounters {
+    requests: StatsBucket,
+    /// (上游状态码, 账号) -> 上游响应数，包含已被重试的失败尝试
+    upstream: BTreeMap<(String, String), u64>,
+}
+
+/// 请求统计收集器 (独立于反代实例，服务重启后保留)
+pub struct StatsCollector {
+    buckets: Mutex<BTreeMap<i64, StatsBucket>>,  // 小时起始时间 (Unix 毫秒) -> 统计
+    key_usage: Mutex<BTreeMap<String, KeyDailyUsage>>,  // API Key 名称 -> 当日 Token 用量
+    lifetime: Mutex<LifetimeCounters>,
+    path: Option<PathBuf>,
+    dirty: AtomicBool,
+    persist_started: AtomicBool,
+}
+
+impl StatsCollector {
+    /// 创建收集器，`path` 存在时从中加载历史统计与当日 API Key 用量
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let snapshot = path
+            .as_ref()
+            .and_then(|p| std::fs::read_to_string(p).ok())
+            .and_then(|content| match serde_json::from_str::<StatsSnapshot>(&content) {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    tracing::warn!("解析反代统计文件失败，将重新统计: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        Self {
+            buckets: Mutex::new(snapshot.buckets),
+            key_usage: Mutex::new(snapshot.key_usage),
+            lifetime: Mutex::new(LifetimeCounters::default()),
+            path,
+            dirty: AtomicBool::new(false),
+            persist_started: AtomicBool::new(false),
+        }
+    }
+
+    /// 记录一次请求，同时清理超出保留期的分桶
+    pub fn record(&self, record: &RequestRecord) {
+        let bucket_start = record.timestamp_ms - record.timestamp_ms.rem_euclid(BUCKET_MS);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.entry(bucket_start).or_default().observe(record);
+        buckets.retain(|start, _| *start > record.timestamp_ms - RETENTION_MS);
+        self.dirty.store(true, Ordering::SeqCst);
+        drop(buckets);
+
+        let mut lifetime = self.lifetime.lock().unwrap_or_else(|e| e.into_inner());
+        lifetime.requests.observe(record);
+    }
+
+    /// 累加 API Key 当日消耗的 Token (跨 UTC 零点时重新计数)
+    pub fn record_key_tokens(&self, key_name: &str, tokens: u64) {
+        let today = utc_today();
+        let mut key_usage = self.key_usage.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = key_usage
+            .entry(key_name.to_string())
+            .or_insert_with(|| KeyDailyUsage { day: today.clone(), tokens: 0 });
+        if entry.day != today {
+            entry.day = today;
+            entry.tokens = 0;
+        }
+        entry.tokens += tokens;
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// API Key 当日已用 Token
+    pub fn key_tokens_today(&self, key_name: &str) -> u64 {
+        let today = utc_today();
+        let key_usage = self.key_usage.lock().unwrap_or_else(|e| e.into_inner());
+        key_usage
+            .get(key_name)
+            .filter(|u| u.day == today)
+            .map(|u| u.tokens)
+            .unwrap_or(0)
+    }
+
+    /// 记录一次上游响应 (每次尝试各计一次)，`status` 为空表示网络错误
+    pub fn record_upstream_response(&self, status: Option<u16>, account: &str) {
+        let status = status.map_or_else(|| "error".to_string(), |s| s.to_string());
+        let mut lifetime = self.lifetime.lock().unwrap_or_else(|e| e.into_inner());
+        *lifetime.upstream.entry((status, account.to_string())).or_insert(0) += 1;
+    }
+
+    /// 查询时间窗口内的统计，窗口按小时分桶对齐 (与窗口有交集的分桶均计入)
+    pub fn query(&self, since_ms: Option<i64>, until_ms: Option<i64>) -> ProxyStats {
+        let merged = self.merged(since_ms, until_ms);
+        ProxyStats {
+            total: merged.total.summary(),
+            since_ms,
+            until_ms,
+            by_protocol: summarize(&merged.by_protocol),
+            by_model: summarize(&merged.by_model),
+            by_mapped_model: summarize(&merged.by_mapped_model),
+            by_account: summarize(&merged.by_account),
+        }
+    }
+
+    fn merged(&self, since_ms: Option<i64>, until_ms: Option<i64>) -> StatsBucket {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut merged = StatsBucket::default();
+        for (start, bucket) in buckets.iter() {
+            let after_since = since_ms.is_none_or(|since| start + BUCKET_MS > since);
+            let before_until = until_ms.is_none_or(|until| *start <= until);
+            if after_since && before_until {
+                merged.merge(bucket);
+            }
+        }
+        merged
+    }
+
+    /// 导出请求相关的 Prometheus 指标 (进程启动以来的累计值)
+    pub fn write_metrics(&self, text: &mut PrometheusText) {
+        let (merged, upstream) = {
+            let lifetime = self.lifetime.lock().unwrap_or_else(|e| e.into_inner());
+            (lifetime.requests.clone(), lifetime.upstream.clone())
+        };
+
+        text.family("requests_total", "counter", "Proxied requests by client protocol and response status.");
+        for (protocol, counters) in &merged.by_protocol {
+            for (status, count) in &counters.status {
+                let status = status.to_string();
+                text.sample("requests_total", &[("protocol", protocol), ("status", &status)], *count as f64);
+            }
+        }
+
+        text.family("model_requests_total", "counter", "Proxied requests by upstream model and response status.");
+        for (model, counters) in &merged.by_mapped_model {
+            for (status, count) in &counters.status {
+                let status = status.to_string();
+                text.sample("model_requests_total", &[("model", model), ("status", &status)], *count as f64);
+            }
+        }
+
+        text.family("account_requests_total", "counter", "Proxied requests by account and response status.");
+        for (account, counters) in &merged.by_account {
+            for (status, count) in &counters.status {
+                let status = status.to_string();
+                text.sample("account_requests_total", &[("account", account), ("status", &status)], *count as f64);
+            }
+        }
+
+        text.family("upstream_responses_total", "counter", "Upstream responses per attempt, including retried failures (status=\"error\" for transport errors).");
+        for ((status, account), count) in &upstream {
+            text.sample("upstream_responses_total", &[("status", status), ("account", account)], *count as f64);
+        }
+
+        text.family("retries_total", "counter", "Extra upstream attempts made after the first one.");
+        for (protocol, counters) in &merged.by_protocol {
+            text.sample("retries_total", &[("protocol", protocol)], counters.retries as f64);
+        }
+
+        text.family("rotations_total", "counter", "Times a request switched to a different account.");
+        for (protocol, counters) in &merged.by_protocol {
+            text.sample("rotations_total", &[("protocol", protocol)], counters.rotations as f64);
+        }
+
+        text.family("tokens_total", "counter", "Tokens reported by upstream usageMetadata.");
+        for (protocol, counters) in &merged.by_protocol {
+            for (kind, value) in [
+                ("input", counters.input_tokens),
+                ("output", counters.output_tokens),
+                ("thinking", counters.thinking_tokens),
+            ] {
+                text.sample("tokens_total", &[("protocol", protocol), ("type", kind)], value as f64);
+            }
+        }
+
+        text.family("request_duration_seconds", "histogram", "End-to-end request latency, including streaming.");
+        for (protocol, counters) in &merged.by_protocol {
+            counters.latency.write_metrics(text, "request_duration_seconds", &[("protocol", protocol)]);
+        }
+
+        text.family("time_to_first_token_seconds", "histogram", "Time until the first streamed chunk reached the client.");
+        for (protocol, counters) in &merged.by_protocol {
+            counters.ttft.write_metrics(text, "time_to_first_token_seconds", &[("protocol", protocol)]);
+        }
+    }
+
+    /// 写入统计文件 (无变更时跳过)
+    pub fn persist(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let content = {
+            let today = utc_today();
+            let snapshot = StatsSnapshot {
+                buckets: self.buckets.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+                key_usage: self
+                    .key_usage
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .iter()
+                    .filter(|(_, u)| u.day == today)
+                    .map(|(name, u)| (name.clone(), u.clone()))
+                    .collect(),
+            };
+            serde_json::to_string(&snapshot).map_err(|e| format!("序列化反代统计失败: {}", e))?
+        };
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(|e| {
+                self.dirty.store(true, Ordering::SeqCst);
+                format!("保存反代统计失败: {}", e)
+            })
+    }
+
+    /// 启动后台定期落盘 (重复调用只启动一次)
+    pub fn start_auto_persist(self: &Arc<Self>) {
+        if self.persist_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let weak = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PERSIST_INTERVAL).await;
+                let Some(collector) = weak.upgrade() else {
+                    break;
+                };
+                if let Err(e) = collector.persist() {
+                    tracing::warn!("{}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp_ms: i64, status: u16, latency_ms: u64) -> RequestRecord {
+        RequestRecord {
+            timestamp_ms,
+            protocol: "claude".to_string(),
+            client_model: Some("claude-sonnet-4-5".to_string()),
+            mapped_model: Some("claude-sonnet-4-5-thinking".to_string()),
+            account: Some("a@example.com".to_string()),
+            status,
+            retries: 1,
+            rotations: 1,
+            latency_ms,
+            ttft_ms: Some(latency_ms / 2),
+            usage: Some(TokenUsage { input_tokens: 10, output_tokens: 5, thinking_tokens: 2 }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_record_and_query_window() {
+        let collector = StatsCollector::new(None);
+        collector.record(&record(0, 200, 80));
+        collector.record(&record(1_000, 429, 900));
+        collector.record(&record(BUCKET_MS * 3, 200, 4_000));
+
+        let all = collector.query(None, None);
+        assert_eq!(all.total.total_requests, 3);
+        assert_eq!(all.total.success_count, 2);
+        assert_eq!(all.total.error_count, 1);
+        assert_eq!(all.total.status_counts.get(&429), Some(&1));
+        assert_eq!(all.total.retries, 3);
+        assert_eq!(all.total.input_tokens, 30);
+        assert_eq!(all.by_protocol["claude"].total_requests, 3);
+        assert_eq!(all.by_mapped_model["claude-sonnet-4-5-thinking"].thinking_tokens, 6);
+        assert_eq!(all.by_account["a@example.com"].rotations, 3);
+        assert_eq!(all.total.latency_ms.p50, Some(1_000));
+        assert_eq!(all.total.latency_ms.p99, Some(5_000));
+
+        let recent = collector.query(Some(BUCKET_MS * 2), None);
+        assert_eq!(recent.total.total_requests, 1);
+        assert_eq!(recent.total.ttft_ms.p50, Some(2_000));
+
+        let early = collector.query(None, Some(BUCKET_MS));
+        assert_eq!(early.total.total_requests, 2);
+    }
+
+    #[test]
+    fn test_persist_and_reload() {
+        let path = std::env::temp_dir().join(format!("proxy_stats_{}.json", uuid::Uuid::new_v4()));
+        let collector = StatsCollector::new(Some(path.clone()));
+        collector.record(&record(0, 200, 80));
+        collector.persist().unwrap();
+
+        let reloaded = StatsCollector::new(Some(path.clone()));
+        assert_eq!(reloaded.query(None, None).total.total_requests, 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_key_usage_survives_restart() {
+        let path = std::env::temp_dir().join(format!("proxy_stats_{}.json", uuid::Uuid::new_v4()));
+        let collector = StatsCollector::new(Some(path.clone()));
+        collector.record_key_tokens("alice", 120);
+        collector.record_key_tokens("alice", 30);
+        collector.persist().unwrap();
+
+        let reloaded = StatsCollector::new(Some(path.clone()));
+        assert_eq!(reloaded.key_tokens_today("alice"), 150);
+        assert_eq!(reloaded.key_tokens_today("bob"), 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_write_metrics() {
+        let collector = StatsCollector::new(None);
+        collector.record(&record(0, 200, 80));
+        collector.record(&record(0, 429, 900));
+
+        let mut text = PrometheusText::new();
+        collector.write_metrics(&mut text);
+        let out = text.finish();
+
+        assert!(out.contains("antigravity_proxy_requests_total{protocol=\"claude\",status=\"429\"} 1\n"));
+        assert!(out.contains("antigravity_proxy_request_duration_seconds_bucket{protocol=\"claude\",le=\"0.1\"} 1\n"));
+        assert!(out.contains("antigravity_proxy_request_duration_seconds_bucket{protocol=\"claude\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("antigravity_proxy_request_duration_seconds_sum{protocol=\"claude\"} 0.98\n"));
+        assert!(out.contains("antigravity_proxy_tokens_total{protocol=\"claude\",type=\"thinking\"} 4\n"));
+    }
+
+    #[test]
+    fn test_metrics_survive_retention_pruning() {
+        let collector = StatsCollector::new(None);
+        collector.record(&record(0, 200, 80));
+        collector.record(&record(RETENTION_MS * 2, 200, 80));
+        collector.record_upstream_response(Some(429), "a@example.com");
+        collector.record_upstream_response(Some(200), "b@example.com");
+        collector.record_upstream_response(None, "b@example.com");
+
+        // 界面统计按保留期清理，导出的计数器不回退
+        assert_eq!(collector.query(None, None).total.total_requests, 1);
+        let mut text = PrometheusText::new();
+        collector.write_metrics(&mut text);
+        let out = text.finish();
+        assert!(out.contains("antigravity_proxy_requests_total{protocol=\"claude\",status=\"200\"} 2\n"));
+        assert!(out.contains("antigravity_proxy_upstream_responses_total{status=\"429\",account=\"a@example.com\"} 1\n"));
+        assert!(out.contains("antigravity_proxy_upstream_responses_total{status=\"error\",account=\"b@example.com\"} 1\n"));
+    }
+}
@@ -1,8 +1,32 @@
 // 移除冗余的顶层导入，因为这些在代码中已由 full path 或局部导入处理
 use dashmap::DashMap;
+use std::collections::HashSet;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+use crate::models::QuotaData;
+use crate::proxy::common::fingerprint::AccountIdentity;
+use crate::proxy::account_health::{AccountHealthStatus, AccountHealthTracker};
+use crate::proxy::config::{ProxyConfig, SchedulingStrategy};
+use crate::proxy::scheduler::{build_strategy, AccountActivity, Candidate, InFlightGuard, SelectionStrategy};
+
+/// 429 未携带 retry delay 时的默认冷却时长
+const DEFAULT_RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+/// 403 (权限/地区限制) 的冷却时长
+const FORBIDDEN_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+/// 会话亲和绑定的空闲过期时间
+const SESSION_TTL: Duration = Duration::from_secs(30 * 60);
+/// 请求时 token 剩余有效期低于该值则同步刷新
+const REFRESH_AHEAD_SECS: i64 = 300;
+/// 后台刷新提前量与巡检间隔
+const BACKGROUND_REFRESH_AHEAD_SECS: i64 = 600;
+const BACKGROUND_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// 账号目录变更检测间隔
+const ACCOUNT_SYNC_INTERVAL: Duration = Duration::from_secs(3);
+/// 熔断后尝试刷新 token 的次数与间隔
+const RECOVERY_ATTEMPTS: u32 = 3;
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone)]
 pub struct ProxyToken {
@@ -14,12 +38,49 @@ pub struct ProxyToken {
     pub email: String,
     pub account_path: PathBuf,  // 账号文件路径，用于更新
     pub project_id: Option<String>,
+    pub quota: Option<QuotaData>,  // 最近一次获取的配额快照
+    pub egress_proxy: Option<String>,  // 账号专属出口代理
+    pub client_profile: Option<String>,  // 客户端指纹名称
+}
+
+impl ProxyToken {
+    /// 访问 Google 时使用的出口代理与客户端指纹
+    pub fn identity(&self) -> AccountIdentity {
+        AccountIdentity::new(self.egress_proxy.as_deref(), self.client_profile.as_deref())
+    }
+}
+
+/// 获取 Token 失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    /// 账号池为空，或配额组内已没有可用账号
+    NoAccount(String),
+    /// 选中的账号刷新 token 或获取 project_id 失败，其他账号仍可尝试
+    Account { account_id: String, message: String },
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoAccount(message) => write!(f, "{}", message),
+            Self::Account { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+/// 会话与账号的亲和绑定
+#[derive(Debug, Clone)]
+struct SessionBinding {
+    account_id: String,
+    last_seen: std::time::Instant,
 }
 
 pub struct TokenManager {
     tokens: Arc<DashMap<String, ProxyToken>>,  // account_id -> ProxyToken
-    current_index: Arc<AtomicUsize>,
-    last_used_account: Arc<tokio::sync::Mutex<Option<(String, std::time::Instant)>>>,
+    strategy: std::sync::RwLock<Arc<dyn SelectionStrategy>>,  // 账号调度策略 (可热切换)
+    activity: Arc<DashMap<String, Arc<AccountActivity>>>,  // account_id -> 最近使用/进行中请求
+    health: Arc<AccountHealthTracker>,  // 冷却与熔断状态
+    sessions: Arc<DashMap<String, SessionBinding>>,  // session key -> 账号
     data_dir: PathBuf,
 }
 
@@ -28,13 +89,17 @@ impl TokenManager {
     pub fn new(data_dir: PathBuf) -> Self {
         Self {
             tokens: Arc::new(DashMap::new()),
-            current_index: Arc::new(AtomicUsize::new(0)),
-            last_used_account: Arc::new(tokio::sync::Mutex::new(None)),
+            strategy: std::sync::RwLock::new(build_strategy(SchedulingStrategy::default(), &[])),
+            activity: Arc::new(DashMap::new()),
+            health: Arc::new(AccountHealthTracker::new()),
+            sessions: Arc::new(DashMap::new()),
             data_dir,
         }
     }
     
     /// 从主应用账号目录加载所有账号
+    /// 与账号目录保持一致：新增/更新文件中的账号，移除已删除或不在账号索引中的账号
+    /// 进行中的请求持有 token 副本，不受移除影响
     pub async fn load_accounts(&self) -> Result<usize, String> {
         let accounts_dir = self.data_dir.join("accounts");
         
@@ -45,7 +110,9 @@ impl TokenManager {
         let entries = std::fs::read_dir(&accounts_dir)
             .map_err(|e| format!("读取账号目录失败: {}", e))?;
         
-        let mut count = 0;
+        // 账号索引存在时，只保留索引中的账号 (删除账号时先更新索引再删文件)
+        let indexed_ids = self.load_indexed_ids();
+        let mut loaded = std::collections::HashSet::new();
         
         for entry in entries {
             let entry = entry.map_err(|e| format!("读取目录项失败: {}", e))?;
@@ -58,9 +125,11 @@ impl TokenManager {
             // 尝试加载账号
             match self.load_single_account(&path).await {
                 Ok(Some(token)) => {
-                    let account_id = token.account_id.clone();
-                    self.tokens.insert(account_id, token);
-                    count += 1;
+                    if indexed_ids.as_ref().is_some_and(|ids| !ids.contains(&token.account_id)) {
+                        continue;
+                    }
+                    loaded.insert(token.account_id.clone());
+                    self.upsert_token(token);
                 },
                 Ok(None) => {
                     // 跳过无效账号
@@ -71,7 +140,99 @@ impl TokenManager {
             }
         }
         
-        Ok(count)
+        let removed: Vec<String> = self.tokens.iter()
+            .filter(|t| !loaded.contains(&t.account_id))
+            .map(|t| t.account_id.clone())
+            .collect();
+        for account_id in removed {
+            self.remove_account(&account_id);
+        }
+        
+        Ok(self.tokens.len())
+    }
+    
+    /// 读取账号索引 (accounts.json) 中的账号 ID，索引不存在或无法解析时返回 None
+    fn load_indexed_ids(&self) -> Option<std::collections::HashSet<String>> {
+        let content = std::fs::read_to_string(self.data_dir.join("accounts.json")).ok()?;
+        let index: crate::models::AccountIndex = serde_json::from_str(&content).ok()?;
+        Some(index.accounts.into_iter().map(|a| a.id).collect())
+    }
+    
+    /// 合并账号文件中的数据，内存中的 token 更新时保留内存版本
+    /// 账号文件带来新的 access token 时关闭熔断
+    fn upsert_token(&self, mut token: ProxyToken) {
+        if let Some(existing) = self.tokens.get(&token.account_id) {
+            if existing.timestamp > token.timestamp {
+                token.access_token = existing.access_token.clone();
+                token.refresh_token = existing.refresh_token.clone();
+                token.expires_in = existing.expires_in;
+                token.timestamp = existing.timestamp;
+            }
+            if token.project_id.is_none() {
+                token.project_id = existing.project_id.clone();
+            }
+            if token.access_token != existing.access_token {
+                self.health.close_circuit(&token.account_id);
+            }
+        } else {
+            tracing::info!("账号池新增账号: {}", token.email);
+        }
+        self.tokens.insert(token.account_id.clone(), token);
+    }
+    
+    /// 从账号池移除账号，并清理其会话绑定与运行时状态
+    pub fn remove_account(&self, account_id: &str) {
+        if let Some((_, token)) = self.tokens.remove(account_id) {
+            tracing::info!("账号池移除账号: {}", token.email);
+        }
+        self.sessions.retain(|_, b| b.account_id != account_id);
+        self.activity.remove(account_id);
+    }
+    
+    /// 账号目录与索引文件的变更指纹 (文件名、修改时间、大小)
+    fn accounts_fingerprint(&self) -> Vec<(PathBuf, Option<std::time::SystemTime>, u64)> {
+        let index_path = self.data_dir.join("accounts.json");
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(self.data_dir.join("accounts"))
+            .map(|entries| entries.filter_map(|e| e.ok().map(|e| e.path())).collect())
+            .unwrap_or_default();
+        paths.push(index_path);
+        paths.sort();
+
+        paths.into_iter()
+            .map(|path| {
+                let meta = std::fs::metadata(&path).ok();
+                let modified = meta.as_ref().and_then(|m| m.modified().ok());
+                let len = meta.map(|m| m.len()).unwrap_or(0);
+                (path, modified, len)
+            })
+            .collect()
+    }
+    
+    /// 启动账号目录同步任务，检测到变更时重新加载账号池
+    /// 任务持有弱引用，TokenManager 释放后自动退出
+    pub fn start_account_sync(self: &Arc<Self>) {
+        let weak = Arc::downgrade(self);
+        let mut last_fingerprint = self.accounts_fingerprint();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(ACCOUNT_SYNC_INTERVAL).await;
+                let Some(manager) = weak.upgrade() else {
+                    break;
+                };
+
+                let fingerprint = manager.accounts_fingerprint();
+                if fingerprint == last_fingerprint {
+                    continue;
+                }
+                last_fingerprint = fingerprint;
+
+                match manager.load_accounts().await {
+                    Ok(count) => tracing::debug!("账号目录已变更，账号池同步完成: {} 个账号", count),
+                    Err(e) => tracing::warn!("账号池同步失败: {}", e),
+                }
+            }
+        });
     }
     
     /// 加载单个账号
@@ -112,6 +273,21 @@ impl TokenManager {
             .and_then(|v| v.as_str())
             .map(|s| s.to_string());
         
+        // quota 是可选的，解析失败时视为未知配额
+        let quota = account.get("quota")
+            .filter(|v| !v.is_null())
+            .and_then(|v| serde_json::from_value::<QuotaData>(v.clone()).ok());
+        
+        let egress_proxy = account.get("egress_proxy")
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.trim().to_string());
+
+        let client_profile = account.get("client_profile")
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string());
+        
         Ok(Some(ProxyToken {
             account_id,
             access_token,
@@ -121,78 +297,61 @@ impl TokenManager {
             email,
             account_path: path.clone(),
             project_id,
+            quota,
+            egress_proxy,
+            client_profile,
         }))
     }
     
-    /// 获取当前可用的 Token（带 60s 时间窗口锁定机制）
-    /// 参数 `_quota_group` 用于区分 "claude" vs "gemini" 组
-    /// 参数 `_session_id` 已废弃，现使用全局时间窗口进行平滑锁定
-    pub async fn get_token(&self, _quota_group: &str, _session_id: Option<&str>) -> Result<(String, String, String), String> {
+    /// 获取当前可用的 Token
+    /// 参数 `quota_group` 用于区分 "claude" vs "gemini" 组，只在该组仍有配额的账号中选择
+    /// 参数 `session_id` 为会话亲和键：同一会话固定使用同一账号，账号不可用时自动切换并重新绑定
+    /// 未绑定的请求由当前调度策略 (`ProxyConfig::scheduling_strategy`) 选择账号
+    /// 参数 `exclude` 为本次请求已失败的账号 ID：既不参与调度也不复用会话绑定，会话改绑到新选中的账号
+    /// 选中账号自身的失败 (刷新 token / 获取 project_id) 返回 `TokenError::Account`，调用方可排除该账号后重试
+    pub async fn get_token(&self, quota_group: &str, session_id: Option<&str>, exclude: &HashSet<String>) -> Result<(String, String, String), TokenError> {
         let total = self.tokens.len();
         if total == 0 {
-            return Err("Token pool is empty".to_string());
+            return Err(TokenError::NoAccount("Token pool is empty".to_string()));
         }
 
-        // 1. 检查时间窗口锁定 (60秒内强制复用上一个账号)
-        let mut target_token = None;
-        {
-            let last_used = self.last_used_account.lock().await;
-            if let Some((account_id, last_time)) = &*last_used {
-                if last_time.elapsed().as_secs() < 60 {
-                    if let Some(entry) = self.tokens.get(account_id) {
-                        tracing::info!("60s 时间窗口内，强制复用上一个账号: {}", entry.email);
-                        target_token = Some(entry.value().clone());
-                    }
-                }
-            }
-        }
+        let now = chrono::Utc::now().timestamp();
+        let now_ms = chrono::Utc::now().timestamp_millis();
 
-        // 2. 如果没有锁定或锁定失效，则进行轮询记录并更新锁定信息
+        // 1. 会话亲和：复用已绑定的账号，前提是该账号在当前配额组仍可用且本次请求未在其上失败
+        let target_token = session_id
+            .and_then(|sid| self.sticky_token(sid, quota_group, now, now_ms))
+            .filter(|t| !exclude.contains(&t.account_id));
+
+        // 2. 如果没有绑定或绑定账号不可用，则按调度策略选择账号并更新绑定
         let mut token = if let Some(t) = target_token {
+            tracing::info!("会话复用已绑定账号: {}", t.email);
             t
         } else {
-            // 简单轮换策略 (Round Robin)
-            let idx = self.current_index.fetch_add(1, Ordering::SeqCst) % total;
-            let selected_token = self.tokens.iter()
-                .nth(idx)
-                .map(|entry| entry.value().clone())
-                .ok_or("Failed to retrieve token from pool")?;
+            let selected_token = self.select_account(quota_group, exclude, now, now_ms).map_err(TokenError::NoAccount)?;
             
-            // 更新最后使用的账号及时间
-            let mut last_used = self.last_used_account.lock().await;
-            *last_used = Some((selected_token.account_id.clone(), std::time::Instant::now()));
+            if let Some(sid) = session_id {
+                self.bind_session(sid, &selected_token.account_id);
+            }
             
-            tracing::info!("时间窗口过期或新请求，切换到账号: {}", selected_token.email);
+            tracing::info!("调度策略选择账号: {}", selected_token.email);
             selected_token
         };
+        self.activity_of(&token.account_id).touch(now_ms);
         
-        // 3. 检查 token 是否过期（提前5分钟刷新）
-        let now = chrono::Utc::now().timestamp();
-        if now >= token.timestamp - 300 {
+        // 3. 检查 token 是否过期（提前5分钟刷新，同一账号的并发刷新会被合并）
+        if now >= token.timestamp - REFRESH_AHEAD_SECS {
             tracing::info!("账号 {} 的 token 即将过期，正在刷新...", token.email);
-            
-            // 调用 OAuth 刷新 token
-            match crate::modules::oauth::refresh_access_token(&token.refresh_token).await {
-                Ok(token_response) => {
-                    tracing::info!("Token 刷新成功！");
-                    
-                    // 更新本地内存对象供后续使用
-                    token.access_token = token_response.access_token.clone();
-                    token.expires_in = token_response.expires_in;
-                    token.timestamp = now + token_response.expires_in;
-                    
-                    // 同步更新跨线程共享的 DashMap
-                    if let Some(mut entry) = self.tokens.get_mut(&token.account_id) {
-                        entry.access_token = token.access_token.clone();
-                        entry.expires_in = token.expires_in;
-                        entry.timestamp = token.timestamp;
-                    }
-                }
+            token = match self.refresh_account(&token.account_id, false).await {
+                Ok(t) => t,
                 Err(e) => {
                     tracing::error!("Token 刷新失败: {}，尝试下一个账号", e);
-                    return Err(format!("Token refresh failed: {}", e));
+                    return Err(TokenError::Account {
+                        account_id: token.account_id.clone(),
+                        message: format!("Token refresh failed for {}: {}", token.email, e),
+                    });
                 }
-            }
+            };
         }
 
         // 4. 确保有 project_id
@@ -200,7 +359,7 @@ impl TokenManager {
             pid.clone()
         } else {
             tracing::info!("账号 {} 缺少 project_id，尝试获取...", token.email);
-            match crate::proxy::project_resolver::fetch_project_id(&token.access_token).await {
+            match crate::proxy::project_resolver::fetch_project_id(&token.access_token, &token.identity()).await {
                 Ok(pid) => {
                     if let Some(mut entry) = self.tokens.get_mut(&token.account_id) {
                         entry.project_id = Some(pid.clone());
@@ -210,7 +369,10 @@ impl TokenManager {
                 }
                 Err(e) => {
                     tracing::error!("Failed to fetch project_id for {}: {}", token.email, e);
-                    return Err(format!("Failed to fetch project_id: {}", e));
+                    return Err(TokenError::Account {
+                        account_id: token.account_id.clone(),
+                        message: format!("Failed to fetch project_id for {}: {}", token.email, e),
+                    });
                 }
             }
         };
@@ -218,52 +380,579 @@ impl TokenManager {
         Ok((token.access_token, project_id, token.email))
     }
     
-    /// 保存 project_id 到账号文件
-    async fn save_project_id(&self, account_id: &str, project_id: &str) -> Result<(), String> {
-        let entry = self.tokens.get(account_id)
+    /// 账号在配额组内的可用额度，综合配额快照与冷却/熔断状态
+    fn headroom(&self, token: &ProxyToken, quota_group: &str, now: i64, now_ms: i64) -> Option<i32> {
+        if !self.health.is_available(&token.account_id, quota_group, now_ms) {
+            return None;
+        }
+        quota_headroom(token.quota.as_ref(), quota_group, now)
+    }
+
+    /// 在配额组的可用账号中按调度策略选择
+    /// 跳过被禁用 (403)、额度耗尽且未到重置时间、处于冷却或熔断状态以及 `exclude` 中的账号
+    fn select_account(&self, quota_group: &str, exclude: &HashSet<String>, now: i64, now_ms: i64) -> Result<ProxyToken, String> {
+        let mut eligible: Vec<(Candidate, ProxyToken)> = self.tokens.iter()
+            .filter(|entry| !exclude.contains(entry.key()))
+            .filter_map(|entry| {
+                let token = entry.value();
+                let headroom = self.headroom(token, quota_group, now, now_ms)?;
+                let activity = self.activity_of(&token.account_id);
+                let candidate = Candidate {
+                    account_id: token.account_id.clone(),
+                    email: token.email.clone(),
+                    headroom,
+                    last_used_ms: activity.last_used_ms(),
+                    in_flight: activity.in_flight(),
+                };
+                Some((candidate, token.clone()))
+            })
+            .collect();
+
+        if eligible.is_empty() {
+            return Err(format!("No account has remaining {} quota (all exhausted, cooling down or disabled)", quota_group));
+        }
+
+        // DashMap 遍历顺序不稳定，按账号 ID 排序保证调度结果可复现
+        eligible.sort_by(|a, b| a.0.account_id.cmp(&b.0.account_id));
+        let (candidates, tokens): (Vec<Candidate>, Vec<ProxyToken>) = eligible.into_iter().unzip();
+
+        let strategy = self.strategy.read().map_err(|e| e.to_string())?.clone();
+        let idx = strategy.select(&candidates).min(tokens.len() - 1);
+        tokens.into_iter().nth(idx).ok_or_else(|| "Failed to retrieve token from pool".to_string())
+    }
+
+    /// 切换调度策略 (热更新)
+    pub fn update_strategy(&self, config: &ProxyConfig) {
+        if let Ok(mut strategy) = self.strategy.write() {
+            *strategy = build_strategy(config.scheduling_strategy, &config.account_priority);
+            tracing::info!("账号调度策略已切换为 {:?}", config.scheduling_strategy);
+        }
+    }
+
+    fn activity_of(&self, account_id: &str) -> Arc<AccountActivity> {
+        self.activity.entry(account_id.to_string()).or_default().clone()
+    }
+
+    /// 标记账号有一个进行中的请求，返回的 guard 在请求 (含流式响应) 结束时 drop
+    pub fn track_in_flight(&self, email: &str) -> Option<InFlightGuard> {
+        let account_id = self.account_id_by_email(email)?;
+        Some(InFlightGuard::new(self.activity_of(&account_id)))
+    }
+
+    /// 若账号存在且在当前配额组可用，返回其 Token
+    fn usable_token(&self, account_id: &str, quota_group: &str, now: i64, now_ms: i64) -> Option<ProxyToken> {
+        let entry = self.tokens.get(account_id)?;
+        if self.headroom(&entry, quota_group, now, now_ms).is_none() {
+            tracing::info!("账号 {} 在 {} 组不可用 (配额耗尽/冷却/熔断)，重新选择账号", entry.email, quota_group);
+            return None;
+        }
+        Some(entry.value().clone())
+    }
+
+    /// 查找会话绑定的账号，过期或不可用的绑定返回 None
+    fn sticky_token(&self, session_id: &str, quota_group: &str, now: i64, now_ms: i64) -> Option<ProxyToken> {
+        let account_id = {
+            let mut binding = self.sessions.get_mut(session_id)?;
+            if binding.last_seen.elapsed() >= SESSION_TTL {
+                None
+            } else {
+                binding.last_seen = std::time::Instant::now();
+                Some(binding.account_id.clone())
+            }
+        };
+
+        match account_id {
+            Some(account_id) => self.usable_token(&account_id, quota_group, now, now_ms),
+            None => {
+                self.sessions.remove(session_id);
+                None
+            }
+        }
+    }
+
+    /// 绑定会话到账号，顺带清理过期绑定
+    fn bind_session(&self, session_id: &str, account_id: &str) {
+        self.sessions.retain(|_, b| b.last_seen.elapsed() < SESSION_TTL);
+        self.sessions.insert(session_id.to_string(), SessionBinding {
+            account_id: account_id.to_string(),
+            last_seen: std::time::Instant::now(),
+        });
+    }
+
+    /// 根据 email 查找账号 ID
+    pub fn account_id_by_email(&self, email: &str) -> Option<String> {
+        self.tokens.iter()
+            .find(|entry| entry.email == email)
+            .map(|entry| entry.account_id.clone())
+    }
+
+    /// 记录上游错误，更新账号的冷却与熔断状态
+    /// - 429: 按 retryDelay / quotaResetDelay 冷却该配额组
+    /// - 403: 所有配额组冷却 10 分钟
+    /// - 401: 连续失败触发熔断，直到 token 刷新成功
+    pub fn report_upstream_error(self: &Arc<Self>, email: &str, quota_group: &str, status: u16, error_text: &str) {
+        let Some(account_id) = self.account_id_by_email(email) else {
+            return;
+        };
+        let now_ms = chrono::Utc::now().timestamp_millis();
+
+        match status {
+            429 => {
+                let delay = crate::proxy::upstream::retry::parse_retry_delay(error_text)
+                    .map(Duration::from_millis)
+                    .unwrap_or(DEFAULT_RATE_LIMIT_COOLDOWN);
+                tracing::warn!("账号 {} 在 {} 组进入冷却 {:?}", email, quota_group, delay);
+                self.health.start_cooldown(&account_id, quota_group, delay, now_ms);
+            }
+            403 => {
+                tracing::warn!("账号 {} 返回 403，全部配额组冷却 {:?}", email, FORBIDDEN_COOLDOWN);
+                self.health.start_global_cooldown(&account_id, FORBIDDEN_COOLDOWN, now_ms);
+            }
+            401 => {
+                let tripped = self.health.record_auth_failure(&account_id);
+                if tripped {
+                    tracing::error!("账号 {} 连续 401，已熔断，等待 token 刷新", email);
+                    self.spawn_recovery(account_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// 记录请求成功
+    pub fn report_success(&self, email: &str) {
+        if let Some(account_id) = self.account_id_by_email(email) {
+            self.health.record_success(&account_id);
+        }
+    }
+
+    /// 熔断后在后台刷新 token，成功则恢复账号 (熔断在 apply_token 中关闭)
+    fn spawn_recovery(self: &Arc<Self>, account_id: String) {
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            for attempt in 1..=RECOVERY_ATTEMPTS {
+                match manager.refresh_account(&account_id, true).await {
+                    Ok(token) => {
+                        tracing::info!("账号 {} token 刷新成功，熔断已恢复", token.email);
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::warn!("账号 {} 熔断恢复失败 ({}/{}): {}", account_id, attempt, RECOVERY_ATTEMPTS, e);
+                        if attempt < RECOVERY_ATTEMPTS {
+                            tokio::time::sleep(RECOVERY_INTERVAL).await;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// 刷新账号 token 并写回账号文件
+    /// 刷新与写回由 `modules::account` 完成，与 GUI 共用单账号刷新锁：同一账号的并发刷新 (含 GUI) 只会触发一次 OAuth 刷新，
+    /// 等待期间账号文件中的 token 已被更新时直接采用
+    /// `force` 为 false 时，若 token 仍在有效期内则直接返回
+    pub async fn refresh_account(&self, account_id: &str, force: bool) -> Result<ProxyToken, String> {
+        let current = self.tokens.get(account_id)
+            .map(|t| t.value().clone())
             .ok_or("账号不存在")?;
-        
-        let path = &entry.account_path;
-        
-        let mut content: serde_json::Value = serde_json::from_str(
-            &std::fs::read_to_string(path).map_err(|e| format!("读取文件失败: {}", e))?
-        ).map_err(|e| format!("解析 JSON 失败: {}", e))?;
-        
-        content["token"]["project_id"] = serde_json::Value::String(project_id.to_string());
-        
-        std::fs::write(path, serde_json::to_string_pretty(&content).unwrap())
-            .map_err(|e| format!("写入文件失败: {}", e))?;
-        
-        tracing::info!("已保存 project_id 到账号 {}", account_id);
-        Ok(())
+        let now = chrono::Utc::now().timestamp();
+        if !force && now < current.timestamp - REFRESH_AHEAD_SECS {
+            return Ok(current);
+        }
+
+        let account = crate::modules::account::refresh_account_token_file(
+            account_id,
+            &current.account_path,
+            &current.access_token,
+            force,
+        ).await?;
+        let refreshed = self.apply_token(account_id, |t| {
+            // 只接受不比内存中更旧的 token
+            if account.token.expiry_timestamp >= t.timestamp {
+                t.access_token = account.token.access_token.clone();
+                t.refresh_token = account.token.refresh_token.clone();
+                t.expires_in = account.token.expires_in;
+                t.timestamp = account.token.expiry_timestamp;
+            }
+        }).ok_or("账号不存在")?;
+
+        if refreshed.access_token != current.access_token {
+            tracing::info!("账号 {} Token 刷新成功", refreshed.email);
+        }
+        Ok(refreshed)
+    }
+
+    /// 更新内存中的 token 并返回更新后的副本
+    /// 换上新的 access token 即视为认证恢复，关闭该账号的熔断
+    fn apply_token(&self, account_id: &str, update: impl FnOnce(&mut ProxyToken)) -> Option<ProxyToken> {
+        let mut entry = self.tokens.get_mut(account_id)?;
+        let previous = entry.access_token.clone();
+        update(&mut entry);
+        if entry.access_token != previous {
+            self.health.close_circuit(account_id);
+        }
+        Some(entry.clone())
+    }
+
+    /// 启动后台刷新任务，在 token 过期前主动续期
+    /// 任务持有弱引用，TokenManager 释放后自动退出
+    pub fn start_auto_refresh(self: &Arc<Self>) {
+        let weak = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(BACKGROUND_REFRESH_INTERVAL).await;
+                let Some(manager) = weak.upgrade() else {
+                    break;
+                };
+
+                let now = chrono::Utc::now().timestamp();
+                let due: Vec<String> = manager.tokens.iter()
+                    .filter(|t| now >= t.timestamp - BACKGROUND_REFRESH_AHEAD_SECS)
+                    .map(|t| t.account_id.clone())
+                    .collect();
+
+                for account_id in due {
+                    let manager = manager.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = manager.refresh_account(&account_id, true).await {
+                            tracing::warn!("后台刷新账号 {} 失败: {}", account_id, e);
+                        }
+                    });
+                }
+            }
+        });
+    }
+
+    /// 所有账号的冷却/熔断状态快照
+    pub fn health_snapshot(&self) -> Vec<AccountHealthStatus> {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let mut list: Vec<AccountHealthStatus> = self.tokens.iter()
+            .map(|entry| self.health.snapshot(&entry.account_id, &entry.email, now_ms))
+            .collect();
+        list.sort_by(|a, b| a.email.cmp(&b.email));
+        list
     }
     
-    /// 保存刷新后的 token 到账号文件
-    #[allow(dead_code)]
-    async fn save_refreshed_token(&self, account_id: &str, token_response: &crate::modules::oauth::TokenResponse) -> Result<(), String> {
-        let entry = self.tokens.get(account_id)
+    /// 保存 project_id 到账号文件 (只改动 project_id，不覆盖账号文件中的其他字段)
+    async fn save_project_id(&self, account_id: &str, project_id: &str) -> Result<(), String> {
+        let path = self.tokens.get(account_id)
+            .map(|t| t.account_path.clone())
             .ok_or("账号不存在")?;
         
-        let path = &entry.account_path;
-        
-        let mut content: serde_json::Value = serde_json::from_str(
-            &std::fs::read_to_string(path).map_err(|e| format!("读取文件失败: {}", e))?
-        ).map_err(|e| format!("解析 JSON 失败: {}", e))?;
-        
-        let now = chrono::Utc::now().timestamp();
-        
-        content["token"]["access_token"] = serde_json::Value::String(token_response.access_token.clone());
-        content["token"]["expires_in"] = serde_json::Value::Number(token_response.expires_in.into());
-        content["token"]["expiry_timestamp"] = serde_json::Value::Number((now + token_response.expires_in).into());
-        
-        std::fs::write(path, serde_json::to_string_pretty(&content).unwrap())
-            .map_err(|e| format!("写入文件失败: {}", e))?;
+        crate::modules::account::update_account_file(account_id, &path, |account| {
+            account.token.project_id = Some(project_id.to_string());
+        }).await?;
         
-        tracing::info!("已保存刷新后的 token 到账号 {}", account_id);
+        tracing::info!("已保存 project_id 到账号 {}", account_id);
         Ok(())
     }
     
+    /// 各账号最近一次获取的配额 (按 email 排序，未获取过配额的账号不包含在内)
+    pub fn quota_snapshot(&self) -> Vec<(String, QuotaData)> {
+        let mut list: Vec<(String, QuotaData)> = self.tokens.iter()
+            .filter_map(|entry| entry.quota.clone().map(|q| (entry.email.clone(), q)))
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list
+    }
+
+    /// 账号访问上游时使用的出口代理与客户端指纹 (账号不存在时使用默认值)
+    pub fn identity(&self, email: &str) -> AccountIdentity {
+        self.tokens.iter()
+            .find(|entry| entry.email == email)
+            .map(|entry| entry.identity())
+            .unwrap_or_default()
+    }
+
     pub fn len(&self) -> usize {
         self.tokens.len()
     }
+
+    /// 直接加入账号 (测试用)
+    #[cfg(test)]
+    pub(crate) fn insert_token(&self, token: ProxyToken) {
+        self.tokens.insert(token.account_id.clone(), token);
+    }
+}
+
+/// 账号在指定配额组下的剩余额度 (0-100)
+/// 返回 None 表示不可用：账号被禁用，或该组所有模型额度已耗尽且尚未到重置时间
+/// 尚未获取过配额的账号视为满额，以便新账号也能参与调度
+fn quota_headroom(quota: Option<&QuotaData>, quota_group: &str, now: i64) -> Option<i32> {
+    let Some(quota) = quota else {
+        return Some(100);
+    };
+    if quota.is_forbidden {
+        return None;
+    }
+
+    let best = quota.models.iter()
+        .filter(|m| m.name.to_lowercase().contains(quota_group))
+        .map(|m| {
+            // 已过重置时间的快照视为额度已恢复
+            let reset_passed = chrono::DateTime::parse_from_rfc3339(&m.reset_time)
+                .map(|t| t.timestamp() <= now)
+                .unwrap_or(false);
+            if reset_passed { 100 } else { m.percentage }
+        })
+        .max();
+
+    match best {
+        None => Some(100),
+        Some(p) if p > 0 => Some(p),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(models: &[(&str, i32, &str)], is_forbidden: bool) -> QuotaData {
+        let mut q = QuotaData::new();
+        for (name, pct, reset) in models {
+            q.add_model(name.to_string(), *pct, reset.to_string());
+        }
+        q.is_forbidden = is_forbidden;
+        q
+    }
+
+    fn token(id: &str, quota: Option<QuotaData>) -> ProxyToken {
+        ProxyToken {
+            account_id: id.to_string(),
+            access_token: format!("at-{}", id),
+            refresh_token: format!("rt-{}", id),
+            expires_in: 3600,
+            timestamp: 0,
+            email: format!("{}@example.com", id),
+            account_path: PathBuf::from(format!("{}.json", id)),
+            project_id: None,
+            quota,
+            egress_proxy: None,
+            client_profile: None,
+        }
+    }
+
+    /// 写入账号文件 `<dir>/<id>.json`，返回文件路径
+    fn write_account_file(dir: &std::path::Path, id: &str, access_token: &str, expiry: i64) -> PathBuf {
+        let account = serde_json::json!({
+            "id": id,
+            "email": format!("{}@example.com", id),
+            "token": {
+                "access_token": access_token,
+                "refresh_token": format!("rt-{}", id),
+                "expires_in": 3600,
+                "expiry_timestamp": expiry,
+                "token_type": "Bearer"
+            },
+            "created_at": 0,
+            "last_used": 0
+        });
+        let path = dir.join(format!("{}.json", id));
+        std::fs::write(&path, account.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_quota_headroom() {
+        let now = 1_700_000_000;
+        let future = "2099-01-01T00:00:00Z";
+        let past = "2020-01-01T00:00:00Z";
+
+        assert_eq!(quota_headroom(None, "claude", now), Some(100));
+        assert_eq!(quota_headroom(Some(&quota(&[("claude-sonnet-4-5", 40, future)], true)), "claude", now), None);
+
+        let q = quota(&[("claude-sonnet-4-5", 0, future), ("gemini-2.5-flash", 70, future)], false);
+        assert_eq!(quota_headroom(Some(&q), "claude", now), None);
+        assert_eq!(quota_headroom(Some(&q), "gemini", now), Some(70));
+
+        let q = quota(&[("claude-sonnet-4-5", 0, past)], false);
+        assert_eq!(quota_headroom(Some(&q), "claude", now), Some(100));
+    }
+
+    #[test]
+    fn test_select_weighted_by_headroom() {
+        let future = "2099-01-01T00:00:00Z";
+        let manager = TokenManager::new(PathBuf::from("/tmp"));
+        manager.tokens.insert("a".into(), token("a", Some(quota(&[("claude-sonnet-4-5", 0, future)], false))));
+        manager.tokens.insert("b".into(), token("b", Some(quota(&[("claude-sonnet-4-5", 30, future)], false))));
+        manager.tokens.insert("c".into(), token("c", Some(quota(&[("claude-sonnet-4-5", 80, future)], false))));
+        manager.tokens.insert("d".into(), token("d", Some(quota(&[("claude-sonnet-4-5", 90, future)], true))));
+
+        // 按剩余配额 30:80 分配，额度耗尽与被禁用的账号不参与
+        let picks: Vec<String> = (0..110)
+            .map(|_| manager.select_account("claude", &HashSet::new(), 0, 0).unwrap().account_id)
+            .collect();
+        assert_eq!(picks.iter().filter(|id| id.as_str() == "b").count(), 30);
+        assert_eq!(picks.iter().filter(|id| id.as_str() == "c").count(), 80);
+
+        // 冷却中的账号被跳过
+        manager.health.start_cooldown("c", "claude", Duration::from_secs(60), 0);
+        assert_eq!(manager.select_account("claude", &HashSet::new(), 0, 0).unwrap().account_id, "b");
+        assert_eq!(manager.select_account("claude", &HashSet::new(), 0, 60_000).unwrap().account_id, "c");
+
+        manager.tokens.remove("b");
+        manager.tokens.remove("c");
+        assert!(manager.select_account("claude", &HashSet::new(), 0, 0).is_err());
+        assert_eq!(manager.select_account("gemini", &HashSet::new(), 0, 0).unwrap().account_id, "a");
+    }
+
+    #[test]
+    fn test_switch_strategy_at_runtime() {
+        let manager = TokenManager::new(PathBuf::from("/tmp"));
+        manager.tokens.insert("a".into(), token("a", None));
+        manager.tokens.insert("b".into(), token("b", None));
+
+        let config = ProxyConfig {
+            scheduling_strategy: SchedulingStrategy::StrictPriority,
+            account_priority: vec!["b@example.com".to_string()],
+            ..Default::default()
+        };
+        manager.update_strategy(&config);
+        assert_eq!(manager.select_account("claude", &HashSet::new(), 0, 0).unwrap().account_id, "b");
+
+        // 进行中请求最少优先
+        let config = ProxyConfig { scheduling_strategy: SchedulingStrategy::LeastInFlight, ..Default::default() };
+        manager.update_strategy(&config);
+        let _busy = manager.track_in_flight("a@example.com");
+        assert_eq!(manager.select_account("claude", &HashSet::new(), 0, 0).unwrap().account_id, "b");
+        drop(_busy);
+        assert_eq!(manager.select_account("claude", &HashSet::new(), 0, 0).unwrap().account_id, "a");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_reuses_valid_tokens() {
+        let dir = std::env::temp_dir().join(format!("tm-refresh-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        let manager = TokenManager::new(dir.clone());
+
+        // 仍在有效期内，不触发刷新
+        let mut fresh = token("a", None);
+        fresh.timestamp = now + 3600;
+        manager.tokens.insert("a".into(), fresh);
+        assert_eq!(manager.refresh_account("a", false).await.unwrap().access_token, "at-a");
+
+        // 内存中已过期，但账号文件中已有主程序刷新后的 token
+        let mut stale = token("b", None);
+        stale.account_path = write_account_file(&dir, "b", "at-b-new", now + 3600);
+        manager.tokens.insert("b".into(), stale);
+
+        let refreshed = manager.refresh_account("b", false).await.unwrap();
+        assert_eq!(refreshed.access_token, "at-b-new");
+        assert_eq!(manager.tokens.get("b").unwrap().timestamp, now + 3600);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_closes_circuit() {
+        let dir = std::env::temp_dir().join(format!("tm-circuit-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        let manager = TokenManager::new(dir.clone());
+        let mut stale = token("a", None);
+        stale.account_path = dir.join("a.json");
+        manager.tokens.insert("a".into(), stale);
+
+        assert!(!manager.health.record_auth_failure("a"));
+        assert!(manager.health.record_auth_failure("a"));
+        assert!(manager.select_account("claude", &HashSet::new(), 0, 0).is_err());
+
+        // 主程序已写入新 token，refresh_account 采用后账号重新可选
+        write_account_file(&dir, "a", "at-a-new", now + 3600);
+        assert_eq!(manager.refresh_account("a", true).await.unwrap().access_token, "at-a-new");
+        assert_eq!(manager.select_account("claude", &HashSet::new(), 0, 0).unwrap().account_id, "a");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_load_accounts_syncs_with_directory() {
+        let dir = std::env::temp_dir().join(format!("tm-sync-{}", uuid::Uuid::new_v4()));
+        let accounts_dir = dir.join("accounts");
+        std::fs::create_dir_all(&accounts_dir).unwrap();
+
+        let write_account = |id: &str, access_token: &str| {
+            write_account_file(&accounts_dir, id, access_token, 4_000_000_000);
+        };
+        write_account("a", "at-a");
+        write_account("b", "at-b");
+
+        let manager = TokenManager::new(dir.clone());
+        assert_eq!(manager.load_accounts().await.unwrap(), 2);
+        manager.bind_session("s1", "b");
+
+        // 删除文件 / 更新文件
+        std::fs::remove_file(accounts_dir.join("b.json")).unwrap();
+        write_account("a", "at-a-2");
+        assert_eq!(manager.load_accounts().await.unwrap(), 1);
+        assert!(manager.tokens.get("b").is_none());
+        assert!(manager.sessions.get("s1").is_none());
+        assert_eq!(manager.tokens.get("a").unwrap().access_token, "at-a-2");
+
+        // 不在账号索引中的账号不会加入账号池
+        write_account("c", "at-c");
+        let index = serde_json::json!({
+            "version": "2.0",
+            "accounts": [{ "id": "a", "email": "a@example.com", "name": null, "created_at": 0, "last_used": 0 }],
+            "current_account_id": "a"
+        });
+        std::fs::write(dir.join("accounts.json"), index.to_string()).unwrap();
+        assert_eq!(manager.load_accounts().await.unwrap(), 1);
+        assert!(manager.tokens.get("c").is_none());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_session_affinity_and_failover() {
+        let manager = TokenManager::new(PathBuf::from("/tmp"));
+        manager.tokens.insert("a".into(), token("a", None));
+        manager.tokens.insert("b".into(), token("b", None));
+
+        manager.bind_session("s1", "a");
+        manager.bind_session("s2", "b");
+        assert_eq!(manager.sticky_token("s1", "claude", 0, 0).unwrap().account_id, "a");
+        assert_eq!(manager.sticky_token("s2", "claude", 0, 0).unwrap().account_id, "b");
+        assert!(manager.sticky_token("s3", "claude", 0, 0).is_none());
+
+        // 绑定账号冷却时返回 None，由调用方重新选择并绑定
+        manager.health.start_cooldown("a", "claude", Duration::from_secs(60), 0);
+        assert!(manager.sticky_token("s1", "claude", 0, 0).is_none());
+        assert_eq!(manager.sticky_token("s1", "gemini", 0, 0).unwrap().account_id, "a");
+
+        // 账号被移出账号池后同样失效
+        manager.tokens.remove("b");
+        assert!(manager.sticky_token("s2", "claude", 0, 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retry_excludes_failed_sticky_account() {
+        let manager = TokenManager::new(PathBuf::from("/tmp"));
+        for id in ["a", "b"] {
+            let mut t = token(id, None);
+            t.timestamp = chrono::Utc::now().timestamp() + 3600;
+            t.project_id = Some(format!("project-{}", id));
+            manager.tokens.insert(id.into(), t);
+        }
+        manager.bind_session("s1", "a");
+
+        let (_, _, email) = manager.get_token("claude", Some("s1"), &HashSet::new()).await.unwrap();
+        assert_eq!(email, "a@example.com");
+
+        // 首次尝试在 a 上网络失败，重试跳过会话绑定换到 b，并把会话改绑到 b
+        let tried = HashSet::from(["a".to_string()]);
+        let (_, project_id, email) = manager.get_token("claude", Some("s1"), &tried).await.unwrap();
+        assert_eq!(email, "b@example.com");
+        assert_eq!(project_id, "project-b");
+        assert_eq!(manager.sessions.get("s1").unwrap().account_id, "b");
+
+        let tried = HashSet::from(["a".to_string(), "b".to_string()]);
+        assert!(matches!(manager.get_token("claude", Some("s1"), &tried).await, Err(TokenError::NoAccount(_))));
+    }
 }
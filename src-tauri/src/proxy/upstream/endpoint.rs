@@ -0,0 +1,147 @@
+// Cloud Code (v1internal) 端点
+// 反代、配额查询与 project_id 获取共用同一组端点，连接失败或 5xx 时按顺序故障转移
+use once_cell::sync::Lazy;
+use reqwest::Response;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// 默认端点
+pub const DEFAULT_BASE_URL: &str = "https://cloudcode-pa.googleapis.com/v1internal";
+
+static ENDPOINTS: Lazy<RwLock<Arc<UpstreamEndpoints>>> = Lazy::new(|| {
+    let urls = crate::modules::config::load_app_config()
+        .map(|c| c.proxy.upstream_endpoints)
+        .unwrap_or_default();
+    RwLock::new(Arc::new(UpstreamEndpoints::new(&urls)))
+});
+
+/// 当前生效的端点
+pub fn current() -> Arc<UpstreamEndpoints> {
+    ENDPOINTS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// 更新端点列表 (服务启动、保存配置时调用)，列表未变化时保留当前优先端点
+pub fn configure(urls: &[String]) {
+    let endpoints = UpstreamEndpoints::new(urls);
+    let mut current = ENDPOINTS.write().unwrap_or_else(|e| e.into_inner());
+    if current.urls != endpoints.urls {
+        tracing::info!("Cloud Code 端点已更新: {:?}", endpoints.urls);
+        *current = Arc::new(endpoints);
+    }
+}
+
+/// 一组按优先级排列的 v1internal 端点
+pub struct UpstreamEndpoints {
+    urls: Vec<String>,
+    /// 最近一次成功的端点，后续请求从它开始尝试
+    preferred: AtomicUsize,
+}
+
+impl UpstreamEndpoints {
+    /// 列表为空时使用默认端点
+    pub fn new(urls: &[String]) -> Self {
+        let mut urls: Vec<String> = urls
+            .iter()
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        if urls.is_empty() {
+            urls.push(DEFAULT_BASE_URL.to_string());
+        }
+        Self { urls, preferred: AtomicUsize::new(0) }
+    }
+
+    /// 拼接方法地址，例如 `<base>:streamGenerateContent?alt=sse`
+    pub fn method_url(base: &str, method: &str, query_string: Option<&str>) -> String {
+        match query_string {
+            Some(qs) => format!("{}:{}?{}", base, method, qs),
+            None => format!("{}:{}", base, method),
+        }
+    }
+
+    /// 依次向各端点发送请求 (从最近成功的端点开始)
+    /// 连接失败或返回 5xx 时切换到下一个端点，全部失败时返回最后一个结果
+    pub async fn post<F, Fut, E>(&self, method: &str, query_string: Option<&str>, mut send: F) -> Result<Response, E>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Result<Response, E>>,
+        E: Display,
+    {
+        let start = self.preferred.load(Ordering::Relaxed) % self.urls.len();
+        for offset in 0..self.urls.len() {
+            let index = (start + offset) % self.urls.len();
+            let base = &self.urls[index];
+            let result = send(Self::method_url(base, method, query_string)).await;
+            let has_next = offset + 1 < self.urls.len();
+
+            match &result {
+                Ok(response) if response.status().is_server_error() && has_next => {
+                    tracing::warn!("端点 {} 返回 {}，切换到下一个端点", base, response.status());
+                }
+                Err(e) if has_next => {
+                    tracing::warn!("端点 {} 请求失败: {}，切换到下一个端点", base, e);
+                }
+                Ok(response) if !response.status().is_server_error() => {
+                    if index != start {
+                        tracing::info!("Cloud Code 端点切换为 {}", base);
+                    }
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return result;
+                }
+                _ => return result,
+            }
+        }
+        // urls 至少有一个元素，最后一个端点必然返回
+        unreachable!("endpoint list is never empty")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16) -> Response {
+        Response::from(axum::http::Response::builder().status(status).body(reqwest::Body::from(Vec::new())).unwrap())
+    }
+
+    #[test]
+    fn test_normalize_urls() {
+        let endpoints = UpstreamEndpoints::new(&[" http://127.0.0.1:9000/v1internal/ ".to_string(), String::new()]);
+        assert_eq!(endpoints.urls, ["http://127.0.0.1:9000/v1internal"]);
+        assert_eq!(UpstreamEndpoints::new(&[]).urls, [DEFAULT_BASE_URL]);
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_stickiness() {
+        let endpoints = UpstreamEndpoints::new(&["http://a".to_string(), "http://b".to_string(), "http://c".to_string()]);
+
+        let mut tried = Vec::new();
+        let result = endpoints
+            .post("generateContent", None, |url| {
+                tried.push(url.clone());
+                async move {
+                    match url.as_str() {
+                        "http://a:generateContent" => Err("connection refused".to_string()),
+                        "http://b:generateContent" => Ok(response(503)),
+                        _ => Ok(response(429)),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap().status(), 429);
+        assert_eq!(tried.len(), 3);
+
+        // 下一次从最近成功的端点开始
+        let mut tried = Vec::new();
+        let result = endpoints
+            .post("generateContent", None, |url| {
+                tried.push(url);
+                async { Ok::<_, String>(response(500)) }
+            })
+            .await;
+        assert_eq!(result.unwrap().status(), 500);
+        assert_eq!(tried, ["http://c:generateContent", "http://a:generateContent", "http://b:generateContent"]);
+    }
+}
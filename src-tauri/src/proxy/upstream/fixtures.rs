@@ -0,0 +1,298 @@
+// 上游录制/回放
+// 录制模式将 v1internal 请求与响应 (含 SSE 分块间隔) 写入夹具目录；回放模式按录制顺序返回夹具，不访问网络
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use futures::StreamExt;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::time::Duration;
+
+use crate::proxy::capture::redact_value;
+use crate::proxy::config::{UpstreamFixtureConfig, UpstreamFixtureMode};
+
+/// 响应数据块
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FixtureChunk {
+    /// 距上一个数据块 (首块为距响应头) 的间隔
+    pub delay_ms: u64,
+    /// 文本内容
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    /// 非 UTF-8 内容 (base64)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_base64: Option<String>,
+}
+
+impl FixtureChunk {
+    fn new(delay_ms: u64, bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Self { delay_ms, data: Some(text.to_string()), data_base64: None },
+            Err(_) => Self { delay_ms, data: None, data_base64: Some(general_purpose::STANDARD.encode(bytes)) },
+        }
+    }
+
+    fn bytes(&self) -> Bytes {
+        match (&self.data, &self.data_base64) {
+            (Some(text), _) => Bytes::from(text.clone()),
+            (None, Some(encoded)) => Bytes::from(general_purpose::STANDARD.decode(encoded).unwrap_or_default()),
+            (None, None) => Bytes::new(),
+        }
+    }
+}
+
+/// 一次上游调用的录制结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    pub method: String,
+    #[serde(default)]
+    pub query: Option<String>,
+    /// 上游请求体 (已脱敏，仅供对比，回放时不参与匹配)
+    pub request: Value,
+    pub status: u16,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// 发出请求到收到响应头的耗时
+    #[serde(default)]
+    pub header_delay_ms: u64,
+    pub chunks: Vec<FixtureChunk>,
+    /// 响应体是否完整读取 (客户端中途断开时为 false)
+    #[serde(default)]
+    pub complete: bool,
+}
+
+/// 录制/回放状态
+pub enum UpstreamFixtures {
+    Record(FixtureRecorder),
+    Replay(FixturePlayer),
+}
+
+impl UpstreamFixtures {
+    /// 按配置创建，mode 为 off 时返回 None
+    pub fn from_config(config: &UpstreamFixtureConfig) -> Result<Option<Self>, String> {
+        if config.mode == UpstreamFixtureMode::Off {
+            return Ok(None);
+        }
+        let dir = if config.dir.trim().is_empty() {
+            crate::modules::account::get_data_dir()?.join("fixtures")
+        } else {
+            PathBuf::from(config.dir.trim())
+        };
+        match config.mode {
+            UpstreamFixtureMode::Off => Ok(None),
+            UpstreamFixtureMode::Record => Ok(Some(Self::Record(FixtureRecorder::new(dir)?))),
+            UpstreamFixtureMode::Replay => Ok(Some(Self::Replay(FixturePlayer::load(&dir)?))),
+        }
+    }
+}
+
+/// 录制器：每次上游调用写一个 `<序号>_<method>.json`
+pub struct FixtureRecorder {
+    dir: PathBuf,
+    next_seq: AtomicU64,
+}
+
+impl FixtureRecorder {
+    /// 在已有夹具之后继续编号
+    pub fn new(dir: PathBuf) -> Result<Self, String> {
+        std::fs::create_dir_all(&dir).map_err(|e| format!("创建夹具目录失败: {}", e))?;
+        let next_seq = fixture_files(&dir)?
+            .iter()
+            .filter_map(|path| file_seq(path))
+            .max()
+            .map_or(1, |seq| seq + 1);
+        tracing::info!("上游录制已开启: {}", dir.display());
+        Ok(Self { dir, next_seq: AtomicU64::new(next_seq) })
+    }
+
+    /// 包装上游响应：响应体原样透传，读取完毕 (或被丢弃) 时写入夹具
+    pub fn record(&self, method: &str, query: Option<&str>, body: &Value, started: Instant, response: Response) -> Response {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let mut request = body.clone();
+        redact_value(&mut request);
+
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+
+        let mut writer = FixtureWriter {
+            path: self.dir.join(format!("{:06}_{}.json", seq, method)),
+            fixture: Fixture {
+                method: method.to_string(),
+                query: query.map(str::to_string),
+                request,
+                status,
+                content_type: content_type.clone(),
+                header_delay_ms: started.elapsed().as_millis() as u64,
+                chunks: Vec::new(),
+                complete: false,
+            },
+            last: Instant::now(),
+        };
+
+        let mut upstream = Box::pin(response.bytes_stream());
+        let stream = async_stream::stream! {
+            while let Some(item) = upstream.next().await {
+                if let Ok(bytes) = &item {
+                    writer.push(bytes);
+                }
+                yield item;
+            }
+            writer.finish();
+        };
+        build_response(status, content_type.as_deref(), reqwest::Body::wrap_stream(stream))
+    }
+}
+
+/// 录制中的单个夹具，Drop 时写入文件
+struct FixtureWriter {
+    path: PathBuf,
+    fixture: Fixture,
+    last: Instant,
+}
+
+impl FixtureWriter {
+    fn push(&mut self, bytes: &[u8]) {
+        let now = Instant::now();
+        let delay_ms = now.duration_since(self.last).as_millis() as u64;
+        self.last = now;
+        self.fixture.chunks.push(FixtureChunk::new(delay_ms, bytes));
+    }
+
+    fn finish(&mut self) {
+        self.fixture.complete = true;
+    }
+}
+
+impl Drop for FixtureWriter {
+    fn drop(&mut self) {
+        let result = serde_json::to_vec_pretty(&self.fixture)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(&self.path, json).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            tracing::warn!("写入上游夹具失败 {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// 回放器：按录制顺序，为每个 method 依次返回下一个未使用的夹具
+pub struct FixturePlayer {
+    dir: PathBuf,
+    pending: Mutex<Vec<Fixture>>,
+}
+
+impl FixturePlayer {
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let mut fixtures = Vec::new();
+        for path in fixture_files(dir)? {
+            let content = std::fs::read(&path).map_err(|e| format!("读取夹具失败 {}: {}", path.display(), e))?;
+            let fixture: Fixture = serde_json::from_slice(&content)
+                .map_err(|e| format!("解析夹具失败 {}: {}", path.display(), e))?;
+            fixtures.push(fixture);
+        }
+        tracing::info!("上游回放已开启: {} ({} 个夹具)", dir.display(), fixtures.len());
+        Ok(Self { dir: dir.to_path_buf(), pending: Mutex::new(fixtures) })
+    }
+
+    /// 取出下一个匹配 method 的夹具
+    pub fn next(&self, method: &str) -> Result<Fixture, String> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let index = pending
+            .iter()
+            .position(|f| f.method == method)
+            .ok_or_else(|| format!("No recorded fixture left for {} in {}", method, self.dir.display()))?;
+        Ok(pending.remove(index))
+    }
+
+    /// 按录制时的间隔回放下一个夹具
+    pub async fn serve(&self, method: &str) -> Result<Response, String> {
+        let fixture = self.next(method)?;
+        tokio::time::sleep(Duration::from_millis(fixture.header_delay_ms)).await;
+
+        let chunks = fixture.chunks;
+        let stream = async_stream::stream! {
+            for chunk in chunks {
+                if chunk.delay_ms > 0 {
+                    tokio::time::sleep(Duration::from_millis(chunk.delay_ms)).await;
+                }
+                yield Ok::<Bytes, std::io::Error>(chunk.bytes());
+            }
+        };
+        Ok(build_response(fixture.status, fixture.content_type.as_deref(), reqwest::Body::wrap_stream(stream)))
+    }
+}
+
+fn build_response(status: u16, content_type: Option<&str>, body: reqwest::Body) -> Response {
+    let mut builder = axum::http::Response::builder().status(status);
+    if let Some(content_type) = content_type {
+        builder = builder.header(reqwest::header::CONTENT_TYPE, content_type);
+    }
+    let response = builder.body(body).unwrap_or_else(|_| {
+        let mut fallback = axum::http::Response::new(reqwest::Body::from(Vec::new()));
+        *fallback.status_mut() = axum::http::StatusCode::BAD_GATEWAY;
+        fallback
+    });
+    Response::from(response)
+}
+
+fn fixture_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("读取夹具目录失败: {}", e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn file_seq(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.split('_').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let dir = std::env::temp_dir().join(format!("ag-fixtures-{}", uuid::Uuid::new_v4().simple()));
+        let recorder = FixtureRecorder::new(dir.clone()).unwrap();
+
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from_static(b"data: {\"a\":1}\n\n")),
+            Ok(Bytes::from_static(&[0xe4, 0xbd])),
+            Ok(Bytes::from_static(&[0xa0, b'\n'])),
+        ];
+        let live = build_response(200, Some("text/event-stream"), reqwest::Body::wrap_stream(futures::stream::iter(chunks)));
+        let body = serde_json::json!({ "project": "p", "access_token": "secret" });
+        let recorded = recorder.record("streamGenerateContent", Some("alt=sse"), &body, Instant::now(), live);
+        let live_bytes = recorded.bytes().await.unwrap();
+
+        let player = FixturePlayer::load(&dir).unwrap();
+        assert!(player.serve("generateContent").await.is_err());
+        let replayed = player.serve("streamGenerateContent").await.unwrap();
+        assert_eq!(replayed.status(), 200);
+        assert_eq!(replayed.headers()[reqwest::header::CONTENT_TYPE], "text/event-stream");
+        assert_eq!(replayed.bytes().await.unwrap(), live_bytes);
+        assert!(player.serve("streamGenerateContent").await.is_err());
+
+        let fixture = &FixturePlayer::load(&dir).unwrap().pending.into_inner().unwrap()[0];
+        assert!(fixture.complete);
+        assert_eq!(fixture.chunks.len(), 3);
+        assert!(fixture.chunks[1].data_base64.is_some());
+        assert_ne!(fixture.request["access_token"], "secret");
+        assert_eq!(FixtureRecorder::new(dir.clone()).unwrap().next_seq.load(Ordering::Relaxed), 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
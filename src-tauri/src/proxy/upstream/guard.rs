@@ -0,0 +1,55 @@
+// 持有占位的响应流
+// 进行中请求计数、并发流占位等需要活到流结束 (或客户端断开) 的 guard 随流一起 drop
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// 将 guard 绑定到流上，流被 drop 时 guard 一并释放
+pub struct GuardedStream<S, G> {
+    inner: S,
+    _guard: G,
+}
+
+impl<S, G> GuardedStream<S, G> {
+    pub fn new(inner: S, guard: G) -> Self {
+        Self { inner, _guard: guard }
+    }
+}
+
+impl<S: Stream + Unpin, G: Unpin> Stream for GuardedStream<S, G> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().inner).poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_guard_released_with_stream() {
+        let active = Arc::new(AtomicUsize::new(1));
+        let mut stream = GuardedStream::new(futures::stream::iter(vec![1, 2]), Counted(active.clone()));
+        assert_eq!(stream.next().await, Some(1));
+        assert_eq!(active.load(Ordering::SeqCst), 1);
+        drop(stream);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+}
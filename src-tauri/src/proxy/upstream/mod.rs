@@ -2,5 +2,10 @@
 // 对应上游通讯接口
 
 pub mod client;
+pub mod endpoint;
+pub mod guard;
 pub mod retry;
+pub mod prefetch;
+pub mod timeout;
+pub mod fixtures;
 pub mod models;
@@ -0,0 +1,132 @@
+// 流式响应预读
+// 首个有效内容到达前缓冲上游数据；在此之前的连接中断、空流或超时都视为失败，
+// 由重试引擎换账号重发，客户端无感知。一旦内容已开始下发，后续错误仍按原有方式处理
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use std::fmt::Display;
+use std::pin::Pin;
+use tokio::time::{timeout_at, Duration, Instant};
+
+pub type ByteStream<E> = Pin<Box<dyn Stream<Item = Result<Bytes, E>> + Send>>;
+
+/// 读取上游 SSE 直到出现有效内容，返回 (已缓冲数据 + 剩余流) 组成的完整流
+pub async fn prefetch_first_content<E: Display + Send + 'static>(
+    mut stream: ByteStream<E>,
+    limit: Duration,
+) -> Result<ByteStream<E>, String> {
+    let deadline = Instant::now() + limit;
+    let mut buffered = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        let bytes = match timeout_at(deadline, stream.next()).await {
+            Err(_) => return Err(format!("Upstream sent no content within {}s", limit.as_secs())),
+            Ok(None) => return Err("Upstream stream ended before any content".to_string()),
+            Ok(Some(Err(e))) => return Err(format!("Upstream stream failed before any content: {}", e)),
+            Ok(Some(Ok(bytes))) => bytes,
+        };
+
+        let mut found = false;
+        for &b in bytes.iter() {
+            if b == b'\n' {
+                found |= line_has_content(&line);
+                line.clear();
+            } else {
+                line.push(b);
+            }
+        }
+        buffered.push(bytes);
+        if found {
+            break;
+        }
+    }
+
+    Ok(Box::pin(futures::stream::iter(buffered.into_iter().map(Ok)).chain(stream)))
+}
+
+/// SSE data 行中是否包含有效内容 (非空文本/思考、工具调用、图片等) 或结束原因
+fn line_has_content(line: &[u8]) -> bool {
+    let Ok(text) = std::str::from_utf8(line) else {
+        return false;
+    };
+    let Some(data) = text.trim().strip_prefix("data:") else {
+        return false;
+    };
+    let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+        return false;
+    };
+    let event = event.get("response").unwrap_or(&event);
+
+    event
+        .get("candidates")
+        .and_then(|c| c.as_array())
+        .is_some_and(|candidates| {
+            candidates.iter().any(|candidate| {
+                candidate.get("finishReason").is_some()
+                    || candidate
+                        .pointer("/content/parts")
+                        .and_then(|p| p.as_array())
+                        .is_some_and(|parts| parts.iter().any(part_has_content))
+            })
+        })
+}
+
+fn part_has_content(part: &Value) -> bool {
+    part.get("text").and_then(|t| t.as_str()).is_some_and(|t| !t.is_empty())
+        || part.get("functionCall").is_some()
+        || part.get("inlineData").is_some()
+        || part.get("executableCode").is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(items: Vec<Result<&'static str, &'static str>>) -> ByteStream<String> {
+        Box::pin(futures::stream::iter(
+            items.into_iter().map(|item| item.map(Bytes::from).map_err(str::to_string)),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_first_content() {
+        let limit = Duration::from_secs(5);
+
+        // 只有元数据与空文本后断开：视为失败
+        let result = prefetch_first_content(
+            stream(vec![
+                Ok("data: {\"response\":{\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"\"}]}}]}}\n\n"),
+                Err("connection reset"),
+            ]),
+            limit,
+        )
+        .await;
+        assert!(result.err().unwrap().contains("connection reset"));
+        assert!(prefetch_first_content(stream(vec![]), limit).await.is_err());
+
+        // 内容跨数据块到达：返回的流包含全部原始数据
+        let chunks = vec![
+            Ok("data: {\"response\":{\"candidates\":[{\"content\":{\"parts\":[{\"te"),
+            Ok("xt\":\"Hi\"}]}}]}}\n\n"),
+            Err("late failure"),
+        ];
+        let Ok(full) = prefetch_first_content(stream(chunks), limit).await else {
+            panic!("content should be found");
+        };
+        let items: Vec<Result<Bytes, String>> = full.collect().await;
+        assert_eq!(items.len(), 3);
+        assert!(items[2].is_err());
+
+        // 没有内容但给出结束原因 (如安全拦截) 时照常透传
+        let blocked = stream(vec![Ok("data: {\"candidates\":[{\"finishReason\":\"SAFETY\"}]}\n\n")]);
+        assert!(prefetch_first_content(blocked, limit).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_timeout() {
+        let pending: ByteStream<String> = Box::pin(futures::stream::pending());
+        let result = prefetch_first_content(pending, Duration::from_millis(20)).await;
+        assert!(result.err().unwrap().contains("no content"));
+    }
+}
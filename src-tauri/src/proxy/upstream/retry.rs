@@ -1,8 +1,461 @@
-// 429 重试策略
-// Duration 解析
+// 重试引擎
+// 三种协议共用的账号获取、错误分类、退避 (含 retryDelay)、账号轮换、模型降级与最终错误；Duration 解析
 
-use regex::Regex;
+use axum::body::Body;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
 use once_cell::sync::Lazy;
+use rand::Rng;
+use regex::Regex;
+use reqwest::Response;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+use crate::proxy::capture::{self, CapturePart, CaptureSession};
+use crate::proxy::common::fingerprint::AccountIdentity;
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::common::usage::tap_usage_stream;
+use crate::proxy::config::RetryPolicy;
+use crate::proxy::middleware::auth::ApiKeyIdentity;
+use crate::proxy::middleware::policy::{model_allowed, KeyPolicyManager};
+use crate::proxy::middleware::stats::RequestTrace;
+use crate::proxy::scheduler::InFlightGuard;
+use crate::proxy::server::AppState;
+use crate::proxy::stats::StatsCollector;
+use crate::proxy::upstream::client::UpstreamClient;
+use crate::proxy::upstream::guard::GuardedStream;
+use crate::proxy::upstream::prefetch::{prefetch_first_content, ByteStream};
+use crate::proxy::upstream::timeout::with_stream_watchdog;
+use crate::proxy::token_manager::TokenError;
+use crate::proxy::TokenManager;
+
+/// 上游错误分类
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamErrorKind {
+    /// 网络错误或超时
+    Transport,
+    /// 429 限流，附带上游给出的 retryDelay (毫秒)
+    RateLimited(Option<u64>),
+    /// 429 且配额已耗尽 (QUOTA_EXHAUSTED)，该账号已进入冷却，换账号重试
+    QuotaExhausted,
+    /// 401 认证失效
+    Unauthorized,
+    /// 403 权限/地区限制
+    Forbidden,
+    /// 5xx
+    ServerError,
+    /// 其他错误 (如 400/404 模型或请求错误)，轮换账号无意义
+    Rejected,
+}
+
+impl UpstreamErrorKind {
+    pub fn classify(status: u16, body: &str) -> Self {
+        match status {
+            429 if body.contains("QUOTA_EXHAUSTED") => Self::QuotaExhausted,
+            429 => Self::RateLimited(parse_retry_delay(body)),
+            401 => Self::Unauthorized,
+            403 => Self::Forbidden,
+            500..=599 => Self::ServerError,
+            _ => Self::Rejected,
+        }
+    }
+}
+
+/// 一次失败的上游调用
+#[derive(Debug, Clone)]
+pub struct UpstreamError {
+    pub kind: UpstreamErrorKind,
+    /// HTTP 状态码 (网络错误时为 None)
+    pub status: Option<u16>,
+    /// 上游响应体或网络错误信息
+    pub body: String,
+}
+
+impl UpstreamError {
+    /// 上游错误信息 (优先取 Google 错误体中的 error.message)
+    pub fn message(&self) -> String {
+        serde_json::from_str::<Value>(&self.body)
+            .ok()
+            .and_then(|v| v.pointer("/error/message").and_then(|m| m.as_str()).map(str::to_string))
+            .unwrap_or_else(|| self.body.trim().to_string())
+    }
+
+    fn label(&self) -> String {
+        match self.status {
+            Some(status) => format!("HTTP {}", status),
+            None => "request failed".to_string(),
+        }
+    }
+}
+
+/// 请求最终失败，按客户端协议格式返回
+#[derive(Debug)]
+pub struct RetryFailure {
+    pub protocol: ClientProtocol,
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl IntoResponse for RetryFailure {
+    fn into_response(self) -> axum::response::Response {
+        self.protocol.error_response(self.status, &self.message)
+    }
+}
+
+/// 一次尝试使用的账号
+pub struct AttemptAccount {
+    pub email: String,
+    pub access_token: String,
+    pub project_id: String,
+    pub identity: AccountIdentity,
+    /// 进行中请求计数，流式响应由 `stream_response` 绑定到响应流上
+    pub in_flight: Option<InFlightGuard>,
+}
+
+/// 重试引擎：处理器在循环中依次调用 `next_account` / `send` / `handle_error` (流式响应交给 `stream_response`)，自身只负责协议转换
+/// 请求体须按 `model()` 构建，目标模型不可用时引擎会切换到降级链中的下一个模型
+pub struct RetryEngine {
+    protocol: ClientProtocol,
+    policy: RetryPolicy,
+    token_manager: Arc<TokenManager>,
+    upstream: Arc<UpstreamClient>,
+    stats: Arc<StatsCollector>,
+    key_policies: Arc<KeyPolicyManager>,
+    trace: Arc<RequestTrace>,
+    /// 调用方 API Key (用于记录流式响应的用量)
+    identity: Option<ApiKeyIdentity>,
+    /// 目标模型及其降级链，当前使用 `models[model_index]`
+    models: Vec<String>,
+    model_index: usize,
+    quota_group: String,
+    session_id: Option<String>,
+    max_attempts: usize,
+    attempt: usize,
+    /// 当前模型下已尝试过的账号 ID，重试时不再选择 (包括会话绑定的账号)
+    tried: HashSet<String>,
+    /// 下一次尝试前的等待
+    pending_delay: Option<Duration>,
+    /// 最近一次上游请求的发出时间，流式响应的响应头与首个内容共用从此开始的首字节时限
+    sent_at: Option<Instant>,
+    last_error: Option<UpstreamError>,
+}
+
+impl RetryEngine {
+    /// `model` 为路由后的目标模型，`quota_group` 为其配额组 (降级后的模型按模型名推断)
+    /// 降级链只保留调用方 API Key 白名单允许的模型
+    pub async fn new(
+        state: &AppState,
+        protocol: ClientProtocol,
+        trace: Arc<RequestTrace>,
+        identity: Option<&ApiKeyIdentity>,
+        model: &str,
+        quota_group: &str,
+        session_id: Option<&str>,
+    ) -> Self {
+        let policy = state.retry.read().await.policy(protocol);
+        let fallbacks = state.model_fallbacks.read().await.get(model).cloned().unwrap_or_default();
+        let allowed_models = match identity {
+            Some(identity) => state.api_keys.read().await
+                .get(&identity.name)
+                .map(|key| key.allowed_models.clone())
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        Self {
+            protocol,
+            policy,
+            token_manager: state.token_manager.clone(),
+            upstream: state.upstream.clone(),
+            stats: state.stats.clone(),
+            key_policies: state.key_policies.clone(),
+            trace,
+            identity: identity.cloned(),
+            models: fallback_chain(model, fallbacks, &allowed_models),
+            model_index: 0,
+            quota_group: quota_group.to_string(),
+            session_id: session_id.map(str::to_string),
+            max_attempts: policy.max_attempts.min(state.token_manager.len()).max(1),
+            attempt: 0,
+            tried: HashSet::new(),
+            pending_delay: None,
+            sent_at: None,
+            last_error: None,
+        }
+    }
+
+    /// 当前使用的上游模型
+    pub fn model(&self) -> &str {
+        &self.models[self.model_index]
+    }
+
+    /// 是否已切换到降级模型
+    pub fn is_fallback(&self) -> bool {
+        self.model_index > 0
+    }
+
+    /// 不再降级，只使用当前模型 (如分批请求的后续批次须与首批使用同一模型)
+    pub fn disable_fallback(&mut self) {
+        self.models.truncate(self.model_index + 1);
+    }
+
+    /// 协议层改写当前模型 (如 Claude 去除 thinking 后改用非 thinking 模型)
+    pub fn replace_model(&mut self, model: &str) {
+        self.models[self.model_index] = model.to_string();
+    }
+
+    /// 获取下一次尝试的账号 (先完成上一次失败安排的等待)，尝试次数用尽时返回 None
+    /// 账号刷新 token 或获取 project_id 失败计为一次失败的尝试，排除该账号后继续
+    /// 尝试次数因限流/配额耗尽用尽，或配额组内已没有可用账号时，先切换到降级模型重新计数
+    pub async fn next_account(&mut self) -> Result<Option<AttemptAccount>, RetryFailure> {
+        let (access_token, project_id, email) = loop {
+            if self.attempt >= self.max_attempts {
+                let rate_limited = self.last_error.as_ref().is_some_and(|e| {
+                    matches!(e.kind, UpstreamErrorKind::RateLimited(_) | UpstreamErrorKind::QuotaExhausted)
+                });
+                if rate_limited && self.fall_back("all attempts rate limited") {
+                    continue;
+                }
+                return Ok(None);
+            }
+            if let Some(delay) = self.pending_delay.take() {
+                tokio::time::sleep(delay).await;
+            }
+            self.attempt += 1;
+
+            match self.token_manager.get_token(&self.quota_group, self.session_id.as_deref(), &self.tried).await {
+                Ok(token) => break token,
+                Err(TokenError::Account { account_id, message }) => {
+                    tracing::warn!(
+                        "{} account unavailable on attempt {}/{}, trying another account: {}",
+                        self.protocol.as_str(), self.attempt, self.max_attempts, message
+                    );
+                    self.tried.insert(account_id);
+                    self.last_error = Some(UpstreamError { kind: UpstreamErrorKind::Transport, status: None, body: message });
+                }
+                Err(TokenError::NoAccount(e)) => {
+                    if self.fall_back(&e) {
+                        continue;
+                    }
+                    // 已有失败的尝试时以最后一次错误结束
+                    if self.last_error.is_some() {
+                        return Ok(None);
+                    }
+                    return Err(self.failure(StatusCode::SERVICE_UNAVAILABLE, format!("No available accounts: {}", e)));
+                }
+            }
+        };
+
+        tracing::info!("Using account: {} for request", email);
+        if let Some(account_id) = self.token_manager.account_id_by_email(&email) {
+            self.tried.insert(account_id);
+        }
+        self.trace.begin_attempt(&email);
+        Ok(Some(AttemptAccount {
+            in_flight: self.token_manager.track_in_flight(&email),
+            identity: self.token_manager.identity(&email),
+            email,
+            access_token,
+            project_id,
+        }))
+    }
+
+    /// 发送上游请求并分类错误，429/403/401 同时计入账号冷却与熔断状态
+    pub async fn send(&mut self, account: &AttemptAccount, method: &str, query: Option<&str>, body: Value) -> Result<Response, UpstreamError> {
+        self.sent_at = Some(Instant::now());
+        let response = self
+            .upstream
+            .call_v1_internal(method, &account.access_token, body, query, &account.identity)
+            .await
+            .map_err(|e| {
+                self.stats.record_upstream_response(None, &account.email);
+                UpstreamError { kind: UpstreamErrorKind::Transport, status: None, body: e }
+            })?;
+
+        let status = response.status();
+        self.stats.record_upstream_response(Some(status.as_u16()), &account.email);
+        if status.is_success() {
+            self.token_manager.report_success(&account.email);
+            self.trace.set_served_model(self.model());
+            return Ok(response);
+        }
+
+        let body = response.text().await.unwrap_or_else(|_| format!("HTTP {}", status));
+        let status = status.as_u16();
+        if matches!(status, 429 | 403 | 401) {
+            self.token_manager.report_upstream_error(&account.email, &self.quota_group, status, &body);
+        }
+        Err(UpstreamError { kind: UpstreamErrorKind::classify(status, &body), status: Some(status), body })
+    }
+
+    /// 流式响应：等待首个有效内容后再交给协议转换，与等待响应头合计不超过首字节超时
+    /// 在此之前的中断、空流或超时按网络错误处理，换账号重试对客户端透明
+    async fn first_content(&self, response: Response) -> Result<ByteStream<reqwest::Error>, UpstreamError> {
+        let first_byte = self.upstream.timeouts().first_byte;
+        let remaining = self.sent_at.map_or(first_byte, |sent_at| first_byte.saturating_sub(sent_at.elapsed()));
+        prefetch_first_content(Box::pin(response.bytes_stream()), remaining)
+            .await
+            .map_err(|e| UpstreamError { kind: UpstreamErrorKind::Transport, status: None, body: e })
+    }
+
+    /// 流式响应：等待首个有效内容后接入各协议共用的处理，返回 SSE 响应
+    /// 进行中请求计数随响应流释放，用量记录、抓包与看门狗在此统一完成，`translate` 只负责协议转换
+    pub async fn stream_response<F>(
+        &self,
+        account: AttemptAccount,
+        response: Response,
+        capture: &Option<Arc<CaptureSession>>,
+        translate: F,
+    ) -> Result<axum::response::Response, UpstreamError>
+    where
+        F: FnOnce(ByteStream<reqwest::Error>) -> ByteStream<String>,
+    {
+        let upstream = self.first_content(response).await?;
+        let upstream: ByteStream<reqwest::Error> = Box::pin(GuardedStream::new(upstream, account.in_flight));
+        let upstream = capture::tap_stream(upstream, capture, CapturePart::UpstreamResponse);
+        let recorder = self.trace.usage_recorder(self.key_policies.usage_recorder(self.identity.clone()));
+        let client = translate(tap_usage_stream(upstream, recorder));
+        // 看门狗中断时响应头早已以 200 发出，需改写本请求记录的状态
+        let trace = self.trace.clone();
+        let client = with_stream_watchdog(client, self.protocol, self.upstream.timeouts(), move || {
+            trace.set_status(StatusCode::GATEWAY_TIMEOUT.as_u16())
+        });
+        let client = capture::tap_stream(client, capture, CapturePart::ClientResponse);
+
+        Ok(axum::response::Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .header(header::CONNECTION, "keep-alive")
+            .body(Body::from_stream(client))
+            .unwrap())
+    }
+
+    /// 按错误分类决定是否继续：可重试时安排等待并返回 Ok，否则返回最终错误
+    /// - 401/403 与配额耗尽: 立即换账号 (配额耗尽的账号已进入冷却，全部耗尽时由 `next_account` 降级)
+    /// - 429: 有 retryDelay 且不超过上限时按其等待，否则指数退避
+    /// - 网络错误与 (允许重试的) 5xx: 指数退避
+    /// - 404: 切换到降级模型，没有降级模型时不重试
+    /// - 其他 4xx: 不重试
+    pub fn handle_error(&mut self, error: UpstreamError) -> Result<(), RetryFailure> {
+        let protocol = self.protocol.as_str();
+        let not_found = error.kind == UpstreamErrorKind::Rejected && error.status == Some(404);
+        if not_found && self.fall_back(&format!("upstream {}", error.label())) {
+            self.last_error = Some(error);
+            return Ok(());
+        }
+
+        let delay = match error.kind {
+            UpstreamErrorKind::Rejected => None,
+            UpstreamErrorKind::ServerError if !self.policy.retry_server_errors => None,
+            UpstreamErrorKind::Unauthorized | UpstreamErrorKind::Forbidden | UpstreamErrorKind::QuotaExhausted => {
+                Some(Duration::ZERO)
+            }
+            UpstreamErrorKind::RateLimited(Some(ms))
+                if self.policy.honor_retry_delay && ms <= self.policy.max_retry_delay_ms =>
+            {
+                Some(Duration::from_millis(ms.saturating_add(200)))
+            }
+            UpstreamErrorKind::RateLimited(_) | UpstreamErrorKind::Transport | UpstreamErrorKind::ServerError => {
+                Some(backoff_delay(&self.policy, self.attempt))
+            }
+        };
+
+        let Some(delay) = delay else {
+            tracing::error!(
+                "{} upstream {} on attempt {}/{} is not retryable: {}",
+                protocol, error.label(), self.attempt, self.max_attempts, error.body
+            );
+            let status = error.status.and_then(|s| StatusCode::from_u16(s).ok()).unwrap_or(StatusCode::BAD_GATEWAY);
+            return Err(self.failure(status, error.message()));
+        };
+
+        tracing::warn!(
+            "{} upstream {} on attempt {}/{}, retrying in {}ms with account rotation",
+            protocol, error.label(), self.attempt, self.max_attempts, delay.as_millis()
+        );
+        self.pending_delay = Some(delay).filter(|d| !d.is_zero());
+        self.last_error = Some(error);
+        Ok(())
+    }
+
+    /// 协议层已修正请求 (如 Claude 去除失效的思维签名)，下一次尝试立即进行，允许继续使用同一账号且不占用尝试次数
+    pub fn retry_repaired(&mut self, account: &AttemptAccount, error: UpstreamError) {
+        if let Some(account_id) = self.token_manager.account_id_by_email(&account.email) {
+            self.tried.remove(&account_id);
+        }
+        self.attempt = self.attempt.saturating_sub(1);
+        self.pending_delay = None;
+        self.last_error = Some(error);
+    }
+
+    /// 尝试次数用尽后的最终错误
+    /// 网络错误与 5xx 返回 502，限流返回 429，其余 (请求错误、认证失效、权限限制) 返回上游状态码
+    pub fn exhausted(&self) -> RetryFailure {
+        let (status, last) = match &self.last_error {
+            Some(e) if matches!(e.kind, UpstreamErrorKind::Transport | UpstreamErrorKind::ServerError) => {
+                (StatusCode::BAD_GATEWAY, format!("{}: {}", e.label(), e.message()))
+            }
+            Some(e) if matches!(e.kind, UpstreamErrorKind::Rejected | UpstreamErrorKind::Unauthorized | UpstreamErrorKind::Forbidden) => {
+                let status = e.status.and_then(|s| StatusCode::from_u16(s).ok()).unwrap_or(StatusCode::BAD_GATEWAY);
+                (status, format!("{}: {}", e.label(), e.message()))
+            }
+            Some(e) => (StatusCode::TOO_MANY_REQUESTS, format!("{}: {}", e.label(), e.message())),
+            None => (StatusCode::TOO_MANY_REQUESTS, String::new()),
+        };
+        self.failure(status, format!("All {} attempts failed. Last error: {}", self.attempt, last))
+    }
+
+    /// 切换到降级链中的下一个模型，重新计算配额组与尝试次数；没有更多模型时返回 false
+    fn fall_back(&mut self, reason: &str) -> bool {
+        if self.model_index + 1 >= self.models.len() {
+            return false;
+        }
+        let from = self.models[self.model_index].clone();
+        self.model_index += 1;
+        self.quota_group = crate::proxy::common::utils::infer_quota_group(self.model());
+        self.attempt = 0;
+        self.tried.clear();
+        self.pending_delay = None;
+        tracing::warn!(
+            "{} model {} unavailable ({}), falling back to {}",
+            self.protocol.as_str(), from, reason, self.model()
+        );
+        true
+    }
+
+    fn failure(&self, status: StatusCode, message: String) -> RetryFailure {
+        RetryFailure { protocol: self.protocol, status, message }
+    }
+}
+
+/// 目标模型与去重后的降级模型，`allowed_models` 非空时剔除白名单外的降级模型
+/// (目标模型已由策略中间件检查)
+fn fallback_chain(model: &str, fallbacks: Vec<String>, allowed_models: &[String]) -> Vec<String> {
+    let mut chain = vec![model.to_string()];
+    for fallback in fallbacks {
+        if fallback.is_empty() || chain.contains(&fallback) {
+            continue;
+        }
+        if !allowed_models.is_empty() && !model_allowed(allowed_models, &[fallback.as_str()]) {
+            tracing::debug!("降级模型 {} 不在 API Key 白名单内，已跳过", fallback);
+            continue;
+        }
+        chain.push(fallback);
+    }
+    chain
+}
+
+/// 第 `retry` 次重试 (从 1 开始) 的退避时长：按 2 的幂增长至上限，并在 [50%, 100%] 区间随机抖动
+pub fn backoff_delay(policy: &RetryPolicy, retry: usize) -> Duration {
+    let exponent = retry.saturating_sub(1).min(16) as u32;
+    let capped = policy.backoff_base_ms.saturating_mul(1u64 << exponent).min(policy.backoff_max_ms);
+    if capped == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(capped / 2..=capped))
+}
 
 static DURATION_RE: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"([\d.]+)\s*(ms|s|m|h)").unwrap()
@@ -69,6 +522,72 @@ pub fn parse_retry_delay(error_text: &str) -> Option<u64> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proxy::token_manager::ProxyToken;
+    use crate::proxy::upstream::fixtures::{Fixture, FixtureChunk, FixturePlayer, UpstreamFixtures};
+    use crate::proxy::upstream::timeout::UpstreamTimeouts;
+    use futures::StreamExt;
+    use serde_json::json;
+    use std::path::{Path, PathBuf};
+
+    /// 单账号池的重试引擎 (不经过 AppState)
+    fn engine(upstream: UpstreamClient) -> RetryEngine {
+        let token_manager = Arc::new(TokenManager::new(std::env::temp_dir()));
+        token_manager.insert_token(ProxyToken {
+            account_id: "a".to_string(),
+            access_token: "at-a".to_string(),
+            refresh_token: "rt-a".to_string(),
+            expires_in: 3600,
+            timestamp: chrono::Utc::now().timestamp() + 3600,
+            email: "a@example.com".to_string(),
+            account_path: PathBuf::from("a.json"),
+            project_id: Some("project-a".to_string()),
+            quota: None,
+            egress_proxy: None,
+            client_profile: None,
+        });
+        let stats = Arc::new(StatsCollector::new(None));
+        RetryEngine {
+            protocol: ClientProtocol::Claude,
+            policy: RetryPolicy::default(),
+            token_manager,
+            upstream: Arc::new(upstream),
+            key_policies: Arc::new(KeyPolicyManager::new(stats.clone())),
+            stats,
+            trace: RequestTrace::detached(),
+            identity: None,
+            models: vec!["claude-sonnet-4-5".to_string()],
+            model_index: 0,
+            quota_group: "claude".to_string(),
+            session_id: None,
+            max_attempts: 1,
+            attempt: 0,
+            tried: HashSet::new(),
+            pending_delay: None,
+            sent_at: None,
+            last_error: None,
+        }
+    }
+
+    /// 回放单个流式响应的上游，响应头与首个内容分别延迟 `header_ms` / `body_ms`
+    fn slow_stream_upstream(dir: &Path, first_byte: Duration, header_ms: u64, body_ms: u64) -> UpstreamClient {
+        let content = r#"data: {"response":{"candidates":[{"content":{"parts":[{"text":"Hi"}]}}]}}"#;
+        let fixture = Fixture {
+            method: "streamGenerateContent".to_string(),
+            query: Some("alt=sse".to_string()),
+            request: Value::Null,
+            status: 200,
+            content_type: Some("text/event-stream".to_string()),
+            header_delay_ms: header_ms,
+            chunks: vec![FixtureChunk { delay_ms: body_ms, data: Some(format!("{}\n\n", content)), data_base64: None }],
+            complete: true,
+        };
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("0001_streamGenerateContent.json"), serde_json::to_string(&fixture).unwrap()).unwrap();
+
+        let upstream = UpstreamClient::new(None, UpstreamTimeouts { request: first_byte, first_byte, idle: first_byte });
+        upstream.set_fixtures(Some(UpstreamFixtures::Replay(FixturePlayer::load(dir).unwrap())));
+        upstream
+    }
 
     #[test]
     fn test_parse_duration_ms() {
@@ -91,4 +610,107 @@ mod tests {
 
         assert_eq!(parse_retry_delay(error_json), Some(1204));
     }
+
+    #[test]
+    fn test_classify() {
+        let rate_limited = r#"{"error":{"code":429,"details":[{"@type":"type.googleapis.com/google.rpc.RetryInfo","retryDelay":"2s"}]}}"#;
+        assert_eq!(UpstreamErrorKind::classify(429, rate_limited), UpstreamErrorKind::RateLimited(Some(2000)));
+        // 只有明确的 QUOTA_EXHAUSTED 才视为配额耗尽
+        assert_eq!(UpstreamErrorKind::classify(429, "Resource has been exhausted (e.g. check quota)."), UpstreamErrorKind::RateLimited(None));
+        assert_eq!(UpstreamErrorKind::classify(429, r#"{"error":{"details":[{"reason":"QUOTA_EXHAUSTED"}]}}"#), UpstreamErrorKind::QuotaExhausted);
+        assert_eq!(UpstreamErrorKind::classify(401, ""), UpstreamErrorKind::Unauthorized);
+        assert_eq!(UpstreamErrorKind::classify(503, ""), UpstreamErrorKind::ServerError);
+        assert_eq!(UpstreamErrorKind::classify(404, ""), UpstreamErrorKind::Rejected);
+
+        let error = UpstreamError { kind: UpstreamErrorKind::Rejected, status: Some(404), body: r#"{"error":{"message":"model not found"}}"#.to_string() };
+        assert_eq!(error.message(), "model not found");
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let policy = RetryPolicy { backoff_base_ms: 200, backoff_max_ms: 1000, ..Default::default() };
+        for _ in 0..20 {
+            let first = backoff_delay(&policy, 1).as_millis();
+            assert!((100..=200).contains(&first));
+            let third = backoff_delay(&policy, 3).as_millis();
+            assert!((400..=800).contains(&third));
+            let capped = backoff_delay(&policy, 10).as_millis();
+            assert!((500..=1000).contains(&capped));
+        }
+        assert_eq!(backoff_delay(&RetryPolicy { backoff_base_ms: 0, ..Default::default() }, 2), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_fallback_chain() {
+        let chain = fallback_chain(
+            "claude-opus-4-5-thinking",
+            vec![
+                "claude-sonnet-4-5-thinking".to_string(),
+                "claude-opus-4-5-thinking".to_string(),
+                String::new(),
+                "gemini-3-pro-high".to_string(),
+                "claude-sonnet-4-5-thinking".to_string(),
+            ],
+            &[],
+        );
+        assert_eq!(chain, vec!["claude-opus-4-5-thinking", "claude-sonnet-4-5-thinking", "gemini-3-pro-high"]);
+        assert_eq!(fallback_chain("gemini-2.5-pro", Vec::new(), &[]), vec!["gemini-2.5-pro"]);
+    }
+
+    #[test]
+    fn test_fallback_chain_respects_allowed_models() {
+        let fallbacks = vec!["claude-sonnet-4-5-thinking".to_string(), "gemini-3-pro-high".to_string()];
+        let chain = fallback_chain("claude-opus-4-5-thinking", fallbacks, &["claude-*".to_string()]);
+        assert_eq!(chain, vec!["claude-opus-4-5-thinking", "claude-sonnet-4-5-thinking"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_first_byte_budget_includes_headers() {
+        let dir = std::env::temp_dir().join(format!("retry-first-byte-{}", uuid::Uuid::new_v4()));
+        let first_byte = Duration::from_millis(300);
+        // 响应头与首个内容各自都在首字节超时内，但合计超出
+        let mut retry = engine(slow_stream_upstream(&dir, first_byte, 250, 250));
+
+        let started = Instant::now();
+        let account = retry.next_account().await.unwrap().unwrap();
+        let response = retry.send(&account, "streamGenerateContent", Some("alt=sse"), json!({})).await.unwrap();
+        let translate = |stream: ByteStream<reqwest::Error>| -> ByteStream<String> {
+            Box::pin(stream.map(|item| item.map_err(|e| e.to_string())))
+        };
+        let Err(error) = retry.stream_response(account, response, &None, translate).await else {
+            panic!("content arriving after the first-byte deadline must fail the attempt");
+        };
+        assert_eq!(error.kind, UpstreamErrorKind::Transport);
+        assert!(started.elapsed() < first_byte + Duration::from_millis(150), "waited {:?}", started.elapsed());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_repaired_retry_on_single_account() {
+        let mut retry = engine(UpstreamClient::new(None, UpstreamTimeouts::from_config(&Default::default())));
+        let signature_error = || UpstreamError {
+            kind: UpstreamErrorKind::Rejected,
+            status: Some(400),
+            body: r#"{"error":{"message":"Invalid `signature` in `thinking` block"}}"#.to_string(),
+        };
+
+        // 修正请求后的重试不占用尝试次数，单账号池也能再试一次
+        let account = retry.next_account().await.unwrap().unwrap();
+        retry.retry_repaired(&account, signature_error());
+        let account = retry.next_account().await.unwrap().expect("repaired retry must get another attempt");
+        assert_eq!(account.email, "a@example.com");
+        drop(account);
+
+        // 尝试次数用尽时按上游状态码返回，而不是 429
+        assert!(retry.next_account().await.unwrap().is_none());
+        let failure = retry.exhausted();
+        assert_eq!(failure.status, StatusCode::BAD_REQUEST);
+        assert!(failure.message.contains("Invalid `signature`"));
+
+        retry.last_error = Some(UpstreamError { kind: UpstreamErrorKind::Forbidden, status: Some(403), body: String::new() });
+        assert_eq!(retry.exhausted().status, StatusCode::FORBIDDEN);
+        retry.last_error = Some(UpstreamError { kind: UpstreamErrorKind::RateLimited(None), status: Some(429), body: String::new() });
+        assert_eq!(retry.exhausted().status, StatusCode::TOO_MANY_REQUESTS);
+    }
 }
@@ -0,0 +1,113 @@
+// 上游超时控制
+// 非流式请求使用整体超时，流式请求使用首字节超时与块间空闲超时
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use tokio::time::{timeout, Duration};
+
+use crate::proxy::common::protocol::ClientProtocol;
+use crate::proxy::config::ProxyConfig;
+
+/// 上游请求超时配置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamTimeouts {
+    /// 非流式请求整体超时
+    pub request: Duration,
+    /// 流式请求首个数据块超时
+    pub first_byte: Duration,
+    /// 流式请求相邻数据块之间的空闲超时
+    pub idle: Duration,
+}
+
+impl UpstreamTimeouts {
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        Self {
+            request: Duration::from_secs(config.request_timeout.max(1)),
+            first_byte: Duration::from_secs(config.stream_first_byte_timeout.max(1)),
+            idle: Duration::from_secs(config.stream_idle_timeout.max(1)),
+        }
+    }
+}
+
+/// 为客户端 SSE 流加上首字节与空闲看门狗
+/// 超时后调用 `on_stall`，向客户端发送符合协议格式的错误事件并结束流 (同时释放上游连接)
+pub fn with_stream_watchdog<E: Send + 'static>(
+    mut stream: Pin<Box<dyn Stream<Item = Result<Bytes, E>> + Send>>,
+    protocol: ClientProtocol,
+    timeouts: UpstreamTimeouts,
+    on_stall: impl FnOnce() + Send + 'static,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, E>> + Send>> {
+    Box::pin(async_stream::stream! {
+        let mut received_any = false;
+        loop {
+            let (limit, phase) = if received_any {
+                (timeouts.idle, "between chunks")
+            } else {
+                (timeouts.first_byte, "before the first chunk")
+            };
+
+            match timeout(limit, stream.next()).await {
+                Ok(Some(item)) => {
+                    received_any = true;
+                    yield item;
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    let message = format!("Upstream stream stalled: no data for {}s {}", limit.as_secs(), phase);
+                    tracing::warn!("[Watchdog] {} 流超时: {}", protocol.as_str(), message);
+                    on_stall();
+                    yield Ok(protocol.sse_error_event(axum::http::StatusCode::GATEWAY_TIMEOUT, &message));
+                    break;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn timeouts(ms: u64) -> UpstreamTimeouts {
+        UpstreamTimeouts {
+            request: Duration::from_millis(ms),
+            first_byte: Duration::from_millis(ms),
+            idle: Duration::from_millis(ms),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_emits_error_on_idle() {
+        let upstream = futures::stream::iter(vec![Ok::<Bytes, String>(Bytes::from("data: 1\n\n"))])
+            .chain(futures::stream::pending());
+        let stalled = Arc::new(AtomicBool::new(false));
+        let flag = stalled.clone();
+        let out: Vec<_> = with_stream_watchdog(Box::pin(upstream), ClientProtocol::OpenAI, timeouts(50), move || {
+            flag.store(true, Ordering::SeqCst)
+        })
+        .collect()
+        .await;
+
+        assert!(stalled.load(Ordering::SeqCst));
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].as_ref().unwrap(), &Bytes::from("data: 1\n\n"));
+        let error = String::from_utf8(out[1].as_ref().unwrap().to_vec()).unwrap();
+        assert!(error.contains("stalled") && error.contains("\"error\""));
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_passes_through_completed_stream() {
+        let upstream = futures::stream::iter(vec![
+            Ok::<Bytes, String>(Bytes::from("a")),
+            Ok(Bytes::from("b")),
+        ]);
+        let out: Vec<_> = with_stream_watchdog(Box::pin(upstream), ClientProtocol::Claude, timeouts(1_000), || {
+            panic!("completed stream must not stall")
+        })
+        .collect()
+        .await;
+        assert_eq!(out.len(), 2);
+    }
+}
@@ -6,6 +6,8 @@ export interface Account {
     quota?: QuotaData;
     created_at: number;
     last_used: number;
+    egress_proxy?: string; // 账号专属出口代理
+    client_profile?: string; // 客户端指纹名称，留空使用默认指纹
 }
 
 export interface TokenData {
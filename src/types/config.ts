@@ -1,18 +1,97 @@
 export interface UpstreamProxyConfig {
     enabled: boolean;
     url: string;
+    username?: string;
+    password?: string;
+    no_proxy?: string[]; // 不经过代理的主机，格式同 NO_PROXY
 }
 
+export interface ApiKeyConfig {
+    name: string;
+    key: string;
+    enabled: boolean;
+    expires_at?: number | null; // Unix 秒
+    allowed_models?: string[]; // 精确条目匹配客户端或目标模型名，`prefix*` 通配只匹配目标模型
+    requests_per_minute?: number | null;
+    max_concurrent_streams?: number | null;
+    daily_token_budget?: number | null;
+}
+
+export interface CaptureConfig {
+    enabled: boolean;
+    api_keys: string[]; // API Key 名称
+    models: string[]; // 支持 `*` 后缀通配
+    max_entries: number;
+}
+
+export interface UpstreamFixtureConfig {
+    mode: 'off' | 'record' | 'replay';
+    dir: string; // 为空时使用数据目录下的 fixtures
+}
+
+export type SchedulingStrategy =
+    | 'round_robin'
+    | 'least_recently_used'
+    | 'least_in_flight'
+    | 'weighted_quota'
+    | 'strict_priority';
+
 export interface ProxyConfig {
     enabled: boolean;
     port: number;
     api_key: string;
+    api_keys?: ApiKeyConfig[];
+    metrics_key?: string; // /metrics 访问密钥，留空时使用客户端 API Key 鉴权
     auto_start: boolean;
     anthropic_mapping?: Record<string, string>;
     openai_mapping?: Record<string, string>;
     custom_mapping?: Record<string, string>;
+    model_fallbacks?: Record<string, string[]>; // 路由后的目标模型 -> 按顺序尝试的备用模型
     request_timeout: number;
+    stream_first_byte_timeout?: number; // 秒
+    stream_idle_timeout?: number; // 秒
     upstream_proxy: UpstreamProxyConfig;
+    upstream_endpoints?: string[]; // v1internal 端点，按顺序故障转移，为空时使用官方端点
+    scheduling_strategy?: SchedulingStrategy;
+    account_priority?: string[]; // strict_priority 下的账号顺序 (email 或账号 ID)
+    capture?: CaptureConfig;
+    fixtures?: UpstreamFixtureConfig;
+    client_profile?: ClientProfile; // 默认客户端指纹
+    client_profiles?: ClientProfile[]; // 账号可选的具名指纹
+    retry?: RetryConfig;
+    media_fetch?: MediaFetchConfig;
+    trust_forwarded_headers?: boolean; // 文件链接使用 X-Forwarded-Host/Proto，仅在反向代理之后开启
+}
+
+export interface MediaFetchConfig {
+    allowed_hosts: string[]; // 允许抓取图片链接的主机，支持 `*.example.com`，为空时拒绝
+    max_bytes: number;
+    timeout_secs: number;
+}
+
+export interface RetryPolicy {
+    max_attempts: number; // 不超过账号池大小
+    backoff_base_ms: number;
+    backoff_max_ms: number;
+    honor_retry_delay: boolean; // 429 携带 retryDelay 时按其等待
+    max_retry_delay_ms: number; // 超过则直接轮换账号
+    retry_server_errors: boolean; // 5xx 是否换账号重试
+}
+
+export interface RetryConfig {
+    claude: RetryPolicy;
+    openai: RetryPolicy;
+    gemini: RetryPolicy;
+}
+
+export interface ClientProfile {
+    name: string;
+    ide_version: string;
+    platform: string; // 如 windows/amd64、darwin/arm64
+    user_agent?: string; // 留空时生成 antigravity/<ide_version> <platform>
+    metadata?: Record<string, unknown>;
+    body_user_agent: string;
+    request_id_prefix: string;
 }
 
 export interface AppConfig {